@@ -0,0 +1,77 @@
+use assert_fs::prelude::*;
+use assert_fs::NamedTempFile;
+use chopstick::ChunkedReader;
+use criterion::{
+    black_box, criterion_group, criterion_main, BenchmarkId, Criterion,
+    Throughput,
+};
+use std::fs::File;
+use std::io::Write;
+
+const NUM_PARTS: u64 = 4;
+const PART_SIZES: [u64; 4] = [64 << 10, 1 << 20, 16 << 20, 64 << 20];
+
+// Reads every part of the file in turn, like chop does. The bytes are copied
+// once more, as they would be when written to a part, so that every page of a
+// mapped file is actually read
+fn read_parts(mut reader: ChunkedReader, part_size: u64) {
+    let mut written = Vec::with_capacity(part_size as usize);
+    (0..NUM_PARTS).for_each(|part| {
+        let start = part * part_size;
+        reader.seek_to(start).unwrap();
+        while let Some(bytes) =
+            reader.read_up_to(start + part_size - 1).unwrap()
+        {
+            written.clear();
+            written.write_all(bytes).unwrap();
+        }
+        black_box(&written);
+    });
+}
+
+fn readers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reader");
+    group.sample_size(10);
+    PART_SIZES.iter().for_each(|&part_size| {
+        let bytes = (0..part_size * NUM_PARTS)
+            .map(|n| (n * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let temp_file = NamedTempFile::new("bench").unwrap();
+        temp_file.write_binary(&bytes).unwrap();
+        let open = || File::open(temp_file.path()).unwrap();
+        let size = bytesize::to_string(part_size, true);
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("buffered", &size),
+            &part_size,
+            |b, &part_size| {
+                b.iter(|| {
+                    // Allocated every time, as it is for each run of chop
+                    let mut buffer = vec![0; part_size as usize];
+                    let reader = ChunkedReader::new(open(), &mut buffer, false);
+                    read_parts(reader, part_size);
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("mapped", &size),
+            &part_size,
+            |b, &part_size| {
+                b.iter(|| {
+                    let reader = ChunkedReader::mapped(
+                        open(),
+                        part_size as usize,
+                        false,
+                    )
+                    .unwrap_or_else(|_| panic!("Couldn't map file"));
+                    read_parts(reader, part_size);
+                })
+            },
+        );
+    });
+    group.finish();
+}
+
+criterion_group!(benches, readers);
+criterion_main!(benches);
@@ -0,0 +1,273 @@
+use std::ffi::OsStr;
+use std::io::{self, BufRead, BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+// Every zip starts with a local file header
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// The kinds of archive chop can bundle parts into
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub const NAMES: [&'static str; 2] = ["tar", "zip"];
+
+    pub const fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    /// Where the archive of `original_path`'s parts goes
+    pub fn path_for<P: AsRef<Path>>(&self, original_path: P) -> PathBuf {
+        let mut os_str = original_path.as_ref().as_os_str().to_owned();
+        os_str.push(format!(".{}", self.extension()));
+        PathBuf::from(os_str)
+    }
+
+    /// The original file whose parts an archive made by chop holds, going by
+    /// the archive's extension
+    pub fn original_for(archive_path: &Path) -> Option<PathBuf> {
+        let extension = archive_path.extension().and_then(OsStr::to_str)?;
+        match ArchiveFormat::from_str(extension) {
+            Ok(_) => Some(archive_path.with_extension("")),
+            Err(_) => None,
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tar" => Ok(ArchiveFormat::Tar),
+            "zip" => Ok(ArchiveFormat::Zip),
+            _ => Err(format!("{:?} isn't an archive format", s)),
+        }
+    }
+}
+
+/// Writes parts (and anything else, like the manifest) as the entries of an
+/// archive. Entries are stored as they are, without compression, and can be
+/// streamed in: started, written to, then finished
+pub struct ArchiveWriter<W: Write + Seek> {
+    format: Format<W>,
+    // The name, size, and bytes written so far of the entry being written
+    entry: Option<(String, u64, u64)>,
+}
+
+enum Format<W: Write + Seek> {
+    Tar(tar::Builder<W>),
+    Zip(zip::ZipWriter<W>),
+}
+
+// Tar entries are padded out to a whole number of these
+const TAR_BLOCK_SIZE: u64 = 512;
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    pub fn new(format: ArchiveFormat, writer: W) -> Self {
+        let format = match format {
+            ArchiveFormat::Tar => Format::Tar(tar::Builder::new(writer)),
+            ArchiveFormat::Zip => Format::Zip(zip::ZipWriter::new(writer)),
+        };
+        ArchiveWriter {
+            format,
+            entry: None,
+        }
+    }
+
+    /// Adds an entry called `name`, holding exactly `size` bytes read from
+    /// `contents`
+    pub fn add_entry<R: Read>(
+        &mut self,
+        name: &str,
+        size: u64,
+        contents: R,
+    ) -> io::Result<()> {
+        self.start_entry(name, size)?;
+        io::copy(&mut contents.take(size), self)?;
+        self.finish_entry()
+    }
+
+    /// Starts an entry called `name`, whose `size` bytes are then written to
+    /// this before `finish_entry` is called
+    pub fn start_entry(&mut self, name: &str, size: u64) -> io::Result<()> {
+        debug_assert!(self.entry.is_none(), "Last entry should be finished");
+        match &mut self.format {
+            Format::Tar(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(size);
+                header.set_mode(0o644);
+                header.set_mtime(
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|since| since.as_secs())
+                        .unwrap_or(0),
+                );
+                // Only writes the header, as there's nothing to copy, and
+                // nothing to pad. The contents are written straight after
+                builder.append_data(&mut header, name, io::empty())?;
+            }
+            Format::Zip(zip) => {
+                let options = FileOptions::default()
+                    .compression_method(CompressionMethod::Stored)
+                    .unix_permissions(0o644)
+                    .large_file(size > u32::MAX as u64);
+                zip.start_file(name, options)?;
+            }
+        }
+        self.entry = Some((name.to_owned(), size, 0));
+        Ok(())
+    }
+
+    /// Ends the entry being written, which must have had all of its bytes
+    /// written to it
+    pub fn finish_entry(&mut self) -> io::Result<()> {
+        let (name, size, written) =
+            self.entry.take().expect("An entry should be started");
+        // A short tar entry would be padded out, and look like it was whole
+        if written != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} ended before all {} bytes were read", name, size),
+            ));
+        }
+        if let Format::Tar(builder) = &mut self.format {
+            let padding =
+                (TAR_BLOCK_SIZE - size % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+            builder
+                .get_mut()
+                .write_all(&[0; TAR_BLOCK_SIZE as usize][..padding as usize])?;
+        }
+        Ok(())
+    }
+
+    /// Writes the end of the archive, giving back what it was written to
+    pub fn finish(self) -> io::Result<W> {
+        debug_assert!(self.entry.is_none(), "Last entry should be finished");
+        match self.format {
+            Format::Tar(builder) => builder.into_inner(),
+            Format::Zip(mut zip) => Ok(zip.finish()?),
+        }
+    }
+}
+
+// Writes to the entry being written, which won't take more than its size
+impl<W: Write + Seek> Write for ArchiveWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (name, size, written) =
+            self.entry.as_mut().expect("An entry should be started");
+        if *written + buf.len() as u64 > *size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} can only hold {} bytes", name, size),
+            ));
+        }
+        let len = match &mut self.format {
+            Format::Tar(builder) => builder.get_mut().write(buf)?,
+            Format::Zip(zip) => zip.write(buf)?,
+        };
+        *written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.format {
+            Format::Tar(builder) => builder.get_mut().flush(),
+            Format::Zip(zip) => zip.flush(),
+        }
+    }
+}
+
+/// Reads the entries of a tar or zip archive in order, handing each to `f`
+/// with its name and size. The archive is read as a stream, so it can come
+/// from a pipe. Stops at the first error from `f`, giving it back as the
+/// inner result, as the outer one is for errors reading the archive itself
+pub fn for_each_entry<R, E, F>(
+    archive: R,
+    mut f: F,
+) -> io::Result<Result<(), E>>
+where
+    R: Read,
+    F: FnMut(&str, u64, &mut dyn Read) -> Result<(), E>,
+{
+    let mut archive = BufReader::new(archive);
+    if archive.fill_buf()?.starts_with(ZIP_MAGIC) {
+        while let Some(mut entry) =
+            zip::read::read_zipfile_from_stream(&mut archive)?
+        {
+            let name = entry.name().to_owned();
+            let size = entry.size();
+            if let Err(why) = f(&name, size, &mut entry) {
+                return Ok(Err(why));
+            }
+        }
+    } else {
+        for entry in tar::Archive::new(archive).entries()? {
+            let mut entry = entry?;
+            let name =
+                String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            let size = entry.size();
+            if let Err(why) = f(&name, size, &mut entry) {
+                return Ok(Err(why));
+            }
+        }
+    }
+    Ok(Ok(()))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut entries = Vec::new();
+        for_each_entry(archive, |name, size, contents| {
+            let mut bytes = Vec::new();
+            contents.read_to_end(&mut bytes)?;
+            assert_eq!(bytes.len() as u64, size);
+            entries.push((name.to_owned(), bytes));
+            Ok::<_, io::Error>(())
+        })
+        .unwrap()
+        .unwrap();
+        entries
+    }
+
+    #[test]
+    fn round_trip() {
+        ArchiveFormat::NAMES.iter().for_each(|name| {
+            let format = name.parse().unwrap();
+            let mut writer =
+                ArchiveWriter::new(format, Cursor::new(Vec::new()));
+            writer.add_entry("a.p1", 3, &b"abcdef"[..]).unwrap();
+            writer.add_entry("a.p2", 2, &b"gh"[..]).unwrap();
+            assert!(writer.add_entry("a.p3", 5, &b"ij"[..]).is_err());
+            let archive = writer.finish().unwrap().into_inner();
+            let entries = entries(&archive);
+            assert_eq!(entries[0], ("a.p1".to_owned(), b"abc".to_vec()));
+            assert_eq!(entries[1], ("a.p2".to_owned(), b"gh".to_vec()));
+        });
+    }
+
+    #[test]
+    fn paths() {
+        let format = ArchiveFormat::Tar;
+        assert_eq!(format.path_for("dir/a.iso"), Path::new("dir/a.iso.tar"));
+        assert_eq!(
+            ArchiveFormat::original_for(Path::new("dir/a.iso.zip")),
+            Some(PathBuf::from("dir/a.iso"))
+        );
+        assert_eq!(ArchiveFormat::original_for(Path::new("a.iso")), None);
+    }
+}
@@ -0,0 +1,173 @@
+use std::future::Future;
+use std::io;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    BufReader,
+};
+
+/// Chops `source` into parts of `part_size` bytes (the last may be smaller),
+/// writing each to whatever `next_part` gives for its part number (from 1).
+/// The source doesn't need a known length, so it can be e.g. an upload still
+/// coming in. A part is only asked for once there are bytes to put in it,
+/// and is shut down once it's full. Returns how many parts were made, or fails
+/// with `InvalidInput` if `part_size` is zero
+pub async fn chop<R, W, F, Fut>(
+    source: R,
+    part_size: u64,
+    mut next_part: F,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = io::Result<W>>,
+{
+    // Nothing would ever be put in a part, so there'd be no end of them
+    if part_size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Part size should be non-zero",
+        ));
+    }
+    let mut source = BufReader::new(source);
+    let mut part_number = 0;
+    // Checked before each part, so that a source that ends on a part boundary
+    // doesn't leave an empty part after it
+    while !source.fill_buf().await?.is_empty() {
+        part_number += 1;
+        let mut part = next_part(part_number).await?;
+        tokio::io::copy_buf(&mut (&mut source).take(part_size), &mut part)
+            .await?;
+        part.shutdown().await?;
+    }
+    Ok(part_number)
+}
+
+/// Sticks parts back together into `sink`, reading each from whatever
+/// `next_part` gives for its part number (from 1), until it gives `None`.
+/// The sink is flushed, but not shut down, so more can be written to it.
+/// Returns how many bytes were written
+pub async fn stick<R, W, F, Fut>(
+    mut next_part: F,
+    sink: &mut W,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = io::Result<Option<R>>>,
+{
+    let mut part_number = 1;
+    let mut written = 0;
+    while let Some(mut part) = next_part(part_number).await? {
+        written += tokio::io::copy(&mut part, sink).await?;
+        part_number += 1;
+    }
+    sink.flush().await?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    fn run<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    // Chops into in-memory parts, which are shared so that their contents
+    // can be looked at once they've been written
+    fn chop_bytes(bytes: &[u8], part_size: u64) -> Vec<Vec<u8>> {
+        let parts = Arc::new(Mutex::new(Vec::new()));
+        let made = run(chop(bytes, part_size, |_| {
+            let parts = Arc::clone(&parts);
+            async move {
+                let part = SharedPart::default();
+                parts.lock().unwrap().push(part.clone());
+                Ok(part)
+            }
+        }))
+        .unwrap();
+        let parts = parts.lock().unwrap();
+        assert_eq!(made, parts.len() as u64);
+        parts.iter().map(|part| part.contents()).collect()
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct SharedPart(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedPart {
+        fn contents(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl AsyncWrite for SharedPart {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn chops_into_parts() {
+        let bytes = (0..=99).collect::<Vec<u8>>();
+        let parts = chop_bytes(&bytes, 30);
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts.concat(), bytes);
+        assert!(parts[..3].iter().all(|part| part.len() == 30));
+        // No empty part when the parts fit exactly
+        assert_eq!(chop_bytes(&bytes, 25).len(), 4);
+        assert!(chop_bytes(&[], 25).is_empty());
+    }
+
+    #[test]
+    fn zero_part_size() {
+        let err = run(chop(&b"hello"[..], 0, |_| async {
+            Ok(SharedPart::default())
+        }))
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn sticks_parts() {
+        let parts = [b"hello".to_vec(), b" ".to_vec(), b"world".to_vec()];
+        let mut sink = Vec::new();
+        let written = run(stick(
+            |part_number| {
+                let part = parts.get(part_number as usize - 1).cloned();
+                async move { Ok(part.map(Cursor::new)) }
+            },
+            &mut sink,
+        ))
+        .unwrap();
+        assert_eq!(written, 11);
+        assert_eq!(sink, b"hello world");
+    }
+}
@@ -0,0 +1,168 @@
+use crate::args::RunConfig;
+use crate::ChopError::*;
+use crate::Result;
+use chopstick::archive::{ArchiveFormat, ArchiveWriter};
+use chopstick::digits;
+use chopstick::filesystem::{Durability, FileHandle, Filesystem, OpenMode};
+use chopstick::manifest::Manifest;
+use chopstick::parts::{chop_into, Copier, PartError, PartSink};
+use chopstick::split::get_part_path_buf;
+use chopstick::ChunkedReader;
+use std::io::{self, Seek, Write};
+use std::ops::Range;
+use std::path::Path;
+
+// Adds each part to an archive, as an entry named after the part
+struct ArchiveParts<'a, W: Write + Seek> {
+    // Handed out as the writer for each part in turn
+    archive: Option<ArchiveWriter<W>>,
+    file_name: &'a Path,
+    // Part numbers are zero-padded to this many digits
+    width: usize,
+    verbose: bool,
+}
+
+impl<'a, W: Write + Seek> PartSink for ArchiveParts<'a, W> {
+    type Writer = ArchiveWriter<W>;
+
+    fn create_part(
+        &mut self,
+        number: u64,
+        size: u64,
+    ) -> io::Result<Self::Writer> {
+        let mut archive = self.archive.take().expect("Archive should be back");
+        let name = get_part_path_buf(self.file_name, number, self.width);
+        archive.start_entry(&name.to_string_lossy(), size)?;
+        Ok(archive)
+    }
+
+    fn finish_part(
+        &mut self,
+        number: u64,
+        mut archive: Self::Writer,
+    ) -> io::Result<()> {
+        archive.finish_entry()?;
+        if self.verbose {
+            let name = get_part_path_buf(self.file_name, number, self.width);
+            eprintln!("Added {} to archive", name.to_string_lossy());
+        }
+        self.archive = Some(archive);
+        Ok(())
+    }
+}
+
+// Writes the manifest, then every part in order, as the entries of a single
+// archive next to the original. The original is only deleted once the whole
+// archive is safely written, so nothing is lost if chop is stopped part way
+pub fn chop_to_archive<F: Filesystem>(
+    fs: &F,
+    config: &RunConfig,
+    format: ArchiveFormat,
+    reader: &mut ChunkedReader<F::File>,
+    copier: &mut Copier,
+    part_ranges: &[Range<u64>],
+) -> Result<()> {
+    let archive_path = format.path_for(&config.path);
+    if !config.dry_run {
+        let file = fs.open(&archive_path, OpenMode::CreateNew).map_err(
+            |why| match why.kind() {
+                io::ErrorKind::AlreadyExists => {
+                    ArchiveAlreadyExists(archive_path.clone())
+                }
+                _ => FailedToWriteArchive(archive_path.clone(), why),
+            },
+        )?;
+        let archive = ArchiveWriter::new(format, file);
+        let written = write_archive(
+            fs,
+            config,
+            archive,
+            &archive_path,
+            reader,
+            copier,
+            part_ranges,
+        );
+        if let Err(why) = written {
+            // The original still has every byte, so an unfinished archive is
+            // only in the way of trying again
+            let _ = fs.remove_file(&archive_path);
+            return Err(why);
+        }
+    } else if config.verbose {
+        eprintln!("[reading and writing happens]");
+    }
+    if config.verbose {
+        eprintln!("Wrote archive {}", archive_path.to_string_lossy());
+    }
+
+    if !config.retain {
+        if !config.dry_run {
+            fs.remove_file(&config.path)
+                .map_err(FailedToDeleteOriginal)?;
+        }
+        if config.verbose {
+            eprintln!("Deleted original file");
+        }
+    }
+    if config.verbose && !config.dry_run {
+        eprintln!("Finished without error!");
+    }
+    Ok(())
+}
+
+fn write_archive<F: Filesystem>(
+    fs: &F,
+    config: &RunConfig,
+    mut archive: ArchiveWriter<F::File>,
+    archive_path: &Path,
+    reader: &mut ChunkedReader<F::File>,
+    copier: &mut Copier,
+    part_ranges: &[Range<u64>],
+) -> Result<()> {
+    let write_error = |why| FailedToWriteArchive(archive_path.into(), why);
+    // Entries are named after the file, not its path, so that they can be
+    // unpacked anywhere
+    let file_name = Path::new(config.path.file_name().unwrap_or_default());
+    let manifest = Manifest::from_ranges(part_ranges).to_string();
+    archive
+        .add_entry(
+            &Manifest::path_for(file_name).to_string_lossy(),
+            manifest.len() as u64,
+            manifest.as_bytes(),
+        )
+        .map_err(write_error)?;
+    let mut sink = ArchiveParts {
+        archive: Some(archive),
+        file_name,
+        width: digits(part_ranges.len() as u64),
+        verbose: config.verbose,
+    };
+    let parts = (1..).zip(part_ranges.iter().cloned());
+    chop_into(reader, parts, &mut sink, copier, |_, _, _| Ok(())).map_err(
+        |why| match why {
+            PartError::Read(_, why) => FailedToReadPart(why),
+            PartError::Open(_, why) | PartError::Write(_, why) => {
+                write_error(why)
+            }
+            PartError::Hook(why) => why,
+        },
+    )?;
+    let archive = sink.archive.expect("Archive should be back");
+    let mut file = archive.finish().map_err(write_error)?;
+    if config.durability >= Durability::Data {
+        file.sync_all().map_err(write_error)?;
+    }
+    if config.durability == Durability::Full {
+        let folder = match archive_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs.sync_directory(folder).map_err(write_error)?;
+    }
+    // Only advice, so it doesn't matter if it's not taken
+    if config.drop_cache {
+        let _ = file.drop_cache(0, 0);
+        let _ = reader.file.drop_cache(0, 0);
+    }
+    Ok(())
+}
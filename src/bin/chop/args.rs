@@ -1,25 +1,133 @@
+use crate::size;
 use crate::*;
 use bytesize::ByteSize;
+use chopstick::archive::ArchiveFormat;
+use chopstick::cli;
+use chopstick::config::{ConfigError, Defaults};
+use chopstick::filesystem::{Durability, RealFilesystem};
+use chopstick::lock::SetLock;
+use chopstick::manifest::Manifest;
+use chopstick::rejoin::RejoinScript;
+use chopstick::split::{Balanced, Chunking, Split};
+use chopstick::throttle::{IoClass, RateLimiter};
+use chopstick::ContentDefinedChunker;
 use clap::{Arg, ArgGroup, ArgMatches};
+use std::cmp::min;
 use std::fs;
+use std::ops::Range;
 use std::path::PathBuf;
+use std::process;
 use std::str::FromStr;
 
+// What chop has been asked to do
+#[derive(Debug)]
+pub enum Task {
+    Chop(RunConfig),
+    Undo(UndoConfig),
+    Rechop(RechopConfig),
+}
+
+impl Task {
+    pub fn new() -> Result<Self> {
+        let matches = RunConfig::create_clap_app().get_matches();
+        if let Some(shell) = matches.value_of("generate-completions") {
+            let mut clap = RunConfig::create_clap_app();
+            print!(
+                "{}",
+                cli::completions(shell, &mut clap, env!("CARGO_BIN_NAME"))
+            );
+            process::exit(0);
+        } else if matches.is_present("generate-man") {
+            let mut clap = RunConfig::create_clap_app();
+            print!("{}", cli::man_page(&mut clap, env!("CARGO_BIN_NAME")));
+            process::exit(0);
+        }
+        let defaults = Defaults::load("chop", matches.value_of("profile"))?;
+        let mut task = RunConfig::process_matches(&matches, &defaults)?;
+        task.lock(matches.is_present("wait"))?;
+        Ok(task)
+    }
+
+    // Stops other runs using the same files, except for a dry run, which
+    // doesn't change any
+    fn lock(&mut self, wait: bool) -> Result<()> {
+        let (path, dry_run, lock) = match self {
+            Task::Chop(config) => {
+                (&config.path, config.dry_run, &mut config.lock)
+            }
+            Task::Undo(config) => {
+                (&config.path, config.dry_run, &mut config.lock)
+            }
+            Task::Rechop(config) => {
+                (&config.path, config.dry_run, &mut config.lock)
+            }
+        };
+        if !dry_run {
+            *lock = Some(SetLock::acquire(path, wait)?);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct RunConfig {
     pub path: PathBuf,
-    pub split: Split,
+    pub chunking: Chunking,
+    // Only make this part (1-indexed), leaving the original untouched
+    pub only: Option<u64>,
+    // Only make a part of exactly these bytes, leaving the original untouched
+    pub range: Option<Range<u64>>,
+    // Shell command each part is piped to, instead of being written to disk
+    pub filter: Option<String>,
+    // Archive the parts go into, instead of separate files
+    pub archive: Option<ArchiveFormat>,
+    // Scripts that stick the parts back together without chopstick
+    pub rejoin_scripts: Vec<RejoinScript>,
     pub retain: bool,
     pub verbose: bool,
     pub dry_run: bool,
+    pub durability: Durability,
+    // Bytes per second to read and write at most
+    pub rate_limit: Option<u64>,
+    pub io_class: Option<IoClass>,
+    // Read the next chunk while the last is being written
+    pub pipeline: bool,
+    // Bypass the page cache with O_DIRECT
+    pub direct: bool,
+    // Tell the OS that bytes won't be needed again once they're copied
+    pub drop_cache: bool,
+    // Record the run, so that it can be undone
+    pub journal: bool,
+    // Stops other runs using the same files (not taken for a dry run)
+    pub lock: Option<SetLock>,
 }
 
-impl RunConfig {
-    pub fn new() -> Result<Self> {
-        let matches = RunConfig::create_clap_app().get_matches();
-        RunConfig::process_matches(&matches)
-    }
+// Puts back the file that the last chop of `path` made parts of
+#[derive(Debug)]
+pub struct UndoConfig {
+    pub path: PathBuf,
+    pub verbose: bool,
+    pub dry_run: bool,
+    // Whether there's a journal to undo from
+    pub journal: bool,
+    pub lock: Option<SetLock>,
+}
 
+// Chops the parts of `path` again, with a different split
+#[derive(Debug)]
+pub struct RechopConfig {
+    pub path: PathBuf,
+    // From the chop that made the parts, saying how big each one is
+    pub manifest: Manifest,
+    pub chunking: Chunking,
+    pub verbose: bool,
+    pub dry_run: bool,
+    pub durability: Durability,
+    pub rate_limit: Option<u64>,
+    pub lock: Option<SetLock>,
+}
+
+impl RunConfig {
     fn create_clap_app() -> clap::Command<'static> {
         clap::Command::new(env!("CARGO_PKG_NAME"))
             .version(env!("CARGO_PKG_VERSION"))
@@ -33,10 +141,26 @@ impl RunConfig {
                     .long_help(
                         "The maximum size each part should be. \
                         Accepts units - e.g. 1GB, 20K, 128MiB. \
+                        Also accepts the name of a medium the parts should fit on: \
+                        fat32, cd700, dvd, dvd-dl, bd25, or email25m; \
+                        or a preset from the [presets] table of the config file. \
                         The last part may be smaller than the others",
                     )
                     .takes_value(true),
             )
+            .arg(
+                Arg::new("fit_to")
+                    .long("fit-to")
+                    .value_name("DIR")
+                    .help("Make parts as large as will fit in this directory")
+                    .long_help(
+                        "Make parts as large as will fit in this directory, \
+                        based on the free space of the disk it's on. \
+                        Parts for a FAT32 disk are kept under 4 GiB",
+                    )
+                    .takes_value(true)
+                    .allow_invalid_utf8(true),
+            )
             .arg(
                 Arg::new("num_parts")
                     .short('n')
@@ -48,6 +172,189 @@ impl RunConfig {
                     )
                     .takes_value(true),
             )
+            .arg(
+                Arg::new("balanced")
+                    .short('b')
+                    .long("balanced")
+                    .help("Make exactly the number of parts given with --parts")
+                    .long_help(
+                        "Make exactly the number of parts given with --parts. \
+                        Part sizes differ by at most one byte, instead of the last \
+                        part being smaller",
+                    )
+                    .requires("num_parts"),
+            )
+            .arg(
+                Arg::new("avg_part_size")
+                    .short('c')
+                    .long("content-defined")
+                    .help("Choose where to split based on the file's contents, aiming for parts of this size")
+                    .long_help(
+                        "Choose where to split based on the file's contents, \
+                        aiming for parts of this size on average. \
+                        An insertion or deletion in the file will only change \
+                        the parts near it, which suits deduplicating storage. \
+                        Accepts units - e.g. 1GB, 20K, 128MiB",
+                    )
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("min_part_size")
+                    .long("min-size")
+                    .help("The minimum size of content-defined parts")
+                    .long_help(
+                        "The minimum size of content-defined parts. \
+                        Defaults to a quarter of the average size",
+                    )
+                    .requires("avg_part_size")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("max_part_size")
+                    .long("max-size")
+                    .help("The maximum size of content-defined parts")
+                    .long_help(
+                        "The maximum size of content-defined parts. \
+                        Defaults to four times the average size",
+                    )
+                    .requires("avg_part_size")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("byte_range")
+                    .long("range")
+                    .help("Only make a part holding these bytes, e.g. 1GiB..2GiB")
+                    .long_help(
+                        "Only make a part holding exactly these bytes, \
+                        e.g. 1GiB..2GiB (the end is exclusive, and a range \
+                        running past the end of the file stops there). \
+                        If it's a part that a chop using its length as the \
+                        part size would make, it's named after that part, \
+                        otherwise after the range, e.g. <file>.45-65. \
+                        The original file is left untouched",
+                    )
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("part_number")
+                    .long("only")
+                    .help("Only make the part with this number")
+                    .long_help(
+                        "Only make the part with this number, named as it \
+                        would be by a full chop. \
+                        The original file is left untouched",
+                    )
+                    .conflicts_with("byte_range")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("filter")
+                    .long("filter")
+                    .help("Pipe each part to this shell command instead of writing it to a file")
+                    .long_help(
+                        "Pipe each part to this shell command instead of \
+                        writing it to a file. \
+                        The command is run once per part, with FILE set to \
+                        the part's file name, CHOP_PART_NUMBER to its number, \
+                        and CHOP_PART_SIZE to its size in bytes. \
+                        The original file is only truncated once the command \
+                        has exited successfully",
+                    )
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("archive")
+                    .long("archive")
+                    .value_name("FORMAT")
+                    .help("Write the parts and manifest into one archive")
+                    .long_help(
+                        "Write the parts and manifest as the entries of one \
+                        archive, <file>.tar or <file>.zip, instead of \
+                        separate files. The original file is only deleted \
+                        once the whole archive is written, and an unfinished \
+                        archive is removed if chop fails. \
+                        stick --from-archive sticks the parts back together",
+                    )
+                    .takes_value(true)
+                    .possible_values(ArchiveFormat::NAMES)
+                    .conflicts_with_all(&["filter", "part_number", "byte_range"]),
+            )
+            .arg(
+                Arg::new("with-rejoin-script")
+                    .long("with-rejoin-script")
+                    .value_name("SHELL")
+                    .help("Also write a script that sticks the parts together")
+                    .long_help(
+                        "Also write a script, <file>.rejoin.sh, that checks \
+                        and sticks the parts back together with only cat, \
+                        cut, and sha256sum, for anyone without stick. \
+                        Give =powershell for a PowerShell script, \
+                        <file>.rejoin.ps1, or =sh,powershell for both. \
+                        Each part is hashed as it's made, and the hashes are \
+                        added to the manifest",
+                    )
+                    .takes_value(true)
+                    .min_values(0)
+                    .require_equals(true)
+                    .use_value_delimiter(true)
+                    .default_missing_value("sh")
+                    .possible_values(RejoinScript::NAMES)
+                    .conflicts_with_all(&[
+                        "filter",
+                        "part_number",
+                        "byte_range",
+                        "archive",
+                    ]),
+            )
+            .arg(
+                Arg::new("undo")
+                    .long("undo")
+                    .help("Undo the last chop of the file")
+                    .long_help(
+                        "Undo the last chop of the file, sticking its parts \
+                        back together, or if the original was retained, \
+                        deleting the parts, manifest, and rejoin scripts \
+                        that were made. Refuses if any of them have changed \
+                        since",
+                    )
+                    .conflicts_with_all(&[
+                        "part_number",
+                        "filter",
+                        "archive",
+                        "with-rejoin-script",
+                        "retain",
+                    ]),
+            )
+            .arg(
+                Arg::new("rechop")
+                    .long("rechop")
+                    .help("Chop the file's parts again, into a different split")
+                    .long_help(
+                        "Chop the file's parts again, into a different split, \
+                        without sticking them back together first. Each new \
+                        part is made from the old parts it covers, which are \
+                        then truncated or deleted, so only about one new \
+                        part's worth of extra disk space is needed. Needs the \
+                        manifest from the chop. New parts are named \
+                        <part>.rechop until every old part is gone, and how \
+                        far it's got is kept in <file>.rechop, so running the \
+                        same rechop again finishes one that was interrupted",
+                    )
+                    .conflicts_with_all(&[
+                        "avg_part_size",
+                        "part_number",
+                        "byte_range",
+                        "filter",
+                        "archive",
+                        "with-rejoin-script",
+                        "undo",
+                        "retain",
+                        "pipeline",
+                        "direct",
+                        "drop-cache",
+                        "ionice",
+                    ]),
+            )
             .arg(
                 Arg::new("retain")
                     .short('r')
@@ -69,56 +376,408 @@ impl RunConfig {
                     .help("Don't actually do anything, just tell me about it")
                     .long_help("Don't actually do anything, just tell me about it (implies --verbose)"),
             )
+            .arg(
+                Arg::new("wait")
+                    .long("wait")
+                    .help("If another run is using the same files, wait for it to finish")
+                    .long_help(
+                        "If another run of chop or stick is using the same \
+                        files, wait for it to finish instead of failing. \
+                        Runs are only locked on unix",
+                    ),
+            )
+            .arg(
+                Arg::new("rate-limit")
+                    .long("rate-limit")
+                    .value_name("RATE")
+                    .help("Read and write at most this much per second")
+                    .long_help(
+                        "Read and write at most this much per second, \
+                        e.g. 50MiB/s, so as not to starve other processes \
+                        using the disk",
+                    )
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("ionice")
+                    .long("ionice")
+                    .value_name("CLASS")
+                    .help("Run in this I/O scheduling class (Linux only)")
+                    .long_help(
+                        "Run in this I/O scheduling class (Linux only). \
+                        idle only uses the disk when nothing else is, and \
+                        best-effort shares it at the lowest priority",
+                    )
+                    .takes_value(true)
+                    .possible_values(IoClass::NAMES),
+            )
+            .arg(
+                Arg::new("pipeline")
+                    .long("pipeline")
+                    .help("Read the next chunk while the last is being written")
+                    .long_help(
+                        "Read the next chunk while the last is being written, \
+                        on another thread, or with io_uring if built with the \
+                        io-uring feature. The buffer is split in two for \
+                        this, so no more memory is used",
+                    ),
+            )
+            .arg(
+                Arg::new("direct")
+                    .long("direct")
+                    .help("Bypass the page cache, with O_DIRECT (Linux only)")
+                    .long_help(
+                        "Bypass the page cache, by reading and writing with \
+                        O_DIRECT (Linux only), so copying a large file doesn't \
+                        push everything else out of it. Anything not aligned \
+                        to 4KiB, such as the end of each part, still goes \
+                        through the page cache",
+                    ),
+            )
+            .arg(
+                Arg::new("drop-cache")
+                    .long("drop-cache")
+                    .help("Drop bytes from the page cache once they're copied")
+                    .long_help(
+                        "Advise the OS to drop bytes from the page cache once \
+                        they're copied (Linux only). Lighter than --direct, \
+                        but only bytes already synced to disk can be dropped",
+                    ),
+            )
+            .arg(
+                Arg::new("durability")
+                    .long("durability")
+                    .help("How much to sync to disk before truncating the original")
+                    .long_help(
+                        "How much to sync to disk before truncating the \
+                        original file. none leaves it to the OS, data syncs \
+                        each part, and full also syncs the part's directory \
+                        entry, so nothing is lost if the power is cut \
+                        [default: full]",
+                    )
+                    .value_name("LEVEL")
+                    .takes_value(true)
+                    .possible_values(Durability::NAMES),
+            )
+            .arg(
+                Arg::new("profile")
+                    .long("profile")
+                    .help("Use the defaults from this profile of the config file")
+                    .long_help(
+                        "Use the defaults from this profile of the config file, \
+                        found in its [profiles.<name>] table. \
+                        Can also be set with CHOPSTICK_PROFILE",
+                    )
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("print-config")
+                    .long("print-config")
+                    .help("Show the defaults in use, and where each came from")
+                    .long_help(
+                        "Show the defaults in use, and where each came from. \
+                        Defaults for --retain, --verbose, --balanced, --pipeline, --durability, and --journal can be set in \
+                        $XDG_CONFIG_HOME/chopstick/config.toml, or with environment \
+                        variables such as CHOPSTICK_RETAIN=1. \
+                        Turn one off for a run with --no-<option>, such as \
+                        --no-retain",
+                    ),
+            )
             .arg(
                 Arg::new("file")
                     .help("The file to split")
-                    .required(true)
+                    .required_unless_present_any([
+                        "print-config",
+                        "generate-completions",
+                        "generate-man",
+                    ])
                     .takes_value(true)
                     .allow_invalid_utf8(true),
             )
             .group(
                 ArgGroup::new("require_exactly_one")
-                    .args(&["part_size", "num_parts"])
+                    .args(&[
+                        "part_size",
+                        "fit_to",
+                        "num_parts",
+                        "avg_part_size",
+                        "byte_range",
+                        // Each of these does something other than chop, so
+                        // stands in for a split
+                        "undo",
+                        "print-config",
+                        "generate-completions",
+                        "generate-man",
+                    ])
                     .required(true),
             )
+            // So that the command line always wins over the config file
+            .args([
+                cli::negation("retain", "no-retain"),
+                cli::negation("verbose", "no-verbose"),
+                cli::negation("balanced", "no-balanced"),
+                cli::negation("pipeline", "no-pipeline"),
+            ])
+            .args(cli::journal_args())
+            .args(cli::generate_args())
     }
 
-    fn process_matches(clap_matches: &ArgMatches) -> Result<Self> {
+    // Nothing is locked yet, so that working out what to do has no effect
+    fn process_matches(
+        clap_matches: &ArgMatches,
+        defaults: &Defaults,
+    ) -> Result<Task> {
+        let retain = defaults.flag(
+            "retain",
+            cli::flag(clap_matches, "retain"),
+            false,
+        )?;
+        let verbose = defaults.flag(
+            "verbose",
+            cli::flag(clap_matches, "verbose"),
+            false,
+        )?;
+        let balanced = defaults.flag(
+            "balanced",
+            cli::flag(clap_matches, "balanced"),
+            false,
+        )?;
+        let pipeline = defaults.flag(
+            "pipeline",
+            cli::flag(clap_matches, "pipeline"),
+            false,
+        )?;
+        let durability = defaults.string(
+            "durability",
+            clap_matches.value_of("durability"),
+            "full",
+        )?;
+        let journal = defaults.flag(
+            "journal",
+            cli::flag(clap_matches, "journal"),
+            false,
+        )?;
+        if clap_matches.is_present("print-config") {
+            print!(
+                "{}",
+                defaults.describe(&[
+                    ("retain", retain.value.to_string(), &retain.source),
+                    ("verbose", verbose.value.to_string(), &verbose.source),
+                    ("balanced", balanced.value.to_string(), &balanced.source),
+                    ("pipeline", pipeline.value.to_string(), &pipeline.source),
+                    (
+                        "durability",
+                        format!("{:?}", durability.value),
+                        &durability.source,
+                    ),
+                    ("journal", journal.value.to_string(), &journal.source),
+                ])
+            );
+            process::exit(0);
+        }
+
+        // Unwrap is assured by "file" being required unless printing config
         let path: PathBuf = clap_matches.value_of_os("file").unwrap().into();
-        let file_size = fs::metadata(&path)?.len();
+        // The file doesn't exist after a chop, so nothing else can be worked
+        // out
+        if clap_matches.is_present("undo") {
+            let dry_run = clap_matches.is_present("dry-run");
+            return Ok(Task::Undo(UndoConfig {
+                path,
+                verbose: dry_run || verbose.value,
+                dry_run,
+                journal: journal.value,
+                lock: None,
+            }));
+        }
+        // The file only exists as parts when rechopping, so its size comes
+        // from their manifest
+        let manifest = match clap_matches.is_present("rechop") {
+            true => {
+                let manifest_path = Manifest::path_for(&path);
+                Some(
+                    Manifest::read(&RealFilesystem, &manifest_path).map_err(
+                        |why| FailedToReadManifest(manifest_path, why),
+                    )?,
+                )
+            }
+            false => None,
+        };
+        let file_size = match &manifest {
+            Some(manifest) => manifest.file_size,
+            None => fs::metadata(&path)?.len(),
+        };
+
+        let only = match clap_matches.value_of("part_number") {
+            Some(only_str) => {
+                Some(only_str.parse().map_err(|_| InvalidPartNumber)?)
+            }
+            None => None,
+        };
+        let mut range = None;
 
-        let split = if let Some(part_size_str) =
+        let chunking = if let Some(part_size_str) =
             clap_matches.value_of("part_size")
         {
-            let ByteSize(part_size) = ByteSize::from_str(part_size_str)?;
-            Split::from_part_size(file_size, part_size)?
+            let part_size = size::parse_size(part_size_str)?;
+            Chunking::Split(Split::from_part_size(file_size, part_size)?)
+        } else if let Some(directory) = clap_matches.value_of_os("fit_to") {
+            let part_size = size::fit_to(directory.as_ref())?;
+            Chunking::Split(Split::from_part_size(file_size, part_size)?)
         } else if let Some(num_parts_str) = clap_matches.value_of("num_parts") {
             let num_parts =
                 num_parts_str.parse().map_err(|_| InvalidNumParts)?;
-            Split::from_num_parts(file_size, num_parts)?
+            if balanced.value {
+                Chunking::Balanced(Balanced::new(file_size, num_parts)?)
+            } else {
+                Chunking::Split(Split::from_num_parts(file_size, num_parts)?)
+            }
+        } else if let Some(avg_size_str) =
+            clap_matches.value_of("avg_part_size")
+        {
+            let ByteSize(avg_size) = ByteSize::from_str(avg_size_str)?;
+            let defaults = ContentDefinedChunker::new(avg_size);
+            let min_size = match clap_matches.value_of("min_part_size") {
+                Some(size_str) => ByteSize::from_str(size_str)?.0,
+                None => defaults.min_size,
+            };
+            let max_size = match clap_matches.value_of("max_part_size") {
+                Some(size_str) => ByteSize::from_str(size_str)?.0,
+                None => defaults.max_size,
+            };
+            let chunker = ContentDefinedChunker::with_bounds(
+                min_size, avg_size, max_size,
+            );
+            if !chunker.is_valid() {
+                return Err(InvalidChunkBounds);
+            } else if avg_size >= file_size {
+                return Err(PartSizeTooLarge);
+            }
+            Chunking::ContentDefined(chunker)
+        } else if let Some(range_str) = clap_matches.value_of("byte_range") {
+            let (start, end) = parse_range(range_str)?;
+            // A range running past the end of the file stops at it
+            let end = min(end, file_size);
+            if start >= end {
+                return Err(RangeOutsideFile(start, file_size));
+            }
+            range = Some(start..end);
+            // The part is named after the one a chop using its length as the
+            // part size would make, if there is such a part
+            let part_size = end - start;
+            let split =
+                Split::from_part_size(file_size, part_size).unwrap_or(Split {
+                    part_size,
+                    num_parts: 1,
+                });
+            Chunking::Split(split)
         } else {
             unreachable!(
-                "Either num_parts or part_size should have been specified"
+                "One of num_parts, part_size, or avg_part_size should have \
+                been specified"
             );
         };
 
-        let retain = clap_matches.is_present("retain");
+        let filter = clap_matches.value_of("filter").map(str::to_owned);
+        let archive = clap_matches
+            .value_of("archive")
+            .map(|format| format.parse().expect("clap checks possible values"));
+        let rejoin_scripts = clap_matches
+            .values_of("with-rejoin-script")
+            .map(|shells| {
+                shells
+                    .map(|shell| {
+                        shell.parse().expect("clap checks possible values")
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        // Making a single part never changes the original
+        let retain = only.is_some() || range.is_some() || retain.value;
         let dry_run = clap_matches.is_present("dry-run");
-        let verbose = dry_run || clap_matches.is_present("verbose");
+        let verbose = dry_run || verbose.value;
+        let durability = durability.value.parse().map_err(|_| {
+            ConfigError::InvalidValue(String::from("durability"))
+        })?;
+        let rate_limit = match clap_matches.value_of("rate-limit") {
+            Some(rate) => Some(
+                RateLimiter::parse_rate(rate)
+                    .ok_or_else(|| InvalidRate(rate.to_owned()))?,
+            ),
+            None => None,
+        };
+        let io_class = clap_matches
+            .value_of("ionice")
+            .map(|class| class.parse().expect("clap checks possible values"));
 
-        Ok(RunConfig {
+        if let Some(manifest) = manifest {
+            return Ok(Task::Rechop(RechopConfig {
+                path,
+                manifest,
+                chunking,
+                verbose,
+                dry_run,
+                durability,
+                rate_limit,
+                lock: None,
+            }));
+        }
+        Ok(Task::Chop(RunConfig {
             path,
-            split,
+            chunking,
+            only,
+            range,
+            filter,
+            archive,
+            rejoin_scripts,
             retain,
             verbose,
             dry_run,
-        })
+            durability,
+            rate_limit,
+            io_class,
+            pipeline: pipeline.value,
+            direct: clap_matches.is_present("direct"),
+            drop_cache: clap_matches.is_present("drop-cache"),
+            journal: journal.value,
+            lock: None,
+        }))
+    }
+
+    // The manifest describes a whole set of parts on disk, so isn't made for
+    // a single part or parts given to a filter. Archives have their own
+    pub fn writes_manifest(&self) -> bool {
+        self.only.is_none()
+            && self.range.is_none()
+            && self.filter.is_none()
+            && self.archive.is_none()
+    }
+}
+
+// Parses START..END, where both ends accept units
+fn parse_range(range_str: &str) -> Result<(u64, u64)> {
+    let (start_str, end_str) = range_str
+        .split_once("..")
+        .ok_or_else(|| InvalidRange(range_str.to_owned()))?;
+    let ByteSize(start) = ByteSize::from_str(start_str.trim())?;
+    let ByteSize(end) = ByteSize::from_str(end_str.trim())?;
+    if start < end {
+        Ok((start, end))
+    } else {
+        Err(InvalidRange(range_str.to_owned()))
     }
 }
 
 #[cfg(test)]
 mod unit_tests {
-    use super::RunConfig;
+    use super::{parse_range, RunConfig, Task};
+    use chopstick::config::{Config, Defaults};
+    use std::collections::HashMap;
+
+    // Nothing from the config file or environment of whoever runs the tests
+    fn defaults() -> Defaults {
+        Defaults::new("chop", None, HashMap::new(), None).unwrap()
+    }
 
     #[test]
     fn requires_file() {
@@ -149,7 +808,7 @@ mod unit_tests {
             ])
             .unwrap();
         assert!(matches.is_present("num_parts"));
-        assert!(RunConfig::process_matches(&matches).is_ok());
+        assert!(RunConfig::process_matches(&matches, &defaults()).is_ok());
 
         let clap = RunConfig::create_clap_app();
         let matches = clap
@@ -161,7 +820,7 @@ mod unit_tests {
             ])
             .unwrap();
         assert!(matches.is_present("part_size"));
-        assert!(RunConfig::process_matches(&matches).is_ok());
+        assert!(RunConfig::process_matches(&matches, &defaults()).is_ok());
 
         // Both
         let clap = RunConfig::create_clap_app();
@@ -177,4 +836,53 @@ mod unit_tests {
             .unwrap_err();
         assert_eq!(err.kind(), clap::ErrorKind::ArgumentConflict);
     }
+
+    #[test]
+    fn defaults_from_config_and_environment() {
+        let config =
+            Config::parse("config.toml".into(), "[chop]\nretain = true\n")
+                .unwrap();
+        let env = [("CHOPSTICK_VERBOSE".to_owned(), "yes".to_owned())];
+        let defaults = Defaults::new(
+            "chop",
+            Some(config),
+            env.into_iter().collect(),
+            None,
+        )
+        .unwrap();
+        let process = |args: &[&str]| {
+            let matches = RunConfig::create_clap_app()
+                .try_get_matches_from(
+                    [env!("CARGO_PKG_NAME"), "-n", "5", "Cargo.toml"]
+                        .iter()
+                        .chain(args),
+                )
+                .unwrap();
+            match RunConfig::process_matches(&matches, &defaults).unwrap() {
+                Task::Chop(config) => config,
+                task => panic!("Not a chop: {:?}", task),
+            }
+        };
+
+        let config = process(&[]);
+        assert!(config.retain);
+        assert!(config.verbose);
+        assert!(!config.journal);
+        // Nothing's locked until the task is run
+        assert!(config.lock.is_none());
+        let config = process(&["--no-retain", "--no-verbose"]);
+        assert!(!config.retain);
+        assert!(!config.verbose);
+    }
+
+    #[test]
+    fn range_parsing() {
+        assert_eq!(parse_range("0..10").unwrap(), (0, 10));
+        assert_eq!(parse_range("1KiB..2KiB").unwrap(), (1024, 2048));
+        assert_eq!(parse_range("1 KiB .. 2 KiB").unwrap(), (1024, 2048));
+        assert!(parse_range("10..10").is_err());
+        assert!(parse_range("20..10").is_err());
+        assert!(parse_range("10-20").is_err());
+        assert!(parse_range("..20").is_err());
+    }
 }
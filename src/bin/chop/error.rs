@@ -1,5 +1,10 @@
+use chopstick::config::ConfigError;
+use chopstick::journal::JournalError;
+use chopstick::lock::LockError;
+use chopstick::split::SplitError;
 use std::error::Error;
 use std::path::PathBuf;
+use std::process::ExitStatus;
 use std::{fmt, io};
 
 pub type Result<T, E = ChopError> = std::result::Result<T, E>;
@@ -8,15 +13,39 @@ pub type Result<T, E = ChopError> = std::result::Result<T, E>;
 pub enum ChopError {
     GenericIo(io::Error),
     ByteSize(String),
+    UnknownSize(String),
+    CantFitTo(PathBuf, &'static str),
+    Config(ConfigError),
+    Locked(LockError),
     PartSizeTooLarge,
     NumPartsTooLarge,
     InvalidNumParts,
+    InvalidChunkBounds,
+    InvalidPartNumber,
+    InvalidRange(String),
+    InvalidRate(String),
+    RangeOutsideFile(u64, u64),
+    NoSuchPart(u64, usize),
     InsufficientDiskSpace,
     PartFileAlreadyExists(PathBuf),
     FailedToReadPart(io::Error),
     FailedToWritePart(PathBuf, io::Error),
+    FailedToWriteManifest(PathBuf, io::Error),
+    FailedToSpawnFilter(io::Error),
+    FilterFailed(PathBuf, ExitStatus),
     FailedToTruncate(io::Error),
     FailedToDeleteOriginal(io::Error),
+    ArchiveAlreadyExists(PathBuf),
+    FailedToWriteArchive(PathBuf, io::Error),
+    FailedToWriteScript(PathBuf, io::Error),
+    Journal(JournalError),
+    FailedToUndo(PathBuf, io::Error),
+    FailedToReadManifest(PathBuf, io::Error),
+    WrongPartSize(PathBuf, u64, u64),
+    FailedToRechop(PathBuf, io::Error),
+    FailedToReadRechop(PathBuf, io::Error),
+    FailedToRecordRechop(PathBuf, io::Error),
+    UnfinishedRechop(PathBuf),
 }
 
 impl ChopError {
@@ -25,15 +54,40 @@ impl ChopError {
         match self {
             GenericIo(_) => 2,
             ByteSize(_) => 1,
+            UnknownSize(_) => 1,
+            CantFitTo(_, _) => 1,
+            Config(_) => 1,
+            Locked(LockError::Held(_, _)) => 1,
+            Locked(LockError::Io(_, _)) => 2,
             PartSizeTooLarge => 1,
             NumPartsTooLarge => 1,
             InvalidNumParts => 1,
+            InvalidChunkBounds => 1,
+            InvalidPartNumber => 1,
+            InvalidRange(_) => 1,
+            InvalidRate(_) => 1,
+            RangeOutsideFile(_, _) => 1,
+            NoSuchPart(_, _) => 1,
             InsufficientDiskSpace => 1,
             PartFileAlreadyExists(_) => 1,
             FailedToReadPart(_) => 2,
             FailedToWritePart(_, _) => 2,
+            FailedToWriteManifest(_, _) => 2,
+            FailedToSpawnFilter(_) => 2,
+            FilterFailed(_, _) => 2,
             FailedToTruncate(_) => 2,
             FailedToDeleteOriginal(_) => 2,
+            ArchiveAlreadyExists(_) => 1,
+            FailedToWriteArchive(_, _) => 2,
+            FailedToWriteScript(_, _) => 2,
+            Journal(why) => why.exit_code(),
+            FailedToUndo(_, _) => 2,
+            FailedToReadManifest(_, _) => 1,
+            WrongPartSize(_, _, _) => 1,
+            FailedToRechop(_, _) => 2,
+            FailedToReadRechop(_, _) => 1,
+            FailedToRecordRechop(_, _) => 2,
+            UnfinishedRechop(_) => 1,
         }
     }
 }
@@ -44,6 +98,19 @@ impl fmt::Display for ChopError {
         match self {
             GenericIo(why) => write!(f, "IO error: {}", why),
             ByteSize(why) => write!(f, "Error parsing file size: {}", why),
+            UnknownSize(size) => write!(
+                f,
+                "{:?} isn't a size (e.g. 1GB, 20K, 128MiB) or a known preset",
+                size
+            ),
+            CantFitTo(path, why) => write!(
+                f,
+                "Couldn't work out how big parts for {} can be: {}",
+                path.to_string_lossy(),
+                why
+            ),
+            Config(why) => write!(f, "{}", why),
+            Locked(why) => write!(f, "{}", why),
             PartSizeTooLarge => {
                 write!(f, "Part size too large. File wouldn't be split")
             }
@@ -52,6 +119,33 @@ impl fmt::Display for ChopError {
                 "Number of parts too large. Each part would be less than 1 byte"
             ),
             InvalidNumParts => write!(f, "Failed to parse number of parts"),
+            InvalidChunkBounds => write!(
+                f,
+                "Part sizes must be non-zero and satisfy minimum <= average <= maximum"
+            ),
+            InvalidPartNumber => write!(f, "Failed to parse part number"),
+            InvalidRange(range) => write!(
+                f,
+                "Invalid range {:?}, expected START..END (e.g. 1GiB..2GiB)",
+                range
+            ),
+            InvalidRate(rate) => write!(
+                f,
+                "Invalid rate limit {:?}, expected a size per second \
+                (e.g. 50MiB/s)",
+                rate
+            ),
+            RangeOutsideFile(start, file_size) => write!(
+                f,
+                "The range starts at byte {}, past the end of the file \
+                ({} bytes)",
+                start, file_size
+            ),
+            NoSuchPart(part, num_parts) => write!(
+                f,
+                "There is no part {} (the file would be chopped into {} parts)",
+                part, num_parts
+            ),
             InsufficientDiskSpace => {
                 write!(f, "Insufficient disk space to perform operation")
             }
@@ -69,12 +163,89 @@ impl fmt::Display for ChopError {
                 path.to_string_lossy(),
                 why
             ),
+            FailedToWriteManifest(path, why) => write!(
+                f,
+                "Failed to write manifest {}: {}",
+                path.to_string_lossy(),
+                why
+            ),
+            FailedToSpawnFilter(why) => {
+                write!(f, "Failed to start filter command: {}", why)
+            }
+            FilterFailed(path, status) => write!(
+                f,
+                "Filter command failed for part {} ({})",
+                path.to_string_lossy(),
+                status
+            ),
             FailedToTruncate(why) => {
                 write!(f, "Failed to truncate original file: {}", why)
             }
             FailedToDeleteOriginal(why) => {
                 write!(f, "Failed to delete original file: {}", why)
             }
+            ArchiveAlreadyExists(path) => write!(
+                f,
+                "Archive already exists at {}",
+                path.to_string_lossy()
+            ),
+            FailedToWriteArchive(path, why) => write!(
+                f,
+                "Failed to write archive {}: {}",
+                path.to_string_lossy(),
+                why
+            ),
+            FailedToWriteScript(path, why) => write!(
+                f,
+                "Failed to write rejoin script {}: {}",
+                path.to_string_lossy(),
+                why
+            ),
+            Journal(why) => write!(f, "{}", why),
+            FailedToUndo(path, why) => write!(
+                f,
+                "Failed to undo the chop at {}: {}",
+                path.to_string_lossy(),
+                why
+            ),
+            FailedToReadManifest(path, why) => write!(
+                f,
+                "Failed to read manifest {}, which rechopping needs: {}",
+                path.to_string_lossy(),
+                why
+            ),
+            WrongPartSize(path, expected, actual) => write!(
+                f,
+                "Part {} should be {} bytes according to the manifest, but \
+                is {}",
+                path.to_string_lossy(),
+                expected,
+                actual
+            ),
+            FailedToRechop(path, why) => write!(
+                f,
+                "Failed to rechop part {}: {}",
+                path.to_string_lossy(),
+                why
+            ),
+            FailedToReadRechop(path, why) => write!(
+                f,
+                "Failed to read {}, left by an unfinished rechop: {}",
+                path.to_string_lossy(),
+                why
+            ),
+            FailedToRecordRechop(path, why) => write!(
+                f,
+                "Failed to record how far the rechop has got in {}: {}",
+                path.to_string_lossy(),
+                why
+            ),
+            UnfinishedRechop(path) => write!(
+                f,
+                "{} records an unfinished rechop into a different split. \
+                Run it again with the same options to finish it",
+                path.to_string_lossy()
+            ),
         }
     }
 }
@@ -87,6 +258,33 @@ impl From<io::Error> for ChopError {
     }
 }
 
+impl From<ConfigError> for ChopError {
+    fn from(err: ConfigError) -> Self {
+        ChopError::Config(err)
+    }
+}
+
+impl From<JournalError> for ChopError {
+    fn from(err: JournalError) -> Self {
+        ChopError::Journal(err)
+    }
+}
+
+impl From<LockError> for ChopError {
+    fn from(err: LockError) -> Self {
+        ChopError::Locked(err)
+    }
+}
+
+impl From<SplitError> for ChopError {
+    fn from(err: SplitError) -> Self {
+        match err {
+            SplitError::PartSizeTooLarge => ChopError::PartSizeTooLarge,
+            SplitError::NumPartsTooLarge => ChopError::NumPartsTooLarge,
+        }
+    }
+}
+
 // For bytesize errors
 impl From<String> for ChopError {
     fn from(string: String) -> Self {
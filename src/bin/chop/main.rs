@@ -1,18 +1,30 @@
 use crate::ChopError::*;
-use args::RunConfig;
-use chopstick::{
-    digits, max_buffer_size, sufficient_disk_space, ChunkedReader,
+use args::{RunConfig, Task};
+use chopstick::filesystem::{
+    DirectFilesystem, FileHandle, Filesystem, OpenMode, RealFilesystem,
 };
+use chopstick::manifest::Manifest;
+use chopstick::parts::{
+    chop_into, ChunkOptions, Copier, FilesystemParts, PartError,
+};
+use chopstick::split::{get_part_path_buf, Chunking};
+use chopstick::throttle::{set_io_class, RateLimiter};
+use chopstick::{digits, max_buffer_size, ChunkedReader};
 pub use error::*;
-pub use lib::*;
-use std::cmp::min;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::{fs, mem, process};
+use output::{FilterExit, FilterParts, Hashed};
+use std::cmp::{max, min};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::{mem, process};
 
+mod archive;
 mod args;
 mod error;
-mod lib;
+mod output;
+mod rechop;
+mod size;
+mod undo;
 
 fn main() {
     if let Err(why) = _main() {
@@ -22,16 +34,56 @@ fn main() {
 }
 
 fn _main() -> Result<()> {
-    let config = RunConfig::new()?;
-    let file_size = fs::metadata(&config.path)?.len();
+    let config = match Task::new()? {
+        Task::Chop(config) => config,
+        Task::Undo(config) => return undo::undo_chop(&RealFilesystem, &config),
+        Task::Rechop(config) => {
+            return rechop::rechop(&RealFilesystem, &config)
+        }
+    };
+    if let Some(class) = config.io_class {
+        match set_io_class(class) {
+            Ok(()) if config.verbose => {
+                eprintln!("Set I/O scheduling class to {:?}", class)
+            }
+            Ok(()) => {}
+            Err(why) => {
+                eprintln!("WARNING: couldn't set I/O scheduling class ({why})")
+            }
+        }
+    }
+    let parts = match config.direct {
+        true => chop(&DirectFilesystem::new(RealFilesystem), &config),
+        false => chop(&RealFilesystem, &config),
+    }?;
+    if !config.dry_run && config.journal {
+        undo::record_chop(&config, &parts);
+    }
+    Ok(())
+}
+
+// Gives the parts left on disk, in order
+fn chop<F: Filesystem>(fs: &F, config: &RunConfig) -> Result<Vec<PathBuf>> {
+    if let (Some(lock), true) = (&config.lock, config.verbose) {
+        eprintln!("Locked {}", lock.path().to_string_lossy());
+    }
+    let file_size = fs.file_size(&config.path)?;
 
     // Check if there is sufficient disk space available
-    let space_needed = if !config.retain {
-        config.split.part_size
+    let max_part_size = config.chunking.max_part_size();
+    // An archive holds a copy of the whole file until it's finished
+    let space_needed = if let Some(range) = &config.range {
+        range.end - range.start
+    } else if config.archive.is_some() {
+        file_size
+    } else if !config.retain || config.only.is_some() {
+        max_part_size
     } else {
         file_size
     };
-    match sufficient_disk_space(&config.path, space_needed) {
+    match fs.sufficient_disk_space(&config.path, space_needed) {
+        // Parts given to a filter don't have to end up on this disk
+        _ if config.filter.is_some() => {}
         Ok(true) => {
             if config.verbose {
                 eprintln!(
@@ -44,103 +96,240 @@ fn _main() -> Result<()> {
         Err(warn) => eprintln!("WARNING: {warn}"),
     }
 
+    let rate_limiter = config.rate_limit.map(RateLimiter::new);
     // Cast is saturating if part_size > usize::MAX
-    let buffer_size = min(config.split.part_size, max_buffer_size()) as usize;
-    let mut buffer = vec![0; buffer_size];
+    let chunk_size = min(max_part_size, max_buffer_size());
+    let chunk_size = match &rate_limiter {
+        Some(rate_limiter) => min(chunk_size, rate_limiter.chunk_size()),
+        None => chunk_size,
+    } as usize;
+    let chunks = ChunkOptions {
+        // Overlapping reads and writes takes two buffers, so each gets half
+        // of the memory
+        chunk_size: match config.pipeline {
+            true => max(chunk_size / 2, 1),
+            false => chunk_size,
+        },
+        // A mapped file mustn't be truncated, so the original is only mapped
+        // if it's being kept. Mapped files are read through the page cache
+        map: (config.retain || config.archive.is_some()) && !config.direct,
+        aligned: config.direct,
+        // Instead of writing on another thread, where io_uring can be used
+        read_ahead: config.pipeline,
+        verbose: config.verbose,
+    };
+    let mut copier = Copier::new(rate_limiter, config.pipeline);
+
+    let mut original_file = fs
+        .open(&config.path, OpenMode::ReadWrite)
+        .map_err(FailedToReadPart)?;
+    let part_ranges = config
+        .chunking
+        .part_ranges(&mut original_file, file_size)
+        .map_err(FailedToReadPart)?;
+    // A range can be the whole file, as it's only copied
+    if part_ranges.len() < 2 && config.range.is_none() {
+        return Err(PartSizeTooLarge);
+    }
     if config.verbose {
-        eprintln!(
-            "Allocated {} buffer",
-            bytesize::to_string(buffer_size as u64, true),
-        );
+        if let Chunking::ContentDefined(_) = config.chunking {
+            eprintln!("Found {} content-defined parts", part_ranges.len());
+        }
+    }
+    if let Some(only) = config.only {
+        if only == 0 || only > part_ranges.len() as u64 {
+            return Err(NoSuchPart(only, part_ranges.len()));
+        } else if config.verbose {
+            eprintln!("Only making part {} of {}", only, part_ranges.len());
+        }
+    }
+    if let (Some(range), true) = (&config.range, config.verbose) {
+        eprintln!("Only making a part of bytes {}..{}", range.start, range.end);
+    }
+    let mut manifest = Manifest::from_ranges(&part_ranges);
+    let manifest_path = Manifest::path_for(&config.path);
+    if config.writes_manifest() {
+        if !config.dry_run {
+            manifest.write(fs, &manifest_path).map_err(|why| {
+                FailedToWriteManifest(manifest_path.clone(), why)
+            })?;
+        }
+        if config.verbose {
+            eprintln!("Wrote manifest {}", manifest_path.to_string_lossy());
+        }
+    }
+    // Only allocated if the original can't be mapped into memory
+    let mut buffer = Vec::new();
+    let mut reader = chunks.reader(original_file, &mut buffer);
+    if reader.is_mapped() && config.verbose {
+        eprintln!("Mapped original file into memory");
+    }
+    if let Some(format) = config.archive {
+        return archive::chop_to_archive(
+            fs,
+            config,
+            format,
+            &mut reader,
+            &mut copier,
+            &part_ranges,
+        )
+        .map(|_| Vec::new());
     }
 
-    let original_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(&config.path)
-        .map_err(FailedToReadPart)?;
-    let mut reader =
-        ChunkedReader::new(original_file, &mut buffer, config.verbose);
-    let zero_pad_width = digits(config.split.num_parts) as usize;
-
-    (0..config.split.num_parts)
-        .into_iter()
-        // Have to make parts backwards because we can only truncate the
-        // original file
-        .rev()
-        .map(|part| {
-            let start = part * config.split.part_size;
-            let end = min(start + config.split.part_size, file_size);
-            let part_path =
-                get_part_path_buf(&config.path, part + 1, zero_pad_width);
-            (start, end, part_path)
-        })
-        .try_for_each(|(start, end, part_path)| -> Result<()> {
-            let mut part_file = if !config.dry_run {
-                OpenOptions::new()
-                    .write(true)
-                    .create_new(true)
-                    .open(&part_path)
-                    .map_err(|err| {
-                        use std::io::ErrorKind::*;
-                        match err.kind() {
-                            AlreadyExists => {
-                                PartFileAlreadyExists(part_path.clone())
-                            }
-                            _ => err.into(),
-                        }
-                    })?
-                    .into()
-            } else {
-                None
+    let zero_pad_width = digits(part_ranges.len() as u64);
+    let (part_paths, mut parts) = match &config.range {
+        Some(range) => {
+            let path = match part_ranges.iter().position(|part| part == range) {
+                Some(index) => get_part_path_buf(
+                    &config.path,
+                    index as u64 + 1,
+                    zero_pad_width,
+                ),
+                None => range_path(&config.path, range),
             };
-            if config.verbose {
-                // Extra newline for some nice spacing
-                eprintln!("\nCreated {}", part_path.to_string_lossy());
-            }
+            (vec![path], vec![(1, range.clone())])
+        }
+        None => {
+            let paths = (1..=part_ranges.len() as u64)
+                .map(|number| {
+                    get_part_path_buf(&config.path, number, zero_pad_width)
+                })
+                .collect::<Vec<_>>();
+            let parts = (1..)
+                .zip(part_ranges)
+                .filter(|(number, _)| match config.only {
+                    Some(only) => *number == only,
+                    None => true,
+                })
+                .collect::<Vec<_>>();
+            (paths, parts)
+        }
+    };
+    let num_parts = part_paths.len();
+    let part_path = |number: u64| part_paths[number as usize - 1].clone();
+    // Have to make parts backwards because we can only truncate the original
+    // file
+    parts.reverse();
+    // Parts are only hashed for the rejoin scripts, which check them
+    let hash_parts = !config.rejoin_scripts.is_empty();
+    let mut parts_made = Vec::new();
 
-            if !config.dry_run {
-                reader.seek_to(start)?;
-                while let Some(bytes) =
-                    reader.read_up_to(end - 1).map_err(FailedToReadPart)?
-                {
-                    part_file.as_mut().unwrap().write_all(bytes).map_err(
-                        |err| FailedToWritePart(part_path.clone(), err),
-                    )?;
-                    if config.verbose {
-                        eprintln!("Wrote buffer to part file");
-                    }
+    if config.dry_run {
+        parts.iter().for_each(|(number, range)| {
+            if config.verbose {
+                match config.filter {
+                    Some(_) => eprint!("\nStarted filter for "),
+                    None => eprint!("\nCreated "),
                 }
-            } else if config.verbose {
+                eprintln!("{}", part_path(*number).to_string_lossy());
                 eprintln!("[reading and writing happens]");
+                if !config.retain {
+                    eprintln!(
+                        "Truncated original file to {}",
+                        bytesize::to_string(range.start, true),
+                    );
+                }
+            }
+        });
+    } else {
+        // The original can't be truncated until the filter's done, or the
+        // part is safely on disk, which the sinks see to before this
+        let after_part = |reader: &mut ChunkedReader<F::File>,
+                          number,
+                          range: Range<u64>| {
+            if config.filter.is_none() {
+                parts_made.push(part_path(number));
+            }
+            // Only advice, so it doesn't matter if it's not taken
+            if config.drop_cache {
+                let _ = reader
+                    .file
+                    .drop_cache(range.start, range.end - range.start);
             }
-
             if !config.retain {
-                if !config.dry_run {
-                    reader.file.set_len(start).map_err(FailedToTruncate)?;
-                }
+                reader.file.set_len(range.start).map_err(FailedToTruncate)?;
                 if config.verbose {
                     eprintln!(
                         "Truncated original file to {}",
-                        bytesize::to_string(start, true),
+                        bytesize::to_string(range.start, true),
                     );
                 }
             }
-
             Ok(())
-        })?;
+        };
+        let (result, hashes) = match &config.filter {
+            Some(command) => {
+                let filter =
+                    FilterParts::new(command, &part_paths, config.verbose);
+                let mut sink = Hashed::new(filter, hash_parts, num_parts);
+                let result = chop_into(
+                    &mut reader,
+                    parts,
+                    &mut sink,
+                    &mut copier,
+                    after_part,
+                );
+                (result, sink.hashes)
+            }
+            None => {
+                let files = FilesystemParts::from_paths(fs, part_paths.clone())
+                    .with_chunks(chunks)
+                    .with_durability(config.durability)
+                    .with_drop_cache(config.drop_cache);
+                let mut sink = Hashed::new(files, hash_parts, num_parts);
+                let result = chop_into(
+                    &mut reader,
+                    parts,
+                    &mut sink,
+                    &mut copier,
+                    after_part,
+                );
+                (result, sink.hashes)
+            }
+        };
+        result.map_err(|why| part_error(config, part_path, why))?;
+        manifest.part_hashes = hashes;
+    }
     // Extra newline for some nice spacing
     if config.verbose {
         eprintln!();
     }
 
+    // The hashes can only go in the manifest once every part's been made
+    if hash_parts {
+        if !config.dry_run {
+            manifest.replace(fs, &manifest_path).map_err(|why| {
+                FailedToWriteManifest(manifest_path.clone(), why)
+            })?;
+        }
+        let file_name = config.path.file_name().unwrap_or_default();
+        config.rejoin_scripts.iter().try_for_each(|script| {
+            let script_path = script.path_for(&config.path);
+            if !config.dry_run {
+                let contents = script
+                    .generate(file_name, &manifest)
+                    .expect("Every part should have been hashed");
+                fs.open(&script_path, OpenMode::CreateNew)
+                    .and_then(|mut file| file.write_all(&contents))
+                    .map_err(|why| {
+                        FailedToWriteScript(script_path.clone(), why)
+                    })?;
+            }
+            if config.verbose {
+                eprintln!("Wrote {}", script_path.to_string_lossy());
+            }
+            Ok::<_, ChopError>(())
+        })?;
+    }
+
     // Drop isn't strictly necessary but saves me trying to use it after the
     // file is deleted
     mem::drop(reader);
     mem::drop(buffer);
     if !config.retain {
         if !config.dry_run {
-            fs::remove_file(&config.path).map_err(FailedToDeleteOriginal)?;
+            fs.remove_file(&config.path)
+                .map_err(FailedToDeleteOriginal)?;
         }
         if config.verbose {
             eprintln!("Deleted original file");
@@ -151,5 +340,262 @@ fn _main() -> Result<()> {
         eprintln!("Finished without error!");
     }
 
-    Ok(())
+    // They were made backwards
+    parts_made.reverse();
+    Ok(parts_made)
+}
+
+// Where a range of bytes that isn't a whole part goes
+fn range_path(original: &Path, range: &Range<u64>) -> PathBuf {
+    let mut os_str = original.as_os_str().to_owned();
+    os_str.push(format!(".{}-{}", range.start, range.end));
+    PathBuf::from(os_str)
+}
+
+// Says which part couldn't be made, and why
+fn part_error<P>(
+    config: &RunConfig,
+    part_path: P,
+    why: PartError<ChopError>,
+) -> ChopError
+where
+    P: Fn(u64) -> PathBuf,
+{
+    match why {
+        PartError::Open(_, why) if config.filter.is_some() => {
+            FailedToSpawnFilter(why)
+        }
+        PartError::Open(number, why)
+            if why.kind() == io::ErrorKind::AlreadyExists =>
+        {
+            PartFileAlreadyExists(part_path(number))
+        }
+        PartError::Open(number, why) | PartError::Write(number, why) => {
+            match why.get_ref().and_then(|why| why.downcast_ref()) {
+                Some(FilterExit(status)) => {
+                    FilterFailed(part_path(number), *status)
+                }
+                None => FailedToWritePart(part_path(number), why),
+            }
+        }
+        PartError::Read(_, why) => FailedToReadPart(why),
+        PartError::Hook(why) => why,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chopstick::archive::ArchiveFormat;
+    use chopstick::filesystem::{Durability, Fault, MemoryFilesystem};
+    use chopstick::split::Split;
+    use std::ops::Range;
+
+    const ORIGINAL: &str = "original";
+    const FILE_SIZE: u64 = 100;
+    const NUM_PARTS: u64 = 4;
+
+    fn test_bytes() -> Vec<u8> {
+        (0..FILE_SIZE).map(|n| (n * 7 % 251) as u8).collect()
+    }
+
+    fn config(retain: bool) -> RunConfig {
+        RunConfig {
+            path: ORIGINAL.into(),
+            chunking: Chunking::Split(
+                Split::from_num_parts(FILE_SIZE, NUM_PARTS).unwrap(),
+            ),
+            only: None,
+            range: None,
+            filter: None,
+            archive: None,
+            rejoin_scripts: Vec::new(),
+            retain,
+            verbose: false,
+            dry_run: false,
+            durability: Durability::Full,
+            rate_limit: None,
+            io_class: None,
+            pipeline: false,
+            direct: false,
+            drop_cache: false,
+            journal: false,
+            lock: None,
+        }
+    }
+
+    fn part_ranges(config: &RunConfig) -> Vec<Range<u64>> {
+        match config.chunking {
+            Chunking::Split(split) => split.part_ranges(FILE_SIZE),
+            _ => unreachable!("Only splits are tested"),
+        }
+    }
+
+    fn part_contents(fs: &MemoryFilesystem, part: u64) -> Option<Vec<u8>> {
+        fs.contents(get_part_path_buf(ORIGINAL, part, digits(NUM_PARTS)))
+    }
+
+    // Every byte must either still be in the original file, or in a complete
+    // part. Gives the first range of bytes that isn't
+    fn lost_bytes(
+        fs: &MemoryFilesystem,
+        config: &RunConfig,
+    ) -> Option<Range<usize>> {
+        let bytes = test_bytes();
+        let original = fs.contents(ORIGINAL).unwrap_or_default();
+        part_ranges(config).into_iter().enumerate().find_map(
+            |(index, range)| {
+                let range = range.start as usize..range.end as usize;
+                let expected = &bytes[range.clone()];
+                let in_original = original.get(range.clone()) == Some(expected);
+                let in_part = part_contents(fs, index as u64 + 1).as_deref()
+                    == Some(expected);
+                (!in_original && !in_part).then(|| range)
+            },
+        )
+    }
+
+    fn assert_bytes_intact(
+        fs: &MemoryFilesystem,
+        config: &RunConfig,
+        context: &str,
+    ) {
+        if let Some(range) = lost_bytes(fs, config) {
+            panic!(
+                "Bytes {:?} lost {}, files left: {:?}",
+                range,
+                context,
+                fs.paths(),
+            );
+        }
+    }
+
+    // Runs like _main would, with the memory filesystem in place of the real
+    // one
+    fn run(fs: &MemoryFilesystem, config: &RunConfig) -> Result<Vec<PathBuf>> {
+        match config.direct {
+            true => chop(&DirectFilesystem::new(fs.clone()), config),
+            false => chop(fs, config),
+        }
+    }
+
+    fn assert_survives_faults(config: &RunConfig) {
+        let new_fs = || {
+            let fs = MemoryFilesystem::new();
+            fs.insert(ORIGINAL, &test_bytes());
+            fs
+        };
+
+        let fs = new_fs();
+        let parts = run(&fs, config).expect("Run without faults failed");
+        assert_eq!(parts.len() as u64, NUM_PARTS);
+        assert!(parts.iter().all(|part| fs.contents(part).is_some()));
+        let total_steps = fs.steps();
+        assert_bytes_intact(&fs, config, "without faults");
+        assert_eq!(fs.contents(ORIGINAL).is_some(), config.retain);
+
+        Fault::ALL.iter().for_each(|&fault| {
+            (0..total_steps).for_each(|step| {
+                let fs = new_fs();
+                fs.inject(step, fault);
+                let result = run(&fs, config);
+                fs.clear_faults();
+                let context = format!("with {:?} at step {}", fault, step);
+                assert_bytes_intact(&fs, config, &context);
+                if fault == Fault::ShortWrite {
+                    assert!(result.is_ok(), "Failed {}", context);
+                }
+            })
+        });
+    }
+
+    #[test]
+    fn faults_never_lose_bytes() {
+        assert_survives_faults(&config(false));
+    }
+
+    #[test]
+    fn faults_never_lose_bytes_retaining() {
+        assert_survives_faults(&config(true));
+    }
+
+    #[test]
+    fn faults_never_lose_bytes_pipelined() {
+        [false, true].into_iter().for_each(|retain| {
+            assert_survives_faults(&RunConfig {
+                pipeline: true,
+                ..config(retain)
+            })
+        });
+    }
+
+    #[test]
+    fn faults_never_lose_bytes_direct() {
+        [false, true].into_iter().for_each(|retain| {
+            assert_survives_faults(&RunConfig {
+                direct: true,
+                ..config(retain)
+            })
+        });
+    }
+
+    // Shows the power cut tests above rely on syncing
+    #[test]
+    fn power_cut_loses_bytes_unless_fully_durable() {
+        [Durability::None, Durability::Data].into_iter().for_each(
+            |durability| {
+                let config = RunConfig {
+                    durability,
+                    ..config(false)
+                };
+                let lost = (0..100).any(|step| {
+                    let fs = MemoryFilesystem::new();
+                    fs.insert(ORIGINAL, &test_bytes());
+                    fs.inject(step, Fault::PowerCut);
+                    let _ = chop(&fs, &config);
+                    fs.clear_faults();
+                    lost_bytes(&fs, &config).is_some()
+                });
+                assert!(lost, "Nothing lost with {:?}", durability);
+            },
+        );
+    }
+
+    #[test]
+    fn faults_leave_no_partial_archive() {
+        let config = RunConfig {
+            archive: Some(ArchiveFormat::Tar),
+            pipeline: true,
+            ..config(false)
+        };
+        let archive_path = ArchiveFormat::Tar.path_for(ORIGINAL);
+        [Fault::NoSpace, Fault::Io].into_iter().for_each(|fault| {
+            (0..50).for_each(|step| {
+                let fs = MemoryFilesystem::new();
+                fs.insert(ORIGINAL, &test_bytes());
+                fs.inject(step, fault);
+                match run(&fs, &config) {
+                    Ok(_) | Err(FailedToDeleteOriginal(_)) => {}
+                    Err(_) => {
+                        assert_eq!(fs.contents(ORIGINAL), Some(test_bytes()));
+                        assert!(
+                            fs.contents(&archive_path).is_none(),
+                            "Archive left with {:?} at step {}",
+                            fault,
+                            step,
+                        );
+                    }
+                }
+            })
+        });
+    }
+
+    #[test]
+    fn insufficient_space() {
+        let fs = MemoryFilesystem::with_capacity(FILE_SIZE + 10);
+        fs.insert(ORIGINAL, &test_bytes());
+        let err = chop(&fs, &config(false)).unwrap_err();
+        assert!(matches!(err, InsufficientDiskSpace));
+        assert_eq!(fs.paths(), vec![std::path::PathBuf::from(ORIGINAL)]);
+    }
 }
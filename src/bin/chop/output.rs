@@ -0,0 +1,180 @@
+use chopstick::cli::shell_command;
+use chopstick::parts::PartSink;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ExitStatus, Stdio};
+
+// Pipes each part to its own run of a command, instead of writing it to a
+// file
+pub struct FilterParts<'a> {
+    command: &'a str,
+    // What each part would be called as a file, in order
+    paths: &'a [PathBuf],
+    verbose: bool,
+}
+
+// A filter that's being given a part
+pub struct Filter {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+// A filter that didn't deal with its part, given as the reason a part
+// couldn't be written
+#[derive(Debug)]
+pub struct FilterExit(pub ExitStatus);
+
+impl<'a> FilterParts<'a> {
+    pub fn new(command: &'a str, paths: &'a [PathBuf], verbose: bool) -> Self {
+        FilterParts {
+            command,
+            paths,
+            verbose,
+        }
+    }
+}
+
+impl<'a> PartSink for FilterParts<'a> {
+    type Writer = Filter;
+
+    // Runs the command in the shell with the part's details in its
+    // environment, ready to have the part piped to its stdin
+    fn create_part(&mut self, number: u64, size: u64) -> io::Result<Filter> {
+        let part_path = &self.paths[number as usize - 1];
+        let mut child = shell_command(self.command)
+            .env("FILE", part_path.file_name().unwrap_or_default())
+            .env("CHOP_PART_NUMBER", number.to_string())
+            .env("CHOP_PART_SIZE", size.to_string())
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin should be piped");
+        if self.verbose {
+            // Extra newline for some nice spacing
+            eprintln!("\nStarted filter for {}", part_path.to_string_lossy());
+        }
+        Ok(Filter { child, stdin })
+    }
+
+    // This is when a filter reports whether it dealt with the part, so must
+    // succeed before the original file is truncated
+    fn finish_part(&mut self, _number: u64, filter: Filter) -> io::Result<()> {
+        let Filter { mut child, stdin } = filter;
+        // Closing stdin lets the filter know the part has ended
+        drop(stdin);
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                FilterExit(status),
+            ));
+        }
+        if self.verbose {
+            eprintln!("Filter finished successfully");
+        }
+        Ok(())
+    }
+
+    // Gives a filter the chance to report its own failure if it stopped
+    // reading before the whole part was written to it
+    fn abandon_part(
+        &mut self,
+        number: u64,
+        filter: Filter,
+        why: io::Error,
+    ) -> io::Error {
+        self.finish_part(number, filter).err().unwrap_or(why)
+    }
+}
+
+impl Write for Filter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.flush()
+    }
+}
+
+impl fmt::Display for FilterExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Filter failed ({})", self.0)
+    }
+}
+
+impl Error for FilterExit {}
+
+// Hashes parts on their way to another sink, if `hashes` is set, for the
+// rejoin scripts to check
+pub struct Hashed<S> {
+    sink: S,
+    pub hashes: Option<Vec<String>>,
+}
+
+pub struct Hashing<W> {
+    writer: W,
+    hasher: Option<Sha256>,
+}
+
+impl<S: PartSink> Hashed<S> {
+    pub fn new(sink: S, hash: bool, num_parts: usize) -> Self {
+        Hashed {
+            sink,
+            hashes: hash.then(|| vec![String::new(); num_parts]),
+        }
+    }
+}
+
+impl<S: PartSink> PartSink for Hashed<S> {
+    type Writer = Hashing<S::Writer>;
+
+    fn create_part(
+        &mut self,
+        number: u64,
+        size: u64,
+    ) -> io::Result<Self::Writer> {
+        Ok(Hashing {
+            writer: self.sink.create_part(number, size)?,
+            hasher: self.hashes.as_ref().map(|_| Sha256::new()),
+        })
+    }
+
+    fn finish_part(
+        &mut self,
+        number: u64,
+        hashing: Self::Writer,
+    ) -> io::Result<()> {
+        self.sink.finish_part(number, hashing.writer)?;
+        if let (Some(hashes), Some(hasher)) = (&mut self.hashes, hashing.hasher)
+        {
+            hashes[number as usize - 1] = format!("{:x}", hasher.finalize());
+        }
+        Ok(())
+    }
+
+    fn abandon_part(
+        &mut self,
+        number: u64,
+        hashing: Self::Writer,
+        why: io::Error,
+    ) -> io::Error {
+        self.sink.abandon_part(number, hashing.writer, why)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
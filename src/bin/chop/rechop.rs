@@ -0,0 +1,875 @@
+use crate::args::RechopConfig;
+use crate::ChopError::*;
+use crate::Result;
+use chopstick::filesystem::{Durability, FileHandle, Filesystem, OpenMode};
+use chopstick::manifest::Manifest;
+use chopstick::rejoin::RejoinScript;
+use chopstick::split::{get_part_path_buf, Chunking};
+use chopstick::throttle::RateLimiter;
+use chopstick::{digits, max_buffer_size};
+use sha2::{Digest, Sha256};
+use std::cmp::{max, min};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const PROGRESS_HEADER: &str = "chopstick rechop 1";
+
+// A part, and the bytes of the file it holds
+type Part = (PathBuf, Range<u64>);
+
+// How far a rechop has got, kept in <file>.rechop from before anything is
+// changed until the manifest has been replaced, so that running the same
+// rechop again after it's interrupted finishes it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Progress {
+    ranges: Vec<Range<u64>>,
+    // Filled in as each new part is made, if they're hashed
+    hashes: Option<Vec<String>>,
+    // The new parts from this index on are made, and the old parts have been
+    // (or were being) cut down to where they start
+    made: usize,
+    // Every new part is made and every old part is gone, so the new parts are
+    // being renamed
+    renaming: bool,
+}
+
+// Chops the parts of a file again, with a different split, without sticking
+// them back together. New parts are made from the end backwards, each copied
+// from the old parts it covers, which are then truncated or deleted, so no
+// more than one new part's worth of bytes is ever on disk twice. New parts
+// can share names with old parts still being read from, so they're made under
+// temporary names, and only renamed once every old part is gone
+pub fn rechop<F: Filesystem>(fs: &F, config: &RechopConfig) -> Result<()> {
+    if let (Some(lock), true) = (&config.lock, config.verbose) {
+        eprintln!("Locked {}", lock.path().to_string_lossy());
+    }
+    let manifest = &config.manifest;
+    let old_ranges = ranges(&manifest.part_sizes);
+    let old_parts = parts(&config.path, &old_ranges);
+    let new_ranges = match config.chunking {
+        Chunking::Split(split) => split.part_ranges(manifest.file_size),
+        Chunking::Balanced(balanced) => {
+            balanced.part_ranges(manifest.file_size)
+        }
+        Chunking::ContentDefined(_) => {
+            unreachable!("--rechop can't be used with --content-defined")
+        }
+    };
+    let new_parts = parts(&config.path, &new_ranges);
+
+    let progress_path = rechop_path(&config.path);
+    let resuming = fs.exists(&progress_path);
+    let mut progress = if resuming {
+        let progress = Progress::read(fs, &progress_path)
+            .map_err(|why| FailedToReadRechop(progress_path.clone(), why))?;
+        if progress.ranges != new_ranges {
+            return Err(UnfinishedRechop(progress_path));
+        }
+        if config.verbose {
+            eprintln!(
+                "Finishing the rechop recorded in {}",
+                progress_path.to_string_lossy()
+            );
+        }
+        progress
+    } else {
+        check_old_parts(fs, &old_parts, manifest.file_size)?;
+        if config.verbose {
+            eprintln!("Found {} parts matching the manifest", old_parts.len());
+        }
+        if new_ranges.len() < 2 {
+            return Err(PartSizeTooLarge);
+        } else if new_ranges == old_ranges {
+            if config.verbose {
+                eprintln!("The parts are already split that way");
+            }
+            return Ok(());
+        }
+        // Old parts are gone by the time new parts are renamed over them, but
+        // nothing else can be in the way
+        new_parts.iter().try_for_each(|(path, _)| {
+            let temp_path = rechop_path(path);
+            let is_old_part =
+                old_parts.iter().any(|(old_path, _)| old_path == path);
+            if fs.file_size(&temp_path).is_ok() {
+                Err(PartFileAlreadyExists(temp_path))
+            } else if !is_old_part && fs.file_size(path).is_ok() {
+                Err(PartFileAlreadyExists(path.clone()))
+            } else {
+                Ok(())
+            }
+        })?;
+        // New parts are only hashed if the old ones were
+        Progress {
+            ranges: new_ranges.clone(),
+            hashes: manifest
+                .part_hashes
+                .as_ref()
+                .map(|_| vec![String::new(); new_ranges.len()]),
+            made: new_ranges.len(),
+            renaming: false,
+        }
+    };
+
+    let folder = folder(&config.path);
+    let largest = new_ranges
+        .iter()
+        .map(|range| range.end - range.start)
+        .max()
+        .unwrap_or(0);
+    match fs.sufficient_disk_space(folder, largest) {
+        Ok(true) => {
+            if config.verbose {
+                eprintln!(
+                    "Sufficient disk space available ({} needed)",
+                    bytesize::to_string(largest, true),
+                );
+            }
+        }
+        Ok(false) => return Err(InsufficientDiskSpace),
+        Err(warn) => eprintln!("WARNING: {warn}"),
+    }
+
+    if !resuming {
+        record(fs, config, &progress)?;
+    } else if !progress.renaming {
+        // The old parts may not have all been cut down to the last new part
+        // made, and the next new part may have been half made
+        let cut = new_ranges
+            .get(progress.made)
+            .map_or(manifest.file_size, |range| range.start);
+        cut_old_parts(fs, config, &old_parts, cut..manifest.file_size)?;
+        check_old_parts(fs, &old_parts, cut)?;
+        if !config.dry_run {
+            new_parts[..progress.made]
+                .iter()
+                .try_for_each(|(path, _)| {
+                    let temp_path = rechop_path(path);
+                    match fs.remove_file(&temp_path) {
+                        Err(why) if why.kind() != io::ErrorKind::NotFound => {
+                            Err(FailedToWritePart(temp_path, why))
+                        }
+                        _ => Ok(()),
+                    }
+                })?;
+        }
+    }
+
+    let mut rate_limiter = config.rate_limit.map(RateLimiter::new);
+    let buffer_size = min(largest, max_buffer_size());
+    let buffer_size = match &rate_limiter {
+        Some(rate_limiter) => min(buffer_size, rate_limiter.chunk_size()),
+        None => buffer_size,
+    } as usize;
+    let mut buffer = match config.dry_run {
+        false => vec![0; buffer_size],
+        true => Vec::new(),
+    };
+    let hash_parts = progress.hashes.is_some();
+
+    new_parts[..progress.made]
+        .iter()
+        .enumerate()
+        .rev()
+        .try_for_each(|(index, (path, range))| -> Result<()> {
+            let temp_path = rechop_path(path);
+            // Everything after this part has already been moved, so these
+            // end where it does
+            let sources = old_parts
+                .iter()
+                .filter(|(_, old)| {
+                    old.start < range.end && old.end > range.start
+                })
+                .collect::<Vec<_>>();
+            if !config.dry_run {
+                let write_error =
+                    |why| FailedToWritePart(temp_path.clone(), why);
+                let mut part_file = fs
+                    .open(&temp_path, OpenMode::CreateNew)
+                    .map_err(write_error)?;
+                let mut hasher = hash_parts.then(Sha256::new);
+                sources.iter().try_for_each(
+                    |(old_path, old)| -> Result<()> {
+                        let read_error =
+                            |why| FailedToRechop(old_path.clone(), why);
+                        let start = max(range.start, old.start);
+                        let mut remaining = min(range.end, old.end) - start;
+                        let mut old_file = fs
+                            .open(old_path, OpenMode::Read)
+                            .map_err(read_error)?;
+                        old_file
+                            .seek(SeekFrom::Start(start - old.start))
+                            .map_err(read_error)?;
+                        while remaining > 0 {
+                            let len = min(remaining, buffer.len() as u64);
+                            let bytes = &mut buffer[..len as usize];
+                            old_file.read_exact(bytes).map_err(read_error)?;
+                            part_file.write_all(bytes).map_err(write_error)?;
+                            if let Some(hasher) = &mut hasher {
+                                hasher.update(&bytes);
+                            }
+                            if let Some(rate_limiter) = &mut rate_limiter {
+                                rate_limiter.consume(len);
+                            }
+                            remaining -= len;
+                        }
+                        Ok(())
+                    },
+                )?;
+                // The old parts can't be cut down until the new one is
+                // safely on disk
+                if config.durability >= Durability::Data {
+                    part_file.sync_all().map_err(write_error)?;
+                }
+                if config.durability == Durability::Full {
+                    fs.sync_directory(folder).map_err(write_error)?;
+                }
+                if let (Some(hashes), Some(hasher)) =
+                    (&mut progress.hashes, hasher)
+                {
+                    hashes[index] = format!("{:x}", hasher.finalize());
+                }
+            }
+            if config.verbose {
+                // Extra newline for some nice spacing
+                eprintln!(
+                    "\nMade {} from {}",
+                    temp_path.to_string_lossy(),
+                    sources
+                        .iter()
+                        .map(|(old_path, _)| old_path.to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                );
+            }
+
+            // Nor until it's recorded as made, or an interrupted rechop
+            // would look for its bytes in them
+            progress.made = index;
+            record(fs, config, &progress)?;
+            cut_old_parts(fs, config, &old_parts, range.clone())
+        })?;
+    if !progress.renaming {
+        progress.renaming = true;
+        record(fs, config, &progress)?;
+    }
+    if config.verbose {
+        eprintln!();
+    }
+
+    new_parts.iter().try_for_each(|(path, _)| -> Result<()> {
+        let temp_path = rechop_path(path);
+        if !config.dry_run {
+            match fs.rename(&temp_path, path) {
+                // Renamed before the rechop was interrupted
+                Err(why)
+                    if resuming && why.kind() == io::ErrorKind::NotFound =>
+                {
+                    return Ok(())
+                }
+                result => result
+                    .map_err(|why| FailedToWritePart(path.clone(), why))?,
+            }
+        }
+        if config.verbose {
+            eprintln!(
+                "Renamed {} to {}",
+                temp_path.to_string_lossy(),
+                path.to_string_lossy(),
+            );
+        }
+        Ok(())
+    })?;
+    if !config.dry_run && config.durability == Durability::Full {
+        fs.sync_directory(folder)
+            .map_err(|why| FailedToWritePart(new_parts[0].0.clone(), why))?;
+    }
+
+    let mut new_manifest = Manifest::from_ranges(&new_ranges);
+    new_manifest.part_hashes = progress.hashes;
+    let manifest_path = Manifest::path_for(&config.path);
+    if !config.dry_run {
+        let contents = new_manifest.to_string();
+        replace(fs, config, &manifest_path, contents.as_bytes())
+            .map_err(|why| FailedToWriteManifest(manifest_path.clone(), why))?;
+    }
+    if config.verbose {
+        eprintln!("Wrote manifest {}", manifest_path.to_string_lossy());
+    }
+    rewrite_rejoin_scripts(fs, config, &new_manifest)?;
+
+    if !config.dry_run {
+        fs.remove_file(&progress_path)
+            .map_err(|why| FailedToRecordRechop(progress_path.clone(), why))?;
+    }
+    if config.verbose && !config.dry_run {
+        eprintln!("Finished without error!");
+    }
+    Ok(())
+}
+
+// Each old part before `end` must hold the bytes the manifest says it does,
+// up to `end`, which is where any earlier run of the rechop cut them down to
+fn check_old_parts<F: Filesystem>(
+    fs: &F,
+    old_parts: &[Part],
+    end: u64,
+) -> Result<()> {
+    old_parts
+        .iter()
+        .filter(|(_, range)| range.start < end)
+        .try_for_each(|(path, range)| {
+            let expected = min(range.end, end) - range.start;
+            match fs.file_size(path) {
+                Ok(size) if size == expected => Ok(()),
+                Ok(size) => Err(WrongPartSize(path.clone(), expected, size)),
+                Err(why) => Err(FailedToRechop(path.clone(), why)),
+            }
+        })
+}
+
+// Truncates or deletes the old parts overlapping `cut`, whose bytes from its
+// start on are all in new parts now. Any that an interrupted run of the
+// rechop already cut down are left alone
+fn cut_old_parts<F: Filesystem>(
+    fs: &F,
+    config: &RechopConfig,
+    old_parts: &[Part],
+    cut: Range<u64>,
+) -> Result<()> {
+    old_parts
+        .iter()
+        .filter(|(_, old)| old.start < cut.end && old.end > cut.start)
+        .try_for_each(|(old_path, old)| -> Result<()> {
+            let rechop_error = |why| FailedToRechop(old_path.clone(), why);
+            let old_size = match fs.file_size(old_path) {
+                Err(why) if why.kind() == io::ErrorKind::NotFound => {
+                    return Ok(())
+                }
+                old_size => old_size.map_err(rechop_error)?,
+            };
+            if old.start < cut.start {
+                let size = cut.start - old.start;
+                if old_size <= size {
+                    return Ok(());
+                }
+                if !config.dry_run {
+                    fs.open(old_path, OpenMode::ReadWrite)
+                        .and_then(|mut old_file| old_file.set_len(size))
+                        .map_err(rechop_error)?;
+                }
+                if config.verbose {
+                    eprintln!(
+                        "Truncated {} to {}",
+                        old_path.to_string_lossy(),
+                        bytesize::to_string(size, true),
+                    );
+                }
+            } else {
+                if !config.dry_run {
+                    fs.remove_file(old_path).map_err(rechop_error)?;
+                }
+                if config.verbose {
+                    eprintln!("Deleted {}", old_path.to_string_lossy());
+                }
+            }
+            Ok(())
+        })
+}
+
+// Writes down how far the rechop has got, which has to be on disk before the
+// old parts are touched
+fn record<F: Filesystem>(
+    fs: &F,
+    config: &RechopConfig,
+    progress: &Progress,
+) -> Result<()> {
+    let path = rechop_path(&config.path);
+    if !config.dry_run {
+        replace(fs, config, &path, progress.to_string().as_bytes())
+            .map_err(|why| FailedToRecordRechop(path.clone(), why))?;
+    }
+    Ok(())
+}
+
+// Writes a file alongside `path` and renames it over, so that there's always a
+// whole file there, synced as far as the durability asks
+fn replace<F: Filesystem>(
+    fs: &F,
+    config: &RechopConfig,
+    path: &Path,
+    contents: &[u8],
+) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    // Left behind by an interrupted run of the rechop
+    if let Err(why) = fs.remove_file(&temp_path) {
+        if why.kind() != io::ErrorKind::NotFound {
+            return Err(why);
+        }
+    }
+    let mut file = fs.open(&temp_path, OpenMode::CreateNew)?;
+    file.write_all(contents)?;
+    if config.durability >= Durability::Data {
+        file.sync_all()?;
+    }
+    drop(file);
+    fs.rename(&temp_path, path)?;
+    if config.durability == Durability::Full {
+        fs.sync_directory(folder(&config.path))?;
+    }
+    Ok(())
+}
+
+// Rejoin scripts left by the chop would look for the old parts, so any there
+// are written again for the new ones
+fn rewrite_rejoin_scripts<F: Filesystem>(
+    fs: &F,
+    config: &RechopConfig,
+    manifest: &Manifest,
+) -> Result<()> {
+    let file_name = config.path.file_name().unwrap_or_default();
+    [RejoinScript::Posix, RejoinScript::PowerShell]
+        .iter()
+        .map(|script| (script, script.path_for(&config.path)))
+        .filter(|(_, script_path)| fs.file_size(script_path).is_ok())
+        .try_for_each(|(script, script_path)| {
+            let contents = script.generate(file_name, manifest);
+            if !config.dry_run {
+                match &contents {
+                    Some(contents) => {
+                        replace(fs, config, &script_path, contents)
+                    }
+                    None => fs.remove_file(&script_path),
+                }
+                .map_err(|why| FailedToWriteScript(script_path.clone(), why))?;
+            }
+            if config.verbose {
+                match contents {
+                    Some(_) => {
+                        eprintln!("Wrote {}", script_path.to_string_lossy())
+                    }
+                    // A script that can't check the parts isn't worth having
+                    None => eprintln!(
+                        "Deleted {}, as the parts weren't hashed",
+                        script_path.to_string_lossy()
+                    ),
+                }
+            }
+            Ok(())
+        })
+}
+
+// The bytes of the file held by parts of these sizes
+fn ranges(part_sizes: &[u64]) -> Vec<Range<u64>> {
+    part_sizes
+        .iter()
+        .scan(0, |start, size| {
+            *start += size;
+            Some(*start - size..*start)
+        })
+        .collect()
+}
+
+// Names the part for each range as chop would
+fn parts(original: &Path, ranges: &[Range<u64>]) -> Vec<Part> {
+    let width = digits(ranges.len() as u64) as usize;
+    ranges
+        .iter()
+        .zip(1..)
+        .map(|(range, number)| {
+            (get_part_path_buf(original, number, width), range.clone())
+        })
+        .collect()
+}
+
+// Where the parts are
+fn folder(original: &Path) -> &Path {
+    match original.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+// Where a new part is made, until the old parts are out of the way, or, for
+// the original file, where how far the rechop has got is kept
+fn rechop_path(path: &Path) -> PathBuf {
+    let mut os_str = path.as_os_str().to_owned();
+    os_str.push(".rechop");
+    PathBuf::from(os_str)
+}
+
+impl Progress {
+    fn read<F: Filesystem>(fs: &F, path: &Path) -> io::Result<Self> {
+        let mut contents = String::new();
+        fs.open(path, OpenMode::Read)?
+            .read_to_string(&mut contents)?;
+        contents.parse()
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", PROGRESS_HEADER)?;
+        if self.hashes.is_some() {
+            writeln!(f, "hashed")?;
+        }
+        self.ranges
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, range)| {
+                write!(f, "part {} {}", index + 1, range.end - range.start)?;
+                match &self.hashes {
+                    Some(hashes) if !hashes[index].is_empty() => {
+                        writeln!(f, " {}", hashes[index])
+                    }
+                    _ => writeln!(f),
+                }
+            })?;
+        writeln!(f, "made {}", self.made)?;
+        if self.renaming {
+            writeln!(f, "renaming")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Progress {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid =
+            |why: &str| io::Error::new(io::ErrorKind::InvalidData, why);
+        let mut lines = s.lines().peekable();
+        if lines.next() != Some(PROGRESS_HEADER) {
+            return Err(invalid("not a record of a rechop"));
+        }
+        let hashed = lines.next_if_eq(&"hashed").is_some();
+        let mut ranges = Vec::<Range<u64>>::new();
+        let mut hashes = Vec::new();
+        while let Some(line) = lines.next_if(|line| line.starts_with("part ")) {
+            let mut words = line.split(' ').skip(1);
+            let number =
+                words.next().and_then(|number| number.parse::<usize>().ok());
+            let size = words.next().and_then(|size| size.parse::<u64>().ok());
+            let start = ranges.last().map_or(0, |range| range.end);
+            match (number, size, words.next(), words.next()) {
+                (Some(number), Some(size), hash, None)
+                    if number == ranges.len() + 1
+                        && (hashed || hash.is_none()) =>
+                {
+                    ranges.push(start..start + size);
+                    hashes.push(hash.unwrap_or_default().to_owned());
+                }
+                _ => return Err(invalid("invalid part")),
+            }
+        }
+        let made = lines
+            .next()
+            .and_then(|line| line.strip_prefix("made "))
+            .and_then(|made| made.parse().ok())
+            .filter(|&made| made <= ranges.len())
+            .ok_or_else(|| invalid("missing how many new parts are made"))?;
+        let renaming = match (lines.next(), lines.next()) {
+            (None, _) => false,
+            (Some("renaming"), None) => true,
+            _ => return Err(invalid("unexpected line")),
+        };
+        Ok(Progress {
+            ranges,
+            hashes: hashed.then(|| hashes),
+            made,
+            renaming,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chopstick::filesystem::{Fault, MemoryFilesystem};
+    use chopstick::split::{Balanced, Split};
+
+    const ORIGINAL: &str = "original";
+    const FILE_SIZE: u64 = 10_000;
+    const OLD_PARTS: u64 = 4;
+
+    fn test_bytes() -> Vec<u8> {
+        (0..FILE_SIZE).map(|n| (n * 7 % 251) as u8).collect()
+    }
+
+    fn old_ranges() -> Vec<Range<u64>> {
+        Split::from_num_parts(FILE_SIZE, OLD_PARTS)
+            .unwrap()
+            .part_ranges(FILE_SIZE)
+    }
+
+    // The parts and manifest of a chop into OLD_PARTS parts
+    fn chopped(capacity: Option<u64>) -> MemoryFilesystem {
+        let fs = match capacity {
+            Some(capacity) => MemoryFilesystem::with_capacity(capacity),
+            None => MemoryFilesystem::new(),
+        };
+        let bytes = test_bytes();
+        parts(Path::new(ORIGINAL), &old_ranges())
+            .into_iter()
+            .for_each(|(path, range)| {
+                fs.insert(
+                    path,
+                    &bytes[range.start as usize..range.end as usize],
+                )
+            });
+        fs.insert(
+            Manifest::path_for(ORIGINAL),
+            Manifest::from_ranges(&old_ranges()).to_string().as_bytes(),
+        );
+        fs
+    }
+
+    fn config(chunking: Chunking) -> RechopConfig {
+        RechopConfig {
+            path: ORIGINAL.into(),
+            manifest: Manifest::from_ranges(&old_ranges()),
+            chunking,
+            verbose: false,
+            dry_run: false,
+            durability: Durability::Full,
+            rate_limit: None,
+            lock: None,
+        }
+    }
+
+    fn chunkings() -> [Chunking; 3] {
+        [
+            Chunking::Split(Split::from_num_parts(FILE_SIZE, 10).unwrap()),
+            Chunking::Balanced(Balanced::new(FILE_SIZE, 3).unwrap()),
+            Chunking::Split(Split::from_part_size(FILE_SIZE, 3000).unwrap()),
+        ]
+    }
+
+    fn new_ranges(chunking: Chunking) -> Vec<Range<u64>> {
+        chunking.part_ranges(&[][..], FILE_SIZE).unwrap()
+    }
+
+    // Every byte must either still be in (what's left of) an old part, or in
+    // a complete new part, under its temporary name or not. Gives the first
+    // byte that isn't
+    fn lost_byte(fs: &MemoryFilesystem, chunking: Chunking) -> Option<u64> {
+        let bytes = test_bytes();
+        let expected = |start: u64, len: usize| {
+            let start = start as usize;
+            bytes.get(start..start + len)
+        };
+        let mut covered = parts(Path::new(ORIGINAL), &old_ranges())
+            .into_iter()
+            .filter_map(|(path, range)| {
+                let contents = fs.contents(path)?;
+                (expected(range.start, contents.len()) == Some(&contents))
+                    .then(|| range.start..range.start + contents.len() as u64)
+            })
+            .collect::<Vec<_>>();
+        parts(Path::new(ORIGINAL), &new_ranges(chunking))
+            .into_iter()
+            .for_each(|(path, range)| {
+                let len = (range.end - range.start) as usize;
+                let whole =
+                    [fs.contents(&path), fs.contents(rechop_path(&path))]
+                        .into_iter()
+                        .flatten()
+                        .any(|contents| {
+                            expected(range.start, len) == Some(&contents)
+                        });
+                if whole {
+                    covered.push(range);
+                }
+            });
+        covered.sort_by_key(|range| range.start);
+        let reached = covered.into_iter().fold(0, |reached, range| {
+            match range.start <= reached {
+                true => max(reached, range.end),
+                false => reached,
+            }
+        });
+        (reached < FILE_SIZE).then(|| reached)
+    }
+
+    // Only the new parts and their manifest are left
+    fn assert_rechopped(fs: &MemoryFilesystem, chunking: Chunking) {
+        let new_parts = parts(Path::new(ORIGINAL), &new_ranges(chunking));
+        let joined = new_parts
+            .iter()
+            .flat_map(|(path, _)| fs.contents(path).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(joined, test_bytes());
+        let mut expected_paths = new_parts
+            .into_iter()
+            .map(|(path, _)| path)
+            .chain([Manifest::path_for(ORIGINAL)])
+            .collect::<Vec<_>>();
+        expected_paths.sort();
+        assert_eq!(fs.paths(), expected_paths);
+        let manifest =
+            Manifest::read(fs, &Manifest::path_for(ORIGINAL)).unwrap();
+        assert_eq!(manifest, Manifest::from_ranges(&new_ranges(chunking)));
+    }
+
+    #[test]
+    fn rechops() {
+        chunkings().into_iter().for_each(|chunking| {
+            let fs = chopped(None);
+            rechop(&fs, &config(chunking)).unwrap();
+            assert_rechopped(&fs, chunking);
+        });
+    }
+
+    #[test]
+    fn needs_one_new_part_of_space() {
+        let manifest_len =
+            Manifest::from_ranges(&old_ranges()).to_string().len() as u64;
+        chunkings().into_iter().for_each(|chunking| {
+            let largest = new_ranges(chunking)
+                .into_iter()
+                .map(|range| range.end - range.start)
+                .max()
+                .unwrap();
+            let fs = chopped(Some(FILE_SIZE + manifest_len + largest));
+            let err = rechop(&fs, &config(chunking)).unwrap_err();
+            assert!(matches!(err, InsufficientDiskSpace));
+
+            // Plenty for the new manifest and the record of how far the
+            // rechop has got, but not a whole copy of the file
+            let fs = chopped(Some(FILE_SIZE + 1000 + largest));
+            rechop(&fs, &config(chunking)).unwrap();
+        });
+    }
+
+    #[test]
+    fn checks_parts_against_manifest() {
+        let fs = chopped(None);
+        let last = get_part_path_buf(ORIGINAL, OLD_PARTS, 1);
+        fs.insert(&last, b"short");
+        let before = fs.paths();
+        let err = rechop(&fs, &config(chunkings()[0])).unwrap_err();
+        assert!(matches!(err, WrongPartSize(path, 2500, 5) if path == last));
+        assert_eq!(fs.paths(), before);
+    }
+
+    #[test]
+    fn faults_never_lose_bytes() {
+        chunkings().into_iter().for_each(|chunking| {
+            let config = config(chunking);
+            let fs = chopped(None);
+            rechop(&fs, &config).unwrap();
+            let total_steps = fs.steps();
+
+            Fault::ALL.iter().for_each(|&fault| {
+                (0..total_steps).for_each(|step| {
+                    let fs = chopped(None);
+                    fs.inject(step, fault);
+                    let result = rechop(&fs, &config);
+                    fs.clear_faults();
+                    let context = format!("with {:?} at step {}", fault, step);
+                    if let Some(byte) = lost_byte(&fs, chunking) {
+                        panic!(
+                            "Byte {} lost {}, files left: {:?}",
+                            byte,
+                            context,
+                            fs.paths(),
+                        );
+                    }
+                    if fault == Fault::ShortWrite {
+                        assert!(result.is_ok(), "Failed {}", context);
+                    }
+                })
+            });
+        });
+    }
+
+    #[test]
+    fn finishes_when_run_again() {
+        chunkings().into_iter().for_each(|chunking| {
+            let config = config(chunking);
+            let fs = chopped(None);
+            rechop(&fs, &config).unwrap();
+            let total_steps = fs.steps();
+
+            Fault::ALL.iter().for_each(|&fault| {
+                (0..total_steps).for_each(|step| {
+                    let fs = chopped(None);
+                    fs.inject(step, fault);
+                    let _ = rechop(&fs, &config);
+                    fs.clear_faults();
+                    // As chop would, reading whichever manifest is there
+                    let manifest_path = Manifest::path_for(ORIGINAL);
+                    let config = RechopConfig {
+                        manifest: Manifest::read(&fs, &manifest_path).unwrap(),
+                        ..self::config(chunking)
+                    };
+                    if let Err(why) = rechop(&fs, &config) {
+                        panic!(
+                            "Failed again with {:?} at step {}: {}",
+                            fault, step, why
+                        );
+                    }
+                    assert_rechopped(&fs, chunking);
+                })
+            });
+        });
+    }
+
+    #[test]
+    fn only_finishes_the_same_split() {
+        let [first, second, _] = chunkings();
+        let progress_path = rechop_path(Path::new(ORIGINAL));
+        // Interrupted once it's got far enough to record what it's doing
+        let fs = (0..)
+            .map(|step| {
+                let fs = chopped(None);
+                fs.inject(step, Fault::Crash);
+                rechop(&fs, &config(first)).unwrap_err();
+                fs.clear_faults();
+                fs
+            })
+            .find(|fs| fs.contents(&progress_path).is_some())
+            .unwrap();
+        let before = fs.paths();
+        let err = rechop(&fs, &config(second)).unwrap_err();
+        assert!(matches!(err, UnfinishedRechop(path) if path == progress_path));
+        assert_eq!(fs.paths(), before);
+        rechop(&fs, &config(first)).unwrap();
+        assert_rechopped(&fs, first);
+    }
+
+    #[test]
+    fn progress_round_trips() {
+        let mut progress = Progress {
+            ranges: new_ranges(chunkings()[1]),
+            hashes: None,
+            made: 3,
+            renaming: false,
+        };
+        assert_eq!(progress.to_string().parse::<Progress>().unwrap(), progress);
+        progress.hashes = Some(vec![String::new(), "ab".into(), "cd".into()]);
+        progress.made = 1;
+        assert_eq!(progress.to_string().parse::<Progress>().unwrap(), progress);
+        progress.made = 0;
+        progress.renaming = true;
+        assert_eq!(progress.to_string().parse::<Progress>().unwrap(), progress);
+
+        [
+            "",
+            "part 1 8\nmade 1\n",
+            "chopstick rechop 1\npart 1 8\n",
+            "chopstick rechop 1\npart 2 8\nmade 1\n",
+            "chopstick rechop 1\npart 1 8 ab\nmade 1\n",
+            "chopstick rechop 1\npart 1 8\nmade 2\n",
+            "chopstick rechop 1\npart 1 8\nmade 0\nrenamed\n",
+        ]
+        .iter()
+        .for_each(|s| assert!(s.parse::<Progress>().is_err(), "{:?}", s));
+    }
+}
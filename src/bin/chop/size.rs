@@ -0,0 +1,83 @@
+use crate::ChopError::*;
+use crate::Result;
+use bytesize::ByteSize;
+use chopstick::config::Config;
+use chopstick::{disk_info, DiskInfo};
+use std::path::Path;
+use std::str::FromStr;
+
+const FAT32_MAX_FILE_SIZE: u64 = 4 * 1024 * 1024 * 1024 - 1;
+
+// Part sizes that fit common media. Where different formats of a disc hold
+// different amounts (e.g. DVD-R and DVD+R), the smaller is used
+pub const PRESETS: [(&str, u64); 6] = [
+    ("fat32", FAT32_MAX_FILE_SIZE),
+    ("cd700", 737_280_000),
+    ("dvd", 4_700_372_992),
+    ("dvd-dl", 8_543_666_176),
+    ("bd25", 25_025_314_816),
+    // Attachments are base64 encoded, which makes them a third bigger
+    ("email25m", 25_000_000 / 4 * 3),
+];
+
+// Accepts sizes with units, presets from the config file, or the presets
+// above, in that order
+pub fn parse_size(size_str: &str) -> Result<u64> {
+    if let Ok(ByteSize(size)) = ByteSize::from_str(size_str) {
+        return Ok(size);
+    }
+    if let Some(config) = Config::load()? {
+        if let Some(size) = config.preset(size_str) {
+            return Ok(size?);
+        }
+    }
+    PRESETS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(size_str))
+        .map(|(_, size)| *size)
+        .ok_or_else(|| UnknownSize(size_str.to_owned()))
+}
+
+// The biggest parts that fit in the free space of `directory`, and on its
+// filesystem
+pub fn fit_to(directory: &Path) -> Result<u64> {
+    let disk =
+        disk_info(directory).map_err(|why| CantFitTo(directory.into(), why))?;
+    match fit_part_size(&disk) {
+        0 => Err(InsufficientDiskSpace),
+        part_size => Ok(part_size),
+    }
+}
+
+fn fit_part_size(disk: &DiskInfo) -> u64 {
+    let max_file_size = match disk.file_system.to_ascii_lowercase().as_str() {
+        "vfat" | "fat" | "fat32" | "msdos" => FAT32_MAX_FILE_SIZE,
+        _ => u64::MAX,
+    };
+    disk.available_space.min(max_file_size)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn presets() {
+        assert_eq!(parse_size("fat32").unwrap(), 4294967295);
+        assert_eq!(parse_size("DVD").unwrap(), 4_700_372_992);
+        assert_eq!(parse_size("128MiB").unwrap(), 128 * 1024 * 1024);
+        assert!(matches!(parse_size("floppy"), Err(UnknownSize(_))));
+    }
+
+    #[test]
+    fn fit_to_filesystem() {
+        let disk = |file_system: &str, available_space| DiskInfo {
+            available_space,
+            file_system: file_system.to_owned(),
+        };
+        assert_eq!(fit_part_size(&disk("ext4", 1000)), 1000);
+        assert_eq!(fit_part_size(&disk("ext4", 1 << 40)), 1 << 40);
+        assert_eq!(fit_part_size(&disk("vfat", 1000)), 1000);
+        assert_eq!(fit_part_size(&disk("vfat", 1 << 40)), FAT32_MAX_FILE_SIZE);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::args::{RunConfig, UndoConfig};
+use crate::ChopError::*;
+use crate::Result;
+use chopstick::filesystem::{FileHandle, Filesystem, OpenMode};
+use chopstick::journal::{
+    absolute_path, Entry, FileRecord, Journal, JournalError, Operation,
+};
+use chopstick::manifest::Manifest;
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Records what a chop left behind, so that it can be undone. Failing to only
+// means it can't be, so the chop itself still succeeds
+pub fn record_chop(config: &RunConfig, parts: &[PathBuf]) {
+    if let Err(why) = try_record_chop(config, parts) {
+        eprintln!(
+            "WARNING: couldn't record the chop, so it can't be undone ({why})"
+        );
+    }
+}
+
+// Runs that didn't leave parts on disk (with --filter or --archive) just
+// forget the last run
+fn try_record_chop(
+    config: &RunConfig,
+    parts: &[PathBuf],
+) -> std::result::Result<(), Box<dyn Error>> {
+    let original = absolute_path(&config.path)?;
+    let entry = match parts.is_empty() {
+        true => None,
+        false => Some(chop_entry(config, &original, parts)?),
+    };
+    Journal::update(|journal| match entry {
+        Some(entry) => journal.record(entry),
+        None => {
+            journal.forget(&original);
+        }
+    })?;
+    Ok(())
+}
+
+fn chop_entry(
+    config: &RunConfig,
+    original: &Path,
+    parts: &[PathBuf],
+) -> io::Result<Entry> {
+    // Everything's recorded by its absolute path, next to the original
+    let present = |path: &Path| {
+        FileRecord::present(
+            original.with_file_name(path.file_name().unwrap_or_default()),
+        )
+    };
+    let parts = parts
+        .iter()
+        .map(|part| present(part))
+        .collect::<io::Result<Vec<_>>>()?;
+    let original = match config.retain {
+        true => FileRecord::present(original)?,
+        false => FileRecord::absent(
+            original,
+            parts.iter().map(|part| part.size).sum(),
+        ),
+    };
+    let mut extras = Vec::new();
+    if config.writes_manifest() {
+        extras.push(present(&Manifest::path_for(&config.path))?);
+    }
+    config.rejoin_scripts.iter().try_for_each(|script| {
+        present(&script.path_for(&config.path)).map(|file| extras.push(file))
+    })?;
+    Ok(Entry {
+        operation: Operation::Chop,
+        retained: config.retain,
+        original,
+        parts,
+        extras,
+        manifest: None,
+    })
+}
+
+// Sticks the parts from the last chop back together, or if the original was
+// retained, deletes them. Either way the manifest and rejoin scripts go too.
+// Nothing's touched if any of them have changed since the chop
+pub fn undo_chop<F: Filesystem>(fs: &F, config: &UndoConfig) -> Result<()> {
+    if let (Some(lock), true) = (&config.lock, config.verbose) {
+        eprintln!("Locked {}", lock.path().to_string_lossy());
+    }
+    if !config.journal {
+        return Err(JournalError::Disabled.into());
+    }
+    let original = absolute_path(&config.path)?;
+    let journal = Journal::load()?;
+    let entry = journal.undoable(Operation::Chop, &original)?;
+    if config.verbose {
+        eprintln!(
+            "Nothing has changed since the chop of {}",
+            original.to_string_lossy()
+        );
+    }
+
+    let leftovers = match entry.retained {
+        true => entry.parts.iter().chain(&entry.extras).collect::<Vec<_>>(),
+        false => {
+            stick_parts(fs, config, entry)?;
+            entry.extras.iter().collect()
+        }
+    };
+    leftovers.into_iter().try_for_each(|file| -> Result<()> {
+        if !config.dry_run {
+            fs.remove_file(&file.path)
+                .map_err(|why| FailedToUndo(file.path.clone(), why))?;
+        }
+        if config.verbose {
+            eprintln!("Deleted {}", file.path.to_string_lossy());
+        }
+        Ok(())
+    })?;
+
+    if !config.dry_run {
+        Journal::update(|journal| {
+            journal.forget(&original);
+        })?;
+    }
+    if config.verbose && !config.dry_run {
+        eprintln!("Finished without error!");
+    }
+    Ok(())
+}
+
+// As stick would: the first part becomes the original, and the rest are
+// appended to it, each deleted once it's safely on disk
+fn stick_parts<F: Filesystem>(
+    fs: &F,
+    config: &UndoConfig,
+    entry: &Entry,
+) -> Result<()> {
+    let original = &entry.original.path;
+    let undo_error = |why| FailedToUndo(original.clone(), why);
+    let (first, rest) =
+        entry.parts.split_first().expect("Chops always make parts");
+    if !config.dry_run {
+        fs.rename(&first.path, original).map_err(undo_error)?;
+    }
+    if config.verbose {
+        eprintln!(
+            "Renamed {} to {}",
+            first.path.to_string_lossy(),
+            original.to_string_lossy(),
+        );
+    }
+    let mut file = match config.dry_run {
+        false => Some(fs.open(original, OpenMode::Append).map_err(undo_error)?),
+        true => None,
+    };
+    let folder = original.parent().unwrap_or_else(|| Path::new("."));
+    if !config.dry_run {
+        fs.sync_directory(folder).map_err(undo_error)?;
+    }
+    rest.iter().try_for_each(|part| -> Result<()> {
+        if let Some(file) = &mut file {
+            fs.open(&part.path, OpenMode::Read)
+                .and_then(|mut part| io::copy(&mut part, file))
+                .and_then(|_| file.sync_all())
+                .and_then(|_| fs.remove_file(&part.path))
+                .map_err(|why| FailedToUndo(part.path.clone(), why))?;
+        }
+        if config.verbose {
+            eprintln!(
+                "Appended {} to {} and deleted it",
+                part.path.to_string_lossy(),
+                original.to_string_lossy(),
+            );
+        }
+        Ok(())
+    })?;
+    if !config.dry_run {
+        fs.sync_directory(folder).map_err(undo_error)?;
+    }
+    Ok(())
+}
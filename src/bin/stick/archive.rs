@@ -0,0 +1,275 @@
+use crate::args::RunConfig;
+use crate::StickError::*;
+use crate::{temp_path_for, Result};
+use chopstick::archive::for_each_entry;
+use chopstick::filesystem::{Durability, FileHandle, Filesystem, OpenMode};
+use chopstick::manifest::{Manifest, MANIFEST_EXTENSION};
+use chopstick::throttle::RateLimiter;
+use std::cmp::min;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Parts are copied out of an archive at most this much at a time
+const COPY_BUFFER_SIZE: u64 = 1 << 20;
+
+// Sticks the parts in an archive made by chop --archive back together. The
+// archive is read as a stream, so the parts never have to be unpacked to disk,
+// and it can come from a pipe. It's only deleted once the file is complete
+pub fn stick_from_archive<F: Filesystem>(
+    fs: &F,
+    config: &RunConfig,
+    archive_path: &Path,
+) -> Result<()> {
+    let from_stdin = archive_path == Path::new("-");
+    match from_stdin {
+        true => stick_entries(fs, config, archive_path, io::stdin().lock())?,
+        false => {
+            let archive = fs
+                .open(archive_path, OpenMode::Read)
+                .map_err(|why| ReadArchive(archive_path.to_owned(), why))?;
+            stick_entries(fs, config, archive_path, archive)?
+        }
+    }
+
+    if !config.retain && !from_stdin {
+        if !config.dry_run {
+            fs.remove_file(archive_path)
+                .map_err(|why| DeleteArchive(archive_path.to_owned(), why))?;
+        }
+        if config.verbose {
+            eprintln!("\nDeleted {}", archive_path.to_string_lossy());
+        }
+    }
+    if config.verbose && !config.dry_run {
+        // Extra new line for flair
+        eprintln!("\nFinished without error!");
+    }
+    Ok(())
+}
+
+// The manifest has to be the first entry, and every part after it has to be
+// the size it says, in order
+fn stick_entries<F: Filesystem, R: Read>(
+    fs: &F,
+    config: &RunConfig,
+    archive_path: &Path,
+    archive: R,
+) -> Result<()> {
+    // When atomic, the file is built under a temporary name so that nothing
+    // incomplete is ever at the original's path
+    let build_path = match config.atomic {
+        true => temp_path_for(&config.original_file),
+        false => config.original_file.clone(),
+    };
+    if let Some(path) = [&config.original_file, &build_path]
+        .into_iter()
+        .find(|path| fs.exists(path))
+    {
+        return Err(CreateOriginal(
+            path.clone(),
+            io::Error::new(io::ErrorKind::AlreadyExists, "The file exists"),
+        ));
+    }
+    let result = build_original(fs, config, archive_path, archive, &build_path);
+    if result.is_err() && !config.dry_run {
+        // Nothing was at either path before, so anything there now is either
+        // unfinished or not known to be safely on disk, and would only be in
+        // the way of trying again. The archive is all still there
+        let _ = fs.remove_file(&build_path);
+        if config.atomic {
+            let _ = fs.remove_file(&config.original_file);
+        }
+    }
+    result
+}
+
+// Writes the parts into the file at `build_path`, which doesn't exist yet
+fn build_original<F: Filesystem, R: Read>(
+    fs: &F,
+    config: &RunConfig,
+    archive_path: &Path,
+    archive: R,
+    build_path: &Path,
+) -> Result<()> {
+    let bad_archive =
+        |why: &str| BadArchive(archive_path.to_owned(), why.into());
+    // The original file doesn't exist yet, so check the folder it'll be in
+    let original_folder = match config.original_file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut rate_limiter = config.rate_limit.map(RateLimiter::new);
+    let buffer_size = match &rate_limiter {
+        Some(rate_limiter) => min(COPY_BUFFER_SIZE, rate_limiter.chunk_size()),
+        None => COPY_BUFFER_SIZE,
+    };
+    let mut buffer = vec![0; buffer_size as usize];
+    let mut manifest: Option<Manifest> = None;
+    // Only created once the manifest has been read, so nothing is made from
+    // an archive that wasn't made by chop
+    let mut original_file = None;
+    let mut parts_read = 0;
+
+    let result =
+        for_each_entry(archive, |name, size, contents| -> Result<()> {
+            let manifest = match &manifest {
+                Some(manifest) => manifest,
+                None => {
+                    if !name.ends_with(&format!(".{}", MANIFEST_EXTENSION)) {
+                        return Err(bad_archive(
+                            "its first entry isn't a manifest",
+                        ));
+                    }
+                    let manifest_path = PathBuf::from(name);
+                    let mut text = String::new();
+                    contents
+                        .read_to_string(&mut text)
+                        .and_then(|_| text.parse())
+                        .map(|parsed| manifest = Some(parsed))
+                        .map_err(|why| ReadManifest(manifest_path, why))?;
+                    let file_size = manifest.as_ref().unwrap().file_size;
+                    original_file = create_original(
+                        fs,
+                        config,
+                        build_path,
+                        original_folder,
+                        file_size,
+                    )?;
+                    return Ok(());
+                }
+            };
+
+            parts_read += 1;
+            let part_path = PathBuf::from(name);
+            match manifest.part_sizes.get(parts_read - 1) {
+                Some(&expected) if expected != size => {
+                    return Err(WrongPartSize(part_path, expected, size))
+                }
+                Some(_) => {}
+                None => {
+                    return Err(WrongPartCount(
+                        manifest.part_sizes.len(),
+                        parts_read,
+                    ))
+                }
+            }
+            if config.verbose {
+                // Extra new line for some nice spacing
+                eprintln!("\nReading {} from archive", name);
+            }
+
+            if let Some(file) = &mut original_file {
+                let mut copied = 0;
+                loop {
+                    let read = contents
+                        .read(&mut buffer)
+                        .map_err(|why| ReadPart(part_path.clone(), why))?;
+                    if read == 0 {
+                        break;
+                    }
+                    file.write_all(&buffer[..read]).map_err(WriteOriginal)?;
+                    copied += read as u64;
+                    if let Some(rate_limiter) = &mut rate_limiter {
+                        rate_limiter.consume(read as u64);
+                    }
+                }
+                if copied != size {
+                    return Err(ReadPart(
+                        part_path,
+                        io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "The archive ended part way through the part",
+                        ),
+                    ));
+                }
+                if config.verbose {
+                    eprintln!("Wrote part to original file");
+                }
+            } else if config.verbose {
+                eprintln!("[reading and writing happens]");
+            }
+            Ok(())
+        });
+    match result {
+        Ok(result) => result?,
+        Err(why) => return Err(ReadArchive(archive_path.to_owned(), why)),
+    }
+    let manifest = manifest.ok_or_else(|| bad_archive("it has no manifest"))?;
+    if parts_read != manifest.part_sizes.len() {
+        return Err(WrongPartCount(manifest.part_sizes.len(), parts_read));
+    }
+
+    if let Some(mut file) = original_file {
+        // The archive can't be deleted until the file is safely on disk
+        if config.durability >= Durability::Data || config.atomic {
+            file.sync_all().map_err(WriteOriginal)?;
+        }
+        let size = file.size().map_err(WriteOriginal)?;
+        if size != manifest.file_size {
+            return Err(IncompleteOriginal(
+                build_path.to_owned(),
+                manifest.file_size,
+                size,
+            ));
+        }
+        // Only advice, so it doesn't matter if it's not taken
+        if config.drop_cache {
+            let _ = file.drop_cache(0, 0);
+        }
+    }
+    if config.atomic {
+        if !config.dry_run {
+            fs.rename(build_path, &config.original_file)
+                .map_err(|why| {
+                    CreateOriginal(config.original_file.clone(), why)
+                })?;
+        }
+        if config.verbose {
+            eprintln!(
+                "\nRenamed {} to {}",
+                build_path.to_string_lossy(),
+                config.original_file.to_string_lossy(),
+            );
+        }
+    }
+    if (config.durability == Durability::Full || config.atomic)
+        && !config.dry_run
+    {
+        fs.sync_directory(original_folder).map_err(WriteOriginal)?;
+    }
+    Ok(())
+}
+
+// Once the manifest says how big the file will be, checks there's room for it,
+// then makes the empty file to write the parts into
+fn create_original<F: Filesystem>(
+    fs: &F,
+    config: &RunConfig,
+    build_path: &Path,
+    original_folder: &Path,
+    file_size: u64,
+) -> Result<Option<F::File>> {
+    match fs.sufficient_disk_space(original_folder, file_size) {
+        Ok(true) => {
+            if config.verbose {
+                eprintln!(
+                    "Sufficient disk space available ({} needed)",
+                    bytesize::to_string(file_size, true),
+                );
+            }
+        }
+        Ok(false) => return Err(InsufficientDiskSpace),
+        Err(warn) => eprintln!("WARNING: {warn}"),
+    }
+    let file = match config.dry_run {
+        false => fs
+            .open(build_path, OpenMode::CreateNew)
+            .map_err(|why| CreateOriginal(build_path.to_owned(), why))?
+            .into(),
+        true => None,
+    };
+    if config.verbose {
+        eprintln!("Created empty file {}", build_path.to_string_lossy());
+    }
+    Ok(file)
+}
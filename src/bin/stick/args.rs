@@ -1,22 +1,73 @@
+use crate::completion;
 use crate::Result;
 use crate::StickError::*;
+use chopstick::archive::ArchiveFormat;
+use chopstick::cli;
+use chopstick::config::{ConfigError, Defaults};
+use chopstick::filesystem::{Durability, RealFilesystem};
+use chopstick::lock::{SetLock, LOCK_EXTENSION};
+use chopstick::manifest::Manifest;
+use chopstick::throttle::{IoClass, RateLimiter};
 use chopstick::EXTENSION_PREFIX;
 use clap::{Arg, ArgMatches};
 use os_str_bytes::RawOsStr;
+use std::cmp::Ordering;
 use std::ffi::{OsStr, OsString};
+use std::iter::Peekable;
 use std::path::{Path, PathBuf};
-use std::{env, fs};
+use std::time::Duration;
+use std::{env, fs, process};
 use walkdir::WalkDir;
 
+const SORT_ORDERS: [&str; 2] = ["natural", "name"];
+const DEFAULT_QUIET_PERIOD: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 pub struct RunConfig {
     pub original_file: PathBuf,
     // Ordered list of parts
     pub part_paths: Vec<PathBuf>,
+    // Size of the largest part
     pub part_size: u64,
+    // The manifest chop left with the parts, if there is one
+    pub manifest: Option<PathBuf>,
     pub retain: bool,
     pub verbose: bool,
     pub dry_run: bool,
+    // Build the file under a temporary name, only giving it the original's
+    // once it's complete
+    pub atomic: bool,
+    pub durability: Durability,
+    // Bytes per second to read and write at most
+    pub rate_limit: Option<u64>,
+    pub io_class: Option<IoClass>,
+    // Read the next chunk while the last is being written
+    pub pipeline: bool,
+    // Bypass the page cache with O_DIRECT
+    pub direct: bool,
+    // Tell the OS that bytes won't be needed again once they're copied
+    pub drop_cache: bool,
+    // Record the run, so that it can be undone
+    pub journal: bool,
+    // Stops other runs using the same files (not taken for a dry run)
+    pub lock: Option<SetLock>,
+    // Stream the parts out of an archive made by chop --archive, instead of
+    // looking for them on disk ("-" is stdin)
+    pub from_archive: Option<PathBuf>,
+    // Keep sticking sets of parts as they arrive in a folder
+    pub watch: Option<WatchConfig>,
+    // Chop the original back into the parts of the last stick of it
+    pub undo: bool,
+}
+
+#[derive(Debug)]
+pub struct WatchConfig {
+    pub dir: PathBuf,
+    // Shell command run after each set is stuck together
+    pub then: Option<String>,
+    // How long a set without a manifest has to go unchanged before it's
+    // taken to be complete
+    pub quiet_period: Duration,
 }
 
 impl RunConfig {
@@ -25,7 +76,7 @@ impl RunConfig {
         RunConfig::process_matches(&matches)
     }
 
-    fn create_clap_app() -> clap::Command<'static> {
+    pub fn create_clap_app() -> clap::Command<'static> {
         clap::Command::new(env!("CARGO_PKG_NAME"))
             .version(env!("CARGO_PKG_VERSION"))
             .author("alpha-tango-kilo <git@heyatk.com>")
@@ -52,6 +103,262 @@ impl RunConfig {
                     .help("Don't actually do anything, just tell me about it")
                     .long_help("Don't actually do anything, just tell me about it (implies --verbose)"),
             )
+            .arg(
+                Arg::new("wait")
+                    .long("wait")
+                    .help("If another run is using the same files, wait for it to finish")
+                    .long_help(
+                        "If another run of chop or stick is using the same \
+                        files, wait for it to finish instead of failing. \
+                        Runs are only locked on unix",
+                    ),
+            )
+            .arg(
+                Arg::new("rate-limit")
+                    .long("rate-limit")
+                    .value_name("RATE")
+                    .help("Read and write at most this much per second")
+                    .long_help(
+                        "Read and write at most this much per second, \
+                        e.g. 50MiB/s, so as not to starve other processes \
+                        using the disk",
+                    )
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("ionice")
+                    .long("ionice")
+                    .value_name("CLASS")
+                    .help("Run in this I/O scheduling class (Linux only)")
+                    .long_help(
+                        "Run in this I/O scheduling class (Linux only). \
+                        idle only uses the disk when nothing else is, and \
+                        best-effort shares it at the lowest priority",
+                    )
+                    .takes_value(true)
+                    .possible_values(IoClass::NAMES),
+            )
+            .arg(
+                Arg::new("atomic")
+                    .long("atomic")
+                    .help("Only put the file in place once it's complete")
+                    .long_help(
+                        "Build the file as <file>.stick-tmp, and only rename \
+                        it to <file> once every part has been written and \
+                        synced to disk, so that other programs never see it \
+                        incomplete",
+                    ),
+            )
+            .arg(
+                Arg::new("parts")
+                    .long("parts")
+                    .help("Stick together these parts, in the order given")
+                    .long_help(
+                        "Stick together these parts, in the order given. \
+                        The parts can have any name and be in any folder. \
+                        Requires --output",
+                    )
+                    .takes_value(true)
+                    .multiple_values(true)
+                    .allow_invalid_utf8(true)
+                    .requires("output")
+                    .conflicts_with_all(&["glob", "file_name"]),
+            )
+            .arg(
+                Arg::new("glob")
+                    .long("glob")
+                    .help("Stick together the parts matching this pattern")
+                    .long_help(
+                        "Stick together the parts matching this pattern, \
+                        e.g. 'downloads/*.part*'. \
+                        Remember to quote the pattern so your shell doesn't \
+                        expand it. \
+                        Requires --output",
+                    )
+                    .takes_value(true)
+                    .requires("output")
+                    .conflicts_with("file_name"),
+            )
+            .arg(
+                Arg::new("from-archive")
+                    .long("from-archive")
+                    .value_name("ARCHIVE")
+                    .help("Stick together the parts in this tar or zip archive")
+                    .long_help(
+                        "Stick together the parts in this tar or zip archive, \
+                        made by chop --archive, without unpacking them first. \
+                        Give - to read the archive from stdin. \
+                        The file is named after the archive, without its \
+                        extension, unless --output is given",
+                    )
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+                    .allow_hyphen_values(true)
+                    .conflicts_with_all(&["parts", "glob", "file_name"]),
+            )
+            .arg(
+                Arg::new("watch")
+                    .long("watch")
+                    .value_name("DIR")
+                    .help("Stick together each set of parts that arrives in DIR")
+                    .long_help(
+                        "Keep watching DIR, sticking together each set of \
+                        parts once it's complete: once every part matches \
+                        its manifest, or without a manifest, once parts \
+                        numbered 1 to n are there, and the set has gone \
+                        unchanged for the quiet period. Parts are checked \
+                        against the hashes in the manifest, if it has them",
+                    )
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+                    .conflicts_with_all(&[
+                        "parts",
+                        "glob",
+                        "file_name",
+                        "from-archive",
+                        "output",
+                    ]),
+            )
+            .arg(
+                Arg::new("then")
+                    .long("then")
+                    .value_name("COMMAND")
+                    .help("Run this shell command after sticking each set")
+                    .long_help(
+                        "Run this shell command after sticking each set \
+                        together, with FILE set to the file that was made",
+                    )
+                    .takes_value(true)
+                    .requires("watch"),
+            )
+            .arg(
+                Arg::new("quiet-period")
+                    .long("quiet-period")
+                    .value_name("SECONDS")
+                    .help("How long a set of parts must go unchanged")
+                    .long_help(
+                        "How long a set of parts has to go unchanged before \
+                        it's taken to be complete, even if it matches its \
+                        manifest, as a part can reach its full size before \
+                        it's finished [default: 30]",
+                    )
+                    .takes_value(true)
+                    .requires("watch"),
+            )
+            .arg(
+                Arg::new("undo")
+                    .long("undo")
+                    .help("Undo the last stick of the file")
+                    .long_help(
+                        "Undo the last stick of the file, chopping it back \
+                        into the parts (and manifest) it was made from, or \
+                        if the parts were retained, deleting it. Refuses if \
+                        the file or the parts have changed since",
+                    )
+                    .requires("file_name")
+                    .conflicts_with_all(&[
+                        "parts",
+                        "glob",
+                        "from-archive",
+                        "watch",
+                        "output",
+                        "retain",
+                        "atomic",
+                    ]),
+            )
+            .arg(
+                Arg::new("sort")
+                    .long("sort")
+                    .help("How to order the parts matched by --glob")
+                    .long_help(
+                        "How to order the parts matched by --glob. \
+                        natural compares numbers in file names by their \
+                        value, so file.p9 comes before file.p10. \
+                        name compares file names character by character",
+                    )
+                    .takes_value(true)
+                    .possible_values(SORT_ORDERS)
+                    .requires("glob"),
+            )
+            .arg(
+                Arg::new("pipeline")
+                    .long("pipeline")
+                    .help("Read the next chunk while the last is being written")
+                    .long_help(
+                        "Read the next chunk while the last is being written, \
+                        on another thread, or with io_uring if built with the \
+                        io-uring feature. The buffer is split in two for \
+                        this, so no more memory is used",
+                    ),
+            )
+            .arg(
+                Arg::new("direct")
+                    .long("direct")
+                    .help("Bypass the page cache, with O_DIRECT (Linux only)")
+                    .long_help(
+                        "Bypass the page cache, by reading and writing with \
+                        O_DIRECT (Linux only), so copying a large file doesn't \
+                        push everything else out of it. Anything not aligned \
+                        to 4KiB, such as the end of each part, still goes \
+                        through the page cache",
+                    ),
+            )
+            .arg(
+                Arg::new("drop-cache")
+                    .long("drop-cache")
+                    .help("Drop bytes from the page cache once they're copied")
+                    .long_help(
+                        "Advise the OS to drop bytes from the page cache once \
+                        they're copied (Linux only). Lighter than --direct, \
+                        but only bytes already synced to disk can be dropped",
+                    ),
+            )
+            .arg(
+                Arg::new("durability")
+                    .long("durability")
+                    .help("How much to sync to disk before deleting each part")
+                    .long_help(
+                        "How much to sync to disk before deleting each part. \
+                        none leaves it to the OS, data syncs the file being \
+                        built, and full also syncs its directory entry, so \
+                        nothing is lost if the power is cut [default: full]",
+                    )
+                    .value_name("LEVEL")
+                    .takes_value(true)
+                    .possible_values(Durability::NAMES),
+            )
+            .arg(
+                Arg::new("profile")
+                    .long("profile")
+                    .help("Use the defaults from this profile of the config file")
+                    .long_help(
+                        "Use the defaults from this profile of the config file, \
+                        found in its [profiles.<name>] table. \
+                        Can also be set with CHOPSTICK_PROFILE",
+                    )
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::new("print-config")
+                    .long("print-config")
+                    .help("Show the defaults in use, and where each came from")
+                    .long_help(
+                        "Show the defaults in use, and where each came from. \
+                        Defaults for --retain, --verbose, --atomic, --sort, --pipeline, --durability, and --journal can be set in \
+                        $XDG_CONFIG_HOME/chopstick/config.toml, or with environment \
+                        variables such as CHOPSTICK_RETAIN=1. \
+                        Turn one off for a run with --no-<option>, such as \
+                        --no-retain",
+                    ),
+            )
+            .arg(
+                Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .help("The file to reconstruct into, when using --parts, --glob, or --from-archive")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true),
+            )
             .arg(
                 Arg::new("file_name")
                     .help("The file to reconstruct")
@@ -60,86 +367,436 @@ impl RunConfig {
                         You only need to specify one part, providing the \
                         extension is optional",
                     )
-                    .required(true)
+                    .required_unless_present_any([
+                        "parts",
+                        "glob",
+                        "from-archive",
+                        "watch",
+                        "print-config",
+                        "generate-completions",
+                        "generate-man",
+                        "complete-parts",
+                    ])
                     .takes_value(true)
                     .allow_invalid_utf8(true),
             )
+            .arg(
+                // Used by the completion scripts to suggest files to stick
+                Arg::new("complete-parts")
+                    .long("complete-parts")
+                    .takes_value(true)
+                    .allow_invalid_utf8(true)
+                    .allow_hyphen_values(true)
+                    .hide(true),
+            )
+            // So that the command line always wins over the config file
+            .args([
+                cli::negation("retain", "no-retain"),
+                cli::negation("verbose", "no-verbose"),
+                cli::negation("atomic", "no-atomic"),
+                cli::negation("pipeline", "no-pipeline"),
+            ])
+            .args(cli::journal_args())
+            .args(cli::generate_args())
     }
 
     fn process_matches(clap_matches: &ArgMatches) -> Result<Self> {
-        let retain = clap_matches.is_present("retain");
-        let dry_run = clap_matches.is_present("dry-run");
-        let verbose = dry_run || clap_matches.is_present("verbose");
-
-        // Unwrap is assured by "file_name" being a required argument taking
-        // a value
-        let path_ref: &Path =
-            clap_matches.value_of_os("file_name").unwrap().as_ref();
-
-        let search_stem = path_ref.remove_chopstick_extension();
-        // Try and use parent folder from given path, failing that use the
-        // working directory
-        let mut parent_folder = match path_ref
-            .parent()
-            // .parent() can just return an empty string which is annoying
-            .filter(|p| !p.as_os_str().is_empty())
+        if let Some(shell) = clap_matches.value_of("generate-completions") {
+            let mut clap = RunConfig::create_clap_app();
+            print!("{}", completion::script(shell, &mut clap));
+            process::exit(0);
+        } else if clap_matches.is_present("generate-man") {
+            let mut clap = RunConfig::create_clap_app();
+            print!("{}", cli::man_page(&mut clap, env!("CARGO_BIN_NAME")));
+            process::exit(0);
+        } else if let Some(prefix) = clap_matches.value_of_os("complete-parts")
         {
-            Some(parent) => parent.to_owned(),
-            None => env::current_dir().map_err(BadParent)?,
+            completion::print_part_stems(prefix);
+            process::exit(0);
+        }
+        let defaults =
+            Defaults::load("stick", clap_matches.value_of("profile"))?;
+        let retain = defaults.flag(
+            "retain",
+            cli::flag(clap_matches, "retain"),
+            false,
+        )?;
+        let verbose = defaults.flag(
+            "verbose",
+            cli::flag(clap_matches, "verbose"),
+            false,
+        )?;
+        let atomic = defaults.flag(
+            "atomic",
+            cli::flag(clap_matches, "atomic"),
+            false,
+        )?;
+        let sort = defaults.string(
+            "sort",
+            clap_matches.value_of("sort"),
+            "natural",
+        )?;
+        if !SORT_ORDERS.contains(&sort.value.as_str()) {
+            return Err(ConfigError::InvalidValue(String::from("sort")).into());
+        }
+        let pipeline = defaults.flag(
+            "pipeline",
+            cli::flag(clap_matches, "pipeline"),
+            false,
+        )?;
+        let durability = defaults.string(
+            "durability",
+            clap_matches.value_of("durability"),
+            "full",
+        )?;
+        let journal = defaults.flag(
+            "journal",
+            cli::flag(clap_matches, "journal"),
+            false,
+        )?;
+        if clap_matches.is_present("print-config") {
+            print!(
+                "{}",
+                defaults.describe(&[
+                    ("retain", retain.value.to_string(), &retain.source),
+                    ("verbose", verbose.value.to_string(), &verbose.source),
+                    ("atomic", atomic.value.to_string(), &atomic.source),
+                    ("sort", format!("{:?}", sort.value), &sort.source),
+                    ("pipeline", pipeline.value.to_string(), &pipeline.source),
+                    (
+                        "durability",
+                        format!("{:?}", durability.value),
+                        &durability.source,
+                    ),
+                    ("journal", journal.value.to_string(), &journal.source),
+                ])
+            );
+            process::exit(0);
+        }
+        let durability = durability.value.parse().map_err(|_| {
+            ConfigError::InvalidValue(String::from("durability"))
+        })?;
+        let rate_limit = match clap_matches.value_of("rate-limit") {
+            Some(rate) => Some(
+                RateLimiter::parse_rate(rate)
+                    .ok_or_else(|| InvalidRate(rate.to_owned()))?,
+            ),
+            None => None,
         };
+        let io_class = clap_matches
+            .value_of("ionice")
+            .map(|class| class.parse().expect("clap checks possible values"));
+        let retain = retain.value;
+        let dry_run = clap_matches.is_present("dry-run");
+        let verbose = dry_run || verbose.value;
 
-        let mut discovered_paths = find_parts_in(&parent_folder, &search_stem);
+        let from_archive =
+            clap_matches.value_of_os("from-archive").map(PathBuf::from);
+        let watch = match clap_matches.value_of_os("watch") {
+            Some(dir) => Some(WatchConfig {
+                dir: dir.into(),
+                then: clap_matches.value_of("then").map(str::to_owned),
+                quiet_period: match clap_matches.value_of("quiet-period") {
+                    Some(seconds) => {
+                        seconds.parse().map(Duration::from_secs).map_err(
+                            |_| InvalidQuietPeriod(seconds.to_owned()),
+                        )?
+                    }
+                    None => DEFAULT_QUIET_PERIOD,
+                },
+            }),
+            None => None,
+        };
+        let undo = clap_matches.is_present("undo");
+        let original_file = match (
+            clap_matches.value_of_os("file_name"),
+            clap_matches.value_of_os("output"),
+            &from_archive,
+        ) {
+            // The file stick made, so it's not taken to be a part
+            (Some(file_name), _, _) if undo => file_name.into(),
+            (Some(file_name), _, _) => original_for(file_name.as_ref())?,
+            (None, Some(output), _) => output.into(),
+            (None, None, Some(archive)) => ArchiveFormat::original_for(archive)
+                .filter(|_| archive != Path::new("-"))
+                .ok_or_else(|| ArchiveNeedsOutput(archive.clone()))?,
+            // Each set's original is found as it arrives
+            (None, None, None) if watch.is_some() => PathBuf::new(),
+            (None, None, None) => output_path(clap_matches),
+        };
+        // Taken before looking for parts, as a run of chop could still be
+        // making them
+        let wait = clap_matches.is_present("wait");
+        let mut lock = match dry_run || watch.is_some() {
+            false => Some(SetLock::acquire(&original_file, wait)?),
+            true => None,
+        };
 
-        if discovered_paths.is_empty() {
-            Err(NoParts)
-        } else if discovered_paths.len() > 1
-            && verify_discovered_parts(&discovered_paths)
-        {
-            let part_size = fs::metadata(&discovered_paths[0])
-                .map_err(|err| ReadPart(discovered_paths.remove(0), err))?
-                .len();
-            // Add file name onto parent folder to reconstruct file into
-            // If we don't use parent_folder here, the file will be recreated
-            // in the working directory, instead of the file's directory
-            parent_folder.push(search_stem);
-            Ok(RunConfig {
-                original_file: parent_folder,
-                part_paths: discovered_paths,
-                part_size,
-                retain,
-                verbose,
-                dry_run,
+        // The parts are only found once the archive's being read, and an
+        // undo gets them from the journal
+        let find_parts = || -> Result<Vec<PathBuf>> {
+            if from_archive.is_some() || watch.is_some() || undo {
+                Ok(Vec::new())
+            } else if let Some(parts) = clap_matches.values_of_os("parts") {
+                check_given_parts(parts.map(PathBuf::from).collect())
+            } else if let Some(pattern) = clap_matches.value_of("glob") {
+                let mut part_paths = glob_parts(pattern)?;
+                if sort.value == "natural" {
+                    part_paths.sort_by(|a, b| natural_cmp(a, b));
+                }
+                Ok(part_paths)
+            } else {
+                discover_parts(&original_file)
+            }
+        };
+        let mut part_paths = find_parts()?;
+        // Parts given by name or glob may not be named after the file being
+        // made, so the file each was chopped from (or the part itself, if it
+        // isn't named like one) is locked too. They're found again in case
+        // a chop was still making them
+        if let Some(lock) = &mut lock {
+            let originals = part_paths
+                .iter()
+                .map(|part| part.remove_chopstick_extension())
+                .collect::<Vec<_>>();
+            lock.extend(originals, wait)?;
+            part_paths = find_parts()?;
+        }
+
+        // Parts aren't necessarily all the same size (e.g. if they were
+        // content-defined), so find the largest
+        let part_sizes = part_paths
+            .iter()
+            .map(|path| {
+                fs::metadata(path)
+                    .map(|md| md.len())
+                    .map_err(|err| ReadPart(path.clone(), err))
             })
+            .collect::<Result<Vec<_>>>()?;
+        let part_size = part_sizes.iter().copied().max().unwrap_or(0);
+
+        let manifest_path = Manifest::path_for(&original_file);
+        let manifest = if manifest_path.is_file()
+            && from_archive.is_none()
+            && watch.is_none()
+            && !undo
+        {
+            let manifest = Manifest::read(&RealFilesystem, &manifest_path)
+                .map_err(|err| ReadManifest(manifest_path.clone(), err))?;
+            check_against_manifest(&manifest, &part_paths, &part_sizes)?;
+            Some(manifest_path)
         } else {
-            // Pretty up format a bit to make life easier for StickError
-            let files_found = discovered_paths
-                .into_iter()
-                .map(|pb| pb.file_name().unwrap().to_owned())
-                .collect::<Vec<_>>();
-            Err(IncompleteParts(files_found))
+            None
+        };
+
+        Ok(RunConfig {
+            original_file,
+            part_paths,
+            part_size,
+            manifest,
+            retain,
+            verbose,
+            dry_run,
+            atomic: atomic.value,
+            durability,
+            rate_limit,
+            io_class,
+            pipeline: pipeline.value,
+            direct: clap_matches.is_present("direct"),
+            drop_cache: clap_matches.is_present("drop-cache"),
+            journal: journal.value,
+            lock,
+            from_archive,
+            watch,
+            undo,
+        })
+    }
+}
+
+// The parts found have to be exactly the ones chop made
+pub fn check_against_manifest(
+    manifest: &Manifest,
+    part_paths: &[PathBuf],
+    part_sizes: &[u64],
+) -> Result<()> {
+    if manifest.part_sizes.len() != part_paths.len() {
+        return Err(WrongPartCount(
+            manifest.part_sizes.len(),
+            part_paths.len(),
+        ));
+    }
+    manifest
+        .part_sizes
+        .iter()
+        .zip(part_sizes)
+        .zip(part_paths)
+        .try_for_each(|((&expected, &actual), path)| {
+            if expected == actual {
+                Ok(())
+            } else {
+                Err(WrongPartSize(path.clone(), expected, actual))
+            }
+        })
+}
+
+// Unwrap is assured by "output" being required with "parts" and "glob"
+fn output_path(clap_matches: &ArgMatches) -> PathBuf {
+    clap_matches.value_of_os("output").unwrap().into()
+}
+
+// The file to reconstruct, given it or one of its parts
+fn original_for(path_ref: &Path) -> Result<PathBuf> {
+    let file_name = path_ref.file_name().ok_or(NoParts)?;
+    // A part can be given instead of the original file, but the original's
+    // name could itself end in something that looks like a part extension
+    // (e.g. foo.p2), so only strip it if there's a part there
+    let search_stem = if path_ref.is_file() {
+        file_name.remove_chopstick_extension()
+    } else {
+        file_name.to_owned()
+    };
+    // Try and use parent folder from given path, failing that use the
+    // working directory
+    let mut parent_folder = match path_ref
+        .parent()
+        // .parent() can just return an empty string which is annoying
+        .filter(|p| !p.as_os_str().is_empty())
+    {
+        Some(parent) => parent.to_owned(),
+        None => env::current_dir().map_err(BadParent)?,
+    };
+    // Add file name onto parent folder to reconstruct file into
+    // If we don't use parent_folder here, the file will be recreated
+    // in the working directory, instead of the file's directory
+    parent_folder.push(search_stem);
+    Ok(parent_folder)
+}
+
+// Finds the parts of `original_file`, in order
+pub fn discover_parts(original_file: &Path) -> Result<Vec<PathBuf>> {
+    // Unwraps are assured by original_for always giving a parent and file name
+    let parent_folder = original_file.parent().unwrap();
+    let search_stem = original_file.file_name().unwrap();
+
+    let discovered_paths = find_parts_in(parent_folder, search_stem)?;
+
+    if discovered_paths.is_empty() {
+        Err(NoParts)
+    } else if discovered_paths.len() > 1
+        && verify_discovered_parts(&discovered_paths)
+    {
+        Ok(discovered_paths)
+    } else {
+        // Pretty up format a bit to make life easier for StickError
+        let files_found = discovered_paths
+            .into_iter()
+            .map(|pb| pb.file_name().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        Err(IncompleteParts(files_found))
+    }
+}
+
+// Parts given explicitly are used in the order given, but must all exist and
+// can't be repeated (the repeat would be missing once the first was deleted)
+fn check_given_parts(part_paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let mut seen = Vec::with_capacity(part_paths.len());
+    for path in &part_paths {
+        let canonical = path
+            .canonicalize()
+            .map_err(|err| ReadPart(path.clone(), err))?;
+        if seen.contains(&canonical) {
+            return Err(RepeatedPart(path.clone()));
+        }
+        seen.push(canonical);
+    }
+    Ok(part_paths)
+}
+
+fn glob_parts(pattern: &str) -> Result<Vec<PathBuf>> {
+    let paths = glob::glob(pattern)
+        .map_err(|err| InvalidGlob(pattern.to_owned(), err))?
+        .filter_map(|entry| match entry {
+            Ok(path) => Some(path),
+            Err(why) => {
+                eprintln!("Failed to read {:?}: {}", why.path(), why.error());
+                None
+            }
+        })
+        // Lock files, from this run or another, are never parts
+        .filter(|path| {
+            path.is_file()
+                && path.extension() != Some(OsStr::new(LOCK_EXTENSION))
+        })
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        Err(NoParts)
+    } else {
+        Ok(paths)
+    }
+}
+
+// Orders file names so that runs of digits are compared by their value, e.g.
+// file.p9 < file.p10 and "file.p3 (1)" < "file.p3 (2)"
+fn natural_cmp(a: &Path, b: &Path) -> Ordering {
+    let a_name = a.file_name().unwrap_or_default().to_string_lossy();
+    let b_name = b.file_name().unwrap_or_default().to_string_lossy();
+    let mut a_chars = a_name.chars().peekable();
+    let mut b_chars = b_name.chars().peekable();
+    loop {
+        let ordering = match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a_char), Some(b_char))
+                if a_char.is_ascii_digit() && b_char.is_ascii_digit() =>
+            {
+                let a_digits = take_digits(&mut a_chars);
+                let b_digits = take_digits(&mut b_chars);
+                // Leading zeroes don't change the value, but do get the
+                // final say so the ordering is consistent
+                let a_value = a_digits.trim_start_matches('0');
+                let b_value = b_digits.trim_start_matches('0');
+                a_value
+                    .len()
+                    .cmp(&b_value.len())
+                    .then_with(|| a_value.cmp(b_value))
+                    .then_with(|| a_digits.len().cmp(&b_digits.len()))
+            }
+            (Some(a_char), Some(b_char)) => {
+                let ordering = a_char.cmp(b_char);
+                a_chars.next();
+                b_chars.next();
+                ordering
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
         }
     }
 }
 
-fn find_parts_in<P: AsRef<Path>>(root: P, search_stem: &OsStr) -> Vec<PathBuf> {
-    WalkDir::new(root)
+fn take_digits<I: Iterator<Item = char>>(chars: &mut Peekable<I>) -> String {
+    let mut digits = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        digits.push(digit);
+    }
+    digits
+}
+
+fn find_parts_in<P: AsRef<Path>>(
+    root: P,
+    search_stem: &OsStr,
+) -> Result<Vec<PathBuf>> {
+    let mut parts = WalkDir::new(root)
         .min_depth(1)
         .max_depth(1) // Search same folder
         .follow_links(true)
-        // Zero padding file names in chop means sorting by file name here
-        // lets us get the parts in numerical order, which is useful later for
-        // verifying we have a full run of them
+        // Only so that which of two duplicates gets reported is consistent
         .sort_by_file_name()
         .into_iter()
         .filter_entry(|e| {
             // Check file extension...
-            e.path()
-                .extension()
-                .and_then(OsStr::to_str)
-                .map(|ext_str| {
-                    ext_str.starts_with(EXTENSION_PREFIX)
-                })
-                .unwrap_or(false)
+            part_number(e.path()).is_some()
                 // ...and file name
                 && e.path().file_name().unwrap().remove_chopstick_extension() == search_stem
         })
@@ -152,7 +809,36 @@ fn find_parts_in<P: AsRef<Path>>(root: P, search_stem: &OsStr) -> Vec<PathBuf> {
                 None
             }
         })
-        .collect()
+        .collect::<Vec<_>>();
+    // Sort by the value of the part number rather than by file name, so
+    // inconsistent zero padding (e.g. from renaming) doesn't matter
+    parts.sort_by_cached_key(|path| part_number(path));
+    match parts
+        .windows(2)
+        .find(|pair| part_number(&pair[0]) == part_number(&pair[1]))
+    {
+        Some(pair) => Err(DuplicateParts(
+            pair[0].file_name().unwrap().to_owned(),
+            pair[1].file_name().unwrap().to_owned(),
+        )),
+        None => Ok(parts),
+    }
+}
+
+// The number in a chopstick extension, e.g. 3 for foo.p03
+pub fn part_number(path: &Path) -> Option<u64> {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .and_then(extension_part_number)
+}
+
+fn extension_part_number(extension: &str) -> Option<u64> {
+    extension
+        .strip_prefix(EXTENSION_PREFIX)
+        .filter(|digits| {
+            !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+        })
+        .and_then(|digits| digits.parse().ok())
 }
 
 // Check extensions indicate a complete set of parts
@@ -161,35 +847,24 @@ fn verify_discovered_parts(part_paths: &[PathBuf]) -> bool {
     part_paths
         .iter()
         .enumerate()
-        .map(|(index, path)| ((index + 1).to_string(), path))
-        .all(|(index, path)| {
-            path.extension()
-                .and_then(OsStr::to_str)
-                // ends_with handily ignores the zero padding
-                .map(|ext| ext.ends_with(&index))
-                .unwrap_or(false)
-        })
+        .all(|(index, path)| part_number(path) == Some(index as u64 + 1))
 }
 
-trait RemoveChopstickExtension {
+pub trait RemoveChopstickExtension {
     fn remove_chopstick_extension(&self) -> OsString;
 }
 
 impl RemoveChopstickExtension for OsStr {
     fn remove_chopstick_extension(&self) -> OsString {
         let haystack = RawOsStr::new(self);
-        let extension_start_index: Option<usize> = haystack.rfind('.');
-        match haystack.as_ref().rsplit_once(EXTENSION_PREFIX) {
-            Some((file_stem, _))
-                if extension_start_index
-                    .map(|index| index + 1 == file_stem.raw_len())
-                    .unwrap_or(false) =>
+        match haystack.rsplit_once('.') {
+            Some((file_stem, extension))
+                if extension
+                    .to_str()
+                    .and_then(extension_part_number)
+                    .is_some() =>
             {
-                file_stem
-                    .strip_suffix('.')
-                    .unwrap()
-                    .to_os_str()
-                    .into_owned()
+                file_stem.to_os_str().into_owned()
             }
             _ => self.to_owned(),
         }
@@ -213,21 +888,57 @@ mod test {
     fn path_discovery() {
         let temp_dir = TempDir::new().unwrap();
         let mut expected_parts = Vec::with_capacity(9);
-        (0..9).into_iter().for_each(|n| {
-            let part = temp_dir.child(&format!("foo.p{}", n + 1));
+        (0..9).for_each(|n| {
+            let part = temp_dir.child(format!("foo.p{}", n + 1));
+            part.touch().expect("Failed to create file");
+            expected_parts.push(part.to_path_buf());
+        });
+        let actual_parts =
+            find_parts_in(&temp_dir, &OsString::from("foo")).unwrap();
+        assert_eq!(actual_parts, expected_parts);
+    }
+
+    #[test]
+    fn path_discovery_numeric_order() {
+        let temp_dir = TempDir::new().unwrap();
+        // Mixed padding, as if hand renamed or made by different tools
+        let names = ["foo.p01", "foo.p2", "foo.p003", "foo.p9", "foo.p10"];
+        let mut expected_parts = Vec::with_capacity(names.len());
+        names.iter().for_each(|name| {
+            let part = temp_dir.child(name);
             part.touch().expect("Failed to create file");
             expected_parts.push(part.to_path_buf());
         });
-        let actual_parts = find_parts_in(&temp_dir, &OsString::from("foo"));
+        // Not parts
+        temp_dir.child("foo.pdf").touch().unwrap();
+        temp_dir.child("foo.p").touch().unwrap();
+        temp_dir.child("foo.p4a").touch().unwrap();
+        let actual_parts =
+            find_parts_in(&temp_dir, &OsString::from("foo")).unwrap();
         assert_eq!(actual_parts, expected_parts);
+        assert!(!verify_discovered_parts(&actual_parts), "Missing part 4");
+    }
+
+    #[test]
+    fn path_discovery_duplicates() {
+        let temp_dir = TempDir::new().unwrap();
+        ["foo.p01", "foo.p1", "foo.p2"].iter().for_each(|name| {
+            temp_dir.child(name).touch().expect("Failed to create file");
+        });
+        let err = find_parts_in(&temp_dir, &OsString::from("foo")).unwrap_err();
+        assert!(
+            matches!(&err, DuplicateParts(a, b) if a == "foo.p01" && b == "foo.p1"),
+            "Unexpected error {:?}",
+            err,
+        );
     }
 
     #[test]
     fn part_verification_good() {
         let temp_dir = TempDir::new().unwrap();
         let mut parts = Vec::with_capacity(9);
-        (0..9).into_iter().for_each(|n| {
-            let part = temp_dir.child(&format!("foo.p{}", n + 1));
+        (0..9).for_each(|n| {
+            let part = temp_dir.child(format!("foo.p{}", n + 1));
             part.touch().expect("Failed to create file");
             parts.push(part.to_path_buf());
         });
@@ -235,8 +946,8 @@ mod test {
 
         let temp_dir = TempDir::new().unwrap();
         let mut parts = Vec::with_capacity(9);
-        (0..9).into_iter().for_each(|n| {
-            let part = temp_dir.child(&format!("foo.tar.gz.p{}", n + 1));
+        (0..9).for_each(|n| {
+            let part = temp_dir.child(format!("foo.tar.gz.p{}", n + 1));
             part.touch().expect("Failed to create file");
             parts.push(part.to_path_buf());
         });
@@ -244,8 +955,8 @@ mod test {
 
         let temp_dir = TempDir::new().unwrap();
         let mut parts = Vec::with_capacity(9);
-        (0..9).into_iter().for_each(|n| {
-            let part = temp_dir.child(&format!("foo.p.bar.p{}", n + 1));
+        (0..9).for_each(|n| {
+            let part = temp_dir.child(format!("foo.p.bar.p{}", n + 1));
             part.touch().expect("Failed to create file");
             parts.push(part.to_path_buf());
         });
@@ -259,9 +970,9 @@ mod test {
     fn part_verification_bad() {
         let temp_dir = TempDir::new().unwrap();
         let mut parts = Vec::with_capacity(9);
-        (0..9).into_iter().for_each(|n| {
+        (0..9).for_each(|n| {
             if n != 2 {
-                let part = temp_dir.child(&format!("foo.p{}", n + 1));
+                let part = temp_dir.child(format!("foo.p{}", n + 1));
                 part.touch().expect("Failed to create file");
                 parts.push(part.to_path_buf());
             }
@@ -270,9 +981,9 @@ mod test {
 
         let temp_dir = TempDir::new().unwrap();
         let mut parts = Vec::with_capacity(9);
-        (0..9).into_iter().for_each(|n| {
+        (0..9).for_each(|n| {
             if n > 1 {
-                let part = temp_dir.child(&format!("foo.p{}", n + 1));
+                let part = temp_dir.child(format!("foo.p{}", n + 1));
                 part.touch().expect("Failed to create file");
                 parts.push(part.to_path_buf());
             }
@@ -280,6 +991,55 @@ mod test {
         assert!(!verify_discovered_parts(&parts), "Two missing");
     }
 
+    #[test]
+    fn natural_ordering() {
+        let mut paths = vec![
+            "file.p10",
+            "file.p9",
+            "file.p3 (2)",
+            "file.p3 (1)",
+            "file.p03",
+            "file.p1",
+            "dl/file.p2",
+        ]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+        paths.sort_by(|a, b| natural_cmp(a, b));
+        let expected = vec![
+            "file.p1",
+            "dl/file.p2",
+            "file.p3 (1)",
+            "file.p3 (2)",
+            "file.p03",
+            "file.p9",
+            "file.p10",
+        ]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn repeated_parts() {
+        let temp_dir = TempDir::new().unwrap();
+        let part = temp_dir.child("foo.p1");
+        part.touch().unwrap();
+        let other = temp_dir.child("bar");
+        other.touch().unwrap();
+        let given = vec![part.to_path_buf(), other.to_path_buf()];
+        assert_eq!(check_given_parts(given.clone()).unwrap(), given);
+
+        // Same file, different path
+        let err = check_given_parts(vec![
+            part.to_path_buf(),
+            temp_dir.path().join(".").join("foo.p1"),
+        ])
+        .unwrap_err();
+        assert!(matches!(err, RepeatedPart(_)));
+    }
+
     fn extension_removal_test_runner(test_data: &[(&str, &str)]) {
         test_data
             .iter()
@@ -307,6 +1067,8 @@ mod test {
             ("foo.tgz", "foo.tgz"),
             ("../foo/bar/../foo", "../foo/bar/../foo"),
             ("barmy.hber.afv.p00.asdf", "barmy.hber.afv.p00.asdf"),
+            ("foo.p", "foo.p"),
+            ("foo.pdf", "foo.pdf"),
         ];
         extension_removal_test_runner(&data);
     }
@@ -0,0 +1,236 @@
+use crate::args::RemoveChopstickExtension;
+use chopstick::cli;
+use os_str_bytes::RawOsStr;
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+const BIN_NAME: &str = env!("CARGO_BIN_NAME");
+
+// clap's completion script, with the file name completed by
+// `stick --complete-parts` so that only parts are suggested
+pub fn script(shell: &str, clap: &mut clap::Command) -> String {
+    let script = cli::completions(shell, clap, BIN_NAME);
+    // Options that take values are left for clap's script to complete
+    let value_options = clap
+        .get_arguments()
+        .filter(|arg| !arg.is_positional() && arg.is_takes_value_set())
+        .flat_map(|arg| {
+            let shorts = arg
+                .get_short_and_visible_aliases()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|short| format!("-{}", short));
+            let longs = arg
+                .get_long_and_visible_aliases()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|long| format!("--{}", long));
+            shorts.chain(longs).collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    match shell {
+        "bash" => bash(script, &value_options),
+        "zsh" => zsh(script),
+        "fish" => fish(script),
+        "elvish" => elvish(script, &value_options),
+        _ => script,
+    }
+}
+
+fn bash(script: String, value_options: &[String]) -> String {
+    format!(
+        r#"{script}
+_{bin}_parts() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    case "${{prev}}" in
+        {options})
+            _{bin} "$@"
+            return
+            ;;
+    esac
+    if [[ ${{cur}} == -* ]]; then
+        _{bin} "$@"
+        return
+    fi
+    local IFS=$'\n'
+    compopt -o filenames
+    COMPREPLY=( $(compgen -d -- "${{cur}}") $({bin} --complete-parts "${{cur}}" 2>/dev/null) )
+}}
+
+complete -F _{bin}_parts -o bashdefault -o default {bin}
+"#,
+        script = script.trim_end(),
+        bin = BIN_NAME,
+        options = value_options.join("|"),
+    )
+}
+
+fn zsh(script: String) -> String {
+    let call = format!("_{} \"$@\"", BIN_NAME);
+    let script = script
+        .lines()
+        .map(|line| match line.starts_with("'::file_name") {
+            true => {
+                line.replacen(":' \\", &format!(":_{}_parts' \\", BIN_NAME), 1)
+            }
+            false => line.to_owned(),
+        })
+        .filter(|line| line.trim_end() != call)
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        r#"{script}
+
+(( $+functions[_{bin}_parts] )) ||
+_{bin}_parts() {{
+    local -a parts
+    parts=(${{(f)"$({bin} --complete-parts "$PREFIX" 2>/dev/null)"}})
+    _path_files -/
+    compadd -a parts
+}}
+
+{call}
+"#,
+        script = script.trim_end(),
+        bin = BIN_NAME,
+        call = call,
+    )
+}
+
+fn fish(script: String) -> String {
+    format!(
+        r#"{script}
+complete -c {bin} -n 'not string match -q -- "-*" (commandline -ct)' -f -a '(__fish_complete_directories (commandline -ct))'
+complete -c {bin} -n 'not string match -q -- "-*" (commandline -ct)' -f -a '({bin} --complete-parts (commandline -ct) 2>/dev/null)'
+"#,
+        script = script.trim_end(),
+        bin = BIN_NAME,
+    )
+}
+
+fn elvish(script: String, value_options: &[String]) -> String {
+    let options = value_options
+        .iter()
+        .map(|option| format!("'{}'", option))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        r#"{script}
+
+var {bin}-options~ = $edit:completion:arg-completer[{bin}]
+set edit:completion:arg-completer[{bin}] = {{|@words|
+    var current = $words[-1]
+    if (or (str:has-prefix $current '-') (has-value [{options}] $words[-2])) {{
+        {bin}-options $@words
+    }} else {{
+        {bin} --complete-parts $current 2>/dev/null | from-lines
+    }}
+}}
+"#,
+        script = script.trim_end(),
+        bin = BIN_NAME,
+        options = options,
+    )
+}
+
+pub fn print_part_stems(prefix: &OsStr) {
+    let mut stdout = io::stdout();
+    part_stems(prefix).into_iter().for_each(|stem| {
+        let _ = stdout
+            .write_all(RawOsStr::new(&stem).as_raw_bytes())
+            .and_then(|_| stdout.write_all(b"\n"));
+    });
+}
+
+// The originals of the parts whose paths start with `prefix`, each only once
+// however many parts it has
+fn part_stems(prefix: &OsStr) -> Vec<OsString> {
+    let raw_prefix = RawOsStr::new(prefix);
+    let (dir, name_prefix) = match raw_prefix.rsplit_once('/') {
+        Some((dir, name_prefix)) => (Some(dir), name_prefix),
+        None => (None, raw_prefix.as_ref()),
+    };
+    let search_dir = match dir {
+        Some(dir) if dir.is_empty() => OsString::from("/"),
+        Some(dir) => dir.to_os_str().into_owned(),
+        None => OsString::from("."),
+    };
+    let entries = match fs::read_dir(Path::new(&search_dir)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .map(|entry| entry.file_name())
+        .filter(|name| RawOsStr::new(name).starts_with_os(name_prefix))
+        .filter_map(|name| {
+            let stem = name.remove_chopstick_extension();
+            (stem != name).then(|| stem)
+        })
+        .map(|stem| match dir {
+            Some(dir) => {
+                let mut path = dir.to_os_str().into_owned();
+                path.push("/");
+                path.push(stem);
+                path
+            }
+            None => stem,
+        })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+
+    #[test]
+    fn scripts_complete_parts() {
+        cli::SHELLS.iter().for_each(|shell| {
+            let mut clap = crate::args::RunConfig::create_clap_app();
+            let script = script(shell, &mut clap);
+            assert!(
+                script.contains(&format!("{} --complete-parts", BIN_NAME)),
+                "{} script doesn't complete parts",
+                shell
+            );
+        });
+        let mut clap = crate::args::RunConfig::create_clap_app();
+        assert!(script("zsh", &mut clap).contains(":_stick_parts' \\"));
+    }
+
+    #[test]
+    fn part_stems_deduplicated() {
+        let temp_dir = TempDir::new().unwrap();
+        [
+            "a.txt.p1", "a.txt.p2", "a.txt.p3", "b.p01", "b.p02", "a.txt", "c",
+        ]
+        .iter()
+        .for_each(|name| temp_dir.child(name).touch().unwrap());
+        temp_dir.child("d.p1").create_dir_all().unwrap();
+
+        let dir = temp_dir.path().to_str().unwrap();
+        let stems = |prefix: &str| part_stems(OsStr::new(prefix));
+        assert_eq!(
+            stems(&format!("{}/", dir)),
+            vec![
+                OsString::from(format!("{}/a.txt", dir)),
+                OsString::from(format!("{}/b", dir)),
+            ],
+        );
+        assert_eq!(
+            stems(&format!("{}/b", dir)),
+            vec![OsString::from(format!("{}/b", dir))],
+        );
+        assert!(stems(&format!("{}/c", dir)).is_empty());
+        assert!(stems(&format!("{}/missing/", dir)).is_empty());
+    }
+}
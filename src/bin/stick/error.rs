@@ -1,3 +1,6 @@
+use chopstick::config::ConfigError;
+use chopstick::journal::JournalError;
+use chopstick::lock::LockError;
 use std::error::Error;
 use std::ffi::OsString;
 use std::path::PathBuf;
@@ -8,27 +11,68 @@ pub type Result<T, E = StickError> = std::result::Result<T, E>;
 
 #[derive(Debug)]
 pub enum StickError {
+    Config(ConfigError),
+    Locked(LockError),
     BadParent(io::Error),
     NoParts,
     IncompleteParts(Vec<OsString>),
+    DuplicateParts(OsString, OsString),
+    InvalidGlob(String, glob::PatternError),
+    RepeatedPart(PathBuf),
+    InvalidRate(String),
+    ReadManifest(PathBuf, io::Error),
+    WrongPartCount(usize, usize),
+    WrongPartSize(PathBuf, u64, u64),
     InsufficientDiskSpace,
     CreateOriginal(PathBuf, io::Error),
     ReadPart(PathBuf, io::Error),
     WriteOriginal(io::Error),
+    IncompleteOriginal(PathBuf, u64, u64),
     DeletePart(PathBuf, io::Error),
+    DeleteManifest(PathBuf, io::Error),
+    ArchiveNeedsOutput(PathBuf),
+    ReadArchive(PathBuf, io::Error),
+    BadArchive(PathBuf, String),
+    DeleteArchive(PathBuf, io::Error),
+    InvalidQuietPeriod(String),
+    Watch(PathBuf, io::Error),
+    CorruptPart(PathBuf),
+    Journal(JournalError),
+    Undo(PathBuf, io::Error),
 }
 
 impl StickError {
     pub fn exit_code(&self) -> i32 {
         match self {
+            Config(_) => 1,
+            Locked(LockError::Held(_, _)) => 1,
+            Locked(LockError::Io(_, _)) => 2,
             BadParent(_) => 1,
             NoParts => 1,
             IncompleteParts(_) => 1,
+            DuplicateParts(_, _) => 1,
+            InvalidGlob(_, _) => 1,
+            RepeatedPart(_) => 1,
+            InvalidRate(_) => 1,
+            ReadManifest(_, _) => 2,
+            WrongPartCount(_, _) => 1,
+            WrongPartSize(_, _, _) => 1,
             InsufficientDiskSpace => 1,
             CreateOriginal(_, _) => 2,
             ReadPart(_, _) => 2,
             WriteOriginal(_) => 2,
+            IncompleteOriginal(_, _, _) => 2,
             DeletePart(_, _) => 2,
+            DeleteManifest(_, _) => 2,
+            ArchiveNeedsOutput(_) => 1,
+            ReadArchive(_, _) => 2,
+            BadArchive(_, _) => 1,
+            DeleteArchive(_, _) => 2,
+            InvalidQuietPeriod(_) => 1,
+            Watch(_, _) => 2,
+            CorruptPart(_) => 1,
+            Journal(why) => why.exit_code(),
+            Undo(_, _) => 2,
         }
     }
 }
@@ -36,16 +80,54 @@ impl StickError {
 impl fmt::Display for StickError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Config(why) => write!(f, "{}", why),
+            Locked(why) => write!(f, "{}", why),
             BadParent(why) => write!(f, "Unable to determine or access parent folder: {}", why),
             NoParts => write!(f, "No parts were found to stick"),
             IncompleteParts(found) => write!(f, "Couldn't find all the parts to stick, only found the following: {:?}", found),
+            DuplicateParts(a, b) => write!(f, "Found more than one of the same part, {:?} and {:?}. Remove one and try again", a, b),
+            InvalidGlob(pattern, why) => write!(f, "Invalid pattern {:?}: {}", pattern, why),
+            RepeatedPart(path) => write!(f, "Part {} was given more than once", path.to_string_lossy()),
+            InvalidRate(rate) => write!(f, "Invalid rate limit {:?}, expected a size per second (e.g. 50MiB/s)", rate),
+            ReadManifest(path, why) => write!(f, "Couldn't read manifest {}: {}", path.to_string_lossy(), why),
+            WrongPartCount(expected, found) => write!(f, "The manifest lists {} parts, but {} were found", expected, found),
+            WrongPartSize(path, expected, actual) => write!(f, "Part {} should be {} bytes according to the manifest, but is {}", path.to_string_lossy(), expected, actual),
             InsufficientDiskSpace => write!(f, "Insufficient disk space to perform operation"),
             CreateOriginal(path, why) => write!(f, "Failed to create file {}: {}", path.to_string_lossy(), why),
             ReadPart(path, why) => write!(f, "Couldn't read part {}: {}", path.to_string_lossy(), why),
             WriteOriginal(why) => write!(f, "Couldn't write to original file: {}", why),
+            IncompleteOriginal(path, expected, actual) => write!(f, "{} should be {} bytes once all the parts are written, but is {}", path.to_string_lossy(), expected, actual),
             DeletePart(path, why) => write!(f, "Couldn't delete part {}: {}", path.to_string_lossy(), why),
+            DeleteManifest(path, why) => write!(f, "Couldn't delete manifest {}: {}", path.to_string_lossy(), why),
+            ArchiveNeedsOutput(path) => write!(f, "Can't tell what file the parts in {} make up, give it with --output", path.to_string_lossy()),
+            ReadArchive(path, why) => write!(f, "Couldn't read archive {}: {}", path.to_string_lossy(), why),
+            BadArchive(path, why) => write!(f, "Archive {} wasn't made by chop: {}", path.to_string_lossy(), why),
+            DeleteArchive(path, why) => write!(f, "Couldn't delete archive {}: {}", path.to_string_lossy(), why),
+            InvalidQuietPeriod(seconds) => write!(f, "Invalid quiet period {:?}, expected a whole number of seconds", seconds),
+            Watch(path, why) => write!(f, "Couldn't watch {}: {}", path.to_string_lossy(), why),
+            CorruptPart(path) => write!(f, "Part {} doesn't match the hash in the manifest", path.to_string_lossy()),
+            Journal(why) => write!(f, "{}", why),
+            Undo(path, why) => write!(f, "Couldn't undo the stick at {}: {}", path.to_string_lossy(), why),
         }
     }
 }
 
 impl Error for StickError {}
+
+impl From<ConfigError> for StickError {
+    fn from(err: ConfigError) -> Self {
+        StickError::Config(err)
+    }
+}
+
+impl From<JournalError> for StickError {
+    fn from(err: JournalError) -> Self {
+        StickError::Journal(err)
+    }
+}
+
+impl From<LockError> for StickError {
+    fn from(err: LockError) -> Self {
+        StickError::Locked(err)
+    }
+}
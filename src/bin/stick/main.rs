@@ -1,15 +1,28 @@
 use crate::args::RunConfig;
 use crate::StickError::*;
-use chopstick::{max_buffer_size, sufficient_disk_space, ChunkedReader};
+use chopstick::filesystem::{
+    crosses_filesystems, DirectFilesystem, Durability, FileHandle, Filesystem,
+    OpenMode, RealFilesystem,
+};
+use chopstick::max_buffer_size;
+use chopstick::parts::{
+    stick_from, ChunkOptions, Copier, FilesystemParts, PartError,
+};
+use chopstick::throttle::{set_io_class, RateLimiter};
 pub use error::*;
-use std::cmp::min;
-use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::path::Path;
-use std::{fs, io, mem, process};
+use std::cmp::{max, min};
+use std::path::{Path, PathBuf};
+use std::{io, process};
+use undo::PendingStick;
 
+const TEMP_EXTENSION: &str = "stick-tmp";
+
+mod archive;
 mod args;
+mod completion;
 mod error;
+mod undo;
+mod watch;
 
 fn main() {
     if let Err(why) = _main() {
@@ -19,17 +32,63 @@ fn main() {
 }
 
 fn _main() -> Result<()> {
-    let mut config = RunConfig::new()?;
+    let config = RunConfig::new()?;
+    if let Some(class) = config.io_class {
+        match set_io_class(class) {
+            Ok(()) if config.verbose => {
+                eprintln!("Set I/O scheduling class to {:?}", class)
+            }
+            Ok(()) => {}
+            Err(why) => {
+                eprintln!("WARNING: couldn't set I/O scheduling class ({why})")
+            }
+        }
+    }
+    match config.watch {
+        Some(_) => watch::watch(config),
+        None if config.undo => undo::undo_stick(&RealFilesystem, &config),
+        None => run(config),
+    }
+}
 
+fn run(config: RunConfig) -> Result<()> {
+    let pending = match config.dry_run || !config.journal {
+        false => Some(PendingStick::new(&config)),
+        true => None,
+    };
+    match config.direct {
+        true => stick(&DirectFilesystem::new(RealFilesystem), config),
+        false => stick(&RealFilesystem, config),
+    }?;
+    if let Some(pending) = pending {
+        pending.record();
+    }
+    Ok(())
+}
+
+fn stick<F: Filesystem>(fs: &F, mut config: RunConfig) -> Result<()> {
+    if let (Some(lock), true) = (&config.lock, config.verbose) {
+        eprintln!("Locked {}", lock.path().to_string_lossy());
+    }
+    if let Some(archive_path) = &config.from_archive {
+        return archive::stick_from_archive(fs, &config, archive_path);
+    }
     // Disk space check
     let space_needed = if !config.retain {
-        fs::metadata(&config.part_paths[0]).map(|md| md.len())
+        // Parts are appended one at a time, deleting each as we go, so at
+        // most one part's worth of extra space is needed
+        Ok(config.part_size)
     } else {
-        total_part_size(&config.part_paths)
+        total_part_size(fs, &config.part_paths)
+    };
+    // The original file doesn't exist yet, so check the folder it'll be in
+    let original_folder = match config.original_file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
     };
     match space_needed {
         Ok(space_needed) => {
-            match sufficient_disk_space(&config.original_file, space_needed) {
+            match fs.sufficient_disk_space(original_folder, space_needed) {
                 Ok(true) => {
                     if config.verbose {
                         eprintln!(
@@ -45,119 +104,217 @@ fn _main() -> Result<()> {
         Err(why) => eprintln!("WARNING: unable to read part file sizes to check if space is available ({why})"),
     }
 
-    let buffer_size = min(config.part_size, max_buffer_size()) as usize;
-    // Buffer must be filled in order to be used in a PartialReader
-    let mut buffer: Vec<u8> = vec![0; buffer_size];
+    let rate_limiter = config.rate_limit.map(RateLimiter::new);
+    let chunk_size = min(config.part_size, max_buffer_size());
+    let chunk_size = match &rate_limiter {
+        Some(rate_limiter) => min(chunk_size, rate_limiter.chunk_size()),
+        None => chunk_size,
+    } as usize;
+    let chunks = ChunkOptions {
+        // Overlapping reads and writes takes two buffers, so each gets half
+        // of the memory
+        chunk_size: match config.pipeline {
+            true => max(chunk_size / 2, 1),
+            false => chunk_size,
+        },
+        // Mapped files are read through the page cache
+        map: !config.direct,
+        aligned: config.direct,
+        // Instead of writing on another thread, where io_uring can be used
+        read_ahead: config.pipeline,
+        verbose: config.verbose,
+    };
+
+    // When atomic, the file is built under a temporary name so that nothing
+    // incomplete is ever at the original's path
+    let build_path = match config.atomic {
+        true => temp_path_for(&config.original_file),
+        false => config.original_file.clone(),
+    };
+    let expected_size = match config.atomic && !config.dry_run {
+        true => {
+            total_part_size(fs, &config.part_paths).map_err(WriteOriginal)?
+        }
+        false => 0,
+    };
 
-    let mut original_file = if !config.retain {
+    let original_file = if !config.retain {
         // Check the original file doesn't already exist, so as not to
-        // overwrite it if it does
-        if config.original_file.exists() {
+        // overwrite it if it does. Nothing stick didn't make is ever
+        // replaced: the file may be another copy, or newer than the parts. A
+        // leftover build file may be the only copy of bytes from parts that
+        // are already deleted
+        if let Some(path) = [&config.original_file, &build_path]
+            .into_iter()
+            .find(|path| fs.exists(path))
+        {
             return Err(CreateOriginal(
-                config.original_file.clone(),
+                path.clone(),
                 io::Error::new(io::ErrorKind::AlreadyExists, "The file exists"),
             ));
         }
-        // Rename first part to the original file and append to it from there
-        // First part is removed from config.part_paths
-        let first_part = config.part_paths.remove(0);
-        if !config.dry_run {
-            fs::rename(&first_part, &config.original_file).map_err(|why| {
-                CreateOriginal(config.original_file.clone(), why)
-            })?;
-        }
-        if config.verbose {
-            eprintln!(
-                "Renamed {} to {}",
-                first_part.to_string_lossy(),
-                config.original_file.to_string_lossy(),
-            );
-        }
-
-        if !config.dry_run {
-            OpenOptions::new()
-                .append(true)
-                .open(&config.original_file)
-                .map_err(WriteOriginal)?
-                .into()
-        } else {
-            None
+        // Rename first part to the original file and append to it from
+        // there, unless it's on another filesystem, in which case it's
+        // copied like the rest
+        let first_part = config.part_paths[0].clone();
+        let renamed = match config.dry_run {
+            true => Ok(()),
+            false => fs.rename(&first_part, &build_path),
+        };
+        match renamed {
+            Ok(()) => {
+                config.part_paths.remove(0);
+                if config.verbose {
+                    eprintln!(
+                        "Renamed {} to {}",
+                        first_part.to_string_lossy(),
+                        build_path.to_string_lossy(),
+                    );
+                }
+                if !config.dry_run {
+                    fs.open(&build_path, OpenMode::Append)
+                        .map_err(WriteOriginal)?
+                        .into()
+                } else {
+                    None
+                }
+            }
+            Err(why) if crosses_filesystems(&why) => {
+                if config.verbose {
+                    eprintln!(
+                        "{} is on another filesystem, so will be copied",
+                        first_part.to_string_lossy(),
+                    );
+                }
+                fs.open(&build_path, OpenMode::CreateNew)
+                    .map_err(|why| CreateOriginal(build_path.clone(), why))?
+                    .into()
+            }
+            Err(why) => return Err(CreateOriginal(build_path.clone(), why)),
         }
     } else {
+        if config.atomic && fs.exists(&config.original_file) {
+            return Err(CreateOriginal(
+                config.original_file.clone(),
+                io::Error::new(io::ErrorKind::AlreadyExists, "The file exists"),
+            ));
+        }
         let of = if !config.dry_run {
             // Just create a new file to store the original in
-            OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&config.original_file)
-                .map_err(|why| {
-                    CreateOriginal(config.original_file.clone(), why)
-                })?
+            fs.open(&build_path, OpenMode::CreateNew)
+                .map_err(|why| CreateOriginal(build_path.clone(), why))?
                 .into()
         } else {
             None
         };
         if config.verbose {
-            eprintln!(
-                "Created empty file {}",
-                config.original_file.to_string_lossy()
-            );
+            eprintln!("Created empty file {}", build_path.to_string_lossy());
         }
         of
     };
+    // The parts can't be deleted unless the file they're going into will
+    // still be there after a power cut
+    if config.durability == Durability::Full && !config.dry_run {
+        fs.sync_directory(original_folder).map_err(WriteOriginal)?;
+    }
 
-    config
-        .part_paths
-        .iter()
-        .try_for_each(|part_path| -> Result<()> {
-            // Step 1: read & write in chunks, controlled by ChunkedReader
-            let mut reader = if !config.dry_run {
-                let part = File::open(part_path)
-                    .map_err(|err| ReadPart(part_path.clone(), err))?;
-                ChunkedReader::new(part, &mut buffer, config.verbose).into()
-            } else {
-                None
-            };
-            if config.verbose {
-                // Extra new line for some nice spacing
-                eprintln!("\nReading from {}", part_path.to_string_lossy(),);
-            }
-
-            if !config.dry_run {
-                while let Some(bytes) = reader
-                    .as_mut()
-                    .unwrap()
-                    .read()
-                    .map_err(|err| ReadPart(part_path.clone(), err))?
-                {
-                    original_file
-                        .as_mut()
-                        .unwrap()
-                        .write_all(bytes)
-                        .map_err(WriteOriginal)?;
+    let original_file = match original_file {
+        Some(file) => {
+            let mut source =
+                FilesystemParts::from_paths(fs, config.part_paths.clone())
+                    .with_chunks(chunks)
+                    .with_drop_cache(config.drop_cache);
+            let mut copier = Copier::new(rate_limiter, config.pipeline);
+            let part_path =
+                |number: u64| config.part_paths[number as usize - 1].clone();
+            let after_part = |file: &mut F::File, number| {
+                if config.retain {
+                    return Ok(());
                 }
-                if config.verbose {
-                    eprintln!("Wrote buffer to original file");
+                if config.durability >= Durability::Data {
+                    file.sync_all().map_err(WriteOriginal)?;
                 }
-            } else if config.verbose {
-                eprintln!("[reading and writing happens]");
-            }
-
-            // Step 2: delete part file
-            // Drop reader so file is no longer open
-            mem::drop(reader);
-            if !config.retain {
-                if !config.dry_run {
-                    fs::remove_file(part_path)
-                        .map_err(|err| DeletePart(part_path.clone(), err))?;
+                // Once synced, what was written can be dropped from the page
+                // cache too
+                if config.drop_cache {
+                    let _ = file.drop_cache(0, 0);
                 }
+                let part_path = part_path(number);
+                fs.remove_file(&part_path)
+                    .map_err(|err| DeletePart(part_path.clone(), err))?;
                 if config.verbose {
                     eprintln!("Deleted {}", part_path.to_string_lossy());
                 }
+                Ok(())
+            };
+            let (file, _) =
+                stick_from(&mut source, file, &mut copier, after_part)
+                    .map_err(|why| match why {
+                        PartError::Open(number, why)
+                        | PartError::Read(number, why) => {
+                            ReadPart(part_path(number), why)
+                        }
+                        PartError::Write(_, why) => WriteOriginal(why),
+                        PartError::Hook(why) => why,
+                    })?;
+            Some(file)
+        }
+        None => {
+            if config.verbose {
+                config.part_paths.iter().for_each(|part_path| {
+                    // Extra new line for some nice spacing
+                    eprintln!("\nReading from {}", part_path.to_string_lossy());
+                    eprintln!("[reading and writing happens]");
+                    if !config.retain {
+                        eprintln!("Deleted {}", part_path.to_string_lossy());
+                    }
+                });
             }
+            None
+        }
+    };
+    // Anything written since the last part was deleted
+    if let (Some(file), true) = (&original_file, config.drop_cache) {
+        let _ = file.drop_cache(0, 0);
+    }
 
-            Ok(())
-        })?;
+    if config.atomic {
+        if let Some(mut file) = original_file {
+            file.sync_all().map_err(WriteOriginal)?;
+            let size = file.size().map_err(WriteOriginal)?;
+            if size != expected_size {
+                return Err(IncompleteOriginal(
+                    build_path,
+                    expected_size,
+                    size,
+                ));
+            }
+            fs.rename(&build_path, &config.original_file)
+                .map_err(|why| {
+                    CreateOriginal(config.original_file.clone(), why)
+                })?;
+            fs.sync_directory(original_folder).map_err(WriteOriginal)?;
+        }
+        if config.verbose {
+            eprintln!(
+                "\nRenamed {} to {}",
+                build_path.to_string_lossy(),
+                config.original_file.to_string_lossy(),
+            );
+        }
+    }
+
+    if let Some(manifest_path) =
+        config.manifest.as_ref().filter(|_| !config.retain)
+    {
+        if !config.dry_run {
+            fs.remove_file(manifest_path)
+                .map_err(|err| DeleteManifest(manifest_path.clone(), err))?;
+        }
+        if config.verbose {
+            eprintln!("\nDeleted {}", manifest_path.to_string_lossy());
+        }
+    }
 
     if config.verbose && !config.dry_run {
         // Extra new line for flair
@@ -167,20 +324,361 @@ fn _main() -> Result<()> {
     Ok(())
 }
 
-fn total_part_size<P: AsRef<Path>>(paths: &[P]) -> io::Result<u64> {
-    // All parts are the same size but the last one, so just multiply the size
-    // of the first part by paths.len() - 1, then add the size of the last part
-    debug_assert!(paths.len() >= 2);
-    let first = fs::metadata(&paths[0])?.len();
-    let last = fs::metadata(paths.last().unwrap())?.len();
-    Ok(first * (paths.len() as u64 - 1) + last)
+// Where the file is built in atomic mode
+fn temp_path_for(original_file: &Path) -> PathBuf {
+    let mut os_str = original_file.as_os_str().to_owned();
+    os_str.push(format!(".{}", TEMP_EXTENSION));
+    PathBuf::from(os_str)
+}
+
+fn total_part_size<F: Filesystem, P: AsRef<Path>>(
+    fs: &F,
+    paths: &[P],
+) -> io::Result<u64> {
+    // Parts can vary in size (e.g. content-defined ones), so each one has to
+    // be checked
+    paths
+        .iter()
+        .try_fold(0, |total, path| Ok(total + fs.file_size(path.as_ref())?))
 }
 
 #[cfg(test)]
 mod test {
-    use super::total_part_size;
+    use super::*;
     use assert_fs::prelude::*;
     use assert_fs::TempDir;
+    use chopstick::archive::{ArchiveFormat, ArchiveWriter};
+    use chopstick::filesystem::{Fault, MemoryFilesystem};
+    use chopstick::manifest::Manifest;
+    use std::iter;
+    use std::ops::Range;
+    use std::path::PathBuf;
+
+    const ORIGINAL: &str = "original";
+    const NUM_PARTS: usize = 4;
+    const PART_SIZE: usize = 25;
+
+    fn test_bytes() -> Vec<u8> {
+        (0..NUM_PARTS * PART_SIZE)
+            .map(|n| (n * 7 % 251) as u8)
+            .collect()
+    }
+
+    fn part_path(part: usize) -> PathBuf {
+        PathBuf::from(format!("{}.p{}", ORIGINAL, part))
+    }
+
+    fn config(retain: bool, atomic: bool) -> RunConfig {
+        RunConfig {
+            original_file: ORIGINAL.into(),
+            part_paths: (1..=NUM_PARTS).map(part_path).collect(),
+            part_size: PART_SIZE as u64,
+            manifest: Some(Manifest::path_for(ORIGINAL)),
+            retain,
+            verbose: false,
+            dry_run: false,
+            atomic,
+            durability: Durability::Full,
+            rate_limit: None,
+            io_class: None,
+            pipeline: false,
+            direct: false,
+            drop_cache: false,
+            journal: false,
+            lock: None,
+            from_archive: None,
+            watch: None,
+            undo: false,
+        }
+    }
+
+    // Every byte must either still be in a complete part, or in the
+    // original file (or the file it's being built in). Gives the first range
+    // of bytes that isn't
+    fn lost_bytes(fs: &MemoryFilesystem) -> Option<Range<usize>> {
+        let bytes = test_bytes();
+        let original = fs
+            .contents(ORIGINAL)
+            .or_else(|| fs.contents(temp_path_for(Path::new(ORIGINAL))))
+            .unwrap_or_default();
+        bytes
+            .chunks(PART_SIZE)
+            .enumerate()
+            .find_map(|(index, expected)| {
+                let range = index * PART_SIZE..(index + 1) * PART_SIZE;
+                let in_original = original.get(range.clone()) == Some(expected);
+                let in_part = fs.contents(part_path(index + 1)).as_deref()
+                    == Some(expected);
+                (!in_original && !in_part).then(|| range)
+            })
+    }
+
+    fn assert_bytes_intact(fs: &MemoryFilesystem, context: &str) {
+        if let Some(range) = lost_bytes(fs) {
+            panic!(
+                "Bytes {:?} lost {}, files left: {:?}",
+                range,
+                context,
+                fs.paths(),
+            );
+        }
+    }
+
+    fn new_fs() -> MemoryFilesystem {
+        let fs = MemoryFilesystem::new();
+        test_bytes()
+            .chunks(PART_SIZE)
+            .enumerate()
+            .for_each(|(index, part)| fs.insert(part_path(index + 1), part));
+        let manifest = Manifest {
+            file_size: test_bytes().len() as u64,
+            part_sizes: vec![PART_SIZE as u64; NUM_PARTS],
+            part_hashes: None,
+        };
+        fs.insert(
+            Manifest::path_for(ORIGINAL),
+            manifest.to_string().as_bytes(),
+        );
+        fs
+    }
+
+    // Runs like _main would, with the memory filesystem in place of the real
+    // one
+    fn run(fs: &MemoryFilesystem, config: RunConfig) -> Result<()> {
+        match config.direct {
+            true => stick(&DirectFilesystem::new(fs.clone()), config),
+            false => stick(fs, config),
+        }
+    }
+
+    fn assert_survives_faults(retain: bool, atomic: bool) {
+        assert_survives_faults_with(|| config(retain, atomic));
+    }
+
+    fn assert_survives_faults_with(config: impl Fn() -> RunConfig) {
+        let RunConfig { retain, atomic, .. } = config();
+        let fs = new_fs();
+        run(&fs, config()).expect("Run without faults failed");
+        let total_steps = fs.steps();
+        assert_eq!(fs.contents(ORIGINAL), Some(test_bytes()));
+        assert!(!fs.exists(&temp_path_for(Path::new(ORIGINAL))));
+        assert_eq!(fs.contents(part_path(NUM_PARTS)).is_some(), retain);
+        assert_eq!(fs.exists(&Manifest::path_for(ORIGINAL)), retain);
+
+        Fault::ALL.iter().for_each(|&fault| {
+            (0..total_steps).for_each(|step| {
+                let fs = new_fs();
+                fs.inject(step, fault);
+                let result = run(&fs, config());
+                fs.clear_faults();
+                let context = format!("with {:?} at step {}", fault, step);
+                assert_bytes_intact(&fs, &context);
+                // Only a complete file is ever put at the original's path
+                if atomic {
+                    if let Some(original) = fs.contents(ORIGINAL) {
+                        assert_eq!(original, test_bytes(), "{}", context);
+                    }
+                }
+                if fault == Fault::ShortWrite {
+                    assert!(result.is_ok(), "Failed {}", context);
+                }
+            })
+        });
+    }
+
+    #[test]
+    fn faults_never_lose_bytes() {
+        assert_survives_faults(false, false);
+    }
+
+    #[test]
+    fn faults_never_lose_bytes_retaining() {
+        assert_survives_faults(true, false);
+    }
+
+    #[test]
+    fn faults_never_lose_bytes_atomic() {
+        assert_survives_faults(false, true);
+        assert_survives_faults(true, true);
+    }
+
+    #[test]
+    fn faults_never_lose_bytes_pipelined() {
+        [false, true].into_iter().for_each(|retain| {
+            assert_survives_faults_with(|| RunConfig {
+                pipeline: true,
+                ..config(retain, false)
+            })
+        });
+    }
+
+    #[test]
+    fn faults_never_lose_bytes_direct() {
+        [false, true].into_iter().for_each(|retain| {
+            assert_survives_faults_with(|| RunConfig {
+                direct: true,
+                ..config(retain, false)
+            })
+        });
+    }
+
+    // Parts on another filesystem than the original can't be renamed into
+    // place, so are all copied
+    #[cfg(unix)]
+    struct OtherDevice(MemoryFilesystem);
+
+    #[cfg(unix)]
+    impl Filesystem for OtherDevice {
+        type File = <MemoryFilesystem as Filesystem>::File;
+
+        fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Self::File> {
+            self.0.open(path, mode)
+        }
+
+        fn rename(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+            Err(io::Error::from_raw_os_error(libc::EXDEV))
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            self.0.remove_file(path)
+        }
+
+        fn file_size(&self, path: &Path) -> io::Result<u64> {
+            self.0.file_size(path)
+        }
+
+        fn sync_directory(&self, path: &Path) -> io::Result<()> {
+            self.0.sync_directory(path)
+        }
+
+        fn sufficient_disk_space(
+            &self,
+            directory: &Path,
+            space_needed: u64,
+        ) -> std::result::Result<bool, &'static str> {
+            self.0.sufficient_disk_space(directory, space_needed)
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copies_parts_across_filesystems() {
+        let fs = new_fs();
+        stick(&OtherDevice(fs.clone()), config(false, false)).unwrap();
+        let total_steps = fs.steps();
+        assert_eq!(fs.contents(ORIGINAL), Some(test_bytes()));
+        assert!(!fs.exists(&part_path(1)));
+        assert!(!fs.exists(&Manifest::path_for(ORIGINAL)));
+
+        Fault::ALL.iter().for_each(|&fault| {
+            (0..total_steps).for_each(|step| {
+                let fs = new_fs();
+                fs.inject(step, fault);
+                let _ = stick(&OtherDevice(fs.clone()), config(false, false));
+                fs.clear_faults();
+                let context = format!("with {:?} at step {}", fault, step);
+                assert_bytes_intact(&fs, &context);
+            })
+        });
+    }
+
+    // Shows the power cut tests above rely on syncing
+    #[test]
+    fn power_cut_loses_bytes_unless_fully_durable() {
+        [Durability::None, Durability::Data].into_iter().for_each(
+            |durability| {
+                let lost = (0..100).any(|step| {
+                    let fs = new_fs();
+                    fs.inject(step, Fault::PowerCut);
+                    let _ = stick(
+                        &fs,
+                        RunConfig {
+                            durability,
+                            ..config(false, false)
+                        },
+                    );
+                    fs.clear_faults();
+                    lost_bytes(&fs).is_some()
+                });
+                assert!(lost, "Nothing lost with {:?}", durability);
+            },
+        );
+    }
+
+    #[test]
+    fn insufficient_space() {
+        let bytes = test_bytes();
+        let fs = MemoryFilesystem::with_capacity(bytes.len() as u64 + 10);
+        bytes
+            .chunks(PART_SIZE)
+            .enumerate()
+            .for_each(|(index, part)| fs.insert(part_path(index + 1), part));
+        let err = stick(&fs, config(true, false)).unwrap_err();
+        assert!(matches!(err, InsufficientDiskSpace));
+        assert!(fs.contents(ORIGINAL).is_none());
+    }
+
+    // A filesystem holding just a tar of the parts, as chop --archive makes
+    fn new_archive_fs() -> (MemoryFilesystem, PathBuf) {
+        let parts = new_fs();
+        let archive_path = ArchiveFormat::Tar.path_for(ORIGINAL);
+        let mut archive =
+            ArchiveWriter::new(ArchiveFormat::Tar, io::Cursor::new(vec![]));
+        let manifest_path = Manifest::path_for(ORIGINAL);
+        let names =
+            iter::once(manifest_path).chain((1..=NUM_PARTS).map(part_path));
+        names.for_each(|name| {
+            let contents = parts.contents(&name).unwrap();
+            archive
+                .add_entry(
+                    &name.to_string_lossy(),
+                    contents.len() as u64,
+                    &contents[..],
+                )
+                .unwrap();
+        });
+        let fs = MemoryFilesystem::new();
+        fs.insert(&archive_path, &archive.finish().unwrap().into_inner());
+        (fs, archive_path)
+    }
+
+    #[test]
+    fn faults_leave_nothing_stuck_from_archive() {
+        [false, true].into_iter().for_each(|atomic| {
+            let config = || {
+                let (_, archive_path) = new_archive_fs();
+                RunConfig {
+                    from_archive: Some(archive_path),
+                    ..config(false, atomic)
+                }
+            };
+            let (fs, _) = new_archive_fs();
+            run(&fs, config()).expect("Run without faults failed");
+            assert_eq!(fs.contents(ORIGINAL), Some(test_bytes()));
+            let total_steps = fs.steps();
+
+            [Fault::NoSpace, Fault::Io].iter().for_each(|&fault| {
+                (0..total_steps).for_each(|step| {
+                    let (fs, _) = new_archive_fs();
+                    fs.inject(step, fault);
+                    // Failing to delete the archive comes after the original
+                    // is safely made
+                    match run(&fs, config()) {
+                        Ok(()) | Err(DeleteArchive(..)) => return,
+                        Err(_) => {}
+                    }
+                    fs.clear_faults();
+                    let context = format!("with {:?} at step {}", fault, step);
+                    assert!(!fs.exists(Path::new(ORIGINAL)), "{}", context);
+                    let temp_path = temp_path_for(Path::new(ORIGINAL));
+                    assert!(!fs.exists(&temp_path), "{}", context);
+                    // So running again just works
+                    run(&fs, config()).expect(&context);
+                    assert_eq!(fs.contents(ORIGINAL), Some(test_bytes()));
+                })
+            });
+        });
+    }
 
     #[test]
     fn required_size_calculation() {
@@ -192,6 +690,6 @@ mod test {
         let file_three = temp_dir.child("three");
         file_three.write_binary(&[4, 120, 54]).unwrap();
         let paths = vec![file_one.path(), file_two.path(), file_three.path()];
-        assert_eq!(total_part_size(&paths).unwrap(), 13);
+        assert_eq!(total_part_size(&RealFilesystem, &paths).unwrap(), 13);
     }
 }
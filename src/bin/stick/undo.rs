@@ -0,0 +1,245 @@
+use crate::args::RunConfig;
+use crate::Result;
+use crate::StickError::*;
+use chopstick::filesystem::{FileHandle, Filesystem, OpenMode, RealFilesystem};
+use chopstick::journal::{
+    absolute_path, Entry, FileRecord, Journal, JournalError, Operation,
+};
+use chopstick::manifest::Manifest;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+// What's needed to record a stick once it's finished, taken beforehand as the
+// parts are used up
+pub struct PendingStick {
+    original: PathBuf,
+    retained: bool,
+    // Empty when sticking from an archive, which can't be undone
+    parts: Vec<PathBuf>,
+    part_sizes: io::Result<Vec<u64>>,
+    manifest: Option<PathBuf>,
+    // What the manifest says, read before the stick deletes it
+    manifest_contents: Option<Manifest>,
+}
+
+impl PendingStick {
+    pub fn new(config: &RunConfig) -> Self {
+        let parts = match config.from_archive {
+            Some(_) => Vec::new(),
+            None => config.part_paths.clone(),
+        };
+        PendingStick {
+            original: config.original_file.clone(),
+            retained: config.retain,
+            part_sizes: parts
+                .iter()
+                .map(|part| fs::metadata(part).map(|md| md.len()))
+                .collect(),
+            parts,
+            manifest: config.manifest.clone(),
+            manifest_contents: config
+                .manifest
+                .as_ref()
+                .and_then(|path| Manifest::read(&RealFilesystem, path).ok()),
+        }
+    }
+
+    // Records what the stick left behind, so that it can be undone. Failing
+    // to only means it can't be, so the stick itself still succeeds
+    pub fn record(self) {
+        if let Err(why) = self.try_record() {
+            eprintln!(
+                "WARNING: couldn't record the stick, so it can't be undone \
+                ({})",
+                why
+            );
+        }
+    }
+
+    // Sticks from archives just forget the last run
+    fn try_record(self) -> std::result::Result<(), Box<dyn Error>> {
+        let original = absolute_path(&self.original)?;
+        let entry = match self.parts.is_empty() {
+            true => None,
+            false => Some(self.entry(&original)?),
+        };
+        Journal::update(|journal| match entry {
+            Some(entry) => journal.record(entry),
+            None => {
+                journal.forget(&original);
+            }
+        })?;
+        Ok(())
+    }
+
+    fn entry(self, original: &Path) -> io::Result<Entry> {
+        let retained = self.retained;
+        let record = |path: &Path, size| match retained {
+            true => FileRecord::present(absolute_path(path)?),
+            false => Ok(FileRecord::absent(absolute_path(path)?, size)),
+        };
+        Ok(Entry {
+            operation: Operation::Stick,
+            retained,
+            original: FileRecord::present(original)?,
+            parts: self
+                .parts
+                .iter()
+                .zip(self.part_sizes?)
+                .map(|(part, size)| record(part, size))
+                .collect::<io::Result<_>>()?,
+            extras: self
+                .manifest
+                .iter()
+                .map(|manifest| record(manifest, 0))
+                .collect::<io::Result<_>>()?,
+            manifest: self.manifest_contents,
+        })
+    }
+}
+
+// Chops the file from the last stick back into the parts it was made from,
+// writing its manifest again if there was one, or if the parts were retained,
+// deletes it. Nothing's touched if the file or parts have changed since
+pub fn undo_stick<F: Filesystem>(fs: &F, config: &RunConfig) -> Result<()> {
+    if let (Some(lock), true) = (&config.lock, config.verbose) {
+        eprintln!("Locked {}", lock.path().to_string_lossy());
+    }
+    if !config.journal {
+        return Err(JournalError::Disabled.into());
+    }
+    let original = absolute_path(&config.original_file).map_err(BadParent)?;
+    let journal = Journal::load()?;
+    let entry = journal.undoable(Operation::Stick, &original)?;
+    if config.verbose {
+        eprintln!(
+            "Nothing has changed since the stick of {}",
+            original.to_string_lossy()
+        );
+    }
+
+    if !entry.retained {
+        chop_original(fs, config, entry)?;
+        entry.extras.iter().try_for_each(|manifest| -> Result<()> {
+            if !config.dry_run {
+                // Written again as it was, unless the journal didn't have it,
+                // when only the part sizes are known
+                let contents = entry.manifest.clone().unwrap_or_else(|| {
+                    let sizes = entry.parts.iter().map(|part| part.size);
+                    let ranges = sizes
+                        .scan(0, |start, size| {
+                            *start += size;
+                            Some(*start - size..*start)
+                        })
+                        .collect::<Vec<_>>();
+                    Manifest::from_ranges(&ranges)
+                });
+                contents
+                    .write(fs, &manifest.path)
+                    .map_err(|why| Undo(manifest.path.clone(), why))?;
+            }
+            if config.verbose {
+                eprintln!("Wrote manifest {}", manifest.path.to_string_lossy());
+            }
+            Ok(())
+        })?;
+    } else {
+        if !config.dry_run {
+            fs.remove_file(&original)
+                .map_err(|why| Undo(original.clone(), why))?;
+        }
+        if config.verbose {
+            eprintln!("Deleted {}", original.to_string_lossy());
+        }
+    }
+
+    if !config.dry_run {
+        Journal::update(|journal| {
+            journal.forget(&original);
+        })?;
+    }
+    if config.verbose && !config.dry_run {
+        eprintln!("\nFinished without error!");
+    }
+    Ok(())
+}
+
+// As chop would: parts are made from the end backwards, truncating the file
+// after each is safely on disk, until what's left of it is the first part
+fn chop_original<F: Filesystem>(
+    fs: &F,
+    config: &RunConfig,
+    entry: &Entry,
+) -> Result<()> {
+    let original = &entry.original.path;
+    let undo_error = |why| Undo(original.clone(), why);
+    let largest = entry.parts.iter().map(|part| part.size).max();
+    let folder = original.parent().unwrap_or_else(|| Path::new("."));
+    match fs.sufficient_disk_space(folder, largest.unwrap_or(0)) {
+        Ok(true) => {}
+        Ok(false) => return Err(InsufficientDiskSpace),
+        Err(warn) => eprintln!("WARNING: {warn}"),
+    }
+
+    let mut file = match config.dry_run {
+        false => {
+            Some(fs.open(original, OpenMode::ReadWrite).map_err(undo_error)?)
+        }
+        true => None,
+    };
+    let (first, rest) =
+        entry.parts.split_first().expect("Sticks always use parts");
+    let mut end = entry.original.size;
+    rest.iter().rev().try_for_each(|part| -> Result<()> {
+        let start = end - part.size;
+        if let Some(file) = &mut file {
+            let part_error = |why| Undo(part.path.clone(), why);
+            let mut part_file = fs
+                .open(&part.path, OpenMode::CreateNew)
+                .map_err(part_error)?;
+            file.seek(SeekFrom::Start(start)).map_err(undo_error)?;
+            let copied =
+                io::copy(&mut file.by_ref().take(part.size), &mut part_file)
+                    .map_err(part_error)?;
+            if copied != part.size {
+                return Err(part_error(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "The file ended part way through the part",
+                )));
+            }
+            part_file.sync_all().map_err(part_error)?;
+            let part_folder =
+                part.path.parent().unwrap_or_else(|| Path::new("."));
+            fs.sync_directory(part_folder).map_err(part_error)?;
+            file.set_len(start).map_err(undo_error)?;
+        }
+        if config.verbose {
+            eprintln!(
+                "Moved {} to {}",
+                bytesize::to_string(part.size, true),
+                part.path.to_string_lossy(),
+            );
+        }
+        end = start;
+        Ok(())
+    })?;
+
+    if let Some(mut file) = file {
+        file.sync_all().map_err(undo_error)?;
+        drop(file);
+        fs.rename(original, &first.path).map_err(undo_error)?;
+        let first_folder =
+            first.path.parent().unwrap_or_else(|| Path::new("."));
+        fs.sync_directory(first_folder).map_err(undo_error)?;
+    }
+    if config.verbose {
+        eprintln!(
+            "Renamed {} to {}",
+            original.to_string_lossy(),
+            first.path.to_string_lossy(),
+        );
+    }
+    Ok(())
+}
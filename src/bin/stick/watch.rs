@@ -0,0 +1,349 @@
+use crate::args::{
+    check_against_manifest, discover_parts, part_number,
+    RemoveChopstickExtension, RunConfig, WatchConfig,
+};
+use crate::StickError::*;
+use crate::{run, Result};
+use chopstick::cli::shell_command;
+use chopstick::filesystem::RealFilesystem;
+use chopstick::lock::SetLock;
+use chopstick::manifest::{Manifest, MANIFEST_EXTENSION};
+use chopstick::watch::DirWatcher;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+// How long to wait when nothing's due, before looking at the folder again
+// anyway, in case a change was missed
+const RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+// The name, size, and modification time of a file, to tell when it changes
+type FileState = (OsString, u64, Option<SystemTime>);
+
+// What's known about one set of parts, named after the file they make up
+struct PartSet {
+    files: Vec<FileState>,
+    changed: Instant,
+    // Stuck together, or failed to be. Either way, it's left alone until it
+    // changes
+    handled: bool,
+}
+
+// Whether a set can be stuck together yet
+#[derive(Debug, Eq, PartialEq)]
+enum Readiness {
+    Ready,
+    // Complete, but could still be being written to
+    QuietIn(Duration),
+    Waiting,
+}
+
+// Sticks together each set of parts that turns up in the folder, forever
+pub fn watch(template: RunConfig) -> Result<()> {
+    let watch = template.watch.as_ref().expect("Only called when watching");
+    let mut watcher = DirWatcher::new(&watch.dir)
+        .map_err(|why| Watch(watch.dir.clone(), why))?;
+    if template.verbose {
+        eprintln!("Watching {}", watch.dir.to_string_lossy());
+    }
+    let mut sets = HashMap::<OsString, PartSet>::new();
+    loop {
+        let now = Instant::now();
+        let found =
+            scan(&watch.dir).map_err(|why| Watch(watch.dir.clone(), why))?;
+        sets.retain(|stem, _| found.contains_key(stem));
+        let mut next_check = RESCAN_INTERVAL;
+        for (stem, files) in found {
+            let set = sets.entry(stem.clone()).or_insert_with(|| PartSet {
+                files: Vec::new(),
+                changed: now,
+                handled: false,
+            });
+            if set.files != files {
+                set.files = files;
+                set.changed = now;
+                set.handled = false;
+            }
+            if set.handled {
+                continue;
+            }
+            let original = watch.dir.join(&stem);
+            match readiness(&original, now - set.changed, watch.quiet_period) {
+                Readiness::Ready => {
+                    set.handled = true;
+                    if let Err(why) = stick_set(&template, watch, original) {
+                        eprintln!("{}", why);
+                    }
+                }
+                Readiness::QuietIn(wait) => next_check = next_check.min(wait),
+                Readiness::Waiting => {}
+            }
+        }
+        watcher
+            .wait(next_check)
+            .map_err(|why| Watch(watch.dir.clone(), why))?;
+    }
+}
+
+// Groups the parts (and manifests) in the folder by the file they make up
+fn scan(dir: &Path) -> io::Result<HashMap<OsString, Vec<FileState>>> {
+    let manifest_extension = format!(".{}", MANIFEST_EXTENSION);
+    let mut found = HashMap::<_, Vec<_>>::new();
+    let mut manifests = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let metadata = match entry.metadata() {
+            Ok(metadata) if metadata.is_file() => metadata,
+            // Gone already, or not a file
+            _ => continue,
+        };
+        let file = (name.clone(), metadata.len(), metadata.modified().ok());
+        if part_number(Path::new(&name)).is_some() {
+            found
+                .entry(name.remove_chopstick_extension())
+                .or_default()
+                .push(file);
+        } else if let Some(stem) = name
+            .to_str()
+            .and_then(|n| n.strip_suffix(&manifest_extension))
+        {
+            manifests.push((OsString::from(stem), file));
+        }
+    }
+    // A manifest on its own isn't a set
+    manifests.into_iter().for_each(|(stem, file)| {
+        if let Some(files) = found.get_mut(&stem) {
+            files.push(file);
+        }
+    });
+    found.values_mut().for_each(|files| files.sort());
+    Ok(found)
+}
+
+fn readiness(
+    original: &Path,
+    quiet_for: Duration,
+    quiet_period: Duration,
+) -> Readiness {
+    let part_paths = match discover_parts(original) {
+        Ok(part_paths) => part_paths,
+        Err(_) => return Readiness::Waiting,
+    };
+    let manifest_path = Manifest::path_for(original);
+    if manifest_path.is_file() {
+        // Parts are only all there once they all match the manifest
+        let sizes = part_sizes(&part_paths);
+        let matches = Manifest::read(&RealFilesystem, &manifest_path)
+            .ok()
+            .zip(sizes.ok())
+            .map(|(manifest, sizes)| {
+                check_against_manifest(&manifest, &part_paths, &sizes).is_ok()
+            });
+        if matches != Some(true) {
+            return Readiness::Waiting;
+        }
+    }
+    // Even a part of the right size may not be finished, e.g. if it was
+    // made at its full size before being written to
+    if quiet_for >= quiet_period {
+        Readiness::Ready
+    } else {
+        Readiness::QuietIn(quiet_period - quiet_for)
+    }
+}
+
+fn part_sizes(part_paths: &[PathBuf]) -> io::Result<Vec<u64>> {
+    part_paths
+        .iter()
+        .map(|path| fs::metadata(path).map(|md| md.len()))
+        .collect()
+}
+
+// Checks the set against its manifest (hashes and all), sticks it together as
+// any other run would, then runs the --then command
+fn stick_set(
+    template: &RunConfig,
+    watch: &WatchConfig,
+    original_file: PathBuf,
+) -> Result<()> {
+    if template.verbose {
+        eprintln!("\nSticking {}", original_file.to_string_lossy());
+    }
+    let lock = match template.dry_run {
+        false => Some(SetLock::acquire(&original_file, false)?),
+        true => None,
+    };
+    let part_paths = discover_parts(&original_file)?;
+    let part_sizes = part_paths
+        .iter()
+        .map(|path| {
+            fs::metadata(path)
+                .map(|md| md.len())
+                .map_err(|err| ReadPart(path.clone(), err))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let manifest_path = Manifest::path_for(&original_file);
+    let manifest = match manifest_path.is_file() {
+        true => {
+            let manifest = Manifest::read(&RealFilesystem, &manifest_path)
+                .map_err(|err| ReadManifest(manifest_path.clone(), err))?;
+            check_against_manifest(&manifest, &part_paths, &part_sizes)?;
+            if let Some(hashes) = &manifest.part_hashes {
+                check_hashes(&part_paths, hashes)?;
+                if template.verbose {
+                    eprintln!("Parts match the hashes in the manifest");
+                }
+            }
+            Some(manifest_path)
+        }
+        false => None,
+    };
+
+    run(RunConfig {
+        original_file: original_file.clone(),
+        part_paths,
+        part_size: part_sizes.iter().copied().max().unwrap_or(0),
+        manifest,
+        retain: template.retain,
+        verbose: template.verbose,
+        dry_run: template.dry_run,
+        atomic: template.atomic,
+        durability: template.durability,
+        rate_limit: template.rate_limit,
+        // Already set for the whole process
+        io_class: None,
+        pipeline: template.pipeline,
+        direct: template.direct,
+        drop_cache: template.drop_cache,
+        journal: template.journal,
+        lock,
+        from_archive: None,
+        watch: None,
+        undo: false,
+    })?;
+
+    if let (Some(command), false) = (&watch.then, template.dry_run) {
+        match shell_command(command).env("FILE", &original_file).status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                eprintln!("WARNING: --then command failed ({status})")
+            }
+            Err(why) => {
+                eprintln!("WARNING: couldn't run --then command ({why})")
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_hashes(part_paths: &[PathBuf], hashes: &[String]) -> Result<()> {
+    part_paths
+        .iter()
+        .zip(hashes)
+        .try_for_each(|(path, expected)| {
+            let mut hasher = Sha256::new();
+            File::open(path)
+                .and_then(|mut part| io::copy(&mut part, &mut hasher))
+                .map_err(|err| ReadPart(path.clone(), err))?;
+            match format!("{:x}", hasher.finalize()) == *expected {
+                true => Ok(()),
+                false => Err(CorruptPart(path.clone())),
+            }
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+
+    const QUIET_PERIOD: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn ready_without_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let original = temp_dir.child("foo");
+        temp_dir.child("foo.p1").write_binary(b"abc").unwrap();
+        assert_eq!(
+            readiness(&original, QUIET_PERIOD, QUIET_PERIOD),
+            Readiness::Waiting,
+            "One part isn't a set"
+        );
+        temp_dir.child("foo.p3").write_binary(b"ghi").unwrap();
+        assert_eq!(
+            readiness(&original, QUIET_PERIOD, QUIET_PERIOD),
+            Readiness::Waiting,
+            "Missing part 2"
+        );
+        temp_dir.child("foo.p2").write_binary(b"def").unwrap();
+        assert_eq!(
+            readiness(&original, Duration::from_secs(10), QUIET_PERIOD),
+            Readiness::QuietIn(Duration::from_secs(20))
+        );
+        assert_eq!(
+            readiness(&original, QUIET_PERIOD, QUIET_PERIOD),
+            Readiness::Ready
+        );
+    }
+
+    #[test]
+    fn ready_with_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let original = temp_dir.child("foo");
+        temp_dir
+            .child("foo.manifest")
+            .write_str("chopstick manifest 1\nsize 6\npart 1 3\npart 2 3\n")
+            .unwrap();
+        temp_dir.child("foo.p1").write_binary(b"abc").unwrap();
+        temp_dir.child("foo.p2").write_binary(b"de").unwrap();
+        // Never ready while a part's the wrong size, no matter how long
+        assert_eq!(
+            readiness(&original, QUIET_PERIOD, QUIET_PERIOD),
+            Readiness::Waiting
+        );
+        temp_dir.child("foo.p2").write_binary(b"def").unwrap();
+        assert_eq!(
+            readiness(&original, QUIET_PERIOD, QUIET_PERIOD),
+            Readiness::Ready
+        );
+    }
+
+    #[test]
+    fn full_size_part_still_being_written() {
+        let temp_dir = TempDir::new().unwrap();
+        let original = temp_dir.child("foo");
+        temp_dir
+            .child("foo.manifest")
+            .write_str("chopstick manifest 1\nsize 6\npart 1 3\npart 2 3\n")
+            .unwrap();
+        temp_dir.child("foo.p1").write_binary(b"abc").unwrap();
+        // Made at its full size, but nothing written to it yet
+        File::create(temp_dir.child("foo.p2"))
+            .and_then(|file| file.set_len(3))
+            .unwrap();
+        assert_eq!(
+            readiness(&original, Duration::from_secs(10), QUIET_PERIOD),
+            Readiness::QuietIn(Duration::from_secs(20))
+        );
+    }
+
+    #[test]
+    fn hashes() {
+        let temp_dir = TempDir::new().unwrap();
+        let part = temp_dir.child("foo.p1");
+        part.write_binary(b"abc").unwrap();
+        // SHA-256 of "abc"
+        let hash = "ba7816bf8f01cfea414140de5dae2223\
+            b00361a396177a9cb410ff61f20015ad";
+        let parts = [part.to_path_buf()];
+        assert!(check_hashes(&parts, &[hash.to_owned()]).is_ok());
+        let wrong = check_hashes(&parts, &["0".repeat(64)]);
+        assert!(matches!(wrong, Err(CorruptPart(_))));
+    }
+}
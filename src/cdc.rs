@@ -0,0 +1,208 @@
+use std::io;
+use std::io::Read;
+use std::ops::Range;
+
+const SCAN_BUFFER_SIZE: usize = 64 * 1024; // 64 KiB
+
+// Table of pseudo-random values for the gear hash, generated with splitmix64
+// from a fixed seed so that cut points are stable between versions
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6368_6f70_7374_6963; // "chopstic"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Picks part boundaries from the content of a file (FastCDC), so that an
+/// insertion or deletion only changes the parts near to it
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ContentDefinedChunker {
+    pub min_size: u64,
+    pub avg_size: u64,
+    pub max_size: u64,
+}
+
+impl ContentDefinedChunker {
+    /// Uses the usual FastCDC bounds of a quarter and four times the average
+    pub const fn new(avg_size: u64) -> Self {
+        ContentDefinedChunker {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size.saturating_mul(4),
+        }
+    }
+
+    pub const fn with_bounds(
+        min_size: u64,
+        avg_size: u64,
+        max_size: u64,
+    ) -> Self {
+        ContentDefinedChunker {
+            min_size,
+            avg_size,
+            max_size,
+        }
+    }
+
+    pub const fn is_valid(&self) -> bool {
+        self.avg_size > 0
+            && self.min_size <= self.avg_size
+            && self.avg_size <= self.max_size
+    }
+
+    /// Scans all of `reader`, returning the byte range of each part in order
+    pub fn part_ranges<R: Read>(
+        &self,
+        mut reader: R,
+    ) -> io::Result<Vec<Range<u64>>> {
+        debug_assert!(self.is_valid(), "invalid content-defined chunk bounds");
+        // Normalised chunking: before reaching the average size a cut point
+        // is harder to find, after it it's easier. This keeps part sizes
+        // clustered around the average
+        let bits = 63 - self.avg_size.leading_zeros();
+        let mask_small = top_bits_mask(bits + 1);
+        let mask_large = top_bits_mask(bits.saturating_sub(1));
+
+        let mut ranges = Vec::new();
+        let mut buffer = vec![0; SCAN_BUFFER_SIZE];
+        let mut part_start = 0;
+        let mut position = 0;
+        let mut hash = 0u64;
+        loop {
+            let bytes_read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(why) if why.kind() == io::ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(why) => return Err(why),
+            };
+            for &byte in &buffer[..bytes_read] {
+                position += 1;
+                let part_len = position - part_start;
+                // Nothing within the minimum size can be a cut point, so
+                // don't bother hashing it
+                if part_len < self.min_size {
+                    continue;
+                }
+                hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+                let mask = if part_len < self.avg_size {
+                    mask_small
+                } else {
+                    mask_large
+                };
+                if hash & mask == 0 || part_len >= self.max_size {
+                    ranges.push(part_start..position);
+                    part_start = position;
+                    hash = 0;
+                }
+            }
+        }
+        if part_start < position {
+            ranges.push(part_start..position);
+        }
+        Ok(ranges)
+    }
+}
+
+// The gear hash shifts left, so the highest bits depend on the most bytes
+const fn top_bits_mask(bits: u32) -> u64 {
+    if bits == 0 {
+        0
+    } else if bits >= 64 {
+        u64::MAX
+    } else {
+        !(u64::MAX >> bits)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use rand::prelude::*;
+    use rand_pcg::Pcg64;
+
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        Pcg64::seed_from_u64(seed).fill_bytes(&mut bytes);
+        bytes
+    }
+
+    fn assert_contiguous(ranges: &[Range<u64>], len: u64) {
+        assert_eq!(ranges.first().map(|r| r.start), Some(0));
+        assert_eq!(ranges.last().map(|r| r.end), Some(len));
+        ranges
+            .windows(2)
+            .for_each(|pair| assert_eq!(pair[0].end, pair[1].start));
+    }
+
+    #[test]
+    fn respects_bounds() {
+        let bytes = random_bytes(1024 * 1024, 1);
+        let chunker = ContentDefinedChunker::new(8 * 1024);
+        let ranges = chunker.part_ranges(bytes.as_slice()).unwrap();
+        assert_contiguous(&ranges, bytes.len() as u64);
+        let (last, rest) = ranges.split_last().unwrap();
+        rest.iter().for_each(|range| {
+            let len = range.end - range.start;
+            assert!(len >= chunker.min_size, "part too small: {}", len);
+            assert!(len <= chunker.max_size, "part too large: {}", len);
+        });
+        assert!(last.end - last.start <= chunker.max_size);
+        // Rough sanity check that the average is being aimed for
+        let avg = bytes.len() as u64 / ranges.len() as u64;
+        assert!((4 * 1024..=16 * 1024).contains(&avg), "average {}", avg);
+    }
+
+    #[test]
+    fn insertion_only_changes_nearby_parts() {
+        let original = random_bytes(512 * 1024, 2);
+        let mut modified = original.clone();
+        modified.splice(200_000..200_000, random_bytes(100, 3));
+        let chunker = ContentDefinedChunker::new(4 * 1024);
+
+        let part_bytes = |bytes: &[u8]| {
+            chunker
+                .part_ranges(bytes)
+                .unwrap()
+                .into_iter()
+                .map(|r| bytes[r.start as usize..r.end as usize].to_vec())
+                .collect::<Vec<_>>()
+        };
+        let before = part_bytes(&original);
+        let after = part_bytes(&modified);
+        let unchanged = after.iter().filter(|p| before.contains(p)).count();
+        // Only a couple of parts either side of the insertion should differ
+        assert!(
+            after.len() - unchanged <= 3,
+            "{} of {} parts changed",
+            after.len() - unchanged,
+            after.len(),
+        );
+    }
+
+    #[test]
+    fn small_input() {
+        let chunker = ContentDefinedChunker::new(1024);
+        let ranges = chunker.part_ranges(&[1u8, 2, 3][..]).unwrap();
+        assert_eq!(ranges, vec![0..3]);
+        let ranges = chunker.part_ranges(&[][..]).unwrap();
+        assert!(ranges.is_empty());
+    }
+
+    #[test]
+    fn validity() {
+        assert!(ContentDefinedChunker::new(1024).is_valid());
+        assert!(!ContentDefinedChunker::new(0).is_valid());
+        assert!(!ContentDefinedChunker::with_bounds(10, 5, 20).is_valid());
+        assert!(!ContentDefinedChunker::with_bounds(1, 50, 20).is_valid());
+    }
+}
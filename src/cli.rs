@@ -0,0 +1,258 @@
+use clap::{Arg, ArgMatches, Command};
+use clap_complete::Shell;
+use std::process;
+
+pub const SHELLS: [&str; 4] = ["bash", "zsh", "fish", "elvish"];
+
+/// Hidden options for packagers, shared by chop and stick
+pub fn generate_args() -> [Arg<'static>; 2] {
+    [
+        Arg::new("generate-completions")
+            .long("generate-completions")
+            .help("Print a completion script for the given shell")
+            .value_name("SHELL")
+            .possible_values(SHELLS)
+            .takes_value(true)
+            .hide(true),
+        Arg::new("generate-man")
+            .long("generate-man")
+            .help("Print a man page")
+            .hide(true),
+    ]
+}
+
+/// Turning the journal (which `--undo` uses) off or on, shared by chop and
+/// stick
+pub fn journal_args() -> [Arg<'static>; 2] {
+    [
+        Arg::new("no-journal")
+            .long("no-journal")
+            .help("Don't record the run, so that it can't be undone [default]")
+            .overrides_with("journal"),
+        Arg::new("journal")
+            .long("journal")
+            .help("Record the run, so that it can be undone")
+            .long_help(
+                "Record the run in the journal \
+                ($XDG_STATE_HOME/chopstick/journal), so that it can be \
+                undone. Can also be set with CHOPSTICK_JOURNAL=1, or \
+                journal = true in the config file",
+            )
+            .overrides_with("no-journal"),
+    ]
+}
+
+/// A hidden `--no-<name>` flag, which turns off a default from the config
+/// file or environment, given as `negated`
+pub fn negation(name: &'static str, negated: &'static str) -> Arg<'static> {
+    Arg::new(negated)
+        .long(negated)
+        .help("Ignore a default from the config file or environment")
+        .overrides_with(name)
+        .hide(true)
+}
+
+/// Whether `--<name>` or `--no-<name>` was given, whichever came last, or None
+/// for neither
+pub fn flag(matches: &ArgMatches, name: &str) -> Option<bool> {
+    if matches.is_present(format!("no-{}", name)) {
+        Some(false)
+    } else if matches.is_present(name) {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+// Both tools' commands are named after the package, so the name of the binary
+// is given separately
+pub fn completions(shell: &str, cmd: &mut Command, bin_name: &str) -> String {
+    let shell: Shell = shell.parse().expect("Shell should be one of SHELLS");
+    let mut script = Vec::new();
+    clap_complete::generate(shell, cmd, bin_name, &mut script);
+    String::from_utf8(script).expect("Completion scripts should be UTF-8")
+}
+
+/// A man page for `cmd`, in roff
+pub fn man_page(cmd: &mut Command, bin_name: &str) -> String {
+    cmd.build();
+    let mut page = format!(
+        ".TH {} 1 \"\" \"{} {}\"\n",
+        bin_name.to_uppercase(),
+        cmd.get_name(),
+        cmd.get_version().unwrap_or_default(),
+    );
+
+    page.push_str(".SH NAME\n");
+    page.push_str(&format!(
+        "{} \\- {}\n",
+        bin_name,
+        escape(cmd.get_about().unwrap_or_default())
+    ));
+
+    page.push_str(".SH SYNOPSIS\n");
+    page.push_str(&format!("\\fB{}\\fR [\\fIOPTIONS\\fR]", bin_name));
+    cmd.get_positionals()
+        .filter(|arg| !arg.is_hide_set())
+        .for_each(|arg| {
+            let value = format!("\\fI{}\\fR", arg.get_id());
+            match arg.is_required_set() {
+                true => page.push_str(&format!(" <{}>", value)),
+                false => page.push_str(&format!(" [{}]", value)),
+            }
+        });
+    page.push('\n');
+
+    if let Some(about) = cmd.get_long_about() {
+        page.push_str(".SH DESCRIPTION\n");
+        page.push_str(&escape(about));
+        page.push('\n');
+    }
+
+    page.push_str(".SH OPTIONS\n");
+    cmd.get_arguments()
+        .filter(|arg| !arg.is_positional() && !arg.is_hide_set())
+        .for_each(|arg| {
+            let mut names = Vec::new();
+            if let Some(short) = arg.get_short() {
+                names.push(format!("\\fB\\-{}\\fR", short));
+            }
+            if let Some(long) = arg.get_long() {
+                names.push(format!("\\fB\\-\\-{}\\fR", escape(long)));
+            }
+            let mut item = names.join(", ");
+            if arg.is_takes_value_set() {
+                let value_name = arg
+                    .get_value_names()
+                    .and_then(|names| names.first().copied())
+                    .unwrap_or_else(|| arg.get_id());
+                item.push_str(&format!(" \\fI<{}>\\fR", value_name));
+            }
+            page.push_str(&format!(".TP\n{}\n", item));
+            page.push_str(&describe(arg));
+        });
+
+    let positionals = cmd
+        .get_positionals()
+        .filter(|arg| !arg.is_hide_set())
+        .collect::<Vec<_>>();
+    if !positionals.is_empty() {
+        page.push_str(".SH ARGUMENTS\n");
+        positionals.into_iter().for_each(|arg| {
+            page.push_str(&format!(".TP\n\\fI{}\\fR\n", arg.get_id()));
+            page.push_str(&describe(arg));
+        });
+    }
+
+    if let Some(author) = cmd.get_author() {
+        page.push_str(".SH AUTHOR\n");
+        page.push_str(&escape(author));
+        page.push('\n');
+    }
+    page
+}
+
+// The long help of `arg`, with its possible values
+fn describe(arg: &Arg) -> String {
+    let mut description = escape(
+        arg.get_long_help()
+            .or_else(|| arg.get_help())
+            .unwrap_or_default(),
+    );
+    if let Some(values) = arg.get_possible_values() {
+        let values = values
+            .iter()
+            .filter(|value| !value.is_hide_set())
+            .map(|value| value.get_name())
+            .collect::<Vec<_>>();
+        if !values.is_empty() {
+            description.push_str(&format!(
+                "\n.br\n[possible values: {}]",
+                escape(&values.join(", "))
+            ));
+        }
+    }
+    description.push('\n');
+    description
+}
+
+// Stops text being read as roff requests or escapes
+fn escape(text: &str) -> String {
+    let text = text.replace('\\', "\\\\").replace('-', "\\-");
+    text.lines()
+        .map(|line| match line.starts_with(&['.', '\''][..]) {
+            true => format!("\\&{}", line),
+            false => line.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Runs `command` in the platform's shell, as given to chop --filter or
+/// stick --then
+#[cfg(unix)]
+pub fn shell_command(command: &str) -> process::Command {
+    let mut shell = process::Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+/// Runs `command` in the platform's shell, as given to chop --filter or
+/// stick --then
+#[cfg(not(unix))]
+pub fn shell_command(command: &str) -> process::Command {
+    let mut shell = process::Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn command() -> Command<'static> {
+        Command::new("package")
+            .version("1.2.3")
+            .about("Does things - carefully")
+            .arg(
+                Arg::new("size")
+                    .short('s')
+                    .long("size")
+                    .help("The size")
+                    .takes_value(true),
+            )
+            .arg(Arg::new("quiet").long("quiet").help(".hidden from roff"))
+            .arg(
+                Arg::new("mode")
+                    .long("mode")
+                    .takes_value(true)
+                    .possible_values(["fast", "slow"]),
+            )
+            .arg(Arg::new("file").required(true))
+            .args(generate_args())
+    }
+
+    #[test]
+    fn man_page_contents() {
+        let page = man_page(&mut command(), "tool");
+        assert!(page.starts_with(".TH TOOL 1 \"\" \"package 1.2.3\"\n"));
+        assert!(page.contains("tool \\- Does things \\- carefully\n"));
+        assert!(page.contains("\\fBtool\\fR [\\fIOPTIONS\\fR] <\\fIfile\\fR>"));
+        assert!(page.contains(
+            ".TP\n\\fB\\-s\\fR, \\fB\\-\\-size\\fR \\fI<size>\\fR\nThe size\n"
+        ));
+        assert!(page.contains("\\&.hidden from roff"));
+        assert!(page.contains("[possible values: fast, slow]"));
+        assert!(page.contains("\\fB\\-\\-help\\fR"));
+        assert!(!page.contains("generate"));
+    }
+
+    #[test]
+    fn completions_for_every_shell() {
+        SHELLS.iter().for_each(|shell| {
+            let script = completions(shell, &mut command(), "tool");
+            assert!(script.contains("tool"), "{} script: {}", shell, script);
+            assert!(script.contains("size"), "{} script: {}", shell, script);
+        });
+    }
+}
@@ -0,0 +1,451 @@
+use bytesize::ByteSize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fmt, fs, io};
+use toml::value::{Table, Value};
+
+/// Settings shared by chop and stick, read from
+/// `$XDG_CONFIG_HOME/chopstick/config.toml`
+#[derive(Debug, Clone)]
+pub struct Config {
+    path: PathBuf,
+    table: Table,
+}
+
+impl Config {
+    /// Where the config file is expected to be, if there's anywhere it could
+    /// be at all
+    pub fn path() -> Option<PathBuf> {
+        let config_home = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ if cfg!(windows) => PathBuf::from(env::var_os("APPDATA")?),
+            _ => Path::new(&env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_home.join("chopstick").join("config.toml"))
+    }
+
+    /// Reads the config file, if there is one
+    pub fn load() -> Result<Option<Self>, ConfigError> {
+        match Config::path() {
+            Some(path) => Config::load_from(path),
+            None => Ok(None),
+        }
+    }
+
+    pub fn load_from(path: PathBuf) -> Result<Option<Self>, ConfigError> {
+        match fs::read_to_string(&path) {
+            Ok(contents) => Config::parse(path, &contents).map(Some),
+            Err(why) if why.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(why) => Err(ConfigError::Read(path, why)),
+        }
+    }
+
+    pub fn parse(path: PathBuf, contents: &str) -> Result<Self, ConfigError> {
+        match contents.parse::<Value>() {
+            Ok(Value::Table(table)) => Ok(Config { path, table }),
+            Ok(_) => unreachable!("TOML documents are always tables"),
+            Err(why) => Err(ConfigError::Parse(path, why)),
+        }
+    }
+
+    pub fn file_path(&self) -> &Path {
+        &self.path
+    }
+
+    // The table found by following `names` down from the top level
+    fn table(&self, names: &[&str]) -> Option<&Table> {
+        names
+            .iter()
+            .try_fold(&self.table, |table, name| table.get(*name)?.as_table())
+    }
+
+    /// A part size from the `[presets]` table, given either in bytes or with
+    /// units (e.g. `zip = "2GiB"`)
+    pub fn preset(&self, name: &str) -> Option<Result<u64, ConfigError>> {
+        let value = self
+            .table
+            .get("presets")?
+            .as_table()?
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))?
+            .1;
+        let invalid = || ConfigError::InvalidValue(format!("presets.{}", name));
+        Some(match value {
+            Value::Integer(bytes) => {
+                u64::try_from(*bytes).map_err(|_| invalid())
+            }
+            Value::String(size) => ByteSize::from_str(size)
+                .map(|ByteSize(bytes)| bytes)
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        })
+    }
+}
+
+/// Where the value of a setting came from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Source {
+    Default,
+    // The config file, and the table in it (empty for the top level)
+    File(PathBuf, String),
+    Environment(String),
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path, table) if table.is_empty() => {
+                write!(f, "{}", path.to_string_lossy())
+            }
+            Source::File(path, table) => {
+                write!(f, "{} [{}]", path.to_string_lossy(), table)
+            }
+            Source::Environment(var) => write!(f, "${}", var),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+/// Defaults for a tool's flags. From lowest to highest priority, these come
+/// from the top level of the config file, the tool's table (e.g. `[chop]`),
+/// the chosen profile's table (`[profiles.usb]`), the profile's table for the
+/// tool (`[profiles.usb.chop]`), then `CHOPSTICK_*` environment variables.
+/// Anything given on the command line takes priority over all of them
+#[derive(Debug, Clone)]
+pub struct Defaults {
+    tool: &'static str,
+    config: Option<Config>,
+    // The CHOPSTICK_* environment variables, by name
+    env: HashMap<String, String>,
+    pub profile: Option<Setting<String>>,
+}
+
+impl Defaults {
+    /// Reads the config file and `CHOPSTICK_*` environment variables, with
+    /// `profile` from the command line, if given
+    pub fn load(
+        tool: &'static str,
+        profile: Option<&str>,
+    ) -> Result<Self, ConfigError> {
+        let env = env::vars()
+            .filter(|(var, _)| var.starts_with("CHOPSTICK_"))
+            .collect();
+        Defaults::new(tool, Config::load()?, env, profile)
+    }
+
+    /// Defaults from the given config file (if any) and environment
+    /// variables, rather than the real ones
+    pub fn new(
+        tool: &'static str,
+        config: Option<Config>,
+        env: HashMap<String, String>,
+        profile: Option<&str>,
+    ) -> Result<Self, ConfigError> {
+        let profile = match profile {
+            Some(profile) => Some(Setting {
+                value: profile.to_owned(),
+                source: Source::CommandLine,
+            }),
+            None => env
+                .get(&env_var("profile"))
+                .filter(|profile| !profile.is_empty())
+                .map(|profile| Setting {
+                    value: profile.clone(),
+                    source: Source::Environment(env_var("profile")),
+                }),
+        };
+        if let Some(profile) = &profile {
+            let exists = config.as_ref().map_or(false, |config| {
+                config.table(&["profiles", &profile.value]).is_some()
+            });
+            if !exists {
+                return Err(ConfigError::NoSuchProfile(profile.value.clone()));
+            }
+        }
+        Ok(Defaults {
+            tool,
+            config,
+            env,
+            profile,
+        })
+    }
+
+    /// The effective settings and where each came from, for --print-config
+    pub fn describe(&self, settings: &[(&str, String, &Source)]) -> String {
+        let mut description = match &self.config {
+            Some(config) => {
+                format!("# config file: {}\n", config.path.to_string_lossy())
+            }
+            None => String::from("# no config file found\n"),
+        };
+        if let Some(profile) = &self.profile {
+            description.push_str(&format!(
+                "# profile: {} ({})\n",
+                profile.value, profile.source
+            ));
+        }
+        settings.iter().for_each(|(key, value, source)| {
+            description.push_str(&format!("{} = {} # {}\n", key, value, source))
+        });
+        description
+    }
+
+    /// A setting that's either on or off, given on the command line as
+    /// `--<key>` or `--no-<key>`
+    pub fn flag(
+        &self,
+        key: &str,
+        given: Option<bool>,
+        default: bool,
+    ) -> Result<Setting<bool>, ConfigError> {
+        if let Some(value) = given {
+            return Ok(Setting {
+                value,
+                source: Source::CommandLine,
+            });
+        }
+        if let Some(value) = self.env.get(&env_var(key)) {
+            return match value.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Ok(true),
+                "0" | "false" | "no" | "off" | "" => Ok(false),
+                _ => Err(ConfigError::InvalidValue(env_var(key))),
+            }
+            .map(|value| Setting {
+                value,
+                source: Source::Environment(env_var(key)),
+            });
+        }
+        match self.lookup(key) {
+            Some((Value::Boolean(value), source)) => Ok(Setting {
+                value: *value,
+                source,
+            }),
+            Some((_, source)) => Err(invalid_in(key, source)),
+            None => Ok(Setting {
+                value: default,
+                source: Source::Default,
+            }),
+        }
+    }
+
+    pub fn string(
+        &self,
+        key: &str,
+        given: Option<&str>,
+        default: &str,
+    ) -> Result<Setting<String>, ConfigError> {
+        if let Some(value) = given {
+            return Ok(Setting {
+                value: value.to_owned(),
+                source: Source::CommandLine,
+            });
+        }
+        if let Some(value) = self.env.get(&env_var(key)) {
+            return Ok(Setting {
+                value: value.clone(),
+                source: Source::Environment(env_var(key)),
+            });
+        }
+        match self.lookup(key) {
+            Some((Value::String(value), source)) => Ok(Setting {
+                value: value.clone(),
+                source,
+            }),
+            Some((_, source)) => Err(invalid_in(key, source)),
+            None => Ok(Setting {
+                value: default.to_owned(),
+                source: Source::Default,
+            }),
+        }
+    }
+
+    // Finds the highest priority table in the config file with `key` in it
+    fn lookup(&self, key: &str) -> Option<(&Value, Source)> {
+        let config = self.config.as_ref()?;
+        let mut tables = vec![vec![], vec![self.tool]];
+        if let Some(profile) = &self.profile {
+            tables.push(vec!["profiles", &profile.value]);
+            tables.push(vec!["profiles", &profile.value, self.tool]);
+        }
+        tables.iter().rev().find_map(|names| {
+            let value = config.table(names)?.get(key)?;
+            let source = Source::File(config.path.clone(), names.join("."));
+            Some((value, source))
+        })
+    }
+}
+
+fn env_var(key: &str) -> String {
+    format!("CHOPSTICK_{}", key.to_ascii_uppercase().replace('-', "_"))
+}
+
+fn invalid_in(key: &str, source: Source) -> ConfigError {
+    match source {
+        Source::File(_, table) if !table.is_empty() => {
+            ConfigError::InvalidValue(format!("{}.{}", table, key))
+        }
+        _ => ConfigError::InvalidValue(key.to_owned()),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    InvalidValue(String),
+    NoSuchProfile(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ConfigError::*;
+        match self {
+            Read(path, why) => write!(
+                f,
+                "Couldn't read config file {}: {}",
+                path.to_string_lossy(),
+                why
+            ),
+            Parse(path, why) => write!(
+                f,
+                "Invalid config file {}: {}",
+                path.to_string_lossy(),
+                why
+            ),
+            InvalidValue(key) => write!(f, "Invalid value for {}", key),
+            NoSuchProfile(profile) => write!(
+                f,
+                "There is no [profiles.{}] table in the config file",
+                profile
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn config(contents: &str) -> Config {
+        Config::parse("config.toml".into(), contents).unwrap()
+    }
+
+    #[test]
+    fn presets() {
+        let config = config(
+            "[presets]\nfloppy = 1474560\nzip = \"100MB\"\nbad = -1\nworse = true\n",
+        );
+        assert_eq!(config.preset("floppy").unwrap().unwrap(), 1474560);
+        assert_eq!(config.preset("ZIP").unwrap().unwrap(), 100_000_000);
+        assert!(config.preset("bad").unwrap().is_err());
+        assert!(config.preset("worse").unwrap().is_err());
+        assert!(config.preset("missing").is_none());
+    }
+
+    #[test]
+    fn layered_defaults() {
+        let config = config(
+            "retain = true\nverbose = true\nsort = \"name\"\n\
+            [chop]\nretain = false\n\
+            [profiles.usb]\nverbose = false\n\
+            [profiles.usb.stick]\nsort = \"natural\"\n",
+        );
+        let defaults = |tool, profile| {
+            Defaults::new(tool, Some(config.clone()), HashMap::new(), profile)
+                .unwrap()
+        };
+        let file =
+            |table: &str| Source::File("config.toml".into(), table.into());
+
+        let stick = defaults("stick", None);
+        let retain = stick.flag("retain", None, false).unwrap();
+        assert!(retain.value);
+        assert_eq!(retain.source, file(""));
+        assert_eq!(
+            stick.flag("retain", Some(true), false).unwrap().source,
+            Source::CommandLine
+        );
+        assert_eq!(stick.lookup("sort").unwrap().1, file(""));
+
+        let chop = defaults("chop", None);
+        let retain = chop.flag("retain", None, false).unwrap();
+        assert!(!retain.value);
+        assert_eq!(retain.source, file("chop"));
+        let balanced = chop.flag("balanced", None, false).unwrap();
+        assert_eq!(balanced.source, Source::Default);
+        assert!(chop.flag("journal", None, true).unwrap().value);
+        assert!(!chop.flag("retain", Some(false), true).unwrap().value);
+
+        let stick = defaults("stick", Some("usb"));
+        assert!(!stick.flag("verbose", None, false).unwrap().value);
+        assert_eq!(stick.lookup("verbose").unwrap().1, file("profiles.usb"));
+        assert_eq!(stick.lookup("sort").unwrap().1, file("profiles.usb.stick"));
+        assert!(stick.flag("sort", None, false).is_err());
+    }
+
+    #[test]
+    fn environment_defaults() {
+        let config = config("[profiles.usb]\nretain = true\n");
+        let env = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(var, value)| (var.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        let defaults = |vars, profile| {
+            Defaults::new("chop", Some(config.clone()), env(vars), profile)
+        };
+
+        let chop = defaults(&[("CHOPSTICK_RETAIN", "no")], None).unwrap();
+        let retain = chop.flag("retain", None, true).unwrap();
+        assert!(!retain.value);
+        assert_eq!(
+            retain.source,
+            Source::Environment("CHOPSTICK_RETAIN".into())
+        );
+        assert!(chop.flag("retain", Some(true), false).unwrap().value);
+        let chop = defaults(&[("CHOPSTICK_RETAIN", "maybe")], None).unwrap();
+        assert!(chop.flag("retain", None, false).is_err());
+        let chop = defaults(&[("CHOPSTICK_DURABILITY", "none")], None).unwrap();
+        assert_eq!(
+            chop.string("durability", None, "full").unwrap().value,
+            "none"
+        );
+
+        // The environment beats the config file, even for the profile's table
+        let chop = defaults(&[("CHOPSTICK_PROFILE", "usb")], None).unwrap();
+        assert!(chop.flag("retain", None, false).unwrap().value);
+        let chop = defaults(
+            &[("CHOPSTICK_PROFILE", "usb"), ("CHOPSTICK_RETAIN", "0")],
+            None,
+        )
+        .unwrap();
+        assert!(!chop.flag("retain", None, true).unwrap().value);
+        assert!(matches!(
+            defaults(&[("CHOPSTICK_PROFILE", "dvd")], None),
+            Err(ConfigError::NoSuchProfile(_))
+        ));
+        // A profile on the command line beats one in the environment
+        assert!(defaults(&[("CHOPSTICK_PROFILE", "dvd")], Some("usb")).is_ok());
+        // And an empty one is no profile at all
+        let chop = defaults(&[("CHOPSTICK_PROFILE", "")], None).unwrap();
+        assert!(chop.profile.is_none());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Config::parse("c".into(), "presets = [").is_err());
+        assert!(config("").preset("anything").is_none());
+    }
+}
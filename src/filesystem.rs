@@ -0,0 +1,969 @@
+use crate::sufficient_disk_space;
+use memmap2::Mmap;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OpenMode {
+    Read,
+    ReadWrite,
+    // Fails if the file already exists
+    CreateNew,
+    Append,
+}
+
+/// All of the filesystem operations chop and stick perform, so that they can
+/// be run against something other than the real filesystem (e.g. to test how
+/// they cope with failures)
+pub trait Filesystem {
+    // Sent to another thread when reads and writes are overlapped
+    type File: FileHandle + Send + 'static;
+
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Self::File>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn file_size(&self, path: &Path) -> io::Result<u64>;
+    // Makes sure renames, creations and removals of the directory's entries
+    // have reached the disk
+    fn sync_directory(&self, path: &Path) -> io::Result<()>;
+    fn sufficient_disk_space(
+        &self,
+        directory: &Path,
+        space_needed: u64,
+    ) -> Result<bool, &'static str>;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.file_size(path).is_ok()
+    }
+}
+
+pub trait FileHandle: Read + Write + Seek {
+    fn set_len(&mut self, size: u64) -> io::Result<()>;
+    fn size(&self) -> io::Result<u64>;
+    // Makes sure the file's contents have reached the disk
+    fn sync_all(&mut self) -> io::Result<()>;
+
+    // Maps the file into memory to be read, if it can be (pipes can't)
+    fn map(&self) -> io::Result<Mmap> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "This file can't be mapped into memory",
+        ))
+    }
+
+    // Turns O_DIRECT on or off, so reads and writes bypass the page cache.
+    // While on, they must be aligned to DIRECT_ALIGNMENT
+    fn set_direct(&mut self, _direct: bool) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Direct I/O is only supported on Linux",
+        ))
+    }
+
+    // Tells the OS that a range of the file (to the end if `len` is zero)
+    // won't be needed again, so its pages can be dropped from the page cache.
+    // Only pages that have been synced can be
+    fn drop_cache(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    // The file descriptor to read the file through io_uring with, if it's a
+    // real file
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        None
+    }
+}
+
+/// What the offsets, lengths, and buffer addresses of direct I/O have to be
+/// multiples of. Most disks need no more than this
+pub const DIRECT_ALIGNMENT: usize = 4096;
+// The most copied at once for direct I/O from or to an unaligned buffer
+const BOUNCE_SIZE: usize = 1024 * 1024;
+
+/// How much chop and stick sync to disk before removing the only other copy
+/// of some data, by truncating the original or deleting a part
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Durability {
+    /// Leave it to the OS, which is quickest but can lose data if the power
+    /// is cut
+    None,
+    /// Sync the contents of each file written
+    Data,
+    /// Also sync the directory, so that new files' names aren't lost
+    Full,
+}
+
+impl Durability {
+    pub const NAMES: [&'static str; 3] = ["none", "data", "full"];
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "none" => Ok(Durability::None),
+            "data" => Ok(Durability::Data),
+            "full" => Ok(Durability::Full),
+            _ => Err(format!("Unknown durability {:?}", s)),
+        }
+    }
+}
+
+/// Whether a rename failed because it would have moved a file to another
+/// filesystem, which only copying it can do
+pub fn crosses_filesystems(why: &io::Error) -> bool {
+    #[cfg(unix)]
+    let cross_device = Some(libc::EXDEV);
+    // ERROR_NOT_SAME_DEVICE
+    #[cfg(windows)]
+    let cross_device = Some(17);
+    #[cfg(not(any(unix, windows)))]
+    let cross_device = None;
+    cross_device.is_some() && why.raw_os_error() == cross_device
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RealFilesystem;
+
+impl Filesystem for RealFilesystem {
+    type File = File;
+
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        match mode {
+            OpenMode::Read => options.read(true),
+            OpenMode::ReadWrite => options.read(true).write(true),
+            OpenMode::CreateNew => options.write(true).create_new(true),
+            OpenMode::Append => options.append(true),
+        };
+        options.open(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        std::fs::metadata(path).map(|md| md.len())
+    }
+
+    #[cfg(unix)]
+    fn sync_directory(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    // Directories can't be opened like files on Windows, where NTFS keeps
+    // them consistent anyway
+    #[cfg(not(unix))]
+    fn sync_directory(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sufficient_disk_space(
+        &self,
+        directory: &Path,
+        space_needed: u64,
+    ) -> Result<bool, &'static str> {
+        sufficient_disk_space(directory, space_needed)
+    }
+}
+
+impl FileHandle for File {
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.metadata().map(|md| md.len())
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+
+    fn map(&self) -> io::Result<Mmap> {
+        // Safe as long as nothing changes the file while it's mapped, which
+        // callers make sure of by only mapping files they won't truncate,
+        // holding the lock that keeps other runs away
+        unsafe { Mmap::map(self) }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_direct(&mut self, direct: bool) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        let fd = self.as_raw_fd();
+        // Safe as the file descriptor is valid for as long as `self` is
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = match direct {
+            true => flags | libc::O_DIRECT,
+            false => flags & !libc::O_DIRECT,
+        };
+        match unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn drop_cache(&self, offset: u64, len: u64) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        // Safe as the file descriptor is valid for as long as `self` is
+        let result = unsafe {
+            libc::posix_fadvise(
+                self.as_raw_fd(),
+                offset as libc::off_t,
+                len as libc::off_t,
+                libc::POSIX_FADV_DONTNEED,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+        Some(self.as_raw_fd())
+    }
+}
+
+/// Opens every file of another [`Filesystem`] for direct I/O, bypassing the
+/// page cache. Reads and writes that aren't aligned to [`DIRECT_ALIGNMENT`]
+/// (e.g. the tail of a part) go through the page cache as usual, as do those
+/// to files on filesystems that don't support direct I/O
+#[derive(Debug, Clone, Default)]
+pub struct DirectFilesystem<F: Filesystem> {
+    inner: F,
+}
+
+impl<F: Filesystem> DirectFilesystem<F> {
+    pub fn new(inner: F) -> Self {
+        DirectFilesystem { inner }
+    }
+}
+
+impl<F: Filesystem> Filesystem for DirectFilesystem<F> {
+    type File = DirectFile<F::File>;
+
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Self::File> {
+        let mut file = self.inner.open(path, mode)?;
+        let supported = file.set_direct(true).is_ok();
+        // Appends go wherever the end of the file is
+        let position = match mode {
+            OpenMode::Append => file.size()?,
+            _ => 0,
+        };
+        Ok(DirectFile {
+            file,
+            supported,
+            direct: supported,
+            position,
+            bounce: Vec::new(),
+        })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        self.inner.file_size(path)
+    }
+
+    fn sync_directory(&self, path: &Path) -> io::Result<()> {
+        self.inner.sync_directory(path)
+    }
+
+    fn sufficient_disk_space(
+        &self,
+        directory: &Path,
+        space_needed: u64,
+    ) -> Result<bool, &'static str> {
+        self.inner.sufficient_disk_space(directory, space_needed)
+    }
+}
+
+/// A file opened by [`DirectFilesystem`], which turns direct I/O off for
+/// anything unaligned, and back on once things are aligned again
+#[derive(Debug)]
+pub struct DirectFile<F: FileHandle> {
+    file: F,
+    // Whether the file can be used for direct I/O at all
+    supported: bool,
+    // Whether it's currently being used for direct I/O
+    direct: bool,
+    position: u64,
+    // Only allocated once the caller's buffer isn't aligned
+    bounce: Vec<u8>,
+}
+
+impl<F: FileHandle> DirectFile<F> {
+    // How many of `len` bytes can be read or written directly at the current
+    // position, if any. Switches direct I/O on or off to suit
+    fn prepare(&mut self, len: usize) -> io::Result<Option<usize>> {
+        let direct_len = len / DIRECT_ALIGNMENT * DIRECT_ALIGNMENT;
+        let direct = self.supported
+            && self.position % DIRECT_ALIGNMENT as u64 == 0
+            && direct_len > 0;
+        if direct != self.direct {
+            self.file.set_direct(direct)?;
+            self.direct = direct;
+        }
+        Ok(direct.then(|| direct_len))
+    }
+
+    // How many of `len` bytes to read or write through the page cache, which
+    // is only as many as it takes to get back to an aligned position
+    fn buffered_len(&self, len: usize) -> usize {
+        match self.supported {
+            true => {
+                let align = DIRECT_ALIGNMENT as u64;
+                min(len as u64, align - self.position % align) as usize
+            }
+            false => len,
+        }
+    }
+}
+
+// An aligned window of at most `len` bytes in `bounce`, to copy through when
+// the caller's buffer isn't aligned
+fn bounce_window(bounce: &mut Vec<u8>, len: usize) -> &mut [u8] {
+    if bounce.is_empty() {
+        *bounce = vec![0; BOUNCE_SIZE + DIRECT_ALIGNMENT];
+    }
+    let start = bounce.as_ptr().align_offset(DIRECT_ALIGNMENT);
+    &mut bounce[start..start + min(len, BOUNCE_SIZE)]
+}
+
+fn is_aligned(bytes: &[u8]) -> bool {
+    bytes.as_ptr() as usize % DIRECT_ALIGNMENT == 0
+}
+
+impl<F: FileHandle> Read for DirectFile<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match self.prepare(buf.len())? {
+            Some(len) if is_aligned(buf) => self.file.read(&mut buf[..len])?,
+            Some(len) => {
+                let bounce = bounce_window(&mut self.bounce, len);
+                let read = self.file.read(bounce)?;
+                buf[..read].copy_from_slice(&bounce[..read]);
+                read
+            }
+            None => {
+                let len = self.buffered_len(buf.len());
+                self.file.read(&mut buf[..len])?
+            }
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<F: FileHandle> Write for DirectFile<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = match self.prepare(buf.len())? {
+            Some(len) if is_aligned(buf) => self.file.write(&buf[..len])?,
+            Some(len) => {
+                let bounce = bounce_window(&mut self.bounce, len);
+                let len = bounce.len();
+                bounce.copy_from_slice(&buf[..len]);
+                self.file.write(bounce)?
+            }
+            None => {
+                let len = self.buffered_len(buf.len());
+                self.file.write(&buf[..len])?
+            }
+        };
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<F: FileHandle> Seek for DirectFile<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.file.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+impl<F: FileHandle> FileHandle for DirectFile<F> {
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.file.size()
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn map(&self) -> io::Result<Mmap> {
+        self.file.map()
+    }
+
+    fn set_direct(&mut self, direct: bool) -> io::Result<()> {
+        self.file.set_direct(direct)
+    }
+
+    fn drop_cache(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.file.drop_cache(offset, len)
+    }
+}
+
+/// A failure [`MemoryFilesystem`] can be told to cause
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Fault {
+    /// The operation fails as if the disk were full
+    NoSpace,
+    /// The operation fails with a generic I/O error
+    Io,
+    /// A write only writes half of what it was given (other operations are
+    /// unaffected)
+    ShortWrite,
+    /// The operation and all those after it fail, as if the process had died
+    Crash,
+    /// As with a crash, but anything that hadn't been synced is lost too, as
+    /// if the power had been cut. Removals and truncations are assumed to
+    /// have reached the disk straight away, as they may well have
+    PowerCut,
+}
+
+impl Fault {
+    pub const ALL: [Fault; 5] = [
+        Fault::NoSpace,
+        Fault::Io,
+        Fault::ShortWrite,
+        Fault::Crash,
+        Fault::PowerCut,
+    ];
+}
+
+#[derive(Debug, Default)]
+struct MemoryData {
+    contents: Vec<u8>,
+    // The contents as of the last sync
+    synced: Vec<u8>,
+}
+
+type SharedData = Arc<Mutex<MemoryData>>;
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    files: HashMap<PathBuf, SharedData>,
+    // The files as of the last directory sync
+    synced_files: HashMap<PathBuf, SharedData>,
+    capacity: Option<u64>,
+    steps: usize,
+    fault: Option<(usize, Fault)>,
+    crashed: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Operation {
+    Write,
+    Other,
+}
+
+impl MemoryState {
+    // Every operation is a step, and a fault may be injected at any of them
+    // Returns whether a write should be cut short
+    fn step(&mut self, operation: Operation) -> io::Result<bool> {
+        if self.crashed {
+            return Err(crashed());
+        }
+        let step = self.steps;
+        self.steps += 1;
+        match self.fault {
+            Some((at, fault)) if at == step => match fault {
+                Fault::NoSpace => Err(no_space()),
+                Fault::Io => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Input/output error (injected)",
+                )),
+                Fault::ShortWrite => Ok(operation == Operation::Write),
+                Fault::Crash => {
+                    self.crashed = true;
+                    Err(crashed())
+                }
+                Fault::PowerCut => {
+                    self.crashed = true;
+                    self.files = self.synced_files.clone();
+                    self.files.values().for_each(|data| {
+                        let mut data = data.lock().unwrap();
+                        data.contents = data.synced.clone();
+                    });
+                    Err(crashed())
+                }
+            },
+            _ => Ok(false),
+        }
+    }
+
+    fn used(&self) -> u64 {
+        self.files
+            .values()
+            .map(|data| data.lock().unwrap().contents.len() as u64)
+            .sum()
+    }
+
+    fn check_growth(&self, by: u64) -> io::Result<()> {
+        match self.capacity {
+            Some(capacity) if self.used() + by > capacity => Err(no_space()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn no_space() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "No space left on device (injected)")
+}
+
+fn crashed() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "Crashed (injected)")
+}
+
+/// An in-memory filesystem which can have faults injected at a chosen step,
+/// where every open, read, write, truncation, rename, removal, sync or size
+/// check is a step. Clones share the same files
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFilesystem {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+impl MemoryFilesystem {
+    pub fn new() -> Self {
+        MemoryFilesystem::default()
+    }
+
+    /// Limits the total size of all files
+    pub fn with_capacity(capacity: u64) -> Self {
+        let fs = MemoryFilesystem::default();
+        fs.state.lock().unwrap().capacity = Some(capacity);
+        fs
+    }
+
+    /// Makes `fault` happen at the given step (counting from zero)
+    pub fn inject(&self, step: usize, fault: Fault) {
+        self.state.lock().unwrap().fault = Some((step, fault));
+    }
+
+    /// Removes any fault, including recovering from a crash
+    pub fn clear_faults(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.fault = None;
+        state.crashed = false;
+    }
+
+    /// The number of steps taken so far
+    pub fn steps(&self) -> usize {
+        self.state.lock().unwrap().steps
+    }
+
+    /// Adds a file without taking a step, as if it had already been synced
+    pub fn insert<P: Into<PathBuf>>(&self, path: P, contents: &[u8]) {
+        let path = path.into();
+        let data = Arc::new(Mutex::new(MemoryData {
+            contents: contents.to_vec(),
+            synced: contents.to_vec(),
+        }));
+        let mut state = self.state.lock().unwrap();
+        state.files.insert(path.clone(), Arc::clone(&data));
+        state.synced_files.insert(path, data);
+    }
+
+    /// Gets the contents of a file without taking a step
+    pub fn contents<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .get(path.as_ref())
+            .map(|data| data.lock().unwrap().contents.clone())
+    }
+
+    /// All of the files, sorted by path
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let mut paths = self
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    fn step(&self, operation: Operation) -> io::Result<bool> {
+        self.state.lock().unwrap().step(operation)
+    }
+}
+
+impl Filesystem for MemoryFilesystem {
+    type File = MemoryFile;
+
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<MemoryFile> {
+        self.step(Operation::Other)?;
+        let mut state = self.state.lock().unwrap();
+        let data = match (mode, state.files.get(path)) {
+            (OpenMode::CreateNew, Some(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "File exists",
+                ))
+            }
+            (OpenMode::CreateNew, None) => {
+                let data = Arc::new(Mutex::new(MemoryData::default()));
+                state.files.insert(path.to_owned(), Arc::clone(&data));
+                data
+            }
+            (_, Some(data)) => Arc::clone(data),
+            (_, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "No such file or directory",
+                ))
+            }
+        };
+        Ok(MemoryFile {
+            data,
+            mode,
+            position: 0,
+            state: Arc::clone(&self.state),
+        })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.step(Operation::Other)?;
+        let mut state = self.state.lock().unwrap();
+        let data = state.files.remove(from).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "No such file or directory")
+        })?;
+        state.files.insert(to.to_owned(), data);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.step(Operation::Other)?;
+        let mut state = self.state.lock().unwrap();
+        state.synced_files.remove(path);
+        state.files.remove(path).map(|_| ()).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "No such file or directory")
+        })
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        self.step(Operation::Other)?;
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .get(path)
+            .map(|data| data.lock().unwrap().contents.len() as u64)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "No such file or directory",
+                )
+            })
+    }
+
+    // All files are treated as being in the same directory
+    fn sync_directory(&self, _path: &Path) -> io::Result<()> {
+        self.step(Operation::Other)?;
+        let mut state = self.state.lock().unwrap();
+        state.synced_files = state.files.clone();
+        Ok(())
+    }
+
+    fn sufficient_disk_space(
+        &self,
+        _directory: &Path,
+        space_needed: u64,
+    ) -> Result<bool, &'static str> {
+        let state = self.state.lock().unwrap();
+        Ok(match state.capacity {
+            Some(capacity) => capacity - state.used() > space_needed,
+            None => true,
+        })
+    }
+}
+
+/// An open file in a [`MemoryFilesystem`]. Removing or renaming the file
+/// doesn't affect handles that are already open
+#[derive(Debug)]
+pub struct MemoryFile {
+    data: SharedData,
+    mode: OpenMode,
+    position: u64,
+    state: Arc<Mutex<MemoryState>>,
+}
+
+impl MemoryFile {
+    fn check_writable(&self) -> io::Result<()> {
+        match self.mode {
+            OpenMode::Read => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "File not opened for writing",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.state.lock().unwrap().step(Operation::Other)?;
+        match self.mode {
+            OpenMode::Read | OpenMode::ReadWrite => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "File not opened for reading",
+                ))
+            }
+        }
+        let data = &self.data.lock().unwrap().contents;
+        let start = min(self.position, data.len() as u64) as usize;
+        let len = min(buf.len(), data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let short = self.state.lock().unwrap().step(Operation::Write)?;
+        self.check_writable()?;
+        let buf = if short && buf.len() > 1 {
+            &buf[..buf.len() / 2]
+        } else {
+            buf
+        };
+        if self.mode == OpenMode::Append {
+            self.position = self.data.lock().unwrap().contents.len() as u64;
+        }
+        let start = self.position as usize;
+        let end = start + buf.len();
+        let growth =
+            end.saturating_sub(self.data.lock().unwrap().contents.len()) as u64;
+        self.state.lock().unwrap().check_growth(growth)?;
+        let data = &mut self.data.lock().unwrap().contents;
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        self.position = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => add_signed(
+                self.data.lock().unwrap().contents.len() as u64,
+                offset,
+            ),
+            SeekFrom::Current(offset) => add_signed(self.position, offset),
+        };
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn add_signed(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    }
+}
+
+impl FileHandle for MemoryFile {
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.state.lock().unwrap().step(Operation::Other)?;
+        self.check_writable()?;
+        let growth = size
+            .saturating_sub(self.data.lock().unwrap().contents.len() as u64);
+        self.state.lock().unwrap().check_growth(growth)?;
+        let mut data = self.data.lock().unwrap();
+        data.contents.resize(size as usize, 0);
+        // Assume the worst, that the truncation reached the disk but nothing
+        // else did
+        data.synced.truncate(size as usize);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.lock().unwrap().contents.len() as u64)
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.state.lock().unwrap().step(Operation::Other)?;
+        let mut data = self.data.lock().unwrap();
+        data.synced = data.contents.clone();
+        Ok(())
+    }
+    // There's no page cache to bypass, but this lets DirectFile be tested
+    fn set_direct(&mut self, _direct: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn memory_files() {
+        let fs = MemoryFilesystem::new();
+        let path = Path::new("foo");
+        let mut file = fs.open(path, OpenMode::CreateNew).unwrap();
+        file.write_all(b"hello world").unwrap();
+        assert!(fs.open(path, OpenMode::CreateNew).is_err());
+        assert_eq!(fs.file_size(path).unwrap(), 11);
+
+        let mut file = fs.open(path, OpenMode::ReadWrite).unwrap();
+        file.seek(SeekFrom::Start(6)).unwrap();
+        let mut read = String::new();
+        file.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "world");
+        file.set_len(5).unwrap();
+
+        let mut file = fs.open(path, OpenMode::Append).unwrap();
+        file.write_all(b"!").unwrap();
+        assert_eq!(fs.contents(path).unwrap(), b"hello!");
+
+        fs.rename(path, Path::new("bar")).unwrap();
+        assert!(!fs.exists(path));
+        fs.remove_file(Path::new("bar")).unwrap();
+        assert!(fs.paths().is_empty());
+    }
+
+    #[test]
+    fn fault_injection() {
+        let fs = MemoryFilesystem::new();
+        fs.insert("foo", b"abc");
+        fs.inject(1, Fault::Io);
+        assert!(fs.file_size(Path::new("foo")).is_ok());
+        assert!(fs.file_size(Path::new("foo")).is_err());
+        assert!(fs.file_size(Path::new("foo")).is_ok());
+
+        fs.inject(fs.steps(), Fault::Crash);
+        assert!(fs.file_size(Path::new("foo")).is_err());
+        assert!(fs.file_size(Path::new("foo")).is_err());
+        fs.clear_faults();
+        assert!(fs.file_size(Path::new("foo")).is_ok());
+
+        let mut file = fs.open(Path::new("foo"), OpenMode::Append).unwrap();
+        fs.inject(fs.steps(), Fault::ShortWrite);
+        assert_eq!(file.write(b"defg").unwrap(), 2);
+        assert_eq!(fs.contents("foo").unwrap(), b"abcde");
+    }
+
+    #[test]
+    fn power_cut() {
+        let fs = MemoryFilesystem::new();
+        fs.insert("foo", b"abcdef");
+        let mut foo = fs.open(Path::new("foo"), OpenMode::ReadWrite).unwrap();
+        foo.set_len(3).unwrap();
+        let mut bar = fs.open(Path::new("bar"), OpenMode::CreateNew).unwrap();
+        bar.write_all(b"def").unwrap();
+        bar.sync_all().unwrap();
+        let mut baz = fs.open(Path::new("baz"), OpenMode::CreateNew).unwrap();
+        baz.write_all(b"ghi").unwrap();
+        fs.sync_directory(Path::new("")).unwrap();
+        let mut qux = fs.open(Path::new("qux"), OpenMode::CreateNew).unwrap();
+        qux.write_all(b"jkl").unwrap();
+        qux.sync_all().unwrap();
+
+        fs.inject(fs.steps(), Fault::PowerCut);
+        assert!(fs.file_size(Path::new("foo")).is_err());
+        fs.clear_faults();
+        // Synced files survive, but unsynced contents and names don't
+        assert_eq!(fs.contents("foo").unwrap(), b"abc");
+        assert_eq!(fs.contents("bar").unwrap(), b"def");
+        assert_eq!(fs.contents("baz").unwrap(), b"");
+        assert!(fs.contents("qux").is_none());
+    }
+
+    #[test]
+    fn capacity() {
+        let fs = MemoryFilesystem::with_capacity(10);
+        fs.insert("foo", b"12345678");
+        assert_eq!(fs.sufficient_disk_space(Path::new(""), 1), Ok(true));
+        assert_eq!(fs.sufficient_disk_space(Path::new(""), 5), Ok(false));
+        let mut file = fs.open(Path::new("foo"), OpenMode::Append).unwrap();
+        file.write_all(b"9").unwrap();
+        assert!(file.write_all(b"ab").is_err());
+    }
+
+    #[test]
+    fn direct_files() {
+        let fs = DirectFilesystem::new(MemoryFilesystem::new());
+        let bytes = (0..3 * DIRECT_ALIGNMENT + 100)
+            .map(|n| n as u8)
+            .collect::<Vec<_>>();
+        let path = Path::new("foo");
+        let mut file = fs.open(path, OpenMode::CreateNew).unwrap();
+        // Only whole blocks at aligned positions are written directly
+        assert_eq!(file.prepare(10_000).unwrap(), Some(2 * DIRECT_ALIGNMENT));
+        assert_eq!(file.prepare(100).unwrap(), None);
+        file.write_all(&bytes[..100]).unwrap();
+        assert_eq!(file.prepare(10_000).unwrap(), None);
+        assert_eq!(file.buffered_len(10_000), DIRECT_ALIGNMENT - 100);
+        // Which means copying through an aligned buffer after this
+        file.write_all(&bytes[100..]).unwrap();
+        assert_eq!(fs.inner.contents(path).unwrap(), bytes);
+
+        let mut file = fs.open(path, OpenMode::Read).unwrap();
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).unwrap();
+        assert_eq!(read, bytes);
+        let file = fs.open(path, OpenMode::Append).unwrap();
+        assert_eq!(file.position, bytes.len() as u64);
+    }
+}
@@ -0,0 +1,567 @@
+use crate::lock::{LockError, SetLock};
+use crate::manifest::Manifest;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, fmt, fs, io, process};
+
+const HEADER: &str = "chopstick journal 1";
+// Older runs are forgotten, so that the journal doesn't grow forever
+const MAX_ENTRIES: usize = 100;
+
+/// The last run of chop or stick on each file, kept in
+/// `$XDG_STATE_HOME/chopstick/journal` so that it can be undone
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Journal {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Operation {
+    Chop,
+    Stick,
+}
+
+/// What a run left behind, as it was when the run finished, so that an undo
+/// can tell whether anything's changed since
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Entry {
+    pub operation: Operation,
+    // Whether the run kept what it started from: the original for chop, or
+    // the parts for stick
+    pub retained: bool,
+    pub original: FileRecord,
+    // In order
+    pub parts: Vec<FileRecord>,
+    // The manifest and any rejoin scripts
+    pub extras: Vec<FileRecord>,
+    // The manifest a stick used up, hashes and all, so that an undo can
+    // write it again exactly as it was
+    pub manifest: Option<Manifest>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileRecord {
+    pub path: PathBuf,
+    pub size: u64,
+    // When it was last modified, or None if the run left nothing there
+    pub modified: Option<SystemTime>,
+}
+
+impl Journal {
+    /// Where the journal is kept, if there's anywhere it could be at all
+    pub fn path() -> Option<PathBuf> {
+        let state_home = match env::var_os("XDG_STATE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ if cfg!(windows) => PathBuf::from(env::var_os("LOCALAPPDATA")?),
+            _ => Path::new(&env::var_os("HOME")?)
+                .join(".local")
+                .join("state"),
+        };
+        Some(state_home.join("chopstick").join("journal"))
+    }
+
+    pub fn load() -> Result<Self, JournalError> {
+        Journal::load_from(Journal::path().ok_or(JournalError::Unavailable)?)
+    }
+
+    // A journal that doesn't exist yet is empty
+    pub fn load_from(path: PathBuf) -> Result<Self, JournalError> {
+        match fs::read_to_string(&path) {
+            Ok(contents) => Journal::parse(path, &contents),
+            Err(why) if why.kind() == io::ErrorKind::NotFound => Ok(Journal {
+                path,
+                entries: Vec::new(),
+            }),
+            Err(why) => Err(JournalError::Io(path, why)),
+        }
+    }
+
+    pub fn parse(path: PathBuf, contents: &str) -> Result<Self, JournalError> {
+        match contents.parse::<Entries>() {
+            Ok(Entries(entries)) => Ok(Journal { path, entries }),
+            Err(why) => Err(JournalError::Io(path, why)),
+        }
+    }
+
+    /// Changes the journal, holding its lock so that runs finishing at the
+    /// same time don't lose each other's entries
+    pub fn update<F: FnOnce(&mut Journal)>(
+        change: F,
+    ) -> Result<(), JournalError> {
+        let path = Journal::path().ok_or(JournalError::Unavailable)?;
+        if let Some(folder) = path.parent() {
+            fs::create_dir_all(folder)
+                .map_err(|why| JournalError::Io(path.clone(), why))?;
+        }
+        let _lock = SetLock::acquire(&path, true)?;
+        let mut journal = Journal::load_from(path)?;
+        change(&mut journal);
+        journal.save()
+    }
+
+    // Written alongside first, so that there's always a whole journal there
+    fn save(&self) -> Result<(), JournalError> {
+        let io_error = |why| JournalError::Io(self.path.clone(), why);
+        let mut temp_path = self.path.as_os_str().to_owned();
+        temp_path.push(format!(".{}.tmp", process::id()));
+        fs::write(&temp_path, Entries(self.entries.clone()).to_string())
+            .map_err(io_error)?;
+        fs::rename(&temp_path, &self.path).map_err(io_error)
+    }
+
+    pub fn entry(&self, original: &Path) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.original.path == original)
+    }
+
+    /// The last run on `original`, as long as it was `operation`, and
+    /// nothing it left behind has changed since
+    pub fn undoable(
+        &self,
+        operation: Operation,
+        original: &Path,
+    ) -> Result<&Entry, JournalError> {
+        let entry = self
+            .entry(original)
+            .filter(|entry| entry.operation == operation)
+            .ok_or_else(|| JournalError::NothingToUndo(original.to_owned()))?;
+        match entry.changed() {
+            Some(path) => Err(JournalError::Changed(path.to_owned())),
+            None => Ok(entry),
+        }
+    }
+
+    /// Replaces whatever was recorded for the entry's original. Entries with
+    /// paths that can't be written in the journal just forget the last run
+    pub fn record(&mut self, entry: Entry) {
+        self.forget(&entry.original.path);
+        if entry.files().all(|file| {
+            file.path.is_absolute()
+                && file.path.to_str().map_or(false, |p| !p.contains('\n'))
+        }) {
+            self.entries.push(entry);
+        }
+        let excess = self.entries.len().saturating_sub(MAX_ENTRIES);
+        self.entries.drain(..excess);
+    }
+
+    pub fn forget(&mut self, original: &Path) -> Option<Entry> {
+        let index = self
+            .entries
+            .iter()
+            .position(|entry| entry.original.path == original)?;
+        Some(self.entries.remove(index))
+    }
+}
+
+impl Entry {
+    pub fn files(&self) -> impl Iterator<Item = &FileRecord> {
+        std::iter::once(&self.original)
+            .chain(&self.parts)
+            .chain(&self.extras)
+    }
+
+    /// The first file that isn't as the run left it
+    pub fn changed(&self) -> Option<&Path> {
+        self.files()
+            .find(|file| !file.is_unchanged())
+            .map(|file| file.path.as_path())
+    }
+}
+
+impl FileRecord {
+    /// The file at `path` as it is now
+    pub fn present<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let metadata = fs::metadata(&path)?;
+        Ok(FileRecord {
+            path: path.as_ref().to_owned(),
+            size: metadata.len(),
+            modified: Some(metadata.modified()?),
+        })
+    }
+
+    /// Nothing at `path`, where there was (or will be) a file of `size` bytes
+    pub fn absent<P: AsRef<Path>>(path: P, size: u64) -> Self {
+        FileRecord {
+            path: path.as_ref().to_owned(),
+            size,
+            modified: None,
+        }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.modified.is_some()
+    }
+
+    fn is_unchanged(&self) -> bool {
+        match (self.modified, fs::metadata(&self.path)) {
+            (Some(modified), Ok(metadata)) => {
+                metadata.is_file()
+                    && metadata.len() == self.size
+                    && metadata.modified().ok() == Some(modified)
+            }
+            (None, Err(why)) => why.kind() == io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+}
+
+/// `path` made absolute, with its folder's links resolved, so that a file is
+/// recorded under the same name however it's given. The file itself doesn't
+/// have to exist
+pub fn absolute_path<P: AsRef<Path>>(path: P) -> io::Result<PathBuf> {
+    let path = path.as_ref();
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Not a file name")
+    })?;
+    let folder = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Ok(fs::canonicalize(folder)?.join(file_name))
+}
+
+// The journal's contents, without where it lives
+struct Entries(Vec<Entry>);
+
+impl fmt::Display for Entries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        self.0.iter().try_for_each(|entry| {
+            let operation = match entry.operation {
+                Operation::Chop => "chop",
+                Operation::Stick => "stick",
+            };
+            let retained = match entry.retained {
+                true => "retained",
+                false => "deleted",
+            };
+            writeln!(f, "{} {}", operation, retained)?;
+            write_file(f, "original", &entry.original)?;
+            entry
+                .parts
+                .iter()
+                .try_for_each(|part| write_file(f, "part", part))?;
+            entry
+                .extras
+                .iter()
+                .try_for_each(|extra| write_file(f, "extra", extra))?;
+            // Line by line, each marked as part of the manifest
+            entry.manifest.iter().try_for_each(|manifest| {
+                manifest
+                    .to_string()
+                    .lines()
+                    .try_for_each(|line| writeln!(f, "manifest {}", line))
+            })
+        })
+    }
+}
+
+// The path goes last, so that it can have spaces in
+fn write_file(
+    f: &mut fmt::Formatter<'_>,
+    kind: &str,
+    file: &FileRecord,
+) -> fmt::Result {
+    write!(f, "{} {} ", kind, file.size)?;
+    match file.modified.map(|time| time.duration_since(UNIX_EPOCH)) {
+        Some(Ok(since)) => {
+            write!(f, "{}.{:09}", since.as_secs(), since.subsec_nanos())?
+        }
+        // Never matches a file, so an undo would refuse to touch it
+        Some(Err(_)) => write!(f, "0.000000000")?,
+        None => write!(f, "-")?,
+    }
+    writeln!(f, " {}", file.path.to_string_lossy())
+}
+
+impl FromStr for Entries {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let mut lines = s.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(invalid("not a chopstick journal"));
+        }
+        let mut entries = Vec::<Entry>::new();
+        // The manifest lines of each entry, parsed once they're all read
+        let mut manifests = Vec::<Option<String>>::new();
+        for line in lines {
+            let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let operation = match kind {
+                "chop" => Some(Operation::Chop),
+                "stick" => Some(Operation::Stick),
+                _ => None,
+            };
+            if let Some(operation) = operation {
+                let retained = match rest {
+                    "retained" => true,
+                    "deleted" => false,
+                    _ => return Err(invalid("bad operation line")),
+                };
+                entries.push(Entry {
+                    operation,
+                    retained,
+                    original: FileRecord::absent(PathBuf::new(), 0),
+                    parts: Vec::new(),
+                    extras: Vec::new(),
+                    manifest: None,
+                });
+                manifests.push(None);
+                continue;
+            }
+            let entry = entries
+                .last_mut()
+                .ok_or_else(|| invalid("file before any operation"))?;
+            if kind == "manifest" {
+                let manifest = manifests
+                    .last_mut()
+                    .expect("There's one for each entry")
+                    .get_or_insert_with(String::new);
+                manifest.push_str(rest);
+                manifest.push('\n');
+                continue;
+            }
+            let file = parse_file(rest)?;
+            match kind {
+                "original" if entry.original.path.as_os_str().is_empty() => {
+                    entry.original = file
+                }
+                "part" => entry.parts.push(file),
+                "extra" => entry.extras.push(file),
+                _ => return Err(invalid("bad file line")),
+            }
+        }
+        entries.iter_mut().zip(manifests).try_for_each(
+            |(entry, manifest)| -> io::Result<()> {
+                entry.manifest =
+                    manifest.map(|text| text.parse()).transpose()?;
+                Ok(())
+            },
+        )?;
+        match entries
+            .iter()
+            .any(|entry| entry.original.path.as_os_str().is_empty())
+        {
+            true => Err(invalid("operation without an original")),
+            false => Ok(Entries(entries)),
+        }
+    }
+}
+
+fn parse_file(line: &str) -> io::Result<FileRecord> {
+    let mut words = line.splitn(3, ' ');
+    let (size, modified, path) =
+        match (words.next(), words.next(), words.next()) {
+            (Some(size), Some(modified), Some(path)) => (size, modified, path),
+            _ => return Err(invalid("bad file line")),
+        };
+    let size = size.parse().map_err(|_| invalid("bad file size"))?;
+    let modified = match modified {
+        "-" => None,
+        time => {
+            let (secs, nanos) =
+                time.split_once('.').ok_or_else(|| invalid("bad time"))?;
+            match (secs.parse(), nanos.parse()) {
+                (Ok(secs), Ok(nanos)) if nanos < 1_000_000_000 => {
+                    Some(UNIX_EPOCH + Duration::new(secs, nanos))
+                }
+                _ => return Err(invalid("bad time")),
+            }
+        }
+    };
+    Ok(FileRecord {
+        path: PathBuf::from(path),
+        size,
+        modified,
+    })
+}
+
+fn invalid(why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, why)
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    // Nowhere to keep it, as there's no home folder
+    Unavailable,
+    // Off, as it is unless turned on with --journal or the journal setting
+    Disabled,
+    Locked(LockError),
+    Io(PathBuf, io::Error),
+    NothingToUndo(PathBuf),
+    Changed(PathBuf),
+}
+
+impl JournalError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            JournalError::Locked(_) | JournalError::Io(_, _) => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JournalError::Unavailable => write!(
+                f,
+                "Nowhere to keep the journal, set XDG_STATE_HOME or HOME"
+            ),
+            JournalError::Disabled => write!(
+                f,
+                "The journal is off, so there's no run to undo (it's turned \
+                on with --journal)"
+            ),
+            JournalError::Locked(why) => write!(f, "{}", why),
+            JournalError::Io(path, why) => write!(
+                f,
+                "Couldn't use the journal {}: {}",
+                path.to_string_lossy(),
+                why
+            ),
+            JournalError::NothingToUndo(path) => write!(
+                f,
+                "There's no run on {} to undo",
+                path.to_string_lossy()
+            ),
+            JournalError::Changed(path) => write!(
+                f,
+                "{} has changed since the run, so it can't be undone",
+                path.to_string_lossy()
+            ),
+        }
+    }
+}
+
+impl Error for JournalError {}
+
+impl From<LockError> for JournalError {
+    fn from(err: LockError) -> Self {
+        JournalError::Locked(err)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+
+    fn entry(original: &Path) -> Entry {
+        Entry {
+            operation: Operation::Chop,
+            retained: false,
+            original: FileRecord::absent(original, 8),
+            parts: vec![FileRecord {
+                path: original.with_file_name("a file.p1"),
+                size: 8,
+                modified: Some(UNIX_EPOCH + Duration::new(1, 5)),
+            }],
+            extras: Vec::new(),
+            manifest: None,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let original = Path::new("/tmp/a file");
+        let entries = Entries(vec![entry(original)]);
+        let text = entries.to_string();
+        assert_eq!(
+            text,
+            "chopstick journal 1\nchop deleted\n\
+            original 8 - /tmp/a file\npart 8 1.000000005 /tmp/a file.p1\n"
+        );
+        let parsed = text.parse::<Entries>().unwrap();
+        assert_eq!(parsed.0, entries.0);
+        // A stick keeps the manifest it used up
+        let mut stuck = entry(original);
+        stuck.operation = Operation::Stick;
+        stuck.manifest = Some(Manifest {
+            file_size: 8,
+            part_sizes: vec![8],
+            part_hashes: Some(vec!["ab".repeat(32)]),
+        });
+        let entries = Entries(vec![stuck, entry(Path::new("/tmp/other"))]);
+        let text = entries.to_string();
+        assert!(text.contains("\nmanifest part 1 8 abab"), "{}", text);
+        assert_eq!(text.parse::<Entries>().unwrap().0, entries.0);
+        [
+            "",
+            "chopstick journal 1\npart 8 - /tmp/a\n",
+            "chopstick journal 1\nchop sometimes\n",
+            "chopstick journal 1\nchop deleted\npart 8 - /tmp/a\n",
+            "chopstick journal 1\nchop deleted\noriginal 8 1 /tmp/a\n",
+            "chopstick journal 1\nchop deleted\noriginal 8 - /tmp/a\n\
+            manifest size 8\n",
+        ]
+        .into_iter()
+        .for_each(|text| {
+            assert!(text.parse::<Entries>().is_err(), "parsed {:?}", text)
+        });
+    }
+
+    #[test]
+    fn record() {
+        let mut journal = Journal {
+            path: PathBuf::new(),
+            entries: Vec::new(),
+        };
+        (0..MAX_ENTRIES + 1).for_each(|n| {
+            journal.record(entry(&Path::new("/tmp").join(n.to_string())))
+        });
+        assert_eq!(journal.entries.len(), MAX_ENTRIES);
+        assert!(journal.entry(Path::new("/tmp/0")).is_none());
+        // A later run replaces the last
+        let mut retained = entry(Path::new("/tmp/1"));
+        retained.retained = true;
+        journal.record(retained.clone());
+        assert_eq!(journal.entry(Path::new("/tmp/1")), Some(&retained));
+        assert_eq!(journal.entries.len(), MAX_ENTRIES);
+        // Relative paths can't be undone from anywhere else
+        journal.record(entry(Path::new("relative")));
+        assert!(journal.entry(Path::new("relative")).is_none());
+    }
+
+    #[test]
+    fn undoable() {
+        let temp_dir = TempDir::new().unwrap();
+        let original = temp_dir.child("original");
+        let part = temp_dir.child("original.p1");
+        part.write_binary(b"part").unwrap();
+        let mut journal = Journal {
+            path: PathBuf::new(),
+            entries: Vec::new(),
+        };
+        journal.record(Entry {
+            operation: Operation::Chop,
+            retained: false,
+            original: FileRecord::absent(original.path(), 4),
+            parts: vec![FileRecord::present(part.path()).unwrap()],
+            extras: Vec::new(),
+            manifest: None,
+        });
+        assert!(journal.undoable(Operation::Chop, original.path()).is_ok());
+        assert!(matches!(
+            journal.undoable(Operation::Stick, original.path()),
+            Err(JournalError::NothingToUndo(_))
+        ));
+        original.write_binary(b"new").unwrap();
+        assert!(matches!(
+            journal.undoable(Operation::Chop, original.path()),
+            Err(JournalError::Changed(path)) if path == original.path()
+        ));
+        fs::remove_file(original.path()).unwrap();
+        part.write_binary(b"parts").unwrap();
+        assert!(matches!(
+            journal.undoable(Operation::Chop, original.path()),
+            Err(JournalError::Changed(path)) if path == part.path()
+        ));
+    }
+}
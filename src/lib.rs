@@ -1,21 +1,63 @@
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::fs::File;
 use std::io;
 use std::io::{Read, Seek, SeekFrom};
+use std::mem;
+use std::ops::Range;
 use std::path::Path;
 use sysinfo::{DiskExt, System, SystemExt};
 
+pub mod archive;
+#[cfg(feature = "async")]
+pub mod async_io;
+mod cdc;
+pub mod cli;
+pub mod config;
+pub mod filesystem;
+pub mod journal;
+pub mod lock;
+pub mod manifest;
+pub mod parts;
+pub mod pipeline;
+pub mod rejoin;
+pub mod split;
+pub mod throttle;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+pub mod watch;
+
+pub use cdc::ContentDefinedChunker;
+use filesystem::{FileHandle, DIRECT_ALIGNMENT};
+use memmap2::Mmap;
+
 pub const EXTENSION_PREFIX: &str = "p";
 const DEFAULT_MAX_BUFFER_SIZE: u64 = 512 * 1024 * 1024; // 512 MiB
 
-pub struct ChunkedReader<'a> {
-    pub file: File,
-    buffer: &'a mut Vec<u8>,
+/// Reads a file (or anything else that can be read and seeked) in chunks no
+/// bigger than a buffer, stopping at the end of each part
+pub struct ChunkedReader<'a, F: Read + Seek = File> {
+    pub file: F,
+    source: Source<'a>,
+    // Whether chunks are read into an aligned part of the buffer
+    aligned: bool,
+    // Where the last chunk was read into the buffer
+    chunk: Range<usize>,
     verbose: bool,
 }
 
-impl<'a> ChunkedReader<'a> {
-    pub fn new(file: File, buffer: &'a mut Vec<u8>, verbose: bool) -> Self {
+// Where the bytes handed out come from
+enum Source<'a> {
+    // Copied into this first
+    Buffer(&'a mut Vec<u8>),
+    // Straight out of the mapped file, at most this many at a time
+    Map(Mmap, usize),
+    // Into the buffer, as with Buffer, but the next chunk is read ahead
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    ReadAhead(&'a mut Vec<u8>, Box<uring::ReadAhead>),
+}
+
+impl<'a, F: Read + Seek> ChunkedReader<'a, F> {
+    pub fn new(file: F, buffer: &'a mut Vec<u8>, verbose: bool) -> Self {
         debug_assert_eq!(
             buffer.len(),
             buffer.capacity(),
@@ -23,14 +65,76 @@ impl<'a> ChunkedReader<'a> {
         );
         ChunkedReader {
             file,
-            buffer,
+            source: Source::Buffer(buffer),
+            aligned: false,
+            chunk: 0..0,
+            verbose,
+        }
+    }
+
+    /// Reads each chunk into the part of `buffer` aligned to
+    /// `DIRECT_ALIGNMENT`, as direct I/O needs. The buffer should be that
+    /// many bytes longer than the chunks to read
+    pub fn aligned(file: F, buffer: &'a mut Vec<u8>, verbose: bool) -> Self {
+        debug_assert!(buffer.len() > DIRECT_ALIGNMENT, "Buffer is too small");
+        ChunkedReader {
+            aligned: true,
+            ..ChunkedReader::new(file, buffer, verbose)
+        }
+    }
+
+    /// Reads through `buffer` as `new` does, but with `ahead` reading the next
+    /// chunk of `file` while the last is being used
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    pub fn read_ahead(
+        file: F,
+        buffer: &'a mut Vec<u8>,
+        ahead: uring::ReadAhead,
+        verbose: bool,
+    ) -> Self {
+        ChunkedReader {
+            file,
+            source: Source::ReadAhead(buffer, Box::new(ahead)),
+            aligned: false,
+            chunk: 0..0,
             verbose,
         }
     }
 
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.source, Source::Map(_, _))
+    }
+
+    /// How big the buffer chunks are read into is, unless they're mapped or
+    /// read ahead, when the buffer can't be handed on
+    pub fn buffer_len(&self) -> Option<usize> {
+        match &self.source {
+            Source::Buffer(buffer) => Some(buffer.len()),
+            _ => None,
+        }
+    }
+
+    /// Swaps the buffer the last chunk was read into for `buffer`, so the
+    /// chunk can be handed on without being copied. Both should be the same
+    /// size. Returns where the chunk is in the buffer swapped out
+    pub fn swap_buffer(&mut self, buffer: &mut Vec<u8>) -> Range<usize> {
+        match &mut self.source {
+            Source::Buffer(own) => {
+                debug_assert_eq!(own.len(), buffer.len());
+                mem::swap(*own, buffer);
+                self.chunk.clone()
+            }
+            Source::Map(_, _) => panic!("Mapped readers don't have a buffer"),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Source::ReadAhead(_, _) => {
+                panic!("Readers reading ahead can't swap their buffer")
+            }
+        }
+    }
+
     pub fn seek_to(&mut self, index: u64) -> io::Result<()> {
         debug_assert!(
-            index < self.file.metadata()?.len(),
+            index < self.file_size()?,
             "index out of bounds of file size",
         );
         self.file.seek(SeekFrom::Start(index))?;
@@ -38,7 +142,10 @@ impl<'a> ChunkedReader<'a> {
     }
 
     pub fn read(&mut self) -> io::Result<Option<&[u8]>> {
-        self.read_up_to(self.file_size()? - 1)
+        match self.file_size()? {
+            0 => Ok(None),
+            size => self.read_up_to(size - 1),
+        }
     }
 
     pub fn read_up_to(
@@ -49,29 +156,117 @@ impl<'a> ChunkedReader<'a> {
             until_index < self.file_size()?,
             "index out of bounds of file size",
         );
-        let bytes_to_end = self.bytes_left(until_index)?;
+        let current_index = self.file.stream_position()?;
+        let bytes_to_end = (until_index + 1).saturating_sub(current_index);
         if bytes_to_end == 0 {
-            Ok(None)
-        } else {
-            let bytes_read = self.file.read(self.buffer)?;
-            if self.verbose {
-                eprintln!(
-                    "Read {} into buffer",
-                    bytesize::to_string(bytes_read as u64, true),
-                );
+            return Ok(None);
+        }
+        match &mut self.source {
+            Source::Buffer(buffer) => {
+                // Buffers can be swapped, so where the aligned part starts
+                // can change between reads
+                let (start, capacity) = match self.aligned {
+                    true => (
+                        buffer.as_ptr().align_offset(DIRECT_ALIGNMENT),
+                        buffer.len() - DIRECT_ALIGNMENT,
+                    ),
+                    false => (0, buffer.len()),
+                };
+                // Don't read past until_index, otherwise bytes belonging to
+                // the next part end up in this one
+                let read_len = min(bytes_to_end, capacity as u64) as usize;
+                let bytes_read =
+                    self.file.read(&mut buffer[start..start + read_len])?;
+                self.chunk = start..start + bytes_read;
+                if bytes_read == 0 {
+                    return Ok(None);
+                }
+                if self.verbose {
+                    eprintln!(
+                        "Read {} into buffer",
+                        bytesize::to_string(bytes_read as u64, true),
+                    );
+                }
+                Ok(Some(&buffer[self.chunk.clone()]))
+            }
+            Source::Map(map, chunk_size) => {
+                // The file may have grown since it was mapped
+                let start = min(current_index, map.len() as u64) as usize;
+                let read_len = min(bytes_to_end, *chunk_size as u64) as usize;
+                let end = min(start + read_len, map.len());
+                if start == end {
+                    return Ok(None);
+                }
+                // Kept in step so the position is right for either source
+                self.file.seek(SeekFrom::Start(end as u64))?;
+                if self.verbose {
+                    eprintln!(
+                        "Read {} from mapped file",
+                        bytesize::to_string((end - start) as u64, true),
+                    );
+                }
+                Ok(Some(&map[start..end]))
+            }
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Source::ReadAhead(buffer, ahead) => {
+                // io_uring reads at most u32::MAX bytes at once
+                let capacity = min(buffer.len() as u64, u32::MAX as u64);
+                let read_len = min(bytes_to_end, capacity);
+                let bytes_read = ahead
+                    .read(buffer, current_index..current_index + read_len)?;
+                self.chunk = 0..bytes_read;
+                if bytes_read == 0 {
+                    return Ok(None);
+                }
+                // Reads go by offset, so the position is kept in step
+                let next_index = current_index + bytes_read as u64;
+                self.file.seek(SeekFrom::Start(next_index))?;
+                // Only up to until_index, as the next part may be read from
+                // somewhere else, or the original truncated
+                let ahead_len =
+                    min((until_index + 1).saturating_sub(next_index), capacity);
+                if ahead_len > 0 {
+                    ahead.start(next_index..next_index + ahead_len)?;
+                }
+                if self.verbose {
+                    eprintln!(
+                        "Read {} into buffer, reading ahead with io_uring",
+                        bytesize::to_string(bytes_read as u64, true),
+                    );
+                }
+                Ok(Some(&buffer[self.chunk.clone()]))
             }
-            Ok(Some(&self.buffer[..bytes_read]))
         }
     }
 
-    // stream_position requires mutability
-    fn bytes_left(&mut self, until_index: u64) -> io::Result<u64> {
-        let current_index = self.file.stream_position()?;
-        Ok(until_index.saturating_sub(current_index))
+    // Only Read and Seek are needed, so this has to seek to the end and back
+    fn file_size(&mut self) -> io::Result<u64> {
+        let position = self.file.stream_position()?;
+        let size = self.file.seek(SeekFrom::End(0))?;
+        self.file.seek(SeekFrom::Start(position))?;
+        Ok(size)
     }
+}
 
-    fn file_size(&self) -> io::Result<u64> {
-        self.file.metadata().map(|md| md.len())
+impl<'a, F: FileHandle> ChunkedReader<'a, F> {
+    /// Hands out slices of `file` mapped into memory, instead of copying it
+    /// through a buffer. The file mustn't be truncated while it's being read.
+    /// If it can't be mapped, `file` is given back so a buffer can be used
+    pub fn mapped(
+        file: F,
+        chunk_size: usize,
+        verbose: bool,
+    ) -> Result<Self, F> {
+        match file.map() {
+            Ok(map) => Ok(ChunkedReader {
+                file,
+                source: Source::Map(map, chunk_size),
+                aligned: false,
+                chunk: 0..0,
+                verbose,
+            }),
+            Err(_) => Err(file),
+        }
     }
 }
 
@@ -97,11 +292,23 @@ pub const fn round_up_div(a: u64, b: u64) -> u64 {
     a / b + (a % b != 0) as u64
 }
 
-//noinspection RsRedundantElse
 pub fn sufficient_disk_space(
     directory: &Path,
     space_needed: u64,
 ) -> Result<bool, &'static str> {
+    disk_info(directory).map(|disk| disk.available_space > space_needed)
+}
+
+/// What's known about the disk a directory is on
+#[derive(Debug, Clone)]
+pub struct DiskInfo {
+    pub available_space: u64,
+    // e.g. ext4, vfat
+    pub file_system: String,
+}
+
+//noinspection RsRedundantElse
+pub fn disk_info(directory: &Path) -> Result<DiskInfo, &'static str> {
     if System::IS_SUPPORTED {
         let directory = directory
             .canonicalize()
@@ -113,17 +320,24 @@ pub fn sufficient_disk_space(
         system
             .disks()
             .iter()
-            .find(|disk| match disk.mount_point().canonicalize() {
+            .filter(|disk| match disk.mount_point().canonicalize() {
                 Ok(disk_path) => directory.starts_with(&disk_path),
                 Err(_) => {
                     err = "unable to check space in appropriate disk";
                     false
                 }
             })
-            .map(|disk| disk.available_space() > space_needed)
+            // Everything is under /, so the longest mount point matching is
+            // the disk actually being used
+            .max_by_key(|disk| disk.mount_point().components().count())
+            .map(|disk| DiskInfo {
+                available_space: disk.available_space(),
+                file_system: String::from_utf8_lossy(disk.file_system())
+                    .into_owned(),
+            })
             .ok_or(err)
     } else {
-        Err("unable to check if there is enough free disk space for this operation")
+        Err("unable to check free disk space on this platform")
     }
 }
 
@@ -141,9 +355,94 @@ pub fn max_buffer_size() -> u64 {
     }
 }
 
+/// How big a buffer `ChunkedReader::aligned` needs to read up to about
+/// `chunk_size` bytes at a time. Direct I/O works in whole blocks, so the
+/// chunk size is rounded down to one, with room to align the chunk
+pub fn aligned_buffer_size(chunk_size: usize) -> usize {
+    let blocks = max(chunk_size / DIRECT_ALIGNMENT, 1);
+    (blocks + 1) * DIRECT_ALIGNMENT
+}
+
 #[cfg(test)]
 mod unit_tests {
     use crate::*;
+    use assert_fs::prelude::*;
+    use assert_fs::NamedTempFile;
+    use std::io::Seek;
+
+    #[test]
+    fn chunked_reader_stops_at_index() {
+        let bytes = (0..=99).collect::<Vec<u8>>();
+        let temp_file = NamedTempFile::new("chunked").unwrap();
+        temp_file.write_binary(&bytes).unwrap();
+        // Buffer smaller than the range being read, which doesn't divide it
+        let mut buffer = vec![0; 4];
+        let file = File::open(temp_file.path()).unwrap();
+        let mut reader = ChunkedReader::new(file, &mut buffer, false);
+
+        reader.seek_to(10).unwrap();
+        let mut read = Vec::new();
+        while let Some(chunk) = reader.read_up_to(28).unwrap() {
+            read.extend_from_slice(chunk);
+        }
+        assert_eq!(read, &bytes[10..=28]);
+    }
+
+    #[test]
+    fn mapped_reader_stops_at_index() {
+        let bytes = (0..=99).collect::<Vec<u8>>();
+        let temp_file = NamedTempFile::new("mapped").unwrap();
+        temp_file.write_binary(&bytes).unwrap();
+        let file = File::open(temp_file.path()).unwrap();
+        let mut reader = ChunkedReader::mapped(file, 4, false)
+            .unwrap_or_else(|_| panic!("Couldn't map file"));
+        assert!(reader.is_mapped());
+
+        reader.seek_to(10).unwrap();
+        let mut read = Vec::new();
+        while let Some(chunk) = reader.read_up_to(28).unwrap() {
+            assert!(chunk.len() <= 4);
+            read.extend_from_slice(chunk);
+        }
+        assert_eq!(read, &bytes[10..=28]);
+        // Reading carries on from the same place either way
+        assert_eq!(reader.file.stream_position().unwrap(), 29);
+        assert_eq!(reader.read().unwrap(), Some(&bytes[29..33]));
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    #[test]
+    fn read_ahead_reader_stops_at_index() {
+        use std::os::unix::io::AsRawFd;
+        let bytes = (0..=99).collect::<Vec<u8>>();
+        let temp_file = NamedTempFile::new("read_ahead").unwrap();
+        temp_file.write_binary(&bytes).unwrap();
+        let file = File::open(temp_file.path()).unwrap();
+        let ahead = match uring::ReadAhead::new(file.as_raw_fd(), 4) {
+            Ok(ahead) => ahead,
+            Err(why) => {
+                eprintln!("Skipped, as io_uring isn't available: {}", why);
+                return;
+            }
+        };
+        let mut buffer = vec![0; 4];
+        let mut reader =
+            ChunkedReader::read_ahead(file, &mut buffer, ahead, false);
+        assert_eq!(reader.buffer_len(), None);
+
+        reader.seek_to(10).unwrap();
+        let mut read = Vec::new();
+        while let Some(chunk) = reader.read_up_to(28).unwrap() {
+            read.extend_from_slice(chunk);
+        }
+        assert_eq!(read, &bytes[10..=28]);
+        assert_eq!(reader.file.stream_position().unwrap(), 29);
+        assert_eq!(reader.read().unwrap(), Some(&bytes[29..33]));
+        assert_eq!(reader.read().unwrap(), Some(&bytes[33..37]));
+        // Moving elsewhere drops what was read ahead
+        reader.seek_to(90).unwrap();
+        assert_eq!(reader.read().unwrap(), Some(&bytes[90..94]));
+    }
 
     #[test]
     fn round_up_division() {
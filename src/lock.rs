@@ -0,0 +1,287 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io, process};
+
+pub const LOCK_EXTENSION: &str = "lock";
+
+/// An exclusive lock on an original file and its parts, so that two runs of
+/// chop or stick can't work on the same set at once. The lock is advisory,
+/// and is released (and its file removed) when this is dropped. Only unix has
+/// locks, so elsewhere nothing stops two runs using the same files
+#[derive(Debug)]
+pub struct SetLock {
+    // The lock for the original file
+    path: PathBuf,
+    // Every lock taken, including the original's
+    held: Vec<Held>,
+}
+
+#[derive(Debug)]
+struct Held {
+    // Only held so the lock lasts as long as this does
+    _file: File,
+    path: PathBuf,
+}
+
+impl SetLock {
+    /// Where the lock for `original_path` lives
+    pub fn path_for<P: AsRef<Path>>(original_path: P) -> PathBuf {
+        let mut os_str = original_path.as_ref().as_os_str().to_owned();
+        os_str.push(format!(".{}", LOCK_EXTENSION));
+        PathBuf::from(os_str)
+    }
+
+    /// Takes the lock for `original_path`. If another process holds it, this
+    /// either fails straight away or blocks until it's released, depending
+    /// on `wait`
+    pub fn acquire<P: AsRef<Path>>(
+        original_path: P,
+        wait: bool,
+    ) -> Result<Self, LockError> {
+        let held = Held::take(SetLock::path_for(original_path), wait)?;
+        Ok(SetLock {
+            path: held.path.clone(),
+            held: vec![held],
+        })
+    }
+
+    /// Also takes the locks for `originals`, such as the files a set of
+    /// parts was chopped from, when they're named after something else
+    pub fn extend<I, P>(
+        &mut self,
+        originals: I,
+        wait: bool,
+    ) -> Result<(), LockError>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut paths = originals
+            .into_iter()
+            .map(SetLock::path_for)
+            .filter(|path| self.held.iter().all(|held| &held.path != path))
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup();
+        for path in paths {
+            match Held::take(path.clone(), false) {
+                Ok(held) => self.held.push(held),
+                Err(LockError::Held(_, _)) if wait => {
+                    // Waiting while holding locks could leave two runs each
+                    // waiting for the other, so everything is let go, then
+                    // taken again in order
+                    let mut all = self
+                        .held
+                        .drain(..)
+                        .map(|held| held.path.clone())
+                        .chain(Some(path))
+                        .collect::<Vec<_>>();
+                    all.sort();
+                    return all.into_iter().try_for_each(|path| {
+                        self.held.push(Held::take(path, true)?);
+                        Ok(())
+                    });
+                }
+                Err(why) => return Err(why),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Held {
+    fn take(path: PathBuf, wait: bool) -> Result<Self, LockError> {
+        let io_error = |why| LockError::Io(path.clone(), why);
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                // Whoever holds the lock wrote their PID in it
+                .truncate(false)
+                .open(&path)
+                .map_err(io_error)?;
+            if !lock(&file, wait).map_err(io_error)? {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                return Err(LockError::Held(path, pid.trim().parse().ok()));
+            }
+            // The last holder removes the file before releasing the lock, so
+            // what was locked might not be at `path` any more
+            if is_file_at(&file, &path) {
+                file.set_len(0)
+                    .and_then(|_| writeln!(file, "{}", process::id()))
+                    .map_err(io_error)?;
+                return Ok(Held { _file: file, path });
+            }
+        }
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        // The lock is released when the file is closed, after this
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Whether the lock was taken, as opposed to being held by someone else
+#[cfg(unix)]
+fn lock(file: &File, wait: bool) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    let operation = match wait {
+        true => libc::LOCK_EX,
+        false => libc::LOCK_EX | libc::LOCK_NB,
+    };
+    loop {
+        // Safe as the file descriptor is valid for as long as `file` is
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(true);
+        }
+        let why = io::Error::last_os_error();
+        match why.kind() {
+            io::ErrorKind::WouldBlock => return Ok(false),
+            io::ErrorKind::Interrupted => continue,
+            _ => return Err(why),
+        }
+    }
+}
+
+// Only unix has advisory locks that are released if the process dies, so runs
+// aren't locked elsewhere: the lock file is made, but anyone can take it
+#[cfg(not(unix))]
+fn lock(_file: &File, _wait: bool) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(unix)]
+fn is_file_at(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(locked), Ok(current)) => {
+            locked.dev() == current.dev() && locked.ino() == current.ino()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_file_at(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    // The lock file, and the PID of the process holding it if known
+    Held(PathBuf, Option<u32>),
+    Io(PathBuf, io::Error),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Held(path, Some(pid)) => write!(
+                f,
+                "Another process (PID {}) is working on these files, as \
+                {} is locked. Use --wait to wait for it to finish",
+                pid,
+                path.to_string_lossy()
+            ),
+            LockError::Held(path, None) => write!(
+                f,
+                "Another process is working on these files, as {} is \
+                locked. Use --wait to wait for it to finish",
+                path.to_string_lossy()
+            ),
+            LockError::Io(path, why) => {
+                write!(f, "Couldn't lock {}: {}", path.to_string_lossy(), why)
+            }
+        }
+    }
+}
+
+impl Error for LockError {}
+
+#[cfg(all(test, unix))]
+mod unit_tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn exclusive() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let original = temp_dir.path().join("original");
+
+        let lock = SetLock::acquire(&original, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(lock.path()).unwrap(),
+            format!("{}\n", process::id())
+        );
+        match SetLock::acquire(&original, false).unwrap_err() {
+            LockError::Held(path, pid) => {
+                assert_eq!(path, SetLock::path_for(&original));
+                assert_eq!(pid, Some(process::id()));
+            }
+            err => panic!("Unexpected error: {}", err),
+        }
+
+        // Waiting gets the lock once it's released
+        let (sender, receiver) = mpsc::channel();
+        let waiter = {
+            let original = original.clone();
+            thread::spawn(move || {
+                let lock = SetLock::acquire(&original, true).unwrap();
+                sender.send(()).unwrap();
+                lock
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(receiver.try_recv().is_err(), "Lock was taken twice");
+        drop(lock);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        let lock = waiter.join().unwrap();
+        assert!(lock.path().exists());
+
+        drop(lock);
+        assert!(!SetLock::path_for(&original).exists());
+    }
+
+    #[test]
+    fn extend() {
+        let temp_dir = assert_fs::TempDir::new().unwrap();
+        let original = temp_dir.path().join("original");
+        let chopped = temp_dir.path().join("chopped");
+
+        let other = SetLock::acquire(&chopped, false).unwrap();
+        let mut lock = SetLock::acquire(&original, false).unwrap();
+        assert!(matches!(
+            lock.extend([&chopped], false),
+            Err(LockError::Held(_, _))
+        ));
+
+        // Waiting lets go of the original's lock until it has both
+        let (sender, receiver) = mpsc::channel();
+        let waiter = thread::spawn(move || {
+            lock.extend([&chopped, &original], true).unwrap();
+            sender.send(()).unwrap();
+            lock
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(receiver.try_recv().is_err(), "Lock was taken twice");
+        drop(other);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        let lock = waiter.join().unwrap();
+        assert_eq!(lock.held.len(), 2);
+
+        drop(lock);
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::filesystem::{Filesystem, OpenMode};
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const HEADER: &str = "chopstick manifest 1";
+pub const MANIFEST_EXTENSION: &str = "manifest";
+
+/// Written alongside the parts by chop, recording the size of each so that
+/// stick doesn't have to assume they're all the same
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Manifest {
+    pub file_size: u64,
+    pub part_sizes: Vec<u64>,
+    // The SHA-256 of each part, in hex, if they were hashed as they were made
+    pub part_hashes: Option<Vec<String>>,
+}
+
+impl Manifest {
+    pub fn from_ranges(ranges: &[Range<u64>]) -> Self {
+        Manifest {
+            file_size: ranges.last().map(|range| range.end).unwrap_or(0),
+            part_sizes: ranges
+                .iter()
+                .map(|range| range.end - range.start)
+                .collect(),
+            part_hashes: None,
+        }
+    }
+
+    /// Where the manifest for `original_path` lives
+    pub fn path_for<P: AsRef<Path>>(original_path: P) -> PathBuf {
+        let mut os_str = original_path.as_ref().as_os_str().to_owned();
+        os_str.push(format!(".{}", MANIFEST_EXTENSION));
+        PathBuf::from(os_str)
+    }
+
+    pub fn read<F: Filesystem>(fs: &F, path: &Path) -> io::Result<Self> {
+        let mut contents = String::new();
+        fs.open(path, OpenMode::Read)?
+            .read_to_string(&mut contents)?;
+        contents.parse()
+    }
+
+    // Fails if there's already a manifest at `path`
+    pub fn write<F: Filesystem>(&self, fs: &F, path: &Path) -> io::Result<()> {
+        fs.open(path, OpenMode::CreateNew)?
+            .write_all(self.to_string().as_bytes())
+    }
+
+    // Swaps the manifest at `path` for this one, writing it alongside first so
+    // that there's always a whole manifest there
+    pub fn replace<F: Filesystem>(
+        &self,
+        fs: &F,
+        path: &Path,
+    ) -> io::Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        self.write(fs, &temp_path)?;
+        fs.rename(&temp_path, path)
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "size {}", self.file_size)?;
+        self.part_sizes
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, size)| {
+                write!(f, "part {} {}", index + 1, size)?;
+                match &self.part_hashes {
+                    Some(hashes) => writeln!(f, " {}", hashes[index]),
+                    None => writeln!(f),
+                }
+            })
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let mut lines = s.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(invalid("not a chopstick manifest"));
+        }
+        let file_size = lines
+            .next()
+            .and_then(|line| line.strip_prefix("size "))
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(|| invalid("missing file size"))?;
+        // Each part's hash is optional, but it's all or none
+        let parts = lines
+            .enumerate()
+            .map(|(index, line)| {
+                let mut words = line.split(' ');
+                match (
+                    words.next(),
+                    words.next(),
+                    words.next(),
+                    words.next(),
+                    words.next(),
+                ) {
+                    (Some("part"), Some(number), Some(size), hash, None)
+                        if number.parse() == Ok(index + 1) =>
+                    {
+                        let size = size
+                            .parse()
+                            .map_err(|_| invalid("bad part size"))?;
+                        match hash {
+                            Some(hash) if !is_sha256(hash) => {
+                                Err(invalid("bad part hash"))
+                            }
+                            _ => Ok((size, hash.map(str::to_owned))),
+                        }
+                    }
+                    _ => Err(invalid("bad part line")),
+                }
+            })
+            .collect::<io::Result<Vec<(u64, Option<String>)>>>()?;
+        let part_sizes =
+            parts.iter().map(|(size, _)| *size).collect::<Vec<_>>();
+        if part_sizes.iter().sum::<u64>() != file_size {
+            return Err(invalid("part sizes don't add up to the file size"));
+        }
+        let part_hashes =
+            match parts.iter().filter(|(_, h)| h.is_some()).count() {
+                0 => None,
+                hashed if hashed == parts.len() => Some(
+                    parts.into_iter().filter_map(|(_, hash)| hash).collect(),
+                ),
+                _ => return Err(invalid("only some parts have hashes")),
+            };
+        Ok(Manifest {
+            file_size,
+            part_sizes,
+            part_hashes,
+        })
+    }
+}
+
+fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn invalid(why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, why)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let manifest = Manifest::from_ranges(&[0..3, 3..6, 6..8]);
+        assert_eq!(manifest.file_size, 8);
+        assert_eq!(manifest.part_sizes, vec![3, 3, 2]);
+        let text = manifest.to_string();
+        assert_eq!(
+            text,
+            "chopstick manifest 1\nsize 8\npart 1 3\npart 2 3\npart 3 2\n"
+        );
+        assert_eq!(text.parse::<Manifest>().unwrap(), manifest);
+    }
+
+    #[test]
+    fn hashes() {
+        let mut manifest = Manifest::from_ranges(&[0..3, 3..5]);
+        manifest.part_hashes = Some(vec!["a".repeat(64), "0".repeat(64)]);
+        let text = manifest.to_string();
+        assert!(text.ends_with(&format!("part 2 2 {}\n", "0".repeat(64))));
+        assert_eq!(text.parse::<Manifest>().unwrap(), manifest);
+        // Either every part has a hash, or none do
+        let mixed = text.replace(&format!(" {}", "a".repeat(64)), "");
+        assert!(mixed.parse::<Manifest>().is_err());
+    }
+
+    #[test]
+    fn invalid_manifests() {
+        [
+            "",
+            "size 8\npart 1 8\n",
+            "chopstick manifest 1\npart 1 8\n",
+            "chopstick manifest 1\nsize 8\npart 2 8\n",
+            "chopstick manifest 1\nsize 8\npart 1 3\npart 2 3\n",
+            "chopstick manifest 1\nsize 8\npart 1 eight\n",
+            "chopstick manifest 1\nsize 8\npart 1 8 nothex\n",
+        ]
+        .into_iter()
+        .for_each(|text| {
+            assert!(text.parse::<Manifest>().is_err(), "parsed {:?}", text)
+        });
+    }
+}
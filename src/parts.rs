@@ -0,0 +1,635 @@
+use crate::filesystem::{Durability, FileHandle, Filesystem, OpenMode};
+use crate::pipeline::Pipeline;
+use crate::split::get_part_path_buf;
+use crate::throttle::RateLimiter;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::ReadAhead;
+use crate::{aligned_buffer_size, digits, ChunkedReader};
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+// Used when nothing better is known about what's being read
+const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024; // 1 MiB
+
+/// Where parts go when a file is chopped: files next to it
+/// ([`FilesystemParts`]), memory ([`MemoryParts`]), or anything else that can
+/// take a stream of bytes per part, like an archive or an object store
+pub trait PartSink {
+    type Writer: Write;
+
+    /// Starts part `number` (counting from 1), which will be `size` bytes
+    fn create_part(
+        &mut self,
+        number: u64,
+        size: u64,
+    ) -> io::Result<Self::Writer>;
+
+    /// Called once every byte of part `number` has been written, after which
+    /// it should be safely stored
+    fn finish_part(
+        &mut self,
+        _number: u64,
+        _writer: Self::Writer,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called instead of `finish_part` if writing part `number` failed with
+    /// `why`, in case the sink knows better why it failed
+    fn abandon_part(
+        &mut self,
+        _number: u64,
+        _writer: Self::Writer,
+        why: io::Error,
+    ) -> io::Error {
+        why
+    }
+}
+
+/// Where parts come from when they're stuck back together
+pub trait PartSource {
+    type Reader: Read + Seek;
+
+    /// Opens part `number` (counting from 1), or gives `None` if there's no
+    /// such part, which is taken to mean the last part has been read
+    fn open_part(&mut self, number: u64) -> io::Result<Option<Self::Reader>>;
+
+    /// How `part` is read in chunks. `buffer` is empty until a chunk has to
+    /// be read into it
+    fn reader<'b>(
+        &self,
+        part: Self::Reader,
+        buffer: &'b mut Vec<u8>,
+    ) -> ChunkedReader<'b, Self::Reader> {
+        if buffer.is_empty() {
+            *buffer = vec![0; DEFAULT_CHUNK_SIZE];
+        }
+        ChunkedReader::new(part, buffer, false)
+    }
+
+    /// Called once every byte of part `number` has been read
+    fn finish_part(
+        &mut self,
+        _number: u64,
+        _reader: Self::Reader,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Why chopping into or sticking from parts stopped
+#[derive(Debug)]
+pub enum PartError<E> {
+    /// Creating or opening part `n` failed
+    Open(u64, io::Error),
+    /// Reading the bytes of part `n` failed: from the original when
+    /// chopping, or from the part when sticking
+    Read(u64, io::Error),
+    /// Writing the bytes of part `n` failed: to the part (including
+    /// finishing it) when chopping, or to the original when sticking
+    Write(u64, io::Error),
+    /// The hook run after each part failed
+    Hook(E),
+}
+
+/// How files are read in chunks: how big they are, and whether they're
+/// mapped into memory or read through a buffer
+#[derive(Debug, Copy, Clone)]
+pub struct ChunkOptions {
+    pub chunk_size: usize,
+    /// Files are mapped into memory when they can be. Mapped files mustn't be
+    /// truncated while they're read
+    pub map: bool,
+    /// Buffers are aligned for direct I/O
+    pub aligned: bool,
+    /// Files are read a chunk ahead with io_uring when they can be (with the
+    /// io-uring feature, on Linux). Reading ahead takes a second buffer
+    pub read_ahead: bool,
+    pub verbose: bool,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        ChunkOptions {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            map: false,
+            aligned: false,
+            read_ahead: false,
+            verbose: false,
+        }
+    }
+}
+
+impl ChunkOptions {
+    /// Reads `file` in chunks, mapped into memory if it should and can be, or
+    /// else through `buffer`, which is allocated if it's empty
+    pub fn reader<'b, F: FileHandle>(
+        &self,
+        file: F,
+        buffer: &'b mut Vec<u8>,
+    ) -> ChunkedReader<'b, F> {
+        let file = match self.map {
+            true => {
+                match ChunkedReader::mapped(file, self.chunk_size, self.verbose)
+                {
+                    Ok(reader) => return reader,
+                    Err(file) => file,
+                }
+            }
+            false => file,
+        };
+        if buffer.is_empty() {
+            let buffer_size = match self.aligned {
+                true => aligned_buffer_size(self.chunk_size),
+                false => self.chunk_size,
+            };
+            *buffer = vec![0; buffer_size];
+            if self.verbose {
+                eprintln!(
+                    "Allocated {} buffer",
+                    bytesize::to_string(buffer_size as u64, true),
+                );
+            }
+        }
+        // Direct I/O would need the reads ahead aligned too
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if self.read_ahead && !self.aligned {
+            match file.raw_fd().map(|fd| ReadAhead::new(fd, buffer.len())) {
+                Some(Ok(ahead)) => {
+                    return ChunkedReader::read_ahead(
+                        file,
+                        buffer,
+                        ahead,
+                        self.verbose,
+                    )
+                }
+                Some(Err(why)) if self.verbose => eprintln!(
+                    "Not reading ahead, as io_uring isn't available: {}",
+                    why
+                ),
+                _ => {}
+            }
+        }
+        match self.aligned {
+            true => ChunkedReader::aligned(file, buffer, self.verbose),
+            false => ChunkedReader::new(file, buffer, self.verbose),
+        }
+    }
+}
+
+/// Copies chunks from a reader to a writer, keeping to a rate limit if
+/// there is one. When pipelined, each chunk is written on another thread
+/// while the next is read, unless the reader is mapped, as then there's no
+/// reading to overlap, or reads ahead itself
+#[derive(Debug, Default)]
+pub struct Copier {
+    rate_limiter: Option<RateLimiter>,
+    pipeline: bool,
+    // Swapped with the reader's buffer when pipelining, so allocated (the
+    // same size) the first time that happens
+    spare: Vec<u8>,
+}
+
+// Which side of a copy failed
+enum Failure {
+    Read(io::Error),
+    Write(io::Error),
+}
+
+impl Failure {
+    fn into_part_error<E>(self, number: u64) -> PartError<E> {
+        match self {
+            Failure::Read(why) => PartError::Read(number, why),
+            Failure::Write(why) => PartError::Write(number, why),
+        }
+    }
+}
+
+impl Copier {
+    pub fn new(rate_limiter: Option<RateLimiter>, pipeline: bool) -> Self {
+        Copier {
+            rate_limiter,
+            pipeline,
+            spare: Vec::new(),
+        }
+    }
+
+    // Copies from where `reader` is up to and including `until_index` (or
+    // the end of the file), giving `writer` back with how many bytes were
+    // copied
+    fn copy<R, W>(
+        &mut self,
+        reader: &mut ChunkedReader<R>,
+        until_index: Option<u64>,
+        mut writer: W,
+    ) -> (W, Result<u64, Failure>)
+    where
+        R: Read + Seek,
+        W: Write + Send + 'static,
+    {
+        let mut copied = 0;
+        match reader.buffer_len() {
+            Some(buffer_len) if self.pipeline => {
+                if self.spare.len() != buffer_len {
+                    self.spare = vec![0; buffer_len];
+                }
+                let mut pipeline = Pipeline::new(
+                    writer,
+                    mem::take(&mut self.spare),
+                    |writer: &mut W, bytes| writer.write_all(bytes),
+                );
+                let read = loop {
+                    let len = match next_chunk(reader, until_index) {
+                        Ok(Some(bytes)) => bytes.len() as u64,
+                        Ok(None) => break Ok(()),
+                        Err(why) => break Err(why),
+                    };
+                    if !pipeline.send(reader) {
+                        break Ok(());
+                    }
+                    copied += len;
+                    if let Some(rate_limiter) = &mut self.rate_limiter {
+                        rate_limiter.consume(len);
+                    }
+                };
+                let (writer, written, spare) = pipeline.finish();
+                self.spare = spare;
+                // The write failing is why reading stopped, if it did
+                let result = match (written, read) {
+                    (Err(why), _) => Err(Failure::Write(why)),
+                    (_, Err(why)) => Err(Failure::Read(why)),
+                    _ => Ok(copied),
+                };
+                (writer, result)
+            }
+            _ => loop {
+                let bytes = match next_chunk(reader, until_index) {
+                    Ok(Some(bytes)) => bytes,
+                    Ok(None) => break (writer, Ok(copied)),
+                    Err(why) => break (writer, Err(Failure::Read(why))),
+                };
+                if let Err(why) = writer.write_all(bytes) {
+                    break (writer, Err(Failure::Write(why)));
+                }
+                copied += bytes.len() as u64;
+                if let Some(rate_limiter) = &mut self.rate_limiter {
+                    rate_limiter.consume(bytes.len() as u64);
+                }
+            },
+        }
+    }
+}
+
+fn next_chunk<'r, R: Read + Seek>(
+    reader: &'r mut ChunkedReader<R>,
+    until_index: Option<u64>,
+) -> io::Result<Option<&'r [u8]>> {
+    match until_index {
+        Some(until_index) => reader.read_up_to(until_index),
+        None => reader.read(),
+    }
+}
+
+/// Chops what `reader` reads into `parts`, numbered ranges of it made in the
+/// order given, written to `sink`. Empty ranges are skipped. `after_part` is
+/// run once each part is finished, e.g. to truncate the original. Returns
+/// how many bytes were copied
+pub fn chop_into<R, S, E, I, A>(
+    reader: &mut ChunkedReader<R>,
+    parts: I,
+    sink: &mut S,
+    copier: &mut Copier,
+    mut after_part: A,
+) -> Result<u64, PartError<E>>
+where
+    R: Read + Seek,
+    S: PartSink,
+    S::Writer: Send + 'static,
+    I: IntoIterator<Item = (u64, Range<u64>)>,
+    A: FnMut(&mut ChunkedReader<R>, u64, Range<u64>) -> Result<(), E>,
+{
+    let mut copied = 0;
+    for (number, range) in parts.into_iter().filter(|(_, r)| !r.is_empty()) {
+        let writer = sink
+            .create_part(number, range.end - range.start)
+            .map_err(|why| PartError::Open(number, why))?;
+        reader
+            .seek_to(range.start)
+            .map_err(|why| PartError::Read(number, why))?;
+        let (writer, result) = copier.copy(reader, Some(range.end - 1), writer);
+        match result {
+            Ok(bytes) => copied += bytes,
+            Err(Failure::Write(why)) => {
+                let why = sink.abandon_part(number, writer, why);
+                return Err(PartError::Write(number, why));
+            }
+            Err(failure) => return Err(failure.into_part_error(number)),
+        }
+        sink.finish_part(number, writer)
+            .map_err(|why| PartError::Write(number, why))?;
+        after_part(reader, number, range).map_err(PartError::Hook)?;
+    }
+    Ok(copied)
+}
+
+/// Sticks every part in `source` together, in order, onto the end of
+/// `original`. `after_part` is run once each part is finished with, e.g. to
+/// delete it. Gives back `original` with how many bytes were copied
+pub fn stick_from<S, W, E, A>(
+    source: &mut S,
+    mut original: W,
+    copier: &mut Copier,
+    mut after_part: A,
+) -> Result<(W, u64), PartError<E>>
+where
+    S: PartSource,
+    W: Write + Send + 'static,
+    A: FnMut(&mut W, u64) -> Result<(), E>,
+{
+    // Only allocated if a part has to be read through it
+    let mut buffer = Vec::new();
+    let mut copied = 0;
+    let mut number = 1;
+    while let Some(part) = source
+        .open_part(number)
+        .map_err(|why| PartError::Open(number, why))?
+    {
+        let mut reader = source.reader(part, &mut buffer);
+        let (writer, result) = copier.copy(&mut reader, None, original);
+        original = writer;
+        copied += result.map_err(|failure| failure.into_part_error(number))?;
+        source
+            .finish_part(number, reader.file)
+            .map_err(|why| PartError::Read(number, why))?;
+        after_part(&mut original, number).map_err(PartError::Hook)?;
+        number += 1;
+    }
+    original
+        .flush()
+        .map_err(|why| PartError::Write(number - 1, why))?;
+    Ok((original, copied))
+}
+
+/// Parts as files, by default next to the original and named like chop and
+/// stick name them
+#[derive(Debug)]
+pub struct FilesystemParts<'a, F: Filesystem> {
+    fs: &'a F,
+    paths: Vec<PathBuf>,
+    chunks: ChunkOptions,
+    durability: Durability,
+    drop_cache: bool,
+}
+
+impl<'a, F: Filesystem> FilesystemParts<'a, F> {
+    pub fn new<P: AsRef<Path>>(fs: &'a F, original: P, num_parts: u64) -> Self {
+        let width = digits(num_parts);
+        let paths = (1..=num_parts)
+            .map(|number| get_part_path_buf(original.as_ref(), number, width))
+            .collect();
+        FilesystemParts::from_paths(fs, paths)
+    }
+
+    /// Parts at `paths`, numbered in order from 1
+    pub fn from_paths(fs: &'a F, paths: Vec<PathBuf>) -> Self {
+        FilesystemParts {
+            fs,
+            paths,
+            chunks: ChunkOptions::default(),
+            durability: Durability::Full,
+            drop_cache: false,
+        }
+    }
+
+    /// How parts are read when they're stuck
+    pub fn with_chunks(mut self, chunks: ChunkOptions) -> Self {
+        self.chunks = chunks;
+        self
+    }
+
+    /// How safely parts are stored once they're made
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Whether parts are dropped from the page cache once they're finished
+    /// with
+    pub fn with_drop_cache(mut self, drop_cache: bool) -> Self {
+        self.drop_cache = drop_cache;
+        self
+    }
+
+    pub fn part_path(&self, number: u64) -> &Path {
+        &self.paths[number as usize - 1]
+    }
+}
+
+impl<'a, F: Filesystem> PartSink for FilesystemParts<'a, F> {
+    type Writer = F::File;
+
+    // Parts that already exist are never overwritten
+    fn create_part(&mut self, number: u64, _size: u64) -> io::Result<F::File> {
+        let path = self.part_path(number);
+        let file = self.fs.open(path, OpenMode::CreateNew)?;
+        if self.chunks.verbose {
+            // Extra newline for some nice spacing
+            eprintln!("\nCreated {}", path.to_string_lossy());
+        }
+        Ok(file)
+    }
+
+    fn finish_part(
+        &mut self,
+        number: u64,
+        mut file: F::File,
+    ) -> io::Result<()> {
+        if self.durability >= Durability::Data {
+            file.sync_all()?;
+        }
+        // Only advice, so it doesn't matter if it's not taken
+        if self.drop_cache {
+            let _ = file.drop_cache(0, 0);
+        }
+        // The part's name has to survive a power cut too
+        if self.durability == Durability::Full {
+            let folder = match self.part_path(number).parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            self.fs.sync_directory(folder)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, F: Filesystem> PartSource for FilesystemParts<'a, F> {
+    type Reader = F::File;
+
+    fn open_part(&mut self, number: u64) -> io::Result<Option<F::File>> {
+        let path = match self.paths.get(number as usize - 1) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let file = self.fs.open(path, OpenMode::Read)?;
+        if self.chunks.verbose {
+            // Extra newline for some nice spacing
+            eprintln!("\nReading from {}", path.to_string_lossy());
+        }
+        Ok(Some(file))
+    }
+
+    fn reader<'b>(
+        &self,
+        part: F::File,
+        buffer: &'b mut Vec<u8>,
+    ) -> ChunkedReader<'b, F::File> {
+        self.chunks.reader(part, buffer)
+    }
+
+    fn finish_part(&mut self, _number: u64, part: F::File) -> io::Result<()> {
+        // Only advice, so it doesn't matter if it's not taken
+        if self.drop_cache {
+            let _ = part.drop_cache(0, 0);
+        }
+        Ok(())
+    }
+}
+
+/// Parts kept in memory, in order
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MemoryParts {
+    pub parts: Vec<Vec<u8>>,
+}
+
+impl PartSink for MemoryParts {
+    type Writer = Vec<u8>;
+
+    fn create_part(&mut self, number: u64, size: u64) -> io::Result<Vec<u8>> {
+        debug_assert_eq!(number, self.parts.len() as u64 + 1);
+        Ok(Vec::with_capacity(size as usize))
+    }
+
+    fn finish_part(&mut self, _number: u64, part: Vec<u8>) -> io::Result<()> {
+        self.parts.push(part);
+        Ok(())
+    }
+}
+
+impl PartSource for MemoryParts {
+    type Reader = Cursor<Vec<u8>>;
+
+    fn open_part(&mut self, number: u64) -> io::Result<Option<Self::Reader>> {
+        let part = (number as usize)
+            .checked_sub(1)
+            .and_then(|index| self.parts.get(index));
+        Ok(part.cloned().map(Cursor::new))
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::filesystem::MemoryFilesystem;
+    use crate::split::Split;
+
+    fn numbered(ranges: Vec<Range<u64>>) -> Vec<(u64, Range<u64>)> {
+        (1..).zip(ranges).collect()
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        let bytes = (0..=99).collect::<Vec<u8>>();
+        let ranges = Split::from_num_parts(100, 3).unwrap().part_ranges(100);
+        let mut parts = MemoryParts::default();
+        let mut buffer = vec![0; 8];
+        let mut reader =
+            ChunkedReader::new(Cursor::new(&bytes), &mut buffer, false);
+        let copied = chop_into(
+            &mut reader,
+            numbered(ranges.clone()),
+            &mut parts,
+            &mut Copier::default(),
+            |_, _, _| Ok::<_, io::Error>(()),
+        )
+        .unwrap();
+        assert_eq!(copied, 100);
+        assert_eq!(parts.parts.len(), 3);
+        assert_eq!(parts.parts[0], &bytes[..ranges[0].end as usize]);
+
+        let (original, copied) = stick_from(
+            &mut parts,
+            Vec::new(),
+            &mut Copier::default(),
+            |_, _| Ok::<_, io::Error>(()),
+        )
+        .unwrap();
+        assert_eq!(copied, 100);
+        assert_eq!(original, bytes);
+    }
+
+    #[test]
+    fn filesystem_round_trip() {
+        let bytes = (0..=99).collect::<Vec<u8>>();
+        let ranges = Split::from_num_parts(100, 10).unwrap().part_ranges(100);
+        let fs = MemoryFilesystem::new();
+        fs.insert("original", &bytes);
+        let original = fs.open(Path::new("original"), OpenMode::Read).unwrap();
+        let mut buffer = vec![0; 8];
+        let mut reader = ChunkedReader::new(original, &mut buffer, false);
+        let mut parts = FilesystemParts::new(&fs, "original", 10);
+        // Backwards, as chop makes them, with a pipeline
+        let mut copier = Copier::new(None, true);
+        let mut finished = Vec::new();
+        chop_into(
+            &mut reader,
+            numbered(ranges.clone()).into_iter().rev(),
+            &mut parts,
+            &mut copier,
+            |_, number, _| {
+                finished.push(number);
+                Ok::<_, io::Error>(())
+            },
+        )
+        .unwrap();
+        assert_eq!(finished, (1..=10).rev().collect::<Vec<_>>());
+        assert_eq!(parts.part_path(1), Path::new("original.p01"));
+        assert_eq!(fs.contents("original.p10").unwrap(), &bytes[90..]);
+        // Existing parts aren't overwritten
+        let again = chop_into(
+            &mut reader,
+            numbered(ranges),
+            &mut parts,
+            &mut copier,
+            |_, _, _| Ok::<_, io::Error>(()),
+        );
+        assert!(matches!(again, Err(PartError::Open(1, _))));
+
+        let (original, _) =
+            stick_from(&mut parts, Vec::new(), &mut copier, |_, _| {
+                Ok::<_, io::Error>(())
+            })
+            .unwrap();
+        assert_eq!(original, bytes);
+    }
+
+    #[test]
+    fn write_errors_name_the_part() {
+        let fs = MemoryFilesystem::with_capacity(150);
+        fs.insert("original", &[1; 100]);
+        let original = fs.open(Path::new("original"), OpenMode::Read).unwrap();
+        let mut buffer = vec![0; 8];
+        let mut reader = ChunkedReader::new(original, &mut buffer, false);
+        let mut parts = FilesystemParts::new(&fs, "original", 2);
+        let result = chop_into(
+            &mut reader,
+            vec![(1, 0..50), (2, 50..100)],
+            &mut parts,
+            &mut Copier::default(),
+            |_, _, _| Ok::<_, io::Error>(()),
+        );
+        assert!(matches!(result, Err(PartError::Write(2, _))));
+    }
+}
@@ -0,0 +1,141 @@
+use crate::ChunkedReader;
+use std::io::{Read, Seek};
+use std::ops::Range;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Writes chunks on another thread, so that the next chunk can be read while
+/// the last is still being written. Buffers are passed back and forth between
+/// the two threads, so only two are ever in use: the reader's, and a spare
+pub struct Pipeline<W, E> {
+    // Buffers to write, and which of their bytes to write
+    chunks: Sender<(Vec<u8>, Range<usize>)>,
+    // Buffers that have been written
+    spares: Receiver<Vec<u8>>,
+    // Set once writing has failed, after which chunks are only sent back
+    failed: Arc<AtomicBool>,
+    writer: JoinHandle<(W, Result<(), E>)>,
+}
+
+impl<W, E> Pipeline<W, E>
+where
+    W: Send + 'static,
+    E: Send + 'static,
+{
+    /// Starts a thread writing chunks to `writer` with `write`. `spare` is
+    /// swapped with the reader's buffer to hand over the first chunk
+    pub fn new<F>(mut writer: W, spare: Vec<u8>, mut write: F) -> Self
+    where
+        F: FnMut(&mut W, &[u8]) -> Result<(), E> + Send + 'static,
+    {
+        let (chunk_sender, chunks) = mpsc::channel::<(Vec<u8>, Range<usize>)>();
+        let (spare_sender, spares) = mpsc::channel();
+        spare_sender.send(spare).expect("Receiver should exist");
+        let failed = Arc::new(AtomicBool::new(false));
+        let writer_failed = Arc::clone(&failed);
+        let writer = thread::spawn(move || {
+            let mut result = Ok(());
+            for (buffer, range) in chunks {
+                if result.is_ok() {
+                    result = write(&mut writer, &buffer[range]);
+                    writer_failed.store(result.is_err(), Ordering::Release);
+                }
+                // Only fails if the pipeline was dropped without finishing
+                let _ = spare_sender.send(buffer);
+            }
+            (writer, result)
+        });
+        Pipeline {
+            chunks: chunk_sender,
+            spares,
+            failed,
+            writer,
+        }
+    }
+
+    /// Hands the chunk `reader` just read over to be written, waiting for a
+    /// spare buffer to swap it for. Returns false if writing has failed,
+    /// which `finish` gives the reason for
+    pub fn send<F: Read + Seek>(
+        &mut self,
+        reader: &mut ChunkedReader<F>,
+    ) -> bool {
+        if self.failed.load(Ordering::Acquire) {
+            return false;
+        }
+        // The writer only stops once the pipeline's finished, so these can't
+        // fail
+        let mut buffer = self.spares.recv().expect("Writer should be running");
+        let range = reader.swap_buffer(&mut buffer);
+        self.chunks
+            .send((buffer, range))
+            .expect("Writer should be running");
+        true
+    }
+
+    /// Waits for every chunk sent to be written, giving back the writer, the
+    /// first error writing (if any), and the spare buffer
+    pub fn finish(self) -> (W, Result<(), E>, Vec<u8>) {
+        drop(self.chunks);
+        let (writer, result) = match self.writer.join() {
+            Ok(finished) => finished,
+            Err(why) => panic::resume_unwind(why),
+        };
+        let spare = self
+            .spares
+            .try_recv()
+            .expect("Every buffer should have been sent back");
+        (writer, result, spare)
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::filesystem::{Filesystem, MemoryFilesystem, OpenMode};
+    use std::io::Write;
+    use std::path::Path;
+
+    fn copy(fail_after: usize) -> (Vec<u8>, Result<(), usize>) {
+        let bytes = (0..=99).collect::<Vec<u8>>();
+        let fs = MemoryFilesystem::new();
+        fs.insert("original", &bytes);
+        let original = fs.open(Path::new("original"), OpenMode::Read).unwrap();
+        let mut buffer = vec![0; 8];
+        let mut reader = ChunkedReader::new(original, &mut buffer, false);
+
+        let mut pipeline = Pipeline::new(
+            Vec::new(),
+            vec![0; 8],
+            move |written: &mut Vec<u8>, bytes| match written.len() {
+                len if len >= fail_after => Err(len),
+                _ => written.write_all(bytes).map_err(|_| 0),
+            },
+        );
+        while reader.read().unwrap().is_some() {
+            if !pipeline.send(&mut reader) {
+                break;
+            }
+        }
+        let (written, result, spare) = pipeline.finish();
+        assert_eq!(spare.len(), 8);
+        (written, result)
+    }
+
+    #[test]
+    fn writes_in_order() {
+        let (written, result) = copy(usize::MAX);
+        assert!(result.is_ok());
+        assert_eq!(written, (0..=99).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn stops_at_first_error() {
+        let (written, result) = copy(40);
+        assert_eq!(result, Err(40));
+        assert_eq!(written, (0..40).collect::<Vec<u8>>());
+    }
+}
@@ -0,0 +1,223 @@
+use crate::digits;
+use crate::manifest::Manifest;
+use crate::split::get_part_path_buf;
+use os_str_bytes::RawOsStr;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Scripts chop can leave with the parts, so that they can be stuck back
+/// together (and checked) on a machine without chopstick
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RejoinScript {
+    // Only needs cat, cut, and sha256sum
+    Posix,
+    PowerShell,
+}
+
+impl RejoinScript {
+    pub const NAMES: [&'static str; 2] = ["sh", "powershell"];
+
+    pub const fn extension(&self) -> &'static str {
+        match self {
+            RejoinScript::Posix => "sh",
+            RejoinScript::PowerShell => "ps1",
+        }
+    }
+
+    /// Where the script for `original_path`'s parts goes
+    pub fn path_for<P: AsRef<Path>>(&self, original_path: P) -> PathBuf {
+        let mut os_str = original_path.as_ref().as_os_str().to_owned();
+        os_str.push(format!(".rejoin.{}", self.extension()));
+        PathBuf::from(os_str)
+    }
+
+    /// Writes the script that sticks together the parts `manifest` lists, as
+    /// chop names them after `file_name`. The script lives in the same folder
+    /// as the parts. Gives `None` if the manifest doesn't have the parts'
+    /// hashes, as a script that can't check them isn't worth having
+    pub fn generate(
+        &self,
+        file_name: &OsStr,
+        manifest: &Manifest,
+    ) -> Option<Vec<u8>> {
+        let hashes = manifest.part_hashes.as_ref()?;
+        let width = digits(hashes.len() as u64);
+        let parts = hashes
+            .iter()
+            .zip(1..)
+            .map(|(hash, number)| {
+                let path = get_part_path_buf(file_name, number, width);
+                (path.into_os_string(), hash.as_str())
+            })
+            .collect::<Vec<_>>();
+        Some(match self {
+            RejoinScript::Posix => posix_script(file_name, &parts),
+            RejoinScript::PowerShell => powershell_script(file_name, &parts),
+        })
+    }
+}
+
+impl FromStr for RejoinScript {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sh" => Ok(RejoinScript::Posix),
+            "powershell" => Ok(RejoinScript::PowerShell),
+            _ => Err(format!("{:?} isn't a kind of rejoin script", s)),
+        }
+    }
+}
+
+fn posix_script(file_name: &OsStr, parts: &[(OsString, &str)]) -> Vec<u8> {
+    let mut script = SH_HEADER.as_bytes().to_vec();
+    script.extend_from_slice(b"file=");
+    script.extend(sh_quote(file_name));
+    script.extend_from_slice(SH_CHECK.as_bytes());
+    parts.iter().for_each(|(part, hash)| {
+        script.extend_from_slice(b"check ");
+        script.extend(sh_quote(part));
+        script.extend_from_slice(format!(" {}\n", hash).as_bytes());
+    });
+    script.extend_from_slice(SH_EXISTS.as_bytes());
+    script.extend_from_slice(b"cat --");
+    parts.iter().for_each(|(part, _)| {
+        script.push(b' ');
+        script.extend(sh_quote(part));
+    });
+    script.extend_from_slice(b" > \"$file\"\necho \"Rejoined $file\"\n");
+    script
+}
+
+const SH_HEADER: &str = r#"#!/bin/sh
+# Sticks the parts made by chop back together, checking each first.
+# Only needs cat, cut, and sha256sum
+set -eu
+cd "$(dirname "$0")"
+"#;
+
+const SH_CHECK: &str = r#"
+
+check() {
+    if [ ! -f "$1" ]; then
+        echo "Missing part $1" >&2
+        exit 1
+    fi
+    if [ "$(sha256sum < "$1" | cut -d ' ' -f 1)" != "$2" ]; then
+        echo "Part $1 is corrupt" >&2
+        exit 1
+    fi
+}
+
+"#;
+
+const SH_EXISTS: &str = r#"
+if [ -e "$file" ]; then
+    echo "$file already exists" >&2
+    exit 1
+fi
+"#;
+
+fn powershell_script(file_name: &OsStr, parts: &[(OsString, &str)]) -> Vec<u8> {
+    let mut script = String::from(PS_HEADER);
+    script += &format!("$file = {}\n", ps_quote(file_name));
+    // The leading commas stop each part's pair being flattened into the list
+    script += "$parts = @(\n";
+    parts.iter().for_each(|(part, hash)| {
+        script += &format!("    ,@({}, '{}')\n", ps_quote(part), hash);
+    });
+    script += ")\n";
+    script += PS_JOIN;
+    script.into_bytes()
+}
+
+const PS_HEADER: &str = r#"# Rejoins the parts made by chop, checking each first
+$ErrorActionPreference = 'Stop'
+Set-Location -LiteralPath $PSScriptRoot
+"#;
+
+const PS_JOIN: &str = r#"
+foreach ($part in $parts) {
+    if (-not (Test-Path -LiteralPath $part[0])) {
+        throw "Missing part $($part[0])"
+    }
+    $hash = (Get-FileHash -Algorithm SHA256 -LiteralPath $part[0]).Hash
+    if ($hash -ne $part[1]) {
+        throw "Part $($part[0]) is corrupt"
+    }
+}
+if (Test-Path -LiteralPath $file) {
+    throw "$file already exists"
+}
+$out = [IO.File]::Open((Join-Path $PSScriptRoot $file), 'CreateNew')
+try {
+    foreach ($part in $parts) {
+        $in = [IO.File]::OpenRead((Join-Path $PSScriptRoot $part[0]))
+        try { $in.CopyTo($out) } finally { $in.Dispose() }
+    }
+} finally {
+    $out.Dispose()
+}
+Write-Output "Rejoined $file"
+"#;
+
+// Single quotes keep everything as it is, apart from single quotes, which
+// have to end the quoting, be escaped, and start it again
+fn sh_quote(s: &OsStr) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    RawOsStr::new(s)
+        .as_raw_bytes()
+        .iter()
+        .for_each(|&b| match b {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            b => quoted.push(b),
+        });
+    quoted.push(b'\'');
+    quoted
+}
+
+// PowerShell escapes a single quote in single quotes by doubling it. It only
+// runs on Windows in practice, where names are always Unicode
+fn ps_quote(s: &OsStr) -> String {
+    format!("'{}'", s.to_string_lossy().replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn scripts() {
+        let mut manifest = Manifest::from_ranges(&[0..3, 3..5]);
+        let file_name = OsStr::new("it's.iso");
+        assert_eq!(RejoinScript::Posix.generate(file_name, &manifest), None);
+
+        manifest.part_hashes = Some(vec!["a".repeat(64), "b".repeat(64)]);
+        let script = RejoinScript::Posix.generate(file_name, &manifest);
+        let script = String::from_utf8(script.unwrap()).unwrap();
+        assert!(script.contains("\nfile='it'\\''s.iso'\n"));
+        assert!(script
+            .contains(&format!("check 'it'\\''s.iso.p2' {}", "b".repeat(64))));
+        let cat = "cat -- 'it'\\''s.iso.p1' 'it'\\''s.iso.p2' >";
+        assert!(script.contains(cat));
+
+        let script = RejoinScript::PowerShell.generate(file_name, &manifest);
+        let script = String::from_utf8(script.unwrap()).unwrap();
+        assert!(script.contains("$file = 'it''s.iso'\n"));
+        assert!(script
+            .contains(&format!(",@('it''s.iso.p1', '{}')", "a".repeat(64))));
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(
+            RejoinScript::Posix.path_for("dir/a.iso"),
+            Path::new("dir/a.iso.rejoin.sh")
+        );
+        assert_eq!(
+            RejoinScript::PowerShell.path_for("a.iso"),
+            Path::new("a.iso.rejoin.ps1")
+        );
+    }
+}
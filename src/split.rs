@@ -0,0 +1,341 @@
+use crate::{round_up_div, ContentDefinedChunker, EXTENSION_PREFIX};
+use std::cmp::min;
+use std::error::Error;
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::{fmt, io};
+
+pub type Result<T, E = SplitError> = std::result::Result<T, E>;
+
+/// Why a file can't be split as asked
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SplitError {
+    PartSizeTooLarge,
+    NumPartsTooLarge,
+}
+
+impl fmt::Display for SplitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SplitError::*;
+        match self {
+            PartSizeTooLarge => {
+                write!(f, "Part size too large. File wouldn't be split")
+            }
+            NumPartsTooLarge => write!(
+                f,
+                "Number of parts too large. Each part would be less than 1 byte"
+            ),
+        }
+    }
+}
+
+impl Error for SplitError {}
+
+/// How a file is divided into parts
+#[derive(Debug, Copy, Clone)]
+pub enum Chunking {
+    Split(Split),
+    Balanced(Balanced),
+    ContentDefined(ContentDefinedChunker),
+}
+
+impl Chunking {
+    // The most bytes any one part can hold
+    pub const fn max_part_size(&self) -> u64 {
+        match self {
+            Chunking::Split(split) => split.part_size,
+            Chunking::Balanced(balanced) => balanced.part_size,
+            Chunking::ContentDefined(chunker) => chunker.max_size,
+        }
+    }
+
+    // Content-defined chunking has to read the whole file to find out where
+    // the parts start and end
+    pub fn part_ranges<R: Read>(
+        &self,
+        file: R,
+        file_size: u64,
+    ) -> io::Result<Vec<Range<u64>>> {
+        match self {
+            Chunking::Split(split) => Ok(split.part_ranges(file_size)),
+            Chunking::Balanced(balanced) => Ok(balanced.part_ranges(file_size)),
+            Chunking::ContentDefined(chunker) => chunker.part_ranges(file),
+        }
+    }
+}
+
+/// Fixed size parts, with only the last allowed to be smaller
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Split {
+    pub part_size: u64,
+    pub num_parts: u64,
+}
+
+impl Split {
+    pub const fn from_part_size(
+        file_size: u64,
+        part_size: u64,
+    ) -> Result<Self> {
+        if part_size >= file_size {
+            Err(SplitError::PartSizeTooLarge)
+        } else {
+            let (part_size, num_parts) =
+                Split::closest_factors_to(file_size, part_size);
+            Ok(Split {
+                part_size,
+                num_parts,
+            })
+        }
+    }
+
+    pub const fn from_num_parts(
+        file_size: u64,
+        num_parts: u64,
+    ) -> Result<Self> {
+        if num_parts >= file_size {
+            Err(SplitError::NumPartsTooLarge)
+        } else {
+            let (_, part_size) =
+                Split::closest_factors_to(file_size, num_parts);
+            // When the file size is a multiple of the part size, the number
+            // of parts from closest_factors_to would leave the last one empty
+            let num_parts = round_up_div(file_size, part_size);
+            Ok(Split {
+                part_size,
+                num_parts,
+            })
+        }
+    }
+
+    /// The byte range of each part in order
+    pub fn part_ranges(&self, file_size: u64) -> Vec<Range<u64>> {
+        (0..self.num_parts)
+            .map(|part| {
+                let start = part * self.part_size;
+                start..min(start + self.part_size, file_size)
+            })
+            .collect()
+    }
+
+    const fn closest_factors_to(target: u64, divisor: u64) -> (u64, u64) {
+        let factor_two = round_up_div(target, divisor);
+        /*
+        In the edge case where the user's choice (`divisor`) would result in
+        an empty part (see test `disobey` below), the expression
+        `(divisor - 1).saturating_sub(target / factor_two)`
+        will be >0, thus disregarding their choice to reduce the error in
+        reproducing the `target`
+        */
+        let factor_one =
+            divisor - (divisor - 1).saturating_sub(target / factor_two);
+        (factor_one, factor_two)
+    }
+}
+
+/// Exactly the number of parts asked for, with the remainder spread over the
+/// first few so that sizes differ by at most one byte
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Balanced {
+    // Size of the largest parts
+    pub part_size: u64,
+    pub num_parts: u64,
+    // How many parts are part_size, the rest are one byte smaller
+    pub larger_parts: u64,
+}
+
+impl Balanced {
+    pub const fn new(file_size: u64, num_parts: u64) -> Result<Self> {
+        if num_parts < 2 {
+            Err(SplitError::PartSizeTooLarge)
+        } else if num_parts > file_size {
+            Err(SplitError::NumPartsTooLarge)
+        } else {
+            let remainder = file_size % num_parts;
+            Ok(Balanced {
+                part_size: round_up_div(file_size, num_parts),
+                num_parts,
+                larger_parts: if remainder == 0 {
+                    num_parts
+                } else {
+                    remainder
+                },
+            })
+        }
+    }
+
+    /// The byte range of each part in order
+    pub fn part_ranges(&self, file_size: u64) -> Vec<Range<u64>> {
+        let mut start = 0;
+        let ranges = (0..self.num_parts)
+            .map(|part| {
+                let size = if part < self.larger_parts {
+                    self.part_size
+                } else {
+                    self.part_size - 1
+                };
+                start += size;
+                start - size..start
+            })
+            .collect::<Vec<_>>();
+        debug_assert_eq!(start, file_size, "parts should cover the file");
+        ranges
+    }
+}
+
+/// The path of part number `index`, zero padded to `width` digits
+pub fn get_part_path_buf<P: AsRef<Path>>(
+    original_path: P,
+    index: u64,
+    width: usize,
+) -> PathBuf {
+    let mut os_str = original_path.as_ref().as_os_str().to_owned();
+    os_str.push(format!(".{}{:0width$}", EXTENSION_PREFIX, index));
+    PathBuf::from(os_str)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    const PART_SIZE_DATA: [(u64, u64, u64); 5] = [
+        (1000, 50, 20),
+        (1024, 49, 21),
+        (12, 5, 3),
+        (603, 47, 13),
+        (156, 79, 2),
+    ];
+
+    const NUM_PARTS_DATA: [(u64, u64, u64); 5] = [
+        (1000, 20, 50),
+        (1024, 21, 49),
+        (12, 3, 4),
+        (603, 13, 47),
+        (156, 2, 78),
+    ];
+
+    #[test]
+    fn split_from_part_size() {
+        PART_SIZE_DATA.into_iter().for_each(
+            |(file_size, part_size, num_parts)| {
+                let split =
+                    Split::from_part_size(file_size, part_size).unwrap();
+                assert_eq!(
+                    split,
+                    Split {
+                        part_size,
+                        num_parts
+                    },
+                    "Split calculation mismatch for file size {}",
+                    file_size,
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn split_from_part_size_err() {
+        let err = Split::from_part_size(10, 10).unwrap_err();
+        assert!(matches!(err, SplitError::PartSizeTooLarge));
+        let err = Split::from_part_size(10, 100).unwrap_err();
+        assert!(matches!(err, SplitError::PartSizeTooLarge));
+    }
+
+    #[test]
+    fn split_from_num_parts() {
+        NUM_PARTS_DATA.into_iter().for_each(
+            |(file_size, num_parts, part_size)| {
+                let split = Split::from_num_parts(file_size, num_parts)
+                    .expect("Unexpected error");
+                assert_eq!(
+                    split,
+                    Split {
+                        part_size,
+                        num_parts
+                    },
+                    "Split calculation mismatch for file size {}",
+                    file_size,
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn split_from_num_parts_err() {
+        let err = Split::from_num_parts(10, 10).unwrap_err();
+        assert!(matches!(err, SplitError::NumPartsTooLarge));
+        let err = Split::from_num_parts(10, 100).unwrap_err();
+        assert!(matches!(err, SplitError::NumPartsTooLarge));
+    }
+
+    #[test]
+    fn split_part_ranges() {
+        let split = Split::from_part_size(100, 15).unwrap();
+        let ranges = split.part_ranges(100);
+        assert_eq!(ranges.len(), 7);
+        assert_eq!(ranges[0], 0..15);
+        assert_eq!(ranges[6], 90..100);
+    }
+
+    #[test]
+    fn balanced_part_ranges() {
+        let balanced = Balanced::new(512000, 986).unwrap();
+        let ranges = balanced.part_ranges(512000);
+        assert_eq!(ranges.len(), 986);
+        assert_eq!(ranges.last().unwrap().end, 512000);
+        ranges.iter().for_each(|range| {
+            let size = range.end - range.start;
+            assert!(size == 519 || size == 520, "part of {} bytes", size);
+        });
+
+        let balanced = Balanced::new(12, 4).unwrap();
+        assert_eq!(balanced.part_ranges(12), vec![0..3, 3..6, 6..9, 9..12]);
+        let balanced = Balanced::new(10, 4).unwrap();
+        assert_eq!(balanced.part_ranges(10), vec![0..3, 3..6, 6..8, 8..10]);
+    }
+
+    #[test]
+    fn balanced_err() {
+        let err = Balanced::new(10, 11).unwrap_err();
+        assert_eq!(err, SplitError::NumPartsTooLarge);
+        let err = Balanced::new(10, 1).unwrap_err();
+        assert_eq!(err, SplitError::PartSizeTooLarge);
+        assert!(Balanced::new(10, 10).is_ok());
+    }
+
+    #[test]
+    fn closest_factors() {
+        assert_eq!(Split::closest_factors_to(512000, 986), (985, 520));
+        assert_eq!(Split::closest_factors_to(1024, 50), (49, 21));
+    }
+
+    #[test]
+    fn disobey() {
+        let split = Split::from_num_parts(512000, 986).unwrap();
+        assert_eq!(
+            split,
+            Split {
+                part_size: 520,
+                num_parts: 985, // We did it, we disobeyed!
+            },
+        );
+
+        let split = Split::from_part_size(1024, 50).unwrap();
+        assert_eq!(
+            split,
+            Split {
+                part_size: 49, // We did it, we disobeyed!
+                num_parts: 21,
+            },
+        );
+
+        let split = Split::from_num_parts(12, 11).unwrap();
+        assert_eq!(
+            split,
+            Split {
+                part_size: 2,
+                num_parts: 6, // Not 7, which would leave the last part empty
+            },
+        );
+    }
+}
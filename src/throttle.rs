@@ -0,0 +1,148 @@
+use bytesize::ByteSize;
+use std::cmp::max;
+use std::io;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Reading less than this at a time makes for a lot of system calls
+const MIN_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Keeps the bytes copied at or under a rate, by sleeping after each chunk
+/// until the total is no longer ahead of schedule
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    started: Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        debug_assert_ne!(bytes_per_second, 0, "Rate limit should be non-zero");
+        RateLimiter {
+            bytes_per_second,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Parses a rate like `50MiB/s` (the `/s` is optional)
+    pub fn parse_rate(rate: &str) -> Option<u64> {
+        let size = rate.strip_suffix("/s").unwrap_or(rate);
+        match ByteSize::from_str(size) {
+            Ok(ByteSize(0)) | Err(_) => None,
+            Ok(ByteSize(bytes_per_second)) => Some(bytes_per_second),
+        }
+    }
+
+    /// The most that should be copied at once, so that the copying is spread
+    /// out instead of coming in bursts
+    pub fn chunk_size(&self) -> u64 {
+        max(self.bytes_per_second / 10, MIN_CHUNK_SIZE)
+    }
+
+    /// Counts `bytes` as copied, sleeping if they put the total over the rate
+    pub fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        let delay = self.delay(self.started.elapsed());
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+
+    // How long until the bytes copied so far are within the rate
+    fn delay(&self, elapsed: Duration) -> Duration {
+        let due = Duration::from_secs_f64(
+            self.bytes as f64 / self.bytes_per_second as f64,
+        );
+        due.saturating_sub(elapsed)
+    }
+}
+
+/// The I/O scheduling classes a run can be put in, so as not to get in the
+/// way of other processes using the disk
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IoClass {
+    // Only gets the disk when nothing else wants it
+    Idle,
+    // Shares the disk, at the lowest priority of the default class
+    BestEffort,
+}
+
+impl IoClass {
+    pub const NAMES: [&'static str; 2] = ["idle", "best-effort"];
+}
+
+impl FromStr for IoClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "idle" => Ok(IoClass::Idle),
+            "best-effort" => Ok(IoClass::BestEffort),
+            _ => Err(format!("{:?} isn't an I/O scheduling class", s)),
+        }
+    }
+}
+
+/// Puts this process in the given I/O scheduling class
+#[cfg(target_os = "linux")]
+pub fn set_io_class(class: IoClass) -> io::Result<()> {
+    // From linux/ioprio.h
+    const IOPRIO_WHO_PROCESS: i32 = 1;
+    const IOPRIO_CLASS_SHIFT: i32 = 13;
+    const IOPRIO_CLASS_BE: i32 = 2;
+    const IOPRIO_CLASS_IDLE: i32 = 3;
+    const IOPRIO_BE_LOWEST: i32 = 7;
+    let priority = match class {
+        IoClass::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        IoClass::BestEffort => {
+            IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT | IOPRIO_BE_LOWEST
+        }
+    };
+    // Safe as ioprio_set only reads its arguments. A PID of 0 is this process
+    let result = unsafe {
+        libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority)
+    };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_io_class(_class: IoClass) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "I/O scheduling classes can only be set on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!(RateLimiter::parse_rate("50MiB/s"), Some(50 * 1024 * 1024));
+        assert_eq!(RateLimiter::parse_rate("1 MB/s"), Some(1_000_000));
+        assert_eq!(RateLimiter::parse_rate("2048"), Some(2048));
+        assert_eq!(RateLimiter::parse_rate("0/s"), None);
+        assert_eq!(RateLimiter::parse_rate("fast"), None);
+        assert_eq!(RateLimiter::parse_rate("50MiB/h"), None);
+    }
+
+    #[test]
+    fn delays_when_ahead() {
+        let mut limiter = RateLimiter::new(1000);
+        limiter.bytes = 500;
+        assert_eq!(
+            limiter.delay(Duration::from_millis(100)),
+            Duration::from_millis(400)
+        );
+        assert!(limiter.delay(Duration::from_secs(1)).is_zero());
+        assert_eq!(limiter.chunk_size(), MIN_CHUNK_SIZE);
+        assert_eq!(RateLimiter::new(50_000_000).chunk_size(), 5_000_000);
+    }
+}
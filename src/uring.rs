@@ -0,0 +1,147 @@
+use io_uring::{opcode, types, IoUring};
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::os::unix::io::RawFd;
+
+/// Reads a file with io_uring, a chunk ahead: while one chunk is being used,
+/// the next is already being read into a second buffer the same size, which
+/// the two are swapped for once it's wanted
+pub struct ReadAhead {
+    ring: IoUring,
+    fd: RawFd,
+    spare: Vec<u8>,
+    // The bytes being read into the spare buffer, if any are
+    pending: Option<Range<u64>>,
+}
+
+impl ReadAhead {
+    /// Fails if io_uring isn't available, as it isn't before Linux 5.6 or
+    /// where it's been turned off. `fd` must stay open for as long as this is
+    /// used
+    pub fn new(fd: RawFd, buffer_len: usize) -> io::Result<Self> {
+        Ok(ReadAhead {
+            ring: IoUring::new(2)?,
+            fd,
+            spare: vec![0; buffer_len],
+            pending: None,
+        })
+    }
+
+    /// Reads `range` of the file into `buffer` (which must be as big as the
+    /// buffer given to `new`), giving how many bytes were read. If that's
+    /// what was being read ahead, it's swapped in rather than read again
+    pub fn read(
+        &mut self,
+        buffer: &mut Vec<u8>,
+        range: Range<u64>,
+    ) -> io::Result<usize> {
+        debug_assert_eq!(buffer.len(), self.spare.len());
+        let ahead = self.pending.clone().map(|pending| (pending, self.wait()));
+        let len = match ahead {
+            Some((pending, Ok(len))) if pending == range => len,
+            // Still in flight, as it's io_uring itself that failed
+            Some((_, Err(why))) if self.pending.is_some() => return Err(why),
+            // The reader moved, or reading ahead failed, in which case it
+            // fails again here
+            _ => {
+                self.start(range)?;
+                self.wait()?
+            }
+        };
+        mem::swap(buffer, &mut self.spare);
+        Ok(len)
+    }
+
+    /// Starts reading `range` into the spare buffer, for `read` to pick up
+    pub fn start(&mut self, range: Range<u64>) -> io::Result<()> {
+        debug_assert!(self.pending.is_none(), "Already reading ahead");
+        let len = range.end - range.start;
+        debug_assert!(len <= self.spare.len() as u64, "Range is too long");
+        let read = opcode::Read::new(
+            types::Fd(self.fd),
+            self.spare.as_mut_ptr(),
+            len as u32,
+        )
+        .offset(range.start as libc::off_t)
+        .build();
+        // Safe as the spare buffer isn't touched again until the read has
+        // been waited for, which dropping this does too
+        unsafe { self.ring.submission().push(&read) }.map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "io_uring queue is full")
+        })?;
+        self.pending = Some(range);
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    // Waits for the read in flight, giving how many bytes it read
+    fn wait(&mut self) -> io::Result<usize> {
+        let completed = loop {
+            if let Some(completed) = self.ring.completion().next() {
+                break completed;
+            }
+            match self.ring.submit_and_wait(1) {
+                Err(why) if why.kind() == io::ErrorKind::Interrupted => {}
+                result => {
+                    result?;
+                }
+            }
+        };
+        self.pending = None;
+        match completed.result() {
+            len if len >= 0 => Ok(len as usize),
+            errno => Err(io::Error::from_raw_os_error(-errno)),
+        }
+    }
+}
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        // The spare buffer can't be freed while it's still being read into,
+        // so if the read can't be waited for it's leaked instead
+        if self.pending.is_some() && self.wait().is_err() {
+            mem::forget(mem::take(&mut self.spare));
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use assert_fs::prelude::*;
+    use assert_fs::NamedTempFile;
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn reads_ahead() {
+        let bytes = (0..=99).collect::<Vec<u8>>();
+        let temp_file = NamedTempFile::new("read_ahead").unwrap();
+        temp_file.write_binary(&bytes).unwrap();
+        let file = File::open(temp_file.path()).unwrap();
+        let mut ahead = match ReadAhead::new(file.as_raw_fd(), 8) {
+            Ok(ahead) => ahead,
+            Err(why) => {
+                eprintln!("Skipped, as io_uring isn't available: {}", why);
+                return;
+            }
+        };
+        let mut buffer = vec![0; 8];
+
+        assert_eq!(ahead.read(&mut buffer, 0..8).unwrap(), 8);
+        assert_eq!(buffer, &bytes[..8]);
+        ahead.start(8..16).unwrap();
+        assert_eq!(ahead.read(&mut buffer, 8..16).unwrap(), 8);
+        assert_eq!(buffer, &bytes[8..16]);
+        // Not what was read ahead, so it's read again
+        ahead.start(16..24).unwrap();
+        assert_eq!(ahead.read(&mut buffer, 50..58).unwrap(), 8);
+        assert_eq!(buffer, &bytes[50..58]);
+        // Reads stop at the end of the file
+        assert_eq!(ahead.read(&mut buffer, 96..104).unwrap(), 4);
+        assert_eq!(buffer[..4], bytes[96..]);
+        // Dropped while reading ahead
+        ahead.start(0..8).unwrap();
+    }
+}
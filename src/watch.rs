@@ -0,0 +1,130 @@
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Waits for the files in a folder to change. On Linux this uses inotify, and
+/// elsewhere it just waits, so that the folder gets looked at again anyway
+pub struct DirWatcher {
+    #[cfg(target_os = "linux")]
+    fd: i32,
+}
+
+#[cfg(target_os = "linux")]
+impl DirWatcher {
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidInput, why))?;
+        // Safe as inotify_init1 only takes flags
+        let fd = unsafe {
+            libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK)
+        };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let watcher = DirWatcher { fd };
+        // Anything that could finish (or restart) a part arriving
+        let events = libc::IN_CREATE
+            | libc::IN_MODIFY
+            | libc::IN_CLOSE_WRITE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO
+            | libc::IN_DELETE;
+        // Safe as the path is a valid C string that outlives the call
+        match unsafe { libc::inotify_add_watch(fd, path.as_ptr(), events) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(watcher),
+        }
+    }
+
+    /// Waits until something in the folder changes, or `timeout` passes.
+    /// Returns whether anything changed
+    pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        // Safe as poll_fd is a single valid pollfd
+        match unsafe { libc::poll(&mut poll_fd, 1, timeout) } {
+            -1 => {
+                let why = io::Error::last_os_error();
+                match why.kind() {
+                    io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(why),
+                }
+            }
+            0 => Ok(false),
+            _ => self.drain().map(|_| true),
+        }
+    }
+
+    // Events only say that something changed, which is all that matters, so
+    // they're read and thrown away
+    fn drain(&mut self) -> io::Result<()> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            // Safe as the buffer is valid for its whole length
+            let read = unsafe {
+                libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len())
+            };
+            if read == -1 {
+                let why = io::Error::last_os_error();
+                return match why.kind() {
+                    io::ErrorKind::WouldBlock => Ok(()),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => Err(why),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for DirWatcher {
+    fn drop(&mut self) {
+        // Safe as the descriptor is owned by the watcher
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl DirWatcher {
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        match dir.is_dir() {
+            true => Ok(DirWatcher {}),
+            false => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Not a folder that can be watched",
+            )),
+        }
+    }
+
+    /// Waits until `timeout` passes, after which anything could have changed
+    pub fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+        std::thread::sleep(timeout);
+        Ok(true)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod unit_tests {
+    use super::*;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+
+    #[test]
+    fn sees_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut watcher = DirWatcher::new(temp_dir.path()).unwrap();
+        assert!(!watcher.wait(Duration::from_millis(10)).unwrap());
+        temp_dir.child("foo.p1").write_binary(b"part").unwrap();
+        assert!(watcher.wait(Duration::from_secs(5)).unwrap());
+        // Everything so far has been read
+        assert!(!watcher.wait(Duration::from_millis(10)).unwrap());
+        assert!(DirWatcher::new(&temp_dir.path().join("missing")).is_err());
+    }
+}
@@ -2,8 +2,10 @@ use assert_cmd::prelude::*;
 use assert_cmd::Command;
 use assert_fs::prelude::*;
 use assert_fs::TempDir;
+use chopstick::manifest::Manifest;
 use chopstick::EXTENSION_PREFIX;
 use std::cmp::min;
+use std::fs;
 use walkdir::WalkDir;
 
 const FILE_NAME: &str = "split_me";
@@ -28,12 +30,12 @@ fn exact_split() {
 
     Command::cargo_bin("chop")
         .unwrap()
-        .args(&["-n", "5", &temp_file.path().to_string_lossy()])
+        .args(["-n", "5", &temp_file.path().to_string_lossy()])
         .unwrap()
         .assert()
         .success();
 
-    (0..5).into_iter().map(|n| (n + 1, n * PART_SIZE)).for_each(
+    (0..5).map(|n| (n + 1, n * PART_SIZE)).for_each(
         |(part_no, part_byte_offset)| {
             let child_path = format!("{FILE_NAME}.{EXTENSION_PREFIX}{part_no}");
             let part = temp_dir.child(&child_path);
@@ -56,20 +58,19 @@ fn split() {
 
     Command::cargo_bin("chop")
         .unwrap()
-        .args(&["-s", "15", &temp_file.path().to_string_lossy()])
+        .args(["-s", "15", &temp_file.path().to_string_lossy()])
         .unwrap()
         .assert()
         .success();
 
-    (0..NUM_PARTS)
-        .into_iter()
-        .map(|n| (n + 1, n * PART_SIZE))
-        .for_each(|(part_no, part_byte_offset)| {
+    (0..NUM_PARTS).map(|n| (n + 1, n * PART_SIZE)).for_each(
+        |(part_no, part_byte_offset)| {
             let child_path = format!("{FILE_NAME}.{EXTENSION_PREFIX}{part_no}");
             let part = temp_dir.child(&child_path);
             let end_index = min(TEST_BYTES.len(), part_byte_offset + PART_SIZE);
             part.assert(&TEST_BYTES[part_byte_offset..end_index]);
-        });
+        },
+    );
 }
 
 #[test]
@@ -82,7 +83,7 @@ fn retain() {
 
     Command::cargo_bin("chop")
         .unwrap()
-        .args(&["-r", "-n", "2", &temp_file.path().to_string_lossy()])
+        .args(["-r", "-n", "2", &temp_file.path().to_string_lossy()])
         .unwrap()
         .assert()
         .success();
@@ -100,7 +101,7 @@ fn dry_run() {
 
     Command::cargo_bin("chop")
         .unwrap()
-        .args(&["--dry-run", "-n", "2", &temp_file.path().to_string_lossy()])
+        .args(["--dry-run", "-n", "2", &temp_file.path().to_string_lossy()])
         .unwrap()
         .assert()
         .success();
@@ -114,3 +115,739 @@ fn dry_run() {
     assert_eq!(num_files, 1, "Should have only been 1 file");
     temp_file.assert(&TEST_BYTES[..]);
 }
+
+#[test]
+fn content_defined() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file
+        .write_binary(&TEST_BYTES)
+        .expect("Failed to write test bytes to temp file");
+
+    // Retaining the original checks that no part reads past its end
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args([
+            "-r",
+            "-c",
+            "16",
+            "--min-size",
+            "4",
+            "--max-size",
+            "40",
+            &temp_file.path().to_string_lossy(),
+        ])
+        .unwrap()
+        .assert()
+        .success();
+
+    let parts = WalkDir::new(&temp_dir)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .map(|de| de.unwrap().into_path())
+        .filter(|path| path != temp_file.path())
+        .filter(|path| path.extension() != Some("manifest".as_ref()))
+        .collect::<Vec<_>>();
+    assert!(parts.len() > 1, "Should have made more than one part");
+    let reassembled = parts
+        .iter()
+        .flat_map(|path| fs::read(path).expect("Failed to read part"))
+        .collect::<Vec<_>>();
+    assert_eq!(reassembled, TEST_BYTES);
+    temp_file.assert(&TEST_BYTES[..]);
+}
+
+#[test]
+fn balanced() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file
+        .write_binary(&TEST_BYTES)
+        .expect("Failed to write test bytes to temp file");
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args(["-n", "8", "--balanced", &temp_file.path().to_string_lossy()])
+        .unwrap()
+        .assert()
+        .success();
+
+    // 100 bytes into 8 is four parts of 13 then four of 12
+    let mut offset = 0;
+    (1..=8).for_each(|part_no| {
+        let size = if part_no <= 4 { 13 } else { 12 };
+        temp_dir
+            .child(format!("{FILE_NAME}.{EXTENSION_PREFIX}{part_no}"))
+            .assert(&TEST_BYTES[offset..offset + size]);
+        offset += size;
+    });
+    temp_dir.child(format!("{FILE_NAME}.manifest")).assert(
+        "chopstick manifest 1\nsize 100\n\
+        part 1 13\npart 2 13\npart 3 13\npart 4 13\n\
+        part 5 12\npart 6 12\npart 7 12\npart 8 12\n",
+    );
+}
+
+#[test]
+fn only() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file
+        .write_binary(&TEST_BYTES)
+        .expect("Failed to write test bytes to temp file");
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args([
+            "-n",
+            "10",
+            "--only",
+            "3",
+            &temp_file.path().to_string_lossy(),
+        ])
+        .unwrap()
+        .assert()
+        .success();
+
+    let num_files = WalkDir::new(&temp_dir).min_depth(1).into_iter().count();
+    assert_eq!(num_files, 2, "Should have only made one part");
+    // Zero padded the same as a full chop would be
+    temp_dir
+        .child(format!("{FILE_NAME}.{EXTENSION_PREFIX}03"))
+        .assert(&TEST_BYTES[20..30]);
+    temp_file.assert(&TEST_BYTES[..]);
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args([
+            "-n",
+            "10",
+            "--only",
+            "11",
+            &temp_file.path().to_string_lossy(),
+        ])
+        .assert()
+        .failure()
+        .code(1);
+}
+
+#[test]
+fn range() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file
+        .write_binary(&TEST_BYTES)
+        .expect("Failed to write test bytes to temp file");
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args(["--range", "40..60", &temp_file.path().to_string_lossy()])
+        .unwrap()
+        .assert()
+        .success();
+
+    let num_files = WalkDir::new(&temp_dir).min_depth(1).into_iter().count();
+    assert_eq!(num_files, 2, "Should have only made one part");
+    temp_dir
+        .child(format!("{FILE_NAME}.{EXTENSION_PREFIX}3"))
+        .assert(&TEST_BYTES[40..60]);
+    temp_file.assert(&TEST_BYTES[..]);
+
+    // Ranges that aren't parts are made exactly, named after the range, and
+    // stop at the end of the file
+    for (range, name, bytes) in [
+        ("45..65", "45-65", &TEST_BYTES[45..65]),
+        ("85..200", "85-100", &TEST_BYTES[85..]),
+    ] {
+        Command::cargo_bin("chop")
+            .unwrap()
+            .args(["--range", range, &temp_file.path().to_string_lossy()])
+            .unwrap()
+            .assert()
+            .success();
+        temp_dir.child(format!("{FILE_NAME}.{name}")).assert(bytes);
+    }
+    temp_file.assert(&TEST_BYTES[..]);
+
+    // Starts past the end of the file
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args(["--range", "100..200", &temp_file.path().to_string_lossy()])
+        .assert()
+        .failure()
+        .code(1);
+}
+
+#[cfg(unix)]
+#[test]
+fn filter() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file
+        .write_binary(&TEST_BYTES)
+        .expect("Failed to write test bytes to temp file");
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args([
+            "-n",
+            "4",
+            "--filter",
+            "cat > \"$FILE.filtered\"",
+            &temp_file.path().to_string_lossy(),
+        ])
+        .current_dir(&temp_dir)
+        .unwrap()
+        .assert()
+        .success();
+
+    assert!(!temp_file.exists(), "Original should have been deleted");
+    (0..4).for_each(|n| {
+        let child_path =
+            format!("{FILE_NAME}.{EXTENSION_PREFIX}{}.filtered", n + 1);
+        temp_dir
+            .child(child_path)
+            .assert(&TEST_BYTES[n * 25..n * 25 + 25]);
+    });
+}
+
+#[cfg(unix)]
+#[test]
+fn filter_environment() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file
+        .write_binary(&TEST_BYTES)
+        .expect("Failed to write test bytes to temp file");
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args([
+            "-r",
+            "-s",
+            "40",
+            "--filter",
+            "cat > /dev/null; \
+            echo \"$CHOP_PART_NUMBER $CHOP_PART_SIZE\" > \"$FILE\"",
+            &temp_file.path().to_string_lossy(),
+        ])
+        .current_dir(&temp_dir)
+        .unwrap()
+        .assert()
+        .success();
+
+    temp_dir
+        .child(format!("{FILE_NAME}.{EXTENSION_PREFIX}1"))
+        .assert("1 34\n");
+    temp_dir
+        .child(format!("{FILE_NAME}.{EXTENSION_PREFIX}3"))
+        .assert("3 32\n");
+}
+
+// FILE is the part's name, not its path, e.g. to name an upload after
+#[cfg(unix)]
+#[test]
+fn filter_file_is_part_name() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child("folder").child(FILE_NAME);
+    temp_file
+        .write_binary(&TEST_BYTES)
+        .expect("Failed to write test bytes to temp file");
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args([
+            "-r",
+            "-n",
+            "2",
+            "--filter",
+            "cat > /dev/null; echo \"$FILE\" >> names",
+            &temp_file.path().to_string_lossy(),
+        ])
+        .current_dir(&temp_dir)
+        .unwrap()
+        .assert()
+        .success();
+
+    // Parts are made backwards
+    temp_dir.child("names").assert(format!(
+        "{FILE_NAME}.{EXTENSION_PREFIX}2\n{FILE_NAME}.{EXTENSION_PREFIX}1\n"
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn filter_failure_keeps_original() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file
+        .write_binary(&TEST_BYTES)
+        .expect("Failed to write test bytes to temp file");
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args([
+            "-n",
+            "2",
+            "--filter",
+            "cat > /dev/null; exit 3",
+            &temp_file.path().to_string_lossy(),
+        ])
+        .assert()
+        .failure()
+        .code(2);
+
+    temp_file.assert(&TEST_BYTES[..]);
+}
+
+#[test]
+fn preset_from_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file
+        .write_binary(&TEST_BYTES)
+        .expect("Failed to write test bytes to temp file");
+    let config_home = TempDir::new().unwrap();
+    config_home
+        .child("chopstick/config.toml")
+        .write_str("[presets]\nfloppy = \"25B\"\n")
+        .unwrap();
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .args(["-s", "floppy", &temp_file.path().to_string_lossy()])
+        .unwrap()
+        .assert()
+        .success();
+
+    (0..4).for_each(|n| {
+        temp_dir
+            .child(format!("{FILE_NAME}.{EXTENSION_PREFIX}{}", n + 1))
+            .assert(&TEST_BYTES[n * 25..n * 25 + 25]);
+    });
+
+    // Not a preset, from the config file or otherwise
+    let other_file = temp_dir.child("other");
+    other_file.write_binary(&TEST_BYTES).unwrap();
+    let assert = Command::cargo_bin("chop")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .args(["-s", "zip", &other_file.path().to_string_lossy()])
+        .assert()
+        .failure()
+        .code(1);
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("known preset"),
+        "Unexpected error: {}",
+        stderr
+    );
+}
+
+#[test]
+fn defaults_from_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file
+        .write_binary(&TEST_BYTES)
+        .expect("Failed to write test bytes to temp file");
+    let config_home = TempDir::new().unwrap();
+    config_home
+        .child("chopstick/config.toml")
+        .write_str("[profiles.keep.chop]\nretain = true\n")
+        .unwrap();
+
+    let assert = Command::cargo_bin("chop")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .env("CHOPSTICK_PROFILE", "keep")
+        .arg("--print-config")
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+    assert!(
+        stdout.contains("retain = true # ")
+            && stdout.contains("[profiles.keep.chop]")
+            && stdout.contains("verbose = false # default"),
+        "Unexpected config: {}",
+        stdout
+    );
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .args(["--profile", "keep", "-n", "2"])
+        .arg(temp_file.path())
+        .assert()
+        .success();
+    temp_file.assert(&TEST_BYTES[..]);
+    temp_dir
+        .child(format!("{FILE_NAME}.{EXTENSION_PREFIX}2"))
+        .assert(&TEST_BYTES[50..]);
+
+    // A default that's on can be turned off on the command line
+    let assert = Command::cargo_bin("chop")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .args(["--profile", "keep", "--no-retain", "--print-config"])
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout);
+    assert!(
+        stdout.contains("retain = false # command line"),
+        "Unexpected config: {}",
+        stdout
+    );
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .args(["--profile", "missing", "-n", "2"])
+        .arg(temp_file.path())
+        .assert()
+        .failure()
+        .code(1);
+}
+
+#[test]
+fn generate_completions_and_man_page() {
+    ["bash", "zsh", "fish", "elvish"].iter().for_each(|shell| {
+        let assert = Command::cargo_bin("chop")
+            .unwrap()
+            .args(["--generate-completions", shell])
+            .assert()
+            .success();
+        let script = String::from_utf8_lossy(&assert.get_output().stdout);
+        assert!(script.contains("--parts"), "{} script: {}", shell, script);
+    });
+
+    let assert = Command::cargo_bin("chop")
+        .unwrap()
+        .arg("--generate-man")
+        .assert()
+        .success();
+    let page = String::from_utf8_lossy(&assert.get_output().stdout);
+    assert!(
+        page.starts_with(".TH CHOP 1"),
+        "Unexpected man page: {}",
+        page
+    );
+}
+
+#[test]
+fn durability() {
+    ["none", "data", "full"].iter().for_each(|durability| {
+        let temp_dir = TempDir::new().unwrap();
+        let temp_file = temp_dir.child(FILE_NAME);
+        temp_file
+            .write_binary(&TEST_BYTES)
+            .expect("Failed to write test bytes to temp file");
+
+        Command::cargo_bin("chop")
+            .unwrap()
+            .args([
+                "--durability",
+                durability,
+                "-n",
+                "2",
+                &temp_file.path().to_string_lossy(),
+            ])
+            .unwrap()
+            .assert()
+            .success();
+
+        temp_dir
+            .child(format!("{FILE_NAME}.{EXTENSION_PREFIX}2"))
+            .assert(&TEST_BYTES[50..]);
+    });
+
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file.write_binary(&TEST_BYTES).unwrap();
+    let assert = Command::cargo_bin("chop")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", temp_dir.path())
+        .env("CHOPSTICK_DURABILITY", "sometimes")
+        .args(["-n", "2", &temp_file.path().to_string_lossy()])
+        .assert()
+        .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("Invalid value for durability"),
+        "Unexpected error: {}",
+        stderr
+    );
+    temp_file.assert(&TEST_BYTES[..]);
+}
+
+#[test]
+fn pipeline() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file
+        .write_binary(&TEST_BYTES)
+        .expect("Failed to write test bytes to temp file");
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args(["--pipeline", "-n", "4", &temp_file.path().to_string_lossy()])
+        .unwrap()
+        .assert()
+        .success();
+
+    (0..4).for_each(|n| {
+        let part =
+            temp_dir.child(format!("{FILE_NAME}.{EXTENSION_PREFIX}{}", n + 1));
+        part.assert(&TEST_BYTES[n * 25..n * 25 + 25]);
+    });
+    assert!(!temp_file.exists(), "Original should have been deleted");
+}
+
+#[test]
+fn direct() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    let bytes = (0..30_000).map(|n| (n * 7 % 251) as u8).collect::<Vec<_>>();
+    temp_file
+        .write_binary(&bytes)
+        .expect("Failed to write test bytes to temp file");
+
+    // 10000 bytes isn't a whole number of blocks, so no part after the first
+    // starts on one
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args([
+            "--direct",
+            "--drop-cache",
+            "-n",
+            "3",
+            &temp_file.path().to_string_lossy(),
+        ])
+        .unwrap()
+        .assert()
+        .success();
+
+    (0..3).for_each(|n| {
+        let part =
+            temp_dir.child(format!("{FILE_NAME}.{EXTENSION_PREFIX}{}", n + 1));
+        let contents = fs::read(part.path()).unwrap();
+        assert_eq!(contents, &bytes[n * 10_000..n * 10_000 + 10_000]);
+    });
+    assert!(!temp_file.exists(), "Original should have been deleted");
+}
+
+#[test]
+fn archive() {
+    // The copying options apply to archives as they do to parts
+    let copying = ["--pipeline", "--direct", "--rate-limit", "1MiB/s"];
+    for (format, options) in [
+        ("tar", &[][..]),
+        ("zip", &[][..]),
+        ("tar", &copying[..]),
+        ("zip", &copying[..]),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let temp_file = temp_dir.child(FILE_NAME);
+        temp_file
+            .write_binary(&TEST_BYTES)
+            .expect("Failed to write test bytes to temp file");
+
+        Command::cargo_bin("chop")
+            .unwrap()
+            .args([
+                "--archive",
+                format,
+                "-n",
+                "4",
+                &temp_file.path().to_string_lossy(),
+            ])
+            .args(options)
+            .unwrap()
+            .assert()
+            .success();
+
+        // Only the archive is left, holding the manifest then the parts
+        assert!(!temp_file.exists(), "Original should have been deleted");
+        assert_eq!(WalkDir::new(&temp_dir).min_depth(1).into_iter().count(), 1);
+        let archive = temp_dir.child(format!("{FILE_NAME}.{format}"));
+        let mut entries = Vec::new();
+        chopstick::archive::for_each_entry(
+            fs::File::open(archive.path()).unwrap(),
+            |name, _, contents| {
+                let mut bytes = Vec::new();
+                contents.read_to_end(&mut bytes)?;
+                entries.push((name.to_owned(), bytes));
+                Ok::<_, std::io::Error>(())
+            },
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[0].0, format!("{FILE_NAME}.manifest"));
+        (0..4).for_each(|n| {
+            let (name, bytes) = &entries[n + 1];
+            assert_eq!(
+                name,
+                &format!("{FILE_NAME}.{EXTENSION_PREFIX}{}", n + 1)
+            );
+            assert_eq!(bytes, &TEST_BYTES[n * 25..n * 25 + 25]);
+        });
+    }
+}
+
+#[test]
+fn rejoin_script() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file
+        .write_binary(&TEST_BYTES)
+        .expect("Failed to write test bytes to temp file");
+
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args([
+            "--with-rejoin-script=sh,powershell",
+            "-n",
+            "4",
+            &temp_file.path().to_string_lossy(),
+        ])
+        .unwrap()
+        .assert()
+        .success();
+
+    let manifest = temp_dir.child(format!("{FILE_NAME}.manifest"));
+    let manifest = fs::read_to_string(manifest.path()).unwrap();
+    let manifest = manifest.parse::<Manifest>().unwrap();
+    assert_eq!(manifest.part_hashes.unwrap().len(), 4);
+    let ps1 = temp_dir.child(format!("{FILE_NAME}.rejoin.ps1"));
+    assert!(ps1.exists());
+
+    // The script works without chopstick, and checks the parts first
+    let script = temp_dir.child(format!("{FILE_NAME}.rejoin.sh"));
+    if cfg!(unix) {
+        Command::new("sh").arg(script.path()).assert().success();
+        temp_file.assert(&TEST_BYTES[..]);
+
+        fs::remove_file(temp_file.path()).unwrap();
+        temp_dir
+            .child(format!("{FILE_NAME}.{EXTENSION_PREFIX}3"))
+            .write_binary(&[0; 25])
+            .unwrap();
+        Command::new("sh").arg(script.path()).assert().failure();
+        assert!(!temp_file.exists());
+    }
+}
+
+#[test]
+fn undo() {
+    let temp_dir = TempDir::new().unwrap();
+    // So that the journal isn't shared with anything else
+    let state_home = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file.write_binary(&TEST_BYTES).unwrap();
+    let chop = |args: &[&str]| {
+        Command::cargo_bin("chop")
+            .unwrap()
+            .env("XDG_STATE_HOME", state_home.path())
+            .env("CHOPSTICK_JOURNAL", "1")
+            .args(args)
+            .arg(temp_file.path())
+            .assert()
+    };
+    let num_files = || WalkDir::new(&temp_dir).min_depth(1).into_iter().count();
+
+    chop(&["-n", "4"]).success();
+    assert!(!temp_file.exists());
+    chop(&["--undo"]).success();
+    temp_file.assert(&TEST_BYTES[..]);
+    assert_eq!(num_files(), 1, "Parts or manifest left behind");
+    // It's already been undone
+    chop(&["--undo"]).failure().code(1);
+
+    // With --retain, only what was made is deleted
+    chop(&["-n", "4", "--retain", "--with-rejoin-script"]).success();
+    chop(&["--undo"]).success();
+    temp_file.assert(&TEST_BYTES[..]);
+    assert_eq!(num_files(), 1, "Parts, manifest, or script left behind");
+
+    // A range makes no manifest, so none is recorded with the part
+    chop(&["--range", "40..60"]).success().stderr("");
+    chop(&["--undo"]).success();
+    temp_file.assert(&TEST_BYTES[..]);
+    assert_eq!(num_files(), 1, "Part left behind");
+
+    // Nothing's touched once anything has changed
+    chop(&["-n", "2"]).success();
+    temp_dir
+        .child(format!("{FILE_NAME}.{EXTENSION_PREFIX}2"))
+        .write_binary(b"changed")
+        .unwrap();
+    chop(&["--undo"]).failure().code(1);
+    assert!(!temp_file.exists());
+    assert_eq!(num_files(), 3);
+}
+
+#[test]
+fn rechop() {
+    let temp_dir = TempDir::new().unwrap();
+    let temp_file = temp_dir.child(FILE_NAME);
+    temp_file.write_binary(&TEST_BYTES).unwrap();
+    let chop = |args: &[&str]| {
+        Command::cargo_bin("chop")
+            .unwrap()
+            .args(args)
+            .arg(temp_file.path())
+            .assert()
+    };
+    let part =
+        |n: &str| temp_dir.child(format!("{FILE_NAME}.{EXTENSION_PREFIX}{n}"));
+    let num_files = || WalkDir::new(&temp_dir).min_depth(1).into_iter().count();
+
+    chop(&["-n", "10", "--with-rejoin-script"]).success();
+    chop(&["--rechop", "-n", "3", "--balanced"]).success();
+    part("1").assert(&TEST_BYTES[..34]);
+    part("2").assert(&TEST_BYTES[34..67]);
+    part("3").assert(&TEST_BYTES[67..]);
+    assert_eq!(num_files(), 5, "Old parts left behind");
+    let manifest = temp_dir.child(format!("{FILE_NAME}.manifest"));
+    let manifest = fs::read_to_string(manifest.path()).unwrap();
+    let manifest = manifest.parse::<Manifest>().unwrap();
+    assert_eq!(manifest.part_sizes, vec![34, 33, 33]);
+    assert_eq!(manifest.part_hashes.unwrap().len(), 3);
+
+    chop(&["--rechop", "--size", "50"]).success();
+    part("1").assert(&TEST_BYTES[..50]);
+    part("2").assert(&TEST_BYTES[50..]);
+    assert_eq!(num_files(), 4);
+    // The rejoin script is written again for the new parts
+    if cfg!(unix) {
+        let script = temp_dir.child(format!("{FILE_NAME}.rejoin.sh"));
+        Command::new("sh").arg(script.path()).assert().success();
+        temp_file.assert(&TEST_BYTES[..]);
+        fs::remove_file(temp_file.path()).unwrap();
+    }
+
+    // An interrupted rechop into 4 parts, that hadn't made any yet, is only
+    // finished by running the same rechop again
+    let progress = temp_dir.child(format!("{FILE_NAME}.rechop"));
+    progress
+        .write_str(
+            "chopstick rechop 1\nhashed\npart 1 25\npart 2 25\npart 3 25\n\
+            part 4 25\nmade 4\n",
+        )
+        .unwrap();
+    chop(&["--rechop", "-n", "5"]).failure().code(1);
+    part("1").assert(&TEST_BYTES[..50]);
+    chop(&["--rechop", "-n", "4"]).success();
+    (1..=4).for_each(|n| {
+        part(&n.to_string()).assert(&TEST_BYTES[(n - 1) * 25..n * 25]);
+    });
+    assert!(!progress.exists());
+
+    // The parts can't be found without the manifest
+    fs::remove_file(temp_dir.child(format!("{FILE_NAME}.manifest"))).unwrap();
+    chop(&["--rechop", "-n", "4"]).failure().code(1);
+    assert!(part("1").exists());
+}
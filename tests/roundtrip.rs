@@ -0,0 +1,194 @@
+use assert_cmd::Command;
+use assert_fs::TempDir;
+use chopstick::digits;
+use chopstick::manifest::Manifest;
+use chopstick::split::{get_part_path_buf, Balanced, Split};
+use proptest::prelude::*;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+const MAX_FILE_SIZE: usize = 4096;
+const MAX_PARTS: u64 = 32;
+
+#[derive(Debug, Clone)]
+struct Case {
+    file_name: OsString,
+    bytes: Vec<u8>,
+    split_by_parts: bool,
+    flag_val: u64,
+    // Only used with split_by_parts
+    balanced: bool,
+    retain: bool,
+    dry_run: bool,
+    // Whether stick is given the full path or just the file name
+    full_path: bool,
+}
+
+impl Case {
+    fn num_parts(&self) -> u64 {
+        let file_size = self.bytes.len() as u64;
+        if self.balanced {
+            return Balanced::new(file_size, self.flag_val)
+                .expect("generated split should be valid")
+                .num_parts;
+        }
+        if self.split_by_parts {
+            Split::from_num_parts(file_size, self.flag_val)
+        } else {
+            Split::from_part_size(file_size, self.flag_val)
+        }
+        .expect("generated split should be valid")
+        .num_parts
+    }
+}
+
+fn file_name() -> impl Strategy<Value = OsString> {
+    let plain = "[a-zA-Z0-9_-]{1,12}";
+    prop_oneof![
+        plain.prop_map(OsString::from),
+        // Dots and extensions
+        "[a-z]{1,6}(\\.[a-z]{1,4}){1,3}".prop_map(OsString::from),
+        // Things that look like part extensions in the stem
+        "[a-z]{1,6}\\.p[0-9]{0,3}(\\.[a-z]{1,4})?".prop_map(OsString::from),
+        non_utf8_name(),
+    ]
+}
+
+#[cfg(unix)]
+fn non_utf8_name() -> impl Strategy<Value = OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    ("[a-z]{1,6}", 0x80u8..=0xff).prop_map(|(stem, byte)| {
+        let mut bytes = stem.into_bytes();
+        bytes.push(byte);
+        OsString::from_vec(bytes)
+    })
+}
+
+#[cfg(not(unix))]
+fn non_utf8_name() -> impl Strategy<Value = OsString> {
+    "[a-z]{1,6} [a-z]{1,6}".prop_map(OsString::from)
+}
+
+fn case() -> impl Strategy<Value = Case> {
+    // There are fewer parts than bytes, so 2 parts takes at least 3 bytes
+    (3..=MAX_FILE_SIZE)
+        .prop_flat_map(|file_size| {
+            let max_parts = (file_size as u64 - 1).min(MAX_PARTS);
+            let min_part_size = (file_size as u64 / MAX_PARTS).max(1);
+            (
+                file_name(),
+                prop::collection::vec(any::<u8>(), file_size),
+                any::<bool>(),
+                2..=max_parts,
+                min_part_size..file_size as u64,
+                any::<bool>(),
+                any::<bool>(),
+                any::<bool>(),
+                any::<bool>(),
+            )
+        })
+        .prop_map(
+            |(
+                file_name,
+                bytes,
+                split_by_parts,
+                num_parts,
+                part_size,
+                balanced,
+                retain,
+                dry_run,
+                full_path,
+            )| {
+                Case {
+                    file_name,
+                    bytes,
+                    split_by_parts,
+                    flag_val: if split_by_parts {
+                        num_parts
+                    } else {
+                        part_size
+                    },
+                    balanced: split_by_parts && balanced,
+                    retain,
+                    dry_run,
+                    full_path,
+                }
+            },
+        )
+}
+
+fn dir_contents(dir: &Path) -> Vec<OsString> {
+    let mut names = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+// Chop then stick must give back the same bytes, with nothing left lying
+// around
+fn round_trip(case: &Case) {
+    let temp_dir = TempDir::new().unwrap();
+    let original = temp_dir.path().join(&case.file_name);
+    fs::write(&original, &case.bytes).unwrap();
+
+    let mut chop = Command::cargo_bin("chop").unwrap();
+    chop.arg(if case.split_by_parts { "-n" } else { "-s" })
+        .arg(case.flag_val.to_string())
+        .arg(&original);
+    if case.balanced {
+        chop.arg("--balanced");
+    }
+    if case.retain {
+        chop.arg("--retain");
+    }
+    if case.dry_run {
+        chop.arg("--dry-run");
+    }
+    chop.assert().success();
+
+    if case.dry_run {
+        assert_eq!(dir_contents(temp_dir.path()), vec![case.file_name.clone()]);
+        assert_eq!(fs::read(&original).unwrap(), case.bytes);
+        return;
+    }
+
+    let num_parts = case.num_parts();
+    let part_count = (0..num_parts)
+        .map(|n| get_part_path_buf(&original, n + 1, digits(num_parts)))
+        .inspect(|part| assert!(part.exists(), "{:?} missing", part))
+        .count();
+    assert!(Manifest::path_for(&original).exists(), "manifest missing");
+    let expected_files = part_count + 1 + case.retain as usize;
+    assert_eq!(dir_contents(temp_dir.path()).len(), expected_files);
+    if case.retain {
+        assert_eq!(fs::read(&original).unwrap(), case.bytes);
+        fs::remove_file(&original).unwrap();
+    }
+
+    let stick_arg = match case.full_path {
+        true => original.clone().into_os_string(),
+        false => case.file_name.clone(),
+    };
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(temp_dir.path())
+        .arg("--")
+        .arg(stick_arg)
+        .assert()
+        .success();
+    assert_eq!(dir_contents(temp_dir.path()), vec![case.file_name.clone()]);
+    assert_eq!(fs::read(&original).unwrap(), case.bytes);
+}
+
+proptest! {
+    // Each case runs both binaries, so keep the count down
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn chop_then_stick(case in case()) {
+        round_trip(&case);
+    }
+}
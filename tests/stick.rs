@@ -2,7 +2,13 @@ use assert_cmd::prelude::*;
 use assert_cmd::Command;
 use assert_fs::prelude::*;
 use assert_fs::TempDir;
+use chopstick::archive::{ArchiveFormat, ArchiveWriter};
+use chopstick::manifest::Manifest;
 use chopstick::EXTENSION_PREFIX;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
 const FILE_NAME: &str = "stick_me";
@@ -20,7 +26,6 @@ const TEST_BYTES: [u8; 100] = [
 fn combine() {
     let temp_dir = TempDir::new().unwrap();
     (0..10)
-        .into_iter()
         .map(|n| (n + 1, &TEST_BYTES[n * 10..n * 10 + 10]))
         .try_for_each(|(part_no, slice)| {
             let child_path =
@@ -48,7 +53,6 @@ fn retain() {
     let temp_dir = TempDir::new().unwrap();
     let mut child_paths = Vec::with_capacity(10);
     (0..10)
-        .into_iter()
         .map(|n| (n + 1, &TEST_BYTES[n * 10..n * 10 + 10]))
         .for_each(|(part_no, slice)| {
             let child_path =
@@ -61,7 +65,7 @@ fn retain() {
     Command::cargo_bin("stick")
         .unwrap()
         .current_dir(&temp_dir)
-        .args(&["-r", FILE_NAME])
+        .args(["-r", FILE_NAME])
         .unwrap()
         .assert()
         .success();
@@ -75,7 +79,6 @@ fn retain() {
 fn dont_overwrite() {
     let temp_dir = TempDir::new().unwrap();
     (0..10)
-        .into_iter()
         .map(|n| (n + 1, &TEST_BYTES[n * 10..n * 10 + 10]))
         .try_for_each(|(part_no, slice)| {
             let child_path =
@@ -104,7 +107,6 @@ fn dry_run() {
     let temp_dir = TempDir::new().unwrap();
     let mut child_paths = Vec::with_capacity(10);
     (0..10)
-        .into_iter()
         .map(|n| (n + 1, &TEST_BYTES[n * 10..n * 10 + 10]))
         .for_each(|(part_no, slice)| {
             let child_path =
@@ -131,3 +133,619 @@ fn dry_run() {
         cp.assert(bytes);
     });
 }
+
+#[test]
+fn explicit_parts() {
+    let temp_dir = TempDir::new().unwrap();
+    let part_dir = temp_dir.child("downloads");
+    part_dir.create_dir_all().unwrap();
+    // Parts with unrelated names, given out of alphabetical order
+    let names = ["zebra", "apple", "mango.bin", "banana (1)"];
+    let mut part_paths = Vec::with_capacity(names.len());
+    names
+        .iter()
+        .zip(TEST_BYTES.chunks(25))
+        .for_each(|(name, slice)| {
+            let part = part_dir.child(name);
+            part.write_binary(slice).expect("Failed to write part");
+            part_paths.push(part);
+        });
+
+    let mut args = vec!["--parts".to_owned()];
+    part_paths
+        .iter()
+        .for_each(|part| args.push(part.to_string_lossy().into_owned()));
+    args.extend(["-o".to_owned(), FILE_NAME.to_owned()]);
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(&args)
+        .unwrap()
+        .assert()
+        .success();
+
+    temp_dir.child(FILE_NAME).assert(&TEST_BYTES[..]);
+    part_paths.into_iter().for_each(|part| {
+        assert!(!part.exists(), "{} not deleted", part.to_string_lossy())
+    });
+}
+
+#[test]
+fn glob_natural_sort() {
+    let temp_dir = TempDir::new().unwrap();
+    let part_dir = temp_dir.child("dl");
+    part_dir.create_dir_all().unwrap();
+    // Inconsistent padding which name sorting would get wrong
+    let mut child_paths = Vec::with_capacity(10);
+    (0..10)
+        .map(|n| (n + 1, &TEST_BYTES[n * 10..n * 10 + 10]))
+        .for_each(|(part_no, slice)| {
+            let part = part_dir.child(format!("{FILE_NAME}.part{part_no}"));
+            part.write_binary(slice).expect("Failed to write part");
+            child_paths.push(part);
+        });
+
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["-r", "--glob", "dl/*.part*", "--sort", "natural"])
+        .args(["-o", FILE_NAME])
+        .unwrap()
+        .assert()
+        .success();
+
+    temp_dir.child(FILE_NAME).assert(&TEST_BYTES[..]);
+    child_paths.into_iter().for_each(|part| {
+        assert!(part.exists(), "{} not found", part.to_string_lossy())
+    });
+}
+
+#[test]
+fn explicit_parts_dont_overwrite() {
+    let temp_dir = TempDir::new().unwrap();
+    let part = temp_dir.child("part");
+    part.write_binary(&TEST_BYTES[..50]).unwrap();
+    let existing = temp_dir.child(FILE_NAME);
+    existing.write_binary(&TEST_BYTES[50..]).unwrap();
+
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--parts", "part", "-o", FILE_NAME])
+        .assert()
+        .failure()
+        .code(2);
+
+    part.assert(&TEST_BYTES[..50]);
+    existing.assert(&TEST_BYTES[50..]);
+}
+
+fn write_manifest(temp_dir: &TempDir, part_sizes: &[usize]) {
+    let mut manifest = format!(
+        "chopstick manifest 1\nsize {}\n",
+        part_sizes.iter().sum::<usize>()
+    );
+    part_sizes.iter().enumerate().for_each(|(index, size)| {
+        manifest.push_str(&format!("part {} {}\n", index + 1, size))
+    });
+    temp_dir
+        .child(format!("{}.manifest", FILE_NAME))
+        .write_str(&manifest)
+        .expect("Failed to write manifest");
+}
+
+#[test]
+fn uneven_parts_with_manifest() {
+    let temp_dir = TempDir::new().unwrap();
+    let part_sizes = [13, 13, 13, 13, 12, 12, 12, 12];
+    let mut offset = 0;
+    part_sizes.iter().enumerate().for_each(|(index, size)| {
+        temp_dir
+            .child(format!("{}.{}{}", FILE_NAME, EXTENSION_PREFIX, index + 1))
+            .write_binary(&TEST_BYTES[offset..offset + size])
+            .expect("Failed to write part");
+        offset += size;
+    });
+    write_manifest(&temp_dir, &part_sizes);
+
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .arg(FILE_NAME)
+        .unwrap()
+        .assert()
+        .success();
+
+    temp_dir.child(FILE_NAME).assert(&TEST_BYTES[..]);
+    assert!(!temp_dir.child(format!("{}.manifest", FILE_NAME)).exists());
+}
+
+#[test]
+fn manifest_mismatch() {
+    let temp_dir = TempDir::new().unwrap();
+    (0..10).for_each(|n| {
+        temp_dir
+            .child(format!("{}.{}{:0>2}", FILE_NAME, EXTENSION_PREFIX, n + 1))
+            .write_binary(&TEST_BYTES[n * 10..n * 10 + 10])
+            .expect("Failed to write part");
+    });
+
+    // An eleventh part is missing
+    write_manifest(&temp_dir, &[10; 11]);
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .arg(FILE_NAME)
+        .assert()
+        .failure()
+        .code(1);
+
+    // The last part is the wrong size
+    fs::remove_file(temp_dir.child(format!("{}.manifest", FILE_NAME))).unwrap();
+    let mut part_sizes = [10; 10];
+    part_sizes[9] = 9;
+    part_sizes[0] = 11;
+    write_manifest(&temp_dir, &part_sizes);
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .arg(FILE_NAME)
+        .assert()
+        .failure()
+        .code(1);
+
+    assert!(!temp_dir.child(FILE_NAME).exists());
+    assert_eq!(WalkDir::new(&temp_dir).min_depth(1).into_iter().count(), 11);
+}
+
+#[test]
+fn defaults_from_environment() {
+    let temp_dir = TempDir::new().unwrap();
+    (0..4).for_each(|n| {
+        temp_dir
+            .child(format!("{}.{}{}", FILE_NAME, EXTENSION_PREFIX, n + 1))
+            .write_binary(&TEST_BYTES[n * 25..n * 25 + 25])
+            .expect("Failed to write part");
+    });
+
+    // The environment overrides the config file
+    let config_home = TempDir::new().unwrap();
+    config_home
+        .child("chopstick/config.toml")
+        .write_str("[stick]\nretain = false\nsort = \"name\"\n")
+        .unwrap();
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .env("CHOPSTICK_RETAIN", "yes")
+        .arg(FILE_NAME)
+        .assert()
+        .success();
+    temp_dir.child(FILE_NAME).assert(&TEST_BYTES[..]);
+    assert_eq!(WalkDir::new(&temp_dir).min_depth(1).into_iter().count(), 5);
+
+    // And the command line overrides both
+    fs::remove_file(temp_dir.child(FILE_NAME).path()).unwrap();
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .env("CHOPSTICK_RETAIN", "yes")
+        .args(["--no-retain", FILE_NAME])
+        .assert()
+        .success();
+    temp_dir.child(FILE_NAME).assert(&TEST_BYTES[..]);
+    assert_eq!(WalkDir::new(&temp_dir).min_depth(1).into_iter().count(), 1);
+
+    Command::cargo_bin("stick")
+        .unwrap()
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .env("CHOPSTICK_SORT", "backwards")
+        .arg("--print-config")
+        .assert()
+        .failure()
+        .code(1);
+}
+
+#[test]
+#[cfg(unix)]
+fn locked_by_another_run() {
+    use chopstick::lock::SetLock;
+    use std::process;
+    use std::thread;
+    use std::time::Duration;
+
+    let temp_dir = TempDir::new().unwrap();
+    (0..4).for_each(|n| {
+        temp_dir
+            .child(format!("{}.{}{}", FILE_NAME, EXTENSION_PREFIX, n + 1))
+            .write_binary(&TEST_BYTES[n * 25..n * 25 + 25])
+            .expect("Failed to write part");
+    });
+    let lock = SetLock::acquire(temp_dir.child(FILE_NAME).path(), false)
+        .expect("Failed to take lock");
+
+    let assert = Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .arg(FILE_NAME)
+        .assert()
+        .failure()
+        .code(1);
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains(&format!("PID {}", process::id())),
+        "Unexpected error: {}",
+        stderr
+    );
+    assert!(!temp_dir.child(FILE_NAME).exists());
+
+    // The parts' own file is locked when sticking them into another
+    let glob = format!("{}.{}*", FILE_NAME, EXTENSION_PREFIX);
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--glob", &glob, "--output", "other"])
+        .assert()
+        .failure()
+        .code(1);
+    assert!(!temp_dir.child("other").exists());
+
+    let mut waiting =
+        process::Command::new(assert_cmd::cargo::cargo_bin("stick"))
+            .current_dir(&temp_dir)
+            .args(["--wait", FILE_NAME])
+            .spawn()
+            .unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert!(waiting.try_wait().unwrap().is_none(), "stick didn't wait");
+    drop(lock);
+    assert!(waiting.wait().unwrap().success());
+    temp_dir.child(FILE_NAME).assert(&TEST_BYTES[..]);
+    assert_eq!(WalkDir::new(&temp_dir).min_depth(1).into_iter().count(), 1);
+}
+
+#[test]
+fn atomic() {
+    let temp_dir = TempDir::new().unwrap();
+    (0..4).for_each(|n| {
+        temp_dir
+            .child(format!("{}.{}{}", FILE_NAME, EXTENSION_PREFIX, n + 1))
+            .write_binary(&TEST_BYTES[n * 25..n * 25 + 25])
+            .expect("Failed to write part");
+    });
+
+    // A leftover from an earlier run isn't overwritten
+    let temp_file = temp_dir.child(format!("{}.stick-tmp", FILE_NAME));
+    temp_file.touch().unwrap();
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--atomic", FILE_NAME])
+        .assert()
+        .failure()
+        .code(2);
+    fs::remove_file(&temp_file).unwrap();
+
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--atomic", FILE_NAME])
+        .assert()
+        .success();
+    temp_dir.child(FILE_NAME).assert(&TEST_BYTES[..]);
+    assert_eq!(WalkDir::new(&temp_dir).min_depth(1).into_iter().count(), 1);
+}
+
+#[test]
+fn rate_limit() {
+    let temp_dir = TempDir::new().unwrap();
+    (0..10)
+        .map(|n| (n + 1, &TEST_BYTES[n * 10..n * 10 + 10]))
+        .try_for_each(|(part_no, slice)| {
+            let child_path =
+                format!("{}.{}{:0>2}", FILE_NAME, EXTENSION_PREFIX, part_no);
+            temp_dir.child(&child_path).write_binary(slice)
+        })
+        .expect("Failed to setup test: writing temp file");
+
+    // The first part is renamed rather than copied, and the other 90 bytes
+    // take at least 225ms at 400 bytes a second
+    let started = Instant::now();
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--rate-limit", "400/s", "--ionice", "idle", FILE_NAME])
+        .unwrap()
+        .assert()
+        .success();
+    assert!(started.elapsed() >= Duration::from_millis(225));
+    temp_dir.child(FILE_NAME).assert(&TEST_BYTES[..]);
+
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--rate-limit", "fast", FILE_NAME])
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn direct() {
+    let temp_dir = TempDir::new().unwrap();
+    // Parts that aren't a whole number of blocks, so most of each is read
+    // directly but its start and end aren't
+    let bytes = (0..30_000).map(|n| (n * 7 % 251) as u8).collect::<Vec<_>>();
+    bytes
+        .chunks(10_000)
+        .enumerate()
+        .try_for_each(|(n, slice)| {
+            let child_path =
+                format!("{}.{}{}", FILE_NAME, EXTENSION_PREFIX, n + 1);
+            temp_dir.child(&child_path).write_binary(slice)
+        })
+        .expect("Failed to setup test: writing temp file");
+
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--direct", "--drop-cache", "--retain", FILE_NAME])
+        .unwrap()
+        .assert()
+        .success();
+    assert_eq!(fs::read(temp_dir.child(FILE_NAME).path()).unwrap(), bytes);
+
+    fs::remove_file(temp_dir.child(FILE_NAME).path()).unwrap();
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--direct", "--pipeline", FILE_NAME])
+        .unwrap()
+        .assert()
+        .success();
+    assert_eq!(fs::read(temp_dir.child(FILE_NAME).path()).unwrap(), bytes);
+}
+
+fn write_archive(format: ArchiveFormat, part_sizes: &[usize]) -> Vec<u8> {
+    let manifest = format!(
+        "chopstick manifest 1\nsize {}\n{}",
+        part_sizes.iter().sum::<usize>(),
+        part_sizes
+            .iter()
+            .enumerate()
+            .map(|(n, size)| format!("part {} {}\n", n + 1, size))
+            .collect::<String>(),
+    );
+    let mut writer = ArchiveWriter::new(format, Cursor::new(Vec::new()));
+    writer
+        .add_entry(
+            &format!("{}.manifest", FILE_NAME),
+            manifest.len() as u64,
+            manifest.as_bytes(),
+        )
+        .unwrap();
+    let mut offset = 0;
+    part_sizes.iter().enumerate().for_each(|(n, &size)| {
+        let name = format!("{}.{}{}", FILE_NAME, EXTENSION_PREFIX, n + 1);
+        let part = &TEST_BYTES[offset..offset + size];
+        writer.add_entry(&name, size as u64, part).unwrap();
+        offset += size;
+    });
+    writer.finish().unwrap().into_inner()
+}
+
+#[test]
+fn from_archive() {
+    for format in [ArchiveFormat::Tar, ArchiveFormat::Zip] {
+        let temp_dir = TempDir::new().unwrap();
+        let archive = temp_dir.child(format.path_for(FILE_NAME));
+        archive
+            .write_binary(&write_archive(format, &[40, 30, 30]))
+            .unwrap();
+
+        Command::cargo_bin("stick")
+            .unwrap()
+            .current_dir(&temp_dir)
+            .args(["--from-archive", &archive.path().to_string_lossy()])
+            .unwrap()
+            .assert()
+            .success();
+        temp_dir.child(FILE_NAME).assert(&TEST_BYTES[..]);
+        assert_eq!(WalkDir::new(&temp_dir).min_depth(1).into_iter().count(), 1);
+
+        // From stdin, which needs to be told where the file goes
+        fs::remove_file(temp_dir.child(FILE_NAME).path()).unwrap();
+        Command::cargo_bin("stick")
+            .unwrap()
+            .current_dir(&temp_dir)
+            .args(["--from-archive", "-"])
+            .write_stdin(write_archive(format, &[40, 30, 30]))
+            .assert()
+            .failure()
+            .code(1);
+        Command::cargo_bin("stick")
+            .unwrap()
+            .current_dir(&temp_dir)
+            .args(["--atomic", "--from-archive", "-", "-o", FILE_NAME])
+            .write_stdin(write_archive(format, &[40, 30, 30]))
+            .assert()
+            .success();
+        temp_dir.child(FILE_NAME).assert(&TEST_BYTES[..]);
+    }
+}
+
+#[test]
+fn from_archive_mismatch() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut bytes = write_archive(ArchiveFormat::Tar, &[40, 30, 30]);
+    // Move a byte from the last part to the one before it in the manifest
+    let listed = b"part 2 30\npart 3 30";
+    let start = bytes
+        .windows(listed.len())
+        .position(|w| w == listed)
+        .unwrap();
+    bytes[start..start + listed.len()].copy_from_slice(b"part 2 31\npart 3 29");
+    let archive = temp_dir.child(ArchiveFormat::Tar.path_for(FILE_NAME));
+    archive.write_binary(&bytes).unwrap();
+
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(&temp_dir)
+        .args(["--from-archive", &archive.path().to_string_lossy()])
+        .assert()
+        .failure()
+        .code(1);
+    // Nothing is deleted when the archive doesn't add up
+    assert_eq!(fs::read(archive.path()).unwrap(), bytes);
+}
+
+// Waits up to 10s for `path` to exist
+fn wait_for(path: &std::path::Path) -> bool {
+    let started = Instant::now();
+    while !path.exists() && started.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    path.exists()
+}
+
+// The --then command needs a POSIX shell
+#[cfg(unix)]
+#[test]
+fn watch() {
+    use std::process;
+
+    // Killed on drop, so a failed assert doesn't leave it running
+    struct Watching(process::Child);
+    impl Drop for Watching {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    let watching = Watching(
+        process::Command::new(assert_cmd::cargo::cargo_bin("stick"))
+            .current_dir(&temp_dir)
+            .args(["--watch", ".", "--quiet-period", "1"])
+            .args(["--then", "cp \"$FILE\" \"$FILE.done\""])
+            .spawn()
+            .unwrap(),
+    );
+
+    // With a manifest, a set is stuck as soon as it's all there
+    write_manifest(&temp_dir, &[50, 50]);
+    let first = temp_dir.child(format!("{}.{}1", FILE_NAME, EXTENSION_PREFIX));
+    first.write_binary(&TEST_BYTES[..50]).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(!temp_dir.child(FILE_NAME).exists(), "Stuck one part");
+    temp_dir
+        .child(format!("{}.{}2", FILE_NAME, EXTENSION_PREFIX))
+        .write_binary(&TEST_BYTES[50..])
+        .unwrap();
+    let done = temp_dir.child(format!("{}.done", FILE_NAME));
+    assert!(wait_for(done.path()), "Set with a manifest wasn't stuck");
+    temp_dir.child(FILE_NAME).assert(&TEST_BYTES[..]);
+    done.assert(&TEST_BYTES[..]);
+
+    // Without one, once the parts have stopped changing
+    (0..4).for_each(|n| {
+        temp_dir
+            .child(format!("other.{}{}", EXTENSION_PREFIX, n + 1))
+            .write_binary(&TEST_BYTES[n * 25..n * 25 + 25])
+            .unwrap();
+    });
+    // The file exists as soon as the first part is renamed, so wait for the
+    // --then command, which only runs once it's complete
+    let done = temp_dir.child("other.done");
+    assert!(wait_for(done.path()), "Set without a manifest wasn't stuck");
+    temp_dir.child("other").assert(&TEST_BYTES[..]);
+
+    drop(watching);
+    let left = WalkDir::new(&temp_dir).min_depth(1).into_iter().count();
+    assert_eq!(left, 4, "Only the files made should be left");
+}
+
+#[test]
+fn undo() {
+    let temp_dir = TempDir::new().unwrap();
+    // So that the journal isn't shared with anything else
+    let state_home = TempDir::new().unwrap();
+    let part_sizes = [30, 30, 40];
+    let part = |number| {
+        temp_dir.child(format!("{}.{}{}", FILE_NAME, EXTENSION_PREFIX, number))
+    };
+    let manifest = temp_dir.child(format!("{}.manifest", FILE_NAME));
+    // With hashes, which have to survive the undo too
+    let write_parts = || {
+        let mut offset = 0;
+        let hashes = part_sizes
+            .iter()
+            .zip(1..)
+            .map(|(size, number)| {
+                let bytes = &TEST_BYTES[offset..offset + size];
+                part(number)
+                    .write_binary(bytes)
+                    .expect("Failed to write part");
+                offset += size;
+                format!("{:x}", Sha256::digest(bytes))
+            })
+            .collect();
+        let contents = Manifest {
+            file_size: TEST_BYTES.len() as u64,
+            part_sizes: part_sizes.iter().map(|&size| size as u64).collect(),
+            part_hashes: Some(hashes),
+        }
+        .to_string();
+        manifest
+            .write_str(&contents)
+            .expect("Failed to write manifest");
+        contents
+    };
+    let stick = |args: &[&str]| {
+        Command::cargo_bin("stick")
+            .unwrap()
+            .current_dir(&temp_dir)
+            .env("XDG_STATE_HOME", state_home.path())
+            .env("CHOPSTICK_JOURNAL", "1")
+            .args(args)
+            .arg(FILE_NAME)
+            .assert()
+    };
+    let original = temp_dir.child(FILE_NAME);
+
+    let manifest_contents = write_parts();
+    stick(&[]).success();
+    stick(&["--undo"]).success();
+    assert!(!original.exists());
+    let mut offset = 0;
+    part_sizes.iter().zip(1..).for_each(|(size, number)| {
+        part(number).assert(&TEST_BYTES[offset..offset + size]);
+        offset += size;
+    });
+    manifest.assert(manifest_contents.as_str());
+    // It's already been undone
+    stick(&["--undo"]).failure().code(1);
+
+    // With --retain, only the file made is deleted
+    stick(&["--retain"]).success();
+    stick(&["--undo"]).success();
+    assert!(!original.exists());
+    assert_eq!(WalkDir::new(&temp_dir).min_depth(1).into_iter().count(), 4);
+
+    // Nothing's touched once anything has changed
+    stick(&[]).success();
+    original.write_binary(&TEST_BYTES[..50]).unwrap();
+    stick(&["--undo"]).failure().code(1);
+    assert!(!part(1).exists());
+    original.assert(&TEST_BYTES[..50]);
+
+    // Nothing's recorded with the journal off, and nothing can be undone
+    fs::remove_file(original.path()).unwrap();
+    write_parts();
+    stick(&["--no-journal"]).success();
+    stick(&["--undo"]).failure().code(1);
+    stick(&["--undo", "--no-journal"]).failure().code(1);
+    original.assert(&TEST_BYTES[..]);
+}
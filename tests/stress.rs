@@ -3,7 +3,8 @@ use assert_cmd::Command;
 use assert_fs::fixture::ChildPath;
 use assert_fs::prelude::*;
 use assert_fs::TempDir;
-use chopstick::{digits, round_up_div, EXTENSION_PREFIX};
+use chopstick::digits;
+use chopstick::split::{get_part_path_buf, Split};
 use rand::prelude::*;
 use rand_pcg::Pcg64;
 use std::cmp::min;
@@ -14,61 +15,24 @@ const FIVE_HUNGE_KIB: usize = 500 * 1024;
 //const ONE_HUNGE_MIB: usize = 100 * 1024 * 1024;
 //const FIVE_GIB: u64 = 5 * 1024 * 1024 * 1024;
 
-// Dangerous amounts of code re-use from chop/lib.rs
-struct Split {
-    part_size: u64,
-    num_parts: u64,
-    flag: &'static str,
+#[derive(Copy, Clone, Debug)]
+enum SplitBy {
+    PartSize,
+    NumParts,
 }
 
-impl Split {
-    fn from_part_size(file_size: u64, part_size: u64) -> Self {
-        if part_size >= file_size {
-            panic!("Part size greater than file size")
-        } else {
-            let (part_size, num_parts) =
-                Split::closest_factors_to(file_size, part_size);
-            Split {
-                part_size,
-                num_parts,
-                flag: "-s",
-            }
+impl SplitBy {
+    fn flag(&self) -> &'static str {
+        match self {
+            SplitBy::PartSize => "-s",
+            SplitBy::NumParts => "-n",
         }
     }
 
-    fn from_num_parts(file_size: u64, num_parts: u64) -> Self {
-        if num_parts >= file_size {
-            panic!("Number of parts greater than file size")
-        } else {
-            let (num_parts, part_size) =
-                Split::closest_factors_to(file_size, num_parts);
-            Split {
-                part_size,
-                num_parts,
-                flag: "-n",
-            }
-        }
-    }
-
-    const fn closest_factors_to(target: u64, divisor: u64) -> (u64, u64) {
-        let factor_two = round_up_div(target, divisor);
-        /*
-        In the edge case where the user's choice (`divisor`) would result in
-        an empty part (see test `disobey` below), the expression
-        `(divisor - 1).saturating_sub(target / factor_two)`
-        will be >0, thus disregarding their choice to reduce the error in
-        reproducing the `target`
-        */
-        let factor_one =
-            divisor - (divisor - 1).saturating_sub(target / factor_two);
-        (factor_one, factor_two)
-    }
-
-    fn flag_val(&self) -> u64 {
-        match self.flag {
-            "-s" => self.part_size,
-            "-n" => self.num_parts,
-            _ => unreachable!(),
+    fn flag_val(&self, split: &Split) -> u64 {
+        match self {
+            SplitBy::PartSize => split.part_size,
+            SplitBy::NumParts => split.num_parts,
         }
     }
 }
@@ -132,22 +96,22 @@ struct TestScenario<const N: usize> {
 }
 
 impl<const N: usize> TestScenario<N> {
-    fn run_with(&self, split: Split) {
+    fn run_with(&self, split_by: SplitBy, split: Split) {
         println!(
             "Chopping {N} byte file into {} parts, {} bytes each",
             split.num_parts, split.part_size,
         );
 
         let flag_val = match self.bytesize_formatted {
-            true => bytesize::to_string(split.flag_val(), true),
-            false => split.flag_val().to_string(),
+            true => bytesize::to_string(split_by.flag_val(&split), true),
+            false => split_by.flag_val(&split).to_string(),
         };
 
         // Chop
         Command::cargo_bin("chop")
             .unwrap()
-            .args(&[
-                split.flag,
+            .args([
+                split_by.flag(),
                 &flag_val,
                 &self.original_file.path().to_string_lossy(),
             ])
@@ -158,17 +122,15 @@ impl<const N: usize> TestScenario<N> {
 
         // Check intermediary parts
         (0..split.num_parts)
-            .into_iter()
             .map(|n| (n + 1, (n * split.part_size) as usize))
             .for_each(|(part_no, file_bytes_offset)| {
-                let child_path = format!(
-                    "{FILE_NAME}.{EXTENSION_PREFIX}{:0>width$}",
+                let part = get_part_path_buf(
+                    self.original_file.path(),
                     part_no,
-                    width = digits(split.num_parts),
+                    digits(split.num_parts),
                 );
-                let part = self.temp_dir.child(&child_path);
-                let part_bytes = fs::read(part.path()).unwrap_or_else(|_| {
-                    panic!("Unable to find/read {:?}", &part.path())
+                let part_bytes = fs::read(&part).unwrap_or_else(|_| {
+                    panic!("Unable to find/read {:?}", &part)
                 });
                 let end_index = min(
                     self.file_bytes.len(),
@@ -220,19 +182,21 @@ impl<const N: usize> Default for TestScenario<N> {
 #[test]
 fn num_parts() {
     let test = TestScenario::<FIVE_HUNGE_KIB>::default();
-    test.run_with(Split::from_num_parts(
+    let split = Split::from_num_parts(
         FIVE_HUNGE_KIB as u64,
         thread_rng().gen_range(10..=1000),
-    ));
+    );
+    test.run_with(SplitBy::NumParts, split.unwrap());
 }
 
 #[test]
 fn part_size() {
     let test = TestScenario::<FIVE_HUNGE_KIB>::default();
-    test.run_with(Split::from_part_size(
+    let split = Split::from_part_size(
         FIVE_HUNGE_KIB as u64,
         thread_rng().gen_range(10..=50 * 1024),
-    ));
+    );
+    test.run_with(SplitBy::PartSize, split.unwrap());
 }
 
 #[test]
@@ -243,7 +207,37 @@ fn formatted_part_size() {
     // Ensure this is a factor of the file size else rounding errors will occur
     let part_size = 125 * 1024;
     println!("Using {} parts", bytesize::to_string(part_size, true));
-    test.run_with(Split::from_part_size(FIVE_HUNGE_KIB as u64, part_size));
+    let split = Split::from_part_size(FIVE_HUNGE_KIB as u64, part_size);
+    test.run_with(SplitBy::PartSize, split.unwrap());
+}
+
+#[test]
+fn content_defined() {
+    let test = TestScenario::<FIVE_HUNGE_KIB>::default();
+    Command::cargo_bin("chop")
+        .unwrap()
+        .args(["-c", "16KiB", &test.original_file.path().to_string_lossy()])
+        .unwrap()
+        .assert()
+        .success();
+    println!("Ran chop");
+
+    Command::cargo_bin("stick")
+        .unwrap()
+        .current_dir(test.temp_dir.path())
+        .arg(FILE_NAME)
+        .unwrap()
+        .assert()
+        .success();
+    println!("Ran stick");
+
+    let reconstructed_bytes = fs::read(test.original_file.path())
+        .expect("Unable to find/read reconstructed file");
+    assert_eq!(
+        reconstructed_bytes.as_slice(),
+        &test.file_bytes[..],
+        "File contents differs",
+    );
 }
 
 #[test]
@@ -258,7 +252,8 @@ fn something_specific() {
         .bytesize_formatted(true)
         //.persist(true)
         .build();
-    test.run_with(Split::from_part_size(FIVE_HUNGE_KIB as u64, part_size));
+    let split = Split::from_part_size(FIVE_HUNGE_KIB as u64, part_size);
+    test.run_with(SplitBy::PartSize, split.unwrap());
 }
 
 // TODO: large files, relative directories